@@ -0,0 +1,154 @@
+use anyhow::Result;
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use crate::compression::CompressionError;
+use crate::file_utils::TempFile;
+
+// Content-defined chunk storage for backups (the Chunks format). Files are cut into
+// chunks with FastCDC, so an edit only changes the chunks around it, and each chunk is
+// stored once, zstd-compressed, under its SHA-256 in a store folder beside the archive.
+// The archive itself is only a manifest listing every file's chunks, so successive
+// backups written to the same folder share all the chunks that didn't change.
+//
+// This is TauZip's own layout: no other tool can read it, and a manifest is useless
+// without the store folder next to it.
+
+// Folder the chunks are kept in, next to the manifests that use them
+pub const STORE_DIR: &str = "tauzip-chunks";
+const MANIFEST_VERSION: u32 = 1;
+
+// Chunk size bounds; large enough to keep the chunk count of big files manageable
+const MIN_CHUNK: u32 = 256 * 1024;
+const AVG_CHUNK: u32 = 1024 * 1024;
+pub const MAX_CHUNK: u32 = 4 * 1024 * 1024;
+const CHUNK_LEVEL: i32 = 3;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub version: u32,
+    pub entries: Vec<ChunkEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    // Path inside the backup, with forward slashes
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Seconds since the epoch
+    pub mtime: u64,
+    // Hex SHA-256 of each chunk, in file order
+    pub chunks: Vec<String>,
+}
+
+impl ChunkManifest {
+    pub fn new() -> Self {
+        Self { version: MANIFEST_VERSION, entries: Vec::new() }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let manifest: ChunkManifest = serde_json::from_reader(std::io::BufReader::new(File::open(path)?))
+            .map_err(|e| CompressionError::Corrupt { reason: format!("unreadable chunk manifest: {}", e) })?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(CompressionError::Unsupported { feature: format!("chunk manifest version {}", manifest.version) }.into());
+        }
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    // The store beside a manifest that is being written, created if needed
+    pub fn create_beside(manifest_path: &Path) -> Result<Self> {
+        let dir = store_dir(manifest_path);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    // The store an existing manifest refers to
+    pub fn open_beside(manifest_path: &Path) -> Result<Self> {
+        let dir = store_dir(manifest_path);
+        if !dir.is_dir() {
+            return Err(anyhow::anyhow!("Chunk store folder not found: {}", dir.display()));
+        }
+        Ok(Self { dir })
+    }
+
+    // Two hex digits of fan-out keep any one folder from growing huge
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    // Cut `reader` into chunks and store the ones not already there. Returns the chunk
+    // hashes and how many bytes were new to the store.
+    pub fn put_stream<R: Read>(&self, reader: R) -> Result<(Vec<String>, u64)> {
+        let mut hashes = Vec::new();
+        let mut new_bytes = 0u64;
+        for chunk in StreamCDC::new(reader, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+            let chunk = chunk.map_err(|e| anyhow::anyhow!("Failed to read chunk: {:?}", e))?;
+            let hash = format!("{:x}", Sha256::digest(&chunk.data));
+            if self.put(&hash, &chunk.data)? {
+                new_bytes += chunk.length as u64;
+            }
+            hashes.push(hash);
+        }
+        Ok((hashes, new_bytes))
+    }
+
+    // Store one chunk unless it is already there; true when it was new
+    fn put(&self, hash: &str, data: &[u8]) -> Result<bool> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            return Ok(false);
+        }
+        let dir = self.dir.join(&hash[..2]);
+        std::fs::create_dir_all(&dir)?;
+
+        // Written aside and renamed, so a crash never leaves a truncated chunk under a
+        // name that later backups would trust
+        let (temp_file, mut file) = TempFile::new_in(&dir, ".chunk-")?;
+        file.write_all(&zstd::encode_all(data, CHUNK_LEVEL)?)?;
+        file.sync_all()?;
+        drop(file);
+        temp_file.persist(&path)?;
+        Ok(true)
+    }
+
+    // Write the data of `chunks` out in order, checking each against its hash
+    pub fn copy_chunks<W: Write>(&self, chunks: &[String], writer: &mut W) -> Result<u64> {
+        let mut written = 0u64;
+        for hash in chunks {
+            // A hash is also a file name, so it must not be able to point elsewhere
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(CompressionError::Corrupt { reason: format!("invalid chunk hash '{}'", hash) }.into());
+            }
+            let compressed = std::fs::read(self.chunk_path(hash))
+                .map_err(|e| CompressionError::Corrupt { reason: format!("chunk {} is missing: {}", hash, e) })?;
+            let data = zstd::decode_all(&compressed[..])
+                .map_err(|e| CompressionError::Corrupt { reason: format!("chunk {} is damaged: {}", hash, e) })?;
+            if format!("{:x}", Sha256::digest(&data)) != *hash {
+                return Err(CompressionError::Corrupt { reason: format!("chunk {} does not match its hash", hash) }.into());
+            }
+            writer.write_all(&data)?;
+            written += data.len() as u64;
+        }
+        Ok(written)
+    }
+}
+
+fn store_dir(manifest_path: &Path) -> PathBuf {
+    let parent = manifest_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    parent.join(STORE_DIR)
+}
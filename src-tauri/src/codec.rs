@@ -0,0 +1,272 @@
+use anyhow::Result;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock};
+use crate::compression::{decompress_files_with_progress, write_builtin_format, CompressContext, CompressOptions, CompressionType, DedupeSummary, ExtractOptions, ExtractSummary, WalkEntry};
+
+// The formats TauZip can write, looked up by name ("Zip", "TarGz", ...). Every archive
+// is written by the codec registered under its format's name. The built-in formats are
+// registered the first time the registry is used; an embedder can add its own (or
+// replace a built-in) with register_codec, and it then shows up in the format list,
+// name parsing and extension lookups like any other.
+
+pub type ProgressFn<'a> = Box<dyn FnMut(f64, String) + Send + 'a>;
+pub type CodecFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+// What Codec::compress wrote
+#[derive(Debug, Default)]
+pub struct CodecOutput {
+    // The files and folders that went in, for CompressOptions::emit_manifest. A codec
+    // that leaves this out can't be used with a manifest.
+    pub entries: Option<Vec<WalkEntry>>,
+    pub dedupe: DedupeSummary,
+}
+
+pub trait Codec: Send + Sync {
+    // Name used by the frontend and in CompressionType::Custom
+    fn name(&self) -> &str;
+    // File name endings with their leading dot, longest match wins; the first one is
+    // given to new archives
+    fn extensions(&self) -> &[&str];
+    fn supports_multiple_files(&self) -> bool;
+    // Write `files` into a new archive at `output_path`. The caller has already
+    // checked the file count and takes care of the scratch file and cancellation;
+    // `context` is the state the compression shares, to pass on to write_builtin_format.
+    fn compress<'a>(
+        &'a self,
+        files: &'a [PathBuf],
+        output_path: &'a Path,
+        options: &'a CompressOptions,
+        context: &'a CompressContext,
+        progress: ProgressFn<'a>,
+    ) -> CodecFuture<'a, CodecOutput>;
+    fn decompress<'a>(
+        &'a self,
+        file_path: &'a Path,
+        output_dir: &'a Path,
+        options: &'a ExtractOptions,
+        progress: ProgressFn<'a>,
+    ) -> CodecFuture<'a, ExtractSummary>;
+}
+
+// One of the formats implemented in compression.rs
+struct BuiltinCodec {
+    name: &'static str,
+    compression_type: CompressionType,
+    extensions: &'static [&'static str],
+    multiple_files: bool,
+}
+
+impl Codec for BuiltinCodec {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn extensions(&self) -> &[&str] {
+        self.extensions
+    }
+
+    fn supports_multiple_files(&self) -> bool {
+        self.multiple_files
+    }
+
+    fn compress<'a>(
+        &'a self,
+        files: &'a [PathBuf],
+        output_path: &'a Path,
+        options: &'a CompressOptions,
+        context: &'a CompressContext,
+        progress: ProgressFn<'a>,
+    ) -> CodecFuture<'a, CodecOutput> {
+        Box::pin(async move {
+            let (entries, dedupe) = write_builtin_format(files, output_path, &self.compression_type, options, context, progress).await?;
+            Ok(CodecOutput { entries: Some(entries), dedupe })
+        })
+    }
+
+    // The built-in readers pick the format from the file itself
+    fn decompress<'a>(
+        &'a self,
+        file_path: &'a Path,
+        output_dir: &'a Path,
+        options: &'a ExtractOptions,
+        progress: ProgressFn<'a>,
+    ) -> CodecFuture<'a, ExtractSummary> {
+        Box::pin(decompress_files_with_progress(file_path, output_dir, options, progress))
+    }
+}
+
+fn builtin_codecs() -> Vec<Registered> {
+    let builtin = |name: &'static str, compression_type: CompressionType, extensions: &'static [&'static str], multiple_files: bool| Registered {
+        codec: Arc::new(BuiltinCodec { name, compression_type: compression_type.clone(), extensions, multiple_files }),
+        compression_type,
+    };
+    vec![
+        builtin("Zip", CompressionType::Zip, &[".zip"], true),
+        builtin("TarGz", CompressionType::TarGz, &[".tar.gz", ".tgz"], true),
+        builtin("TarBr", CompressionType::TarBr, &[".tar.br"], true),
+        builtin("Gz", CompressionType::Gz, &[".gz"], false),
+        builtin("Br", CompressionType::Br, &[".br"], false),
+        builtin("Gzip", CompressionType::Gzip, &[".gzip"], false),
+        builtin("Bzip2", CompressionType::Bzip2, &[".bz2", ".bzip2"], false),
+        builtin("Cpio", CompressionType::Cpio, &[".cpio"], true),
+        builtin("CpioGz", CompressionType::CpioGz, &[".cpio.gz"], true),
+        builtin("TarZst", CompressionType::TarZst, &[".tar.zst", ".tzst"], true),
+        builtin("Chunks", CompressionType::Chunks, &[".tzchunks"], true),
+    ]
+}
+
+struct Registered {
+    codec: Arc<dyn Codec>,
+    // What compress_files_with_progress is given for this codec
+    compression_type: CompressionType,
+}
+
+static REGISTRY: OnceLock<RwLock<Vec<Registered>>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<Vec<Registered>> {
+    REGISTRY.get_or_init(|| RwLock::new(builtin_codecs()))
+}
+
+// Add a codec, replacing any registered under the same name (built-ins included)
+pub fn register_codec(codec: Arc<dyn Codec>) {
+    let compression_type = CompressionType::Custom(codec.name().to_string());
+    let mut codecs = registry().write().unwrap_or_else(|e| e.into_inner());
+    match codecs.iter_mut().find(|registered| registered.codec.name() == codec.name()) {
+        Some(registered) => *registered = Registered { codec, compression_type },
+        None => codecs.push(Registered { codec, compression_type }),
+    }
+}
+
+pub fn find_codec(name: &str) -> Option<Arc<dyn Codec>> {
+    registry().read().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|registered| registered.codec.name() == name)
+        .map(|registered| registered.codec.clone())
+}
+
+// Registered names in registration order, built-ins first
+pub fn codec_names() -> Vec<String> {
+    registry().read().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|registered| registered.codec.name().to_string())
+        .collect()
+}
+
+pub fn compression_type_for(name: &str) -> Option<CompressionType> {
+    registry().read().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|registered| registered.codec.name() == name)
+        .map(|registered| registered.compression_type.clone())
+}
+
+// The compression type whose codec claims this ending, e.g. ".tgz"
+pub fn compression_type_for_extension(ext: &str) -> Option<CompressionType> {
+    let ext = ext.to_lowercase();
+    registry().read().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|registered| registered.codec.extensions().iter().any(|candidate| candidate.to_lowercase() == ext))
+        .map(|registered| registered.compression_type.clone())
+}
+
+// A registered (non built-in) codec that reads this file, judged by the longest
+// extension its name ends with
+pub fn custom_codec_for_file(path: &Path) -> Option<Arc<dyn Codec>> {
+    let file_name = path.file_name()?.to_string_lossy().to_lowercase();
+    let codecs = registry().read().unwrap_or_else(|e| e.into_inner());
+    codecs.iter()
+        .filter(|registered| matches!(registered.compression_type, CompressionType::Custom(_)))
+        .filter_map(|registered| {
+            registered.codec.extensions().iter()
+                .filter(|ext| file_name.ends_with(&ext.to_lowercase()))
+                .map(|ext| ext.len())
+                .max()
+                .map(|len| (len, registered.codec.clone()))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, codec)| codec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{compress_files_with_progress, CompressionError, ManifestFormat};
+
+    // Stores its one file with the bytes in reverse order
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn name(&self) -> &str {
+            "Reversed"
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &[".rev"]
+        }
+
+        fn supports_multiple_files(&self) -> bool {
+            false
+        }
+
+        fn compress<'a>(
+            &'a self,
+            files: &'a [PathBuf],
+            output_path: &'a Path,
+            _options: &'a CompressOptions,
+            _context: &'a CompressContext,
+            mut progress: ProgressFn<'a>,
+        ) -> CodecFuture<'a, CodecOutput> {
+            Box::pin(async move {
+                let mut data = std::fs::read(&files[0])?;
+                data.reverse();
+                std::fs::write(output_path, data)?;
+                progress(100.0, "Complete".to_string());
+                Ok(CodecOutput::default())
+            })
+        }
+
+        fn decompress<'a>(
+            &'a self,
+            file_path: &'a Path,
+            output_dir: &'a Path,
+            _options: &'a ExtractOptions,
+            _progress: ProgressFn<'a>,
+        ) -> CodecFuture<'a, ExtractSummary> {
+            Box::pin(async move {
+                let mut data = std::fs::read(file_path)?;
+                data.reverse();
+                std::fs::write(output_dir.join(file_path.file_stem().unwrap_or_default()), data)?;
+                Ok(ExtractSummary::default())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_codec_round_trips() {
+        register_codec(Arc::new(Reversed));
+        assert!(codec_names().iter().any(|name| name == "Reversed"));
+        let compression_type = CompressionType::from_name("Reversed").unwrap();
+        assert!(matches!(&compression_type, CompressionType::Custom(name) if name == "Reversed"));
+        assert!(matches!(compression_type_for_extension(".REV"), Some(CompressionType::Custom(_))));
+        assert_eq!(compression_type.extension(), ".rev");
+        
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("notes.txt");
+        std::fs::write(&source, b"abc123").unwrap();
+        let output = dir.path().join("notes.txt.rev");
+        compress_files_with_progress(&[source.clone()], &output, compression_type.clone(), &CompressOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"321cba");
+        assert!(custom_codec_for_file(&output).is_some());
+        
+        let extract_dir = dir.path().join("out");
+        decompress_files_with_progress(&output, &extract_dir, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(extract_dir.join("notes.txt")).unwrap(), b"abc123");
+        
+        // The codec doesn't list its entries, so a manifest can't be written
+        let options = CompressOptions { emit_manifest: Some(ManifestFormat::Json), ..CompressOptions::default() };
+        let error = compress_files_with_progress(&[source], &dir.path().join("again.rev"), compression_type, &options, |_, _| {}).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<CompressionError>(), Some(CompressionError::Unsupported { .. })), "{:?}", error);
+        assert!(!dir.path().join("again.rev").exists());
+    }
+}
@@ -1,11 +1,20 @@
 use anyhow::{Context, Result};
 use flate2::{write::GzEncoder, Compression as FlateCompression, GzBuilder};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write, Read};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write, Read, Seek};
 use std::path::{Path, PathBuf};
 use tar::Builder as TarBuilder;
 use zip::{write::FileOptions, ZipWriter};
+use crate::file_utils::{available_space, default_max_open_files, get_unique_name, jobs_dir, name_bytes, path_from_bytes, read_file_list, real_dir, sync_dir, DirNaming, LimitedFile, OpenFileLimit, SparseWriter, TempFile};
+use crate::chunkstore::{ChunkEntry, ChunkManifest, ChunkStore, MAX_CHUNK, STORE_DIR};
+use crate::codec;
+use crate::cpio::{CpioHeader, CpioReader, CpioWriter};
+use crate::deflate;
+use crate::iso::{IsoEntry, IsoImage};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompressionType {
@@ -16,827 +25,8572 @@ pub enum CompressionType {
     Br,
     Gzip,
     Bzip2,
+    Cpio,
+    CpioGz,
+    TarZst,
+    // Backup manifest plus a shared chunk store; TauZip-only, see chunkstore.rs
+    Chunks,
+    // Resolved to one of the above by sampling the inputs; see choose_auto_format
+    Auto,
+    // A codec added with codec::register_codec, by name
+    Custom(String),
 }
 
+// Extensions and capabilities come from the codec registry (see codec.rs), so a
+// registered codec is described the same way as a built-in one
 impl CompressionType {
-    pub fn extension(&self) -> &'static str {
+    pub fn name(&self) -> String {
         match self {
-            CompressionType::Zip => ".zip",
-            CompressionType::TarGz => ".tar.gz",
-            CompressionType::TarBr => ".tar.br",
-            CompressionType::Gz => ".gz",
-            CompressionType::Br => ".br",
-            CompressionType::Gzip => ".gzip",
-            CompressionType::Bzip2 => ".bz2",
+            CompressionType::Custom(name) => name.clone(),
+            other => format!("{:?}", other),
         }
     }
 
-    pub fn from_extension(ext: &str) -> Option<Self> {
-        match ext.to_lowercase().as_str() {
-            ".zip" => Some(CompressionType::Zip),
-            ".tar.gz" | ".tgz" => Some(CompressionType::TarGz),
-            ".tar.br" => Some(CompressionType::TarBr),
-            ".gz" => Some(CompressionType::Gz),
-            ".br" => Some(CompressionType::Br),
-            ".gzip" => Some(CompressionType::Gzip),
-            ".bz2" | ".bzip2" => Some(CompressionType::Bzip2),
-            _ => None,
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Auto" => Some(CompressionType::Auto),
+            _ => codec::compression_type_for(name),
+        }
+    }
+
+    pub fn extension(&self) -> String {
+        match self {
+            // The chosen format's extension is added once it is known
+            CompressionType::Auto => String::new(),
+            _ => codec::find_codec(&self.name())
+                .and_then(|codec| codec.extensions().first().map(|ext| ext.to_string()))
+                .unwrap_or_default(),
         }
     }
 
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        codec::compression_type_for_extension(ext)
+    }
+
     pub fn supports_multiple_files(&self) -> bool {
         match self {
-            CompressionType::Zip | CompressionType::TarGz | CompressionType::TarBr => true,
-            CompressionType::Gz | CompressionType::Br | CompressionType::Gzip | CompressionType::Bzip2 => false,
+            CompressionType::Auto => true,
+            _ => codec::find_codec(&self.name()).is_some_and(|codec| codec.supports_multiple_files()),
         }
     }
 }
 
-pub async fn compress_files(
-    files: &[PathBuf],
-    output_path: &Path,
-    compression_type: CompressionType,
-) -> Result<()> {
-    compress_files_with_progress(files, output_path, compression_type, |_, _| {}).await
+// Errors the frontend needs to tell apart (e.g. to show a password prompt).
+// They travel inside anyhow::Error and can be recovered with downcast_ref.
+#[derive(Debug)]
+pub enum CompressionError {
+    NeedsPassword,
+    WrongPassword,
+    WeakPassword { reason: String },
+    Cancelled,
+    // The archive relies on something TauZip can't read, e.g. "strong encryption"
+    Unsupported { feature: String },
+    // The data doesn't match its own checksum or length, e.g. a truncated download
+    Corrupt { reason: String },
+    // The archive looks built to cause harm on extraction, e.g. absurdly deep paths
+    SuspiciousArchive { reason: String },
+    // The disk filled up part way; the partial output has been removed
+    DiskFull { bytes_written: u64 },
 }
 
-pub async fn compress_files_with_progress<F>(
-    files: &[PathBuf],
-    output_path: &Path,
-    compression_type: CompressionType,
-    mut progress_callback: F,
-) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    if !compression_type.supports_multiple_files() && files.len() > 1 {
-        return Err(anyhow::anyhow!(
-            "Compression type {:?} does not support multiple files",
-            compression_type
-        ));
-    }
-
-    match compression_type {
-        CompressionType::Zip => compress_zip_with_progress(files, output_path, progress_callback).await,
-        CompressionType::TarGz => compress_tar_gz_with_progress(files, output_path, progress_callback).await,
-        CompressionType::TarBr => compress_tar_br_with_progress(files, output_path, progress_callback).await,
-        CompressionType::Gz | CompressionType::Gzip => {
-            let filename = files[0].file_name().unwrap_or_default().to_string_lossy().to_string();
-            compress_gz_with_progress(&files[0], output_path, move |progress| {
-                progress_callback(progress, filename.clone())
-            }).await
-        },
-        CompressionType::Br => {
-            let filename = files[0].file_name().unwrap_or_default().to_string_lossy().to_string();
-            compress_br_with_progress(&files[0], output_path, move |progress| {
-                progress_callback(progress, filename.clone())
-            }).await
-        },
-        CompressionType::Bzip2 => {
-            let filename = files[0].file_name().unwrap_or_default().to_string_lossy().to_string();
-            compress_bzip2_with_progress(&files[0], output_path, move |progress| {
-                progress_callback(progress, filename.clone())
-            }).await
-        },
+impl std::fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressionError::NeedsPassword => write!(f, "NeedsPassword: this archive is password protected"),
+            CompressionError::WrongPassword => write!(f, "WrongPassword: the password is incorrect"),
+            CompressionError::WeakPassword { reason } => write!(f, "WeakPassword: the password {}", reason),
+            CompressionError::Cancelled => write!(f, "Cancelled: the operation was cancelled"),
+            CompressionError::Unsupported { feature } => write!(f, "Unsupported: this archive uses {}, which is not supported", feature),
+            CompressionError::Corrupt { reason } => write!(f, "Corrupt: {}", reason),
+            CompressionError::SuspiciousArchive { reason } => write!(f, "SuspiciousArchive: {}", reason),
+            CompressionError::DiskFull { bytes_written } => write!(f, "DiskFull: the disk filled up after {} bytes were written", bytes_written),
+        }
     }
 }
 
-async fn compress_zip_with_progress<F>(files: &[PathBuf], output_path: &Path, mut progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    let file = File::create(output_path)
-        .with_context(|| format!("Failed to create output file: {}", output_path.display()))?;
-    
-    let mut zip = ZipWriter::new(BufWriter::new(file));
-    let options = FileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755);
-
-    // Calculate the common base directory for all files
-    let base_dir = if files.len() == 1 {
-        // For a single file, use its parent directory
-        files[0].parent().unwrap_or_else(|| Path::new(".")).to_path_buf()
+impl std::error::Error for CompressionError {}
+
+// Turn an error caused by the disk running out of space into CompressionError::DiskFull,
+// so it doesn't reach the user as a bare IO error. Other errors pass through.
+fn disk_full_error(e: anyhow::Error, bytes_written: impl FnOnce() -> u64) -> anyhow::Error {
+    let disk_full = e.chain().any(|cause| {
+        let io_error = match cause.downcast_ref::<zip::result::ZipError>() {
+            Some(zip::result::ZipError::Io(io_error)) => Some(io_error),
+            _ => cause.downcast_ref::<std::io::Error>(),
+        };
+        io_error.map_or(false, |io_error| matches!(io_error.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::WriteZero))
+    });
+    if disk_full {
+        e.context(CompressionError::DiskFull { bytes_written: bytes_written() })
     } else {
-        // For multiple files, find the common parent directory
-        find_common_base_dir(files).unwrap_or_else(|| PathBuf::from("."))
-    };
+        e
+    }
+}
 
-    println!("Using base directory: {}", base_dir.display());
+// How far a scratch file got before a failure, removing it on the way out
+fn scratch_file_size(temp_file: TempFile) -> u64 {
+    std::fs::metadata(temp_file.path()).map(|metadata| metadata.len()).unwrap_or(0)
+}
 
-    // Calculate total size for progress tracking
-    let total_size = calculate_total_size(files)?;
-    let mut processed_size = 0u64;
+// What to do when an extracted file would land on a path that already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Skip,
+    Rename,
+}
 
-    for (index, file_path) in files.iter().enumerate() {
-        let current_filename = file_path.file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
-        
-        println!("Adding file: {}", file_path.display());
-        
-        // Update progress before processing each file
-        let file_progress = if total_size > 0 {
-            (processed_size as f64 / total_size as f64) * 100.0
-        } else {
-            (index as f64 / files.len() as f64) * 100.0
-        };
-        progress_callback(file_progress, current_filename.clone());
-        
-        add_to_zip_with_progress(&mut zip, file_path, &base_dir, &options, &mut processed_size, &mut progress_callback, total_size).await?;
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Rename
     }
+}
 
-    progress_callback(100.0, "Complete".to_string());
-    zip.finish()?;
-    Ok(())
+// Options for compress_files_with_progress, sent by the frontend as a JSON object
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressOptions {
+    // Where scratch files go when a format needs one (defaults to the OS temp dir).
+    // Every format streams straight into its encoder: Zip writes entries directly,
+    // TarGz/TarBr pipe the tar builder into the gzip/brotli writer, and the single-file
+    // formats copy from the source. The exceptions are zip entries deflated with a
+    // deflate_strategy and the copies dedupe makes, each built in a scratch file here
+    // first. This is not where the archive itself is written: that scratch file goes
+    // beside the output, so it can be renamed into place without a copy.
+    pub temp_dir: Option<PathBuf>,
+    // Windows junctions and directory symlinks are skipped by default so a link back to
+    // an ancestor (common in user profile folders) cannot make the walk loop forever or
+    // pull in a whole linked volume. When followed, each directory is still only walked
+    // once, which breaks any loop.
+    pub follow_junctions: bool,
+    // Read single-file sources (Gz/Br/Bzip2) through a memory map (see open_source;
+    // mmap_source_speedup in the tests measures what it saves)
+    pub use_mmap: bool,
+    // Advanced: tag every zip entry with a custom extra field
+    pub entry_metadata: Option<EntryMetadata>,
+    pub folder_mode: FolderMode,
+    // Zip only
+    pub compatibility: ZipCompat,
+    // Zip only: write Zip64 fields for every entry and a Zip64 end of central directory
+    // even when nothing needs them, for tools that expect Zip64 throughout. Wins over
+    // ZipCompat::Legacy, which otherwise keeps Zip64 to entries over 4 GiB.
+    pub force_zip64: bool,
+    // Advanced: zlib strategy for deflated zip entries and Gz/Gzip files
+    pub deflate_strategy: DeflateStrategy,
+    // Rewrite CRLF/CR line endings in text files (like git's autocrlf). Files are picked
+    // by `text_globs` (a built-in list of source/text extensions when empty), and any file
+    // with a NUL byte near the start is treated as binary and stored untouched.
+    pub normalize_text: Option<LineEnding>,
+    pub text_globs: Vec<String>,
+    // Open every source file for reading before writing anything, and fail straight
+    // away with the full list of files that can't be read
+    pub preflight: bool,
+    // Leave unreadable files out of the archive instead of failing
+    pub skip_errors: bool,
+    // Store Windows file attributes in zip entries (on by default on Windows)
+    pub preserve_attributes: Option<bool>,
+    // Write `<archive>.manifest.json` (or `.txt`) listing every file that went in
+    pub emit_manifest: Option<ManifestFormat>,
+    // Include each file's SHA-256 in the manifest
+    pub manifest_hashes: bool,
+    // Set from another thread to stop the operation; checked between entries and
+    // while reading file contents
+    #[serde(skip)]
+    pub cancel: Option<Arc<AtomicBool>>,
+    // Told how many entries the walk has found so far while it is still enumerating
+    // the sources, which can take minutes on a slow network mount
+    #[serde(skip)]
+    pub scan_progress: Option<ScanProgress>,
+    // Told when the operation moves into a phase its progress callback doesn't cover:
+    // Finalizing once the archive is written, and Verifying while compress_replace reads
+    // its output back
+    #[serde(skip)]
+    pub phase_change: Option<PhaseChange>,
+    pub entry_order: EntryOrder,
+    // Zip only: store entries uncompressed, for inputs that are already compressed
+    // (photos, video, other archives) where deflate only costs time
+    pub store: bool,
+    // Most source files open at once, so huge trees can't run out of file descriptors
+    // (defaults to the OS limit less some headroom)
+    pub max_open_files: Option<usize>,
+    // Header style for the tar-based formats
+    pub tar_format: TarFormat,
+    // Drop a leading "./" from tar entry names (on by default)
+    pub normalize_paths: Option<bool>,
+    // Read further paths to compress from this list file, merged with the ones passed in
+    // (like `tar -T`); see file_utils::read_file_list. `files_from0` means NUL-separated.
+    pub files_from: Option<PathBuf>,
+    pub files_from0: bool,
+    // Tar-based formats only (Unix): store FIFOs and device nodes as tar special-file
+    // entries instead of skipping them. Sockets can't be archived and are always skipped.
+    pub preserve_special: bool,
+    // Split the inputs into one archive per group instead of a single archive; see
+    // group_sources
+    pub group_by: GroupBy,
+    // Zip only: add each distinct file content once and copy later files with the same
+    // content from it, already compressed, instead of compressing them again (see
+    // rewrite_zip_entries). Every entry still has its own data, so extracting writes out
+    // all the files in full as usual.
+    pub dedupe: bool,
+    // Only take files modified after this time (like `tar --newer`), for incremental
+    // backups. Folders are still walked and kept. Gz, Br and Bzip2 compress their one
+    // file regardless.
+    pub newer_than: Option<std::time::SystemTime>,
+    // With newer_than, write an archive of just the folders when no file is newer
+    // instead of failing
+    pub allow_empty: bool,
+    // Leave out files smaller than min_size or larger than max_size (in bytes), e.g. to
+    // keep videos out of a backup. Folders are still walked and kept, and what was left
+    // out comes back in CompressResult::size_filtered. Gz, Br and Bzip2 compress their
+    // one file regardless.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // TarZst only: compress with this zstd dictionary (see train_zstd_dictionary), which
+    // helps a lot with many small, similar files. Its id goes into the frame header, and
+    // the archive can then only be extracted with the same dictionary.
+    pub zstd_dictionary: Option<PathBuf>,
+    // Cap on the codec's working memory in bytes, for low-memory machines. The brotli
+    // and zstd windows and the bzip2 block size are lowered until the estimate fits
+    // (see fit_codec_memory); the settings used come back in CompressResult::memory.
+    pub memory_limit: Option<usize>,
+    // Flush the finished archive to disk (fsync), and on Unix the folder entry that
+    // names it, before reporting success, so a power cut right after can't leave an
+    // empty or partial file (on by default). It waits for the whole archive to reach
+    // the disk, which for a multi-gigabyte archive on a slow or USB drive can add
+    // seconds to minutes; turn it off for throwaway output.
+    pub durable: Option<bool>,
+    // Gz and TarGz: deflate on this many threads at once (see deflate::ParallelGzWriter),
+    // for multi-gigabyte inputs that would otherwise keep one core busy. The output is
+    // still a standard gzip file. Unset or 1 compresses on one thread; a deflate_strategy
+    // other than Default also keeps Gz on one thread.
+    pub threads: Option<usize>,
+    // Zip, Cpio and Chunks only: name every entry by its full canonical path on this
+    // machine ("/home/me/notes.txt", "C:/Users/me/notes.txt") instead of relative to the
+    // selection, for backup tools that put files back exactly where they came from.
+    // SECURITY: such an archive is a set of instructions to write anywhere on disk. It
+    // gives away the machine's folder layout and user names, and extracting it with
+    // ExtractOptions::restore_absolute overwrites whatever is at those paths. Without
+    // restore_absolute the leading root is stripped and it extracts like any other
+    // archive. The tar formats can't hold such names and are refused.
+    pub absolute_paths: bool,
+}
+
+// Order of the entries inside the archive. Directories always come first (by path) so
+// each one is created before anything inside it; the order only applies to files.
+// ExtensionGrouped puts files of the same type next to each other, which helps solid
+// and window-based codecs (tar.gz, tar.br, cpio.gz) find repeats across similar files. Zip
+// compresses every entry on its own, so there the order only affects browsing.
+// SizeDesc handles the largest files first: every format reports byte progress in entry
+// order, so the slow part comes while the estimate still has room to adjust and the
+// last stretch is a quick run of small files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryOrder {
+    AsGiven,
+    PathAsc,
+    SizeAsc,
+    SizeDesc,
+    ExtensionGrouped,
 }
 
-fn calculate_total_size(files: &[PathBuf]) -> Result<u64> {
-    let mut total = 0u64;
-    for file_path in files {
-        total += calculate_path_size(file_path)?;
+impl Default for EntryOrder {
+    fn default() -> Self {
+        EntryOrder::AsGiven
     }
-    Ok(total)
 }
 
-fn calculate_path_size(path: &Path) -> Result<u64> {
-    let mut total = 0u64;
-    if path.is_file() {
-        total += std::fs::metadata(path)?.len();
-    } else if path.is_dir() {
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            total += calculate_path_size(&entry.path())?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ManifestFormat {
+    Json,
+    Txt,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    // Seconds since the Unix epoch
+    #[serde(default)]
+    mtime: u64,
+    sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
         }
     }
-    Ok(total)
 }
 
-fn find_common_base_dir(files: &[PathBuf]) -> Option<PathBuf> {
-    if files.is_empty() {
-        return None;
+const DEFAULT_TEXT_GLOBS: &[&str] = &[
+    "*.txt", "*.md", "*.rs", "*.toml", "*.json", "*.yaml", "*.yml", "*.xml", "*.html", "*.css",
+    "*.js", "*.ts", "*.py", "*.c", "*.h", "*.cpp", "*.hpp", "*.cs", "*.java", "*.go", "*.sh",
+    "*.bat", "*.ps1", "*.ini", "*.cfg", "*.csv", "*.sql",
+];
+
+// Which zip readers the archive has to satisfy.
+// Legacy targets readers without Zip64 or data-descriptor support: Windows XP/Vista
+// "Compressed Folders", Info-ZIP unzip 5.x, and java.util.zip before Java 7. Entries are
+// deflated, their sizes are patched into the local headers by seeking back (never a data
+// descriptor), and Zip64 is only used for an entry that can't fit in 4 GiB. The zip
+// crate seeks back for any file it writes, and the headers rewrite_zip_entries writes
+// have the sizes in them, so no zip we write has data descriptors; what Legacy changes
+// is when Zip64 is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ZipCompat {
+    Modern,
+    Legacy,
+}
+
+impl Default for ZipCompat {
+    fn default() -> Self {
+        ZipCompat::Modern
     }
+}
 
-    // Start with the first file's parent directory
-    let mut common_base = files[0].parent()?.to_path_buf();
+// zlib's deflate strategies. Filtered suits data that has already been run through a
+// predictor (PNG rows, some audio), HuffmanOnly skips string matching entirely and Rle
+// only matches runs of the same byte. Anything but Default goes through
+// deflate::StrategyEncoder and is somewhat slower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeflateStrategy {
+    Default,
+    Filtered,
+    HuffmanOnly,
+    Rle,
+}
 
-    // Find the common ancestor of all file paths
-    for file in &files[1..] {
-        if let Some(file_parent) = file.parent() {
-            // Find the common path between current common_base and this file's parent
-            if let Some(new_common) = find_common_path(&common_base, file_parent) {
-                common_base = new_common;
-            } else {
-                // No common path found, use current directory
-                return Some(PathBuf::from("."));
-            }
-        }
+impl Default for DeflateStrategy {
+    fn default() -> Self {
+        DeflateStrategy::Default
     }
+}
 
-    Some(common_base)
+// Tar header style. Ustar is the most portable but limits paths to 255 bytes (100 for
+// the file name part) and files to 8 GiB. Pax is ustar plus extended headers for
+// whatever doesn't fit; Gnu uses GNU long-name records, for older GNU-only tooling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TarFormat {
+    Ustar,
+    Pax,
+    Gnu,
 }
 
-fn find_common_path(path1: &Path, path2: &Path) -> Option<PathBuf> {
-    let components1: Vec<_> = path1.components().collect();
-    let components2: Vec<_> = path2.components().collect();
-    
-    let mut common_components = Vec::new();
-    
-    for (comp1, comp2) in components1.iter().zip(components2.iter()) {
-        if comp1 == comp2 {
-            common_components.push(comp1.as_os_str());
-        } else {
-            break;
-        }
+impl Default for TarFormat {
+    fn default() -> Self {
+        TarFormat::Pax
     }
-    
-    if common_components.is_empty() {
-        None
-    } else {
-        // Reconstruct the path from components
-        let mut result = PathBuf::new();
-        for component in common_components {
-            result.push(component);
-        }
-        Some(result)
+}
+
+// How the compress command splits its inputs into separate archives. Parent makes one
+// archive per distinct parent folder, Extension one per file type (folders and files
+// without an extension go in an "other" group).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupBy {
+    None,
+    Parent,
+    Extension,
+}
+
+impl Default for GroupBy {
+    fn default() -> Self {
+        GroupBy::None
     }
 }
 
-fn add_to_zip_sync_with_progress<F>(
-    zip: &mut ZipWriter<BufWriter<File>>,
-    file_path: &Path,
-    base_dir: &Path,
-    options: &FileOptions,
-    processed_size: &mut u64,
-    progress_callback: &mut F,
-    total_size: u64,
-) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    if file_path.is_file() {
-        // Calculate relative path from base directory
-        let relative_path = if let Ok(rel_path) = file_path.strip_prefix(base_dir) {
-            rel_path
-        } else {
-            // If strip_prefix fails, just use the filename
-            Path::new(file_path.file_name().unwrap_or_default())
-        };
+// Largest size an octal ustar size field holds
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
 
-        let current_filename = file_path.file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
+// Whether a selected folder is archived as itself (extracts to `folder/...`)
+// or only its contents are (extracts straight to the files)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FolderMode {
+    IncludeFolder,
+    ContentsOnly,
+}
 
-        println!("Adding file with relative path: {}", relative_path.display());
-        
-        // Ensure we use forward slashes for zip paths (cross-platform compatibility)
-        let zip_path = relative_path.to_string_lossy().replace('\\', "/");
-        
-        zip.start_file(&zip_path, *options)?;
-        
-        let mut file = File::open(file_path)?;
-        let bytes_copied = std::io::copy(&mut file, zip)?;
-        *processed_size += bytes_copied;
-        
-        // Update progress after processing this file
-        let current_progress = if total_size > 0 {
-            (*processed_size as f64 / total_size as f64) * 100.0
-        } else {
-            100.0
-        };
-        progress_callback(current_progress, current_filename);
-        
-    } else if file_path.is_dir() {
-        // For directories, recursively add all files
-        for entry in std::fs::read_dir(file_path)? {
-            let entry = entry?;
-            add_to_zip_sync_with_progress(zip, &entry.path(), base_dir, options, processed_size, progress_callback, total_size)?;
-        }
+impl Default for FolderMode {
+    fn default() -> Self {
+        FolderMode::IncludeFolder
     }
-    Ok(())
 }
 
-async fn add_to_zip_with_progress<F>(
-    zip: &mut ZipWriter<BufWriter<File>>,
-    file_path: &Path,
-    base_dir: &Path,
-    options: &FileOptions,
-    processed_size: &mut u64,
-    progress_callback: &mut F,
-    total_size: u64,
-) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    add_to_zip_sync_with_progress(zip, file_path, base_dir, options, processed_size, progress_callback, total_size)
+// A custom zip extra field. Tools that don't know the ID simply ignore it,
+// so the value survives round trips through other zip utilities.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    pub field_id: u16,
+    pub value: String,
 }
 
-async fn compress_tar_gz_with_progress<F>(files: &[PathBuf], output_path: &Path, mut progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    let file = File::create(output_path)?;
-    let gz_encoder = GzEncoder::new(BufWriter::new(file), FlateCompression::default());
-    let mut tar = TarBuilder::new(gz_encoder);
+// One entry of an archive listing
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+    // Value of the requested custom extra field (zip only)
+    pub metadata: Option<String>,
+    // How the data is compressed, e.g. "Deflate" or "AES (Deflate)". Tarball entries
+    // get the codec of the whole stream.
+    pub method: String,
+    pub encrypted: bool,
+}
 
-    let total_size = calculate_total_size(files)?;
-    let mut processed_size = 0u64;
+// A folder or file in list_archive_tree. A folder's size is the total of everything
+// below it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeNode {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub children: Vec<TreeNode>,
+}
 
-    for (index, file_path) in files.iter().enumerate() {
-        let current_filename = file_path.file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
-        
-        let file_progress = if total_size > 0 {
-            (processed_size as f64 / total_size as f64) * 100.0
-        } else {
-            (index as f64 / files.len() as f64) * 100.0
-        };
-        progress_callback(file_progress, current_filename.clone());
-        
-        add_to_tar_with_progress(&mut tar, file_path, &mut processed_size).await?;
-    }
+// What inspect_archive found out before extracting
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveInspection {
+    pub entry_count: usize,
+    // Files and folders an "extract here" would create in the target folder
+    pub top_level_items: usize,
+    // The one folder everything is inside, if there is one
+    pub common_root: Option<String>,
+    // More loose top-level items than the threshold: extracting here would scatter
+    // them over the target folder, so a new folder is the better choice
+    pub many_loose_items: bool,
+}
 
-    progress_callback(100.0, "Complete".to_string());
-    tar.finish()?;
-    Ok(())
+// How long extracting an archive should take, from estimate_extraction. Writing the
+// files isn't timed, so a slow target drive adds to this.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractionEstimate {
+    pub uncompressed_size: u64,
+    // The size was extrapolated from the probe, as the archive doesn't record it
+    pub size_estimated: bool,
+    pub seconds: f64,
 }
 
-async fn compress_tar_br_with_progress<F>(files: &[PathBuf], output_path: &Path, mut progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    let file = File::create(output_path)?;
-    let br_encoder = brotli::CompressorWriter::new(BufWriter::new(file), 4096, 6, 22);
-    let mut tar = TarBuilder::new(br_encoder);
+impl ExtractionEstimate {
+    // The estimate for extracting several archives one after another
+    pub fn add(&mut self, other: &ExtractionEstimate) {
+        self.uncompressed_size += other.uncompressed_size;
+        self.size_estimated |= other.size_estimated;
+        self.seconds += other.seconds;
+    }
+}
 
-    let total_size = calculate_total_size(files)?;
-    let mut processed_size = 0u64;
+// Output decoded to time the decoder for estimate_extraction
+const ESTIMATE_PROBE_BYTES: u64 = 4 * 1024 * 1024;
 
-    for (index, file_path) in files.iter().enumerate() {
-        let current_filename = file_path.file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string();
-        
-        let file_progress = if total_size > 0 {
-            (processed_size as f64 / total_size as f64) * 100.0
-        } else {
-            (index as f64 / files.len() as f64) * 100.0
-        };
-        progress_callback(file_progress, current_filename.clone());
-        
-        add_to_tar_with_progress(&mut tar, file_path, &mut processed_size).await?;
-    }
+// Top-level items an "extract here" may create before inspect_archive warns
+pub const DEFAULT_LOOSE_ITEMS_THRESHOLD: usize = 20;
 
-    progress_callback(100.0, "Complete".to_string());
-    tar.finish()?;
-    Ok(())
+// One page of an archive listing, from list_archive_page
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchivePage {
+    pub entries: Vec<ArchiveEntry>,
+    // Entries in the whole archive; None for tarballs, which would have to be
+    // decompressed to the end just to count them
+    pub total: Option<usize>,
+    pub has_more: bool,
 }
 
-async fn add_to_tar_with_progress<W: Write>(tar: &mut TarBuilder<W>, file_path: &Path, processed_size: &mut u64) -> Result<()> {
-    if file_path.is_file() {
-        // For tar files, we can use the filename directly
-        let filename = file_path.file_name().unwrap_or_default();
-        tar.append_path_with_name(file_path, filename)?;
+impl CompressOptions {
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+    
+    pub fn preserve_attributes(&self) -> bool {
+        self.preserve_attributes.unwrap_or(cfg!(windows))
+    }
+    
+    pub fn durable(&self) -> bool {
+        self.durable.unwrap_or(true)
+    }
+    
+    pub fn normalize_paths(&self) -> bool {
+        self.normalize_paths.unwrap_or(true)
+    }
+    
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(1).max(1)
+    }
+    
+    // The given files plus any listed in `files_from`, without duplicates
+    pub fn source_files(&self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let Some(list_path) = &self.files_from else { return Ok(files) };
+        let listed = read_file_list(list_path, self.files_from0)
+            .with_context(|| format!("Failed to read file list: {}", list_path.display()))?;
         
-        // Update processed size
-        if let Ok(metadata) = std::fs::metadata(file_path) {
-            *processed_size += metadata.len();
+        let mut files = files;
+        for path in listed {
+            if !files.contains(&path) {
+                files.push(path);
+            }
         }
-    } else if file_path.is_dir() {
-        tar.append_dir_all(file_path.file_name().unwrap(), file_path)?;
-        
-        // Update processed size for directory
-        if let Ok(size) = calculate_path_size(file_path) {
-            *processed_size += size;
+        Ok(files)
+    }
+    
+    fn check_cancelled(&self) -> Result<()> {
+        if is_cancelled(&self.cancel) {
+            return Err(CompressionError::Cancelled.into());
+        }
+        Ok(())
+    }
+    
+    fn size_allowed(&self, size: u64) -> bool {
+        !self.min_size.is_some_and(|min| size < min) && !self.max_size.is_some_and(|max| size > max)
+    }
+    
+    fn enter_phase(&self, phase: OperationPhase) {
+        if let Some(phase_change) = &self.phase_change {
+            (phase_change.0)(phase);
+        }
+    }
+    
+    fn report_scan(&self, found: usize) {
+        if let Some(scan_progress) = &self.scan_progress {
+            (scan_progress.0)(found);
         }
     }
-    Ok(())
 }
 
-// Progress tracking writer wrapper for compression with filename tracking
-struct ProgressWriter<W, F> {
-    inner: W,
-    progress_callback: F,
-    total_size: u64,
-    bytes_written: u64,
-    filename: String,
+// What one compression shares between its steps, made from its CompressOptions when it
+// starts (see CompressContext::new) and passed alongside them
+#[derive(Debug)]
+pub struct CompressContext {
+    // Shared by everything the compression opens
+    open_file_limit: Arc<OpenFileLimit>,
+    // Files found and finished so far, for the CancelReport
+    tally: Tally,
+    // text_globs compiled once, when normalize_text is on
+    text_matcher: Option<globset::GlobSet>,
 }
 
-impl<W: Write, F: FnMut(f64)> ProgressWriter<W, F> {
-    fn new(inner: W, total_size: u64, filename: String, progress_callback: F) -> Self {
-        Self {
-            inner,
-            progress_callback,
-            total_size,
-            bytes_written: 0,
-            filename,
+impl CompressContext {
+    pub fn new(options: &CompressOptions) -> Result<Self> {
+        Ok(Self {
+            open_file_limit: Arc::new(OpenFileLimit::new(options.max_open_files.unwrap_or_else(default_max_open_files))),
+            tally: Tally::default(),
+            text_matcher: options.normalize_text.map(|_| text_glob_set(options)).transpose()?,
+        })
+    }
+    
+    // Open a source file, waiting while max_open_files others are open
+    fn open_file(&self, path: &Path) -> std::io::Result<LimitedFile> {
+        self.open_file_limit.open(path)
+    }
+    
+    fn file_done(&self) {
+        self.tally.files_done.fetch_add(1, Ordering::SeqCst);
+    }
+    
+    fn size_filtered(&self) -> SizeFilterSummary {
+        SizeFilterSummary {
+            skipped_files: self.tally.size_filtered_files.load(Ordering::SeqCst),
+            skipped_bytes: self.tally.size_filtered_bytes.load(Ordering::SeqCst),
         }
     }
+    
+    // Note something for CompressResult::warnings
+    fn warn(&self, message: String) {
+        self.tally.warnings.push(message);
+    }
+    
+    fn warnings(&self) -> Vec<String> {
+        self.tally.warnings.take()
+    }
 }
 
-impl<W: Write, F: FnMut(f64)> Write for ProgressWriter<W, F> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let bytes = self.inner.write(buf)?;
-        self.bytes_written += bytes as u64;
-        
-        if self.total_size > 0 {
-            let progress = (self.bytes_written as f64 / self.total_size as f64) * 100.0;
-            (self.progress_callback)(progress.min(100.0));
+#[derive(Debug, Default)]
+struct Tally {
+    files_total: AtomicUsize,
+    files_done: AtomicUsize,
+    // Left out by min_size / max_size
+    size_filtered_files: AtomicUsize,
+    size_filtered_bytes: AtomicU64,
+    warnings: Warnings,
+}
+
+// What an operation did other than what was asked (a file left out, a name changed),
+// one line each, for its result. A note made twice, say by a second walk of the same
+// sources, is kept once.
+#[derive(Debug, Default)]
+struct Warnings(Mutex<Vec<String>>);
+
+impl Warnings {
+    fn push(&self, message: String) {
+        let mut warnings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !warnings.contains(&message) {
+            warnings.push(message);
         }
-        
-        Ok(bytes)
     }
     
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.inner.flush()
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
     }
 }
 
-async fn compress_gz_with_progress<F>(file_path: &Path, output_path: &Path, progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64),
-{
-    let input = File::open(file_path)?;
-    let output = File::create(output_path)?;
-    let file_size = std::fs::metadata(file_path)?.len();
-    
-    let filename = file_path.file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-    
-    let progress_output = ProgressWriter::new(output, file_size, filename, progress_callback);
-    
-    // Create encoder with optional filename in header
-    let mut encoder = match file_path.file_name().and_then(|name| name.to_str()) {
-        Some(filename_str) => {
-            // Store the original filename in the gzip header
-            GzBuilder::new()
-                .filename(filename_str)
-                .write(BufWriter::new(progress_output), FlateCompression::default())
-        }
-        None => {
-            // No filename available, create without header filename
-            GzBuilder::new().write(BufWriter::new(progress_output), FlateCompression::default())
+// What a cancelled compression had done, attached to its error as context (find it with
+// downcast_ref). `bytes_written` is how far the scratch archive had got.
+#[derive(Debug, Clone, Serialize)]
+pub struct CancelReport {
+    pub files_completed: usize,
+    pub files_remaining: usize,
+    pub bytes_written: u64,
+    pub partial_output_cleaned: bool,
+}
+
+impl std::fmt::Display for CancelReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled: {} of {} files were compressed, ", self.files_completed, self.files_completed + self.files_remaining)?;
+        if self.partial_output_cleaned {
+            write!(f, "partial archive removed")
+        } else {
+            write!(f, "partial archive could not be removed")
         }
-    };
-    
-    let mut reader = BufReader::new(input);
-    std::io::copy(&mut reader, &mut encoder)?;
-    encoder.finish()?;
-    Ok(())
+    }
 }
 
-async fn compress_br_with_progress<F>(file_path: &Path, output_path: &Path, progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64),
-{
-    let input = File::open(file_path)?;
-    let output = File::create(output_path)?;
-    let file_size = std::fs::metadata(file_path)?.len();
-    
-    let filename = file_path.file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-    
-    let progress_output = ProgressWriter::new(output, file_size, filename, progress_callback);
-    let mut encoder = brotli::CompressorWriter::new(BufWriter::new(progress_output), 4096, 6, 22);
-    
-    let mut reader = BufReader::new(input);
-    std::io::copy(&mut reader, &mut encoder)?;
-    encoder.flush()?;
-    Ok(())
-}
+#[derive(Clone)]
+pub struct ScanProgress(pub Arc<dyn Fn(usize) + Send + Sync>);
 
-async fn compress_bzip2_with_progress<F>(file_path: &Path, output_path: &Path, progress_callback: F) -> Result<()>
-where
-    F: FnMut(f64),
-{
-    let input = File::open(file_path)?;
-    let output = File::create(output_path)?;
-    let file_size = std::fs::metadata(file_path)?.len();
-    
-    let filename = file_path.file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-    
-    let progress_output = ProgressWriter::new(output, file_size, filename, progress_callback);
-    let mut encoder = bzip2::write::BzEncoder::new(BufWriter::new(progress_output), bzip2::Compression::default());
-    
-    let mut reader = BufReader::new(input);
-    std::io::copy(&mut reader, &mut encoder)?;
-    encoder.finish()?;
-    Ok(())
+impl std::fmt::Debug for ScanProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ScanProgress")
+    }
 }
 
-// Standard decompression without progress (backward compatibility)
-pub async fn decompress_files(file_path: &Path, output_dir: &Path) -> Result<()> {
-    decompress_files_with_progress(file_path, output_dir, |_, _| {}).await
+// The stage a long operation is in, sent to the frontend as the progress update's
+// `operation` so it can label it. Compressing walks the sources (Scanning), writes the
+// archive (Compressing, or Archiving where entries are packed without compressing them:
+// appending to a tar, re-encrypting a zip), and then flushes and renames it (Finalizing).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationPhase {
+    Scanning,
+    Archiving,
+    Compressing,
+    Extracting,
+    Verifying,
+    Finalizing,
+    // Nothing pauses yet; here so the frontend's states are all in one list
+    Paused,
+    Cancelled,
 }
 
-// New decompression function with filename-aware progress callback
-pub async fn decompress_files_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path,
-    mut progress_callback: F
-) -> Result<()> 
-where
-    F: FnMut(f64, String),
-{
-    let extension = file_path.extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
+#[derive(Clone)]
+pub struct PhaseChange(pub Arc<dyn Fn(OperationPhase) + Send + Sync>);
 
-    let file_name = file_path.file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or("");
+impl std::fmt::Debug for PhaseChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PhaseChange")
+    }
+}
 
-    // Get file size for progress calculation
-    let file_size = std::fs::metadata(file_path)?.len();
-    
-    let archive_name = file_path.file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
+#[derive(Clone)]
+pub struct EntryFailed(pub Arc<dyn Fn(&FailedEntry) + Send + Sync>);
 
-    if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
-        decompress_tar_gz_with_progress(file_path, output_dir, file_size, move |progress, _| {
-            progress_callback(progress, archive_name.clone())
-        }).await
-    } else if file_name.ends_with(".tar.br") {
-        decompress_tar_br_with_progress(file_path, output_dir, file_size, move |progress, _| {
-            progress_callback(progress, archive_name.clone())
-        }).await
-    } else {
-        match extension {
-            "zip" => decompress_zip_with_progress(file_path, output_dir, move |progress, _| {
-                progress_callback(progress, archive_name.clone())
-            }).await,
-            "gz" | "gzip" => decompress_gz_with_progress(file_path, output_dir, file_size, move |progress, _| {
-                progress_callback(progress, archive_name.clone())
-            }).await,
-            "br" => decompress_br_with_progress(file_path, output_dir, file_size, move |progress, _| {
-                progress_callback(progress, archive_name.clone())
-            }).await,
-            "bz2" | "bzip2" => decompress_bzip2_with_progress(file_path, output_dir, file_size, move |progress, _| {
-                progress_callback(progress, archive_name.clone())
-            }).await,
-            #[cfg(feature = "rar-support")]
-            "rar" => decompress_rar(file_path, output_dir).await,
-            _ => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
-        }
+impl std::fmt::Debug for EntryFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EntryFailed")
     }
 }
 
-// Progress tracking reader wrapper for decompression with filename tracking
-struct ProgressReader<R, F> {
+// How often the walk reports, in entries found
+const SCAN_REPORT_INTERVAL: usize = 256;
+
+fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+    cancel.as_ref().is_some_and(|flag| flag.load(Ordering::SeqCst))
+}
+
+// Fails the read once cancellation is requested, so a large file stops mid-copy
+struct CancellableReader<R> {
     inner: R,
-    progress_callback: F,
-    total_size: u64,
-    bytes_read: u64,
-    filename: String,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
-impl<R: Read, F: FnMut(f64, String)> ProgressReader<R, F> {
-    fn new(inner: R, total_size: u64, filename: String, progress_callback: F) -> Self {
-        Self {
-            inner,
-            progress_callback,
-            total_size,
-            bytes_read: 0,
-            filename,
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if is_cancelled(&self.cancel) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, CompressionError::Cancelled));
         }
+        self.inner.read(buf)
     }
 }
 
-impl<R: Read, F: FnMut(f64, String)> Read for ProgressReader<R, F> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let bytes = self.inner.read(buf)?;
-        self.bytes_read += bytes as u64;
-        
-        if self.total_size > 0 {
-            let progress = (self.bytes_read as f64 / self.total_size as f64) * 100.0;
-            (self.progress_callback)(progress.min(100.0), self.filename.clone());
+// Options for decompress_files_with_progress, sent by the frontend as a JSON object
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractOptions {
+    // Strip all directory components and write every file straight into the output dir
+    pub flatten: bool,
+    pub conflict_policy: ConflictPolicy,
+    // Used for encrypted zip and 7z archives
+    pub password: Option<String>,
+    // Log and skip entries that fail to extract instead of aborting the whole archive
+    pub continue_on_error: bool,
+    // How the extraction folder is named when the archive's name is already taken
+    pub dir_naming: DirNaming,
+    // How many levels of nested archives to unwrap when an extraction yields nothing but
+    // a single archive (e.g. a .zip holding one .tar.gz). 0 keeps the plain behavior.
+    pub recursive_extract: usize,
+    // Restore Windows file attributes stored in zip entries (on by default on Windows)
+    pub preserve_attributes: Option<bool>,
+    // Keep a journal of finished entries in the output folder (zip and tar) so an
+    // interrupted extraction can continue where it stopped instead of starting over
+    pub resume: bool,
+    // Leave holes for long runs of zeros (VM images, databases) instead of writing
+    // them out (zip, 7z, iso and cpio). On by default on Unix; NTFS only makes holes in
+    // files flagged sparse, so it is off by default on Windows. Tar doesn't look at it:
+    // the holes a GNU sparse entry records are always kept (the tar crate seeks over
+    // them), and zeros stored as data in any other tar entry are written out.
+    pub preserve_sparse: Option<bool>,
+    // Modification time given to the output folder once extraction is done
+    pub dir_timestamp: DirTimestamp,
+    // Check a .gz file's trailer (CRC-32 and length) against what was decompressed, and
+    // fail with Corrupt instead of keeping partial output (on by default). zstd frames
+    // are always checked by the decoder when they carry a checksum.
+    pub verify: Option<bool>,
+    // Refuse the whole archive when an entry's path is nested deeper than this many
+    // components (DEFAULT_MAX_DEPTH when unset, 0 turns the check off)
+    pub max_depth: Option<usize>,
+    // Only extract entries whose path matches this glob, e.g. "*.jpg" (`*` also crosses
+    // folders). Zip, tar, cpio, 7z and iso; see EntryPattern.
+    pub pattern: Option<String>,
+    // Dictionary a .tar.zst was compressed with; required when its frame names one
+    pub zstd_dictionary: Option<PathBuf>,
+    // Extract straight into this existing folder instead of a new one, for updating an
+    // earlier extraction: each file in the archive goes through conflict_policy, and
+    // files already there that the archive doesn't have are left alone. The summary's
+    // `merged` counts what happened.
+    pub merge_into: Option<PathBuf>,
+    // Most memory in bytes an xz or zstd stream may ask for to be decoded
+    // (DEFAULT_DECOMPRESS_MEMORY_LIMIT when unset). The encoder picks the window size,
+    // so without a cap a crafted stream could make extraction allocate gigabytes.
+    pub decompress_memory_limit: Option<usize>,
+    // Append a line to this file for every file written, overwritten, renamed or
+    // skipped, as it happens, for an audit trail of what an archive put where. Each
+    // line is "<action>\t<entry name>\t<output path>"; see ExtractionLog.
+    pub extraction_log: Option<PathBuf>,
+    // Write entries through a folder that already exists as a symbolic link (or a
+    // junction) inside the output folder. Off by default, since such a link, whether an
+    // earlier archive or someone else put it there, could send files anywhere.
+    pub allow_symlink_dirs: bool,
+    // Write entries with an absolute name (see CompressOptions::absolute_paths) to that
+    // exact path instead of inside the output folder. Zip, cpio, 7z and Chunks; ignored
+    // with flatten, and a name that isn't absolute on this system (a C:/ path on Unix)
+    // still goes into the output folder. DANGEROUS: the archive decides which files get
+    // created or replaced anywhere the user can write, system folders and startup
+    // scripts included, and only conflict_policy stands in the way. Only set it for
+    // archives you made yourself, e.g. when restoring your own backup.
+    pub restore_absolute: bool,
+    // Where a spanned zip's disks are joined before extraction (defaults to the OS temp
+    // dir). It needs room for the whole set.
+    pub temp_dir: Option<PathBuf>,
+    // Opened by decompress_files_with_progress for extraction_log
+    #[serde(skip)]
+    log: Option<Arc<ExtractionLog>>,
+    // Set up by decompress_files_with_progress for merge_into
+    #[serde(skip)]
+    merge_tally: Option<Arc<MergeTally>>,
+    // Set up on Windows by decompress_files_with_progress
+    #[serde(skip)]
+    case_folds: Option<Arc<CaseFolds>>,
+    // Set up by decompress_files_with_progress for ExtractSummary::warnings
+    #[serde(skip)]
+    warnings: Option<Arc<Warnings>>,
+    // Bytes the extraction's progress has counted, for what a disk-full failure reports
+    #[serde(skip)]
+    progress_bytes: Option<Arc<AtomicU64>>,
+    // Told about each entry continue_on_error skips as it fails, while the rest of the
+    // archive is still being extracted
+    #[serde(skip)]
+    pub entry_failed: Option<EntryFailed>,
+}
+
+// The open ExtractOptions::extraction_log. Every line goes to the file in a single
+// unbuffered write, so a crash leaves the log complete up to the entry it was on.
+#[derive(Debug)]
+struct ExtractionLog(Mutex<File>);
+
+impl ExtractionLog {
+    fn open(path: &Path, archive: &Path, output_dir: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open extraction log '{}': {}", path.display(), e))?;
+        let log = Self(Mutex::new(file));
+        log.write(&format!("# {} -> {}", archive.display(), output_dir.display()))
+            .map_err(|e| anyhow::anyhow!("Failed to write extraction log '{}': {}", path.display(), e))?;
+        Ok(log)
+    }
+
+    fn record(&self, placement: Placement, name: &str, path: &Path) -> std::io::Result<()> {
+        let action = match placement {
+            Placement::Added => "written",
+            Placement::Overwritten => "overwritten",
+            Placement::Renamed => "renamed",
+            Placement::Skipped => "skipped",
+        };
+        self.write(&format!("{}\t{}\t{}", action, name, path.display()))
+    }
+
+    fn write(&self, line: &str) -> std::io::Result<()> {
+        let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(format!("{}\n", line).as_bytes())
+    }
+}
+
+// What happened to one extracted file when it met the output folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    Added,
+    Overwritten,
+    Renamed,
+    Skipped,
+}
+
+// Files written so far in one extraction, by their case-folded path. On a
+// case-insensitive filesystem README and readme are the same file, so the second would
+// silently replace the first; this tells such a pair apart from a file that was
+// already in the folder, so it can be reported.
+#[derive(Debug, Default)]
+struct CaseFolds {
+    written: Mutex<HashMap<String, String>>,
+    collisions: Mutex<Vec<String>>,
+}
+
+impl CaseFolds {
+    fn fold(path: &Path) -> String {
+        path.to_string_lossy().to_lowercase()
+    }
+
+    // Note it when `target` is a file an entry with a differently cased name has already
+    // been written to
+    fn check(&self, target: &Path, name: &str) {
+        let written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(earlier) = written.get(&Self::fold(target)).filter(|earlier| *earlier != name) {
+            self.collisions.lock().unwrap_or_else(|e| e.into_inner()).push(format!("{} / {}", earlier, name));
         }
-        
-        Ok(bytes)
+    }
+
+    fn record(&self, path: &Path, name: &str) {
+        self.written.lock().unwrap_or_else(|e| e.into_inner()).insert(Self::fold(path), name.to_string());
     }
 }
 
-async fn decompress_zip_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    mut progress_callback: F
-) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    let file = File::open(file_path)?;
-    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
-    let archive_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    
-    std::fs::create_dir_all(output_dir)?;
+#[derive(Debug, Default)]
+struct MergeTally {
+    added: AtomicUsize,
+    overwritten: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+// Files a merge_into extraction added, wrote over, or left alone because of the
+// conflict policy. A renamed file counts as added.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MergeCounts {
+    pub added: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+}
+
+const DEFAULT_MAX_DEPTH: usize = 64;
+// Enough for xz -9 and zstd --long=30 streams
+const DEFAULT_DECOMPRESS_MEMORY_LIMIT: usize = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DirTimestamp {
+    // Left as created
+    Now,
+    // The archive file's own modification time
+    FromArchive,
+    // The newest entry time recorded in the archive (zip and tar), or the newest
+    // extracted file for other formats
+    FromNewestEntry,
+}
+
+impl Default for DirTimestamp {
+    fn default() -> Self {
+        DirTimestamp::Now
+    }
+}
+
+impl ExtractOptions {
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    pub fn preserve_attributes(&self) -> bool {
+        self.preserve_attributes.unwrap_or(cfg!(windows))
+    }
+
+    pub fn preserve_sparse(&self) -> bool {
+        self.preserve_sparse.unwrap_or(cfg!(unix))
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify.unwrap_or(true)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn decompress_memory_limit(&self) -> usize {
+        self.decompress_memory_limit.unwrap_or(DEFAULT_DECOMPRESS_MEMORY_LIMIT)
+    }
 
-    let total_files = archive.len();
+    // Note a file in the extraction log, if there is one. A log that can't be written
+    // doesn't stop the extraction itself.
+    fn log_entry(&self, placement: Placement, name: &str, path: &Path) {
+        if let Some(Err(e)) = self.log.as_ref().map(|log| log.record(placement, name, path)) {
+            self.warn(format!("Could not write to the extraction log: {}", e));
+        }
+    }
     
-    for i in 0..archive.len() {
-        // Update progress based on file count
-        let progress = (i as f64 / total_files as f64) * 100.0;
-        progress_callback(progress, archive_name.clone());
-        
-        let mut file = archive.by_index(i)?;
-        let outpath = output_dir.join(file.name());
+    // Note something for ExtractSummary::warnings
+    fn warn(&self, message: String) {
+        if let Some(warnings) = &self.warnings {
+            warnings.push(message);
+        }
+    }
+}
 
-        if file.is_dir() {
-            std::fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                std::fs::create_dir_all(p)?;
+// What happened during an extraction, beyond plain success
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractSummary {
+    pub failed_entries: Vec<FailedEntry>,
+    // Pairs of entries whose names differ only in case, as "first / second" (Windows
+    // only). The second went through the conflict policy instead of replacing the first.
+    pub case_collisions: Vec<String>,
+    // Only for merge_into extractions
+    pub merged: Option<MergeCounts>,
+    // Entries refused or placed other than the archive says (a path climbing out of the
+    // folder, an absolute path made relative), nested archives left wrapped, and times
+    // or log lines that couldn't be written; one line each
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedEntry {
+    pub name: String,
+    pub error: String,
+}
+
+// What compress_files_with_progress wrote. For Auto this is the format it picked and
+// the output path with that format's extension added.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompressResult {
+    pub output_path: PathBuf,
+    pub compression_type: CompressionType,
+    pub dedupe: DedupeSummary,
+    pub size_filtered: SizeFilterSummary,
+    // Only when CompressOptions::memory_limit was given
+    pub memory: Option<CodecMemory>,
+    // Files left out (special, unreadable, a link loop) and names or settings that had
+    // to change, one line each
+    pub warnings: Vec<String>,
+}
+
+// The codec settings that decide how much memory compressing takes, fitted to
+// CompressOptions::memory_limit. Only the one that applies to the format is set.
+// `estimated_bytes` is the encoder's own working memory from each library's documented
+// sizes, rounded up; file buffers come on top.
+#[derive(Debug, Clone, Serialize)]
+pub struct CodecMemory {
+    // log2 of the window size
+    pub brotli_lgwin: Option<u32>,
+    pub zstd_window_log: Option<u32>,
+    // 1-9, in 100 KB blocks
+    pub bzip2_level: Option<u32>,
+    pub estimated_bytes: usize,
+}
+
+const BROTLI_QUALITY: u32 = 6;
+const BROTLI_LGWIN: u32 = 22;
+const BROTLI_MIN_LGWIN: u32 = 10;
+// What zstd picks for its default level on large inputs
+const ZSTD_WINDOW_LOG: u32 = 21;
+const ZSTD_MIN_WINDOW_LOG: u32 = 10;
+const BZIP2_LEVEL: u32 = 6;
+// zlib: 256 KB of hash tables and buffers plus the 32 KB window
+const DEFLATE_MEMORY: usize = 320 * 1024;
+
+// Ring buffer, plus half as much again for the block being built, plus the level-6
+// hasher's fixed 2 MB
+fn brotli_memory(lgwin: u32) -> usize {
+    (1usize << lgwin) * 3 / 2 + (2 << 20)
+}
+
+// The streaming input buffer holds a window plus a block, and the default level's
+// hash and chain tables take under 1 MB
+fn zstd_memory(window_log: u32) -> usize {
+    2 * (1usize << window_log) + (1 << 20)
+}
+
+// From the bzip2 manual: 400 KB plus eight times the block size
+fn bzip2_memory(level: u32) -> usize {
+    400_000 + 8 * level as usize * 100_000
+}
+
+// The largest settings for `compression_type` whose estimate fits in `limit` (the
+// defaults without one), or an error when even the smallest doesn't fit
+pub fn fit_codec_memory(compression_type: &CompressionType, limit: Option<usize>) -> Result<CodecMemory> {
+    let budget = limit.unwrap_or(usize::MAX);
+    let too_small = |needed: usize| anyhow::anyhow!(
+        "A memory limit of {} bytes is too small for {:?}, which needs at least {} bytes",
+        budget, compression_type, needed
+    );
+    let none = CodecMemory { brotli_lgwin: None, zstd_window_log: None, bzip2_level: None, estimated_bytes: 0 };
+    
+    match compression_type {
+        CompressionType::TarBr | CompressionType::Br => (BROTLI_MIN_LGWIN..=BROTLI_LGWIN).rev()
+            .find(|lgwin| brotli_memory(*lgwin) <= budget)
+            .map(|lgwin| CodecMemory { brotli_lgwin: Some(lgwin), estimated_bytes: brotli_memory(lgwin), ..none.clone() })
+            .ok_or_else(|| too_small(brotli_memory(BROTLI_MIN_LGWIN))),
+        CompressionType::TarZst => (ZSTD_MIN_WINDOW_LOG..=ZSTD_WINDOW_LOG).rev()
+            .find(|window_log| zstd_memory(*window_log) <= budget)
+            .map(|window_log| CodecMemory { zstd_window_log: Some(window_log), estimated_bytes: zstd_memory(window_log), ..none.clone() })
+            .ok_or_else(|| too_small(zstd_memory(ZSTD_MIN_WINDOW_LOG))),
+        CompressionType::Bzip2 => (1..=BZIP2_LEVEL).rev()
+            .find(|level| bzip2_memory(*level) <= budget)
+            .map(|level| CodecMemory { bzip2_level: Some(level), estimated_bytes: bzip2_memory(level), ..none.clone() })
+            .ok_or_else(|| too_small(bzip2_memory(1))),
+        // Deflate's memory is small and fixed
+        CompressionType::Zip | CompressionType::TarGz | CompressionType::Gz | CompressionType::Gzip | CompressionType::CpioGz => {
+            if DEFLATE_MEMORY > budget {
+                return Err(too_small(DEFLATE_MEMORY));
+            }
+            Ok(CodecMemory { estimated_bytes: DEFLATE_MEMORY, ..none })
+        }
+        // Each chunk is compressed whole, at zstd's default window
+        CompressionType::Chunks => {
+            let needed = zstd_memory(ZSTD_WINDOW_LOG) + 2 * MAX_CHUNK as usize;
+            if needed > budget {
+                return Err(too_small(needed));
             }
-            let mut outfile = File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
+            Ok(CodecMemory { estimated_bytes: needed, ..none })
         }
+        // Cpio is stored as-is; Auto is fitted once it has picked a format, and a
+        // registered codec's needs aren't known
+        CompressionType::Cpio | CompressionType::Auto | CompressionType::Custom(_) => Ok(none),
+    }
+}
 
-        // Set file permissions if available
-        #[cfg(unix)]
-        if let Some(mode) = file.unix_mode() {
-            use std::os::unix::fs::PermissionsExt;
-            std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(mode))?;
+// Files CompressOptions::dedupe copied instead of compressing, and their total
+// uncompressed size
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DedupeSummary {
+    pub duplicate_files: usize,
+    pub bytes_saved: u64,
+}
+
+// Files CompressOptions::min_size and max_size left out, and their total size
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SizeFilterSummary {
+    pub skipped_files: usize,
+    pub skipped_bytes: u64,
+}
+
+// Sizes before and after optimize_archive
+#[derive(Debug, Clone, Serialize)]
+pub struct OptimizeResult {
+    pub output_path: PathBuf,
+    pub original_size: u64,
+    pub optimized_size: u64,
+}
+
+// Outcome of compress_replace
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplaceResult {
+    pub output_path: PathBuf,
+    pub original_size: u64,
+    pub compressed_size: u64,
+}
+
+// Something that would stop a compression, found by validate_compression_request
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestProblem {
+    // The source or output the problem is with, when it is about one path
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+// detect_creator's best guess at the tool that wrote a zip
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatorGuess {
+    // e.g. "7-Zip", or "Unknown" when nothing fits
+    pub creator: String,
+    // The first entry's raw version-made-by: host system in the high byte, zip spec
+    // version (major * 10 + minor) in the low byte
+    pub version_made_by: u16,
+    pub host: String,
+    // What the guess rests on, one detail per line
+    pub evidence: Vec<String>,
+}
+
+// Outcome of rebuild_central_directory
+#[derive(Debug, Clone, Serialize)]
+pub struct RebuildResult {
+    pub output_path: PathBuf,
+    pub entries_recovered: usize,
+}
+
+// Start of a text entry, read for previewing without extracting to disk
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryText {
+    pub text: String,
+    // The entry is longer than what was read
+    pub truncated: bool,
+}
+
+// How much of an entry read_entry_text returns
+const PREVIEW_LIMIT: u64 = 1024 * 1024;
+
+impl ExtractSummary {
+    fn record_failure(&mut self, options: &ExtractOptions, name: String, error: anyhow::Error) {
+        let failed = FailedEntry { name, error: error.to_string() };
+        if let Some(EntryFailed(hook)) = &options.entry_failed {
+            hook(&failed);
         }
+        self.failed_entries.push(failed);
     }
+}
 
-    progress_callback(100.0, archive_name);
-    Ok(())
+pub async fn compress_files(
+    files: &[PathBuf],
+    output_path: &Path,
+    compression_type: CompressionType,
+) -> Result<()> {
+    compress_files_with_progress(files, output_path, compression_type, &CompressOptions::default(), |_, _| {}).await
+        .map(|_| ())
 }
 
-async fn decompress_tar_gz_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    file_size: u64,
-    progress_callback: F
-) -> Result<()>
+pub async fn compress_files_with_progress<F>(
+    files: &[PathBuf],
+    output_path: &Path,
+    compression_type: CompressionType,
+    options: &CompressOptions,
+    progress_callback: F,
+) -> Result<CompressResult>
 where
-    F: FnMut(f64, String),
+    F: FnMut(f64, String) + Send,
 {
-    let file = File::open(file_path)?;
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    let progress_reader = ProgressReader::new(file, file_size, filename, progress_callback);
-    let gz_decoder = flate2::read::GzDecoder::new(BufReader::new(progress_reader));
-    let mut archive = tar::Archive::new(gz_decoder);
+    let context = &CompressContext::new(options)?;
     
-    std::fs::create_dir_all(output_dir)?;
-    archive.unpack(output_dir)?;
-    Ok(())
+    if let CompressionType::Auto = compression_type {
+        let (chosen, store) = choose_auto_format(files, options, context)?;
+        
+        let mut output_name = output_path.as_os_str().to_os_string();
+        output_name.push(chosen.extension());
+        let output_path = PathBuf::from(output_name);
+        let options = CompressOptions { store, ..options.clone() };
+        let memory = options.memory_limit.map(|limit| fit_codec_memory(&chosen, Some(limit))).transpose()?;
+        let dedupe = compress_with_format(files, &output_path, chosen.clone(), &options, context, progress_callback).await?;
+        let size_filtered = context.size_filtered();
+        return Ok(CompressResult { output_path, compression_type: chosen, dedupe, size_filtered, memory, warnings: context.warnings() });
+    }
+    
+    // Checked up front, so a limit that can't be met fails before anything is written
+    let memory = options.memory_limit.map(|limit| fit_codec_memory(&compression_type, Some(limit))).transpose()?;
+    let dedupe = compress_with_format(files, output_path, compression_type.clone(), options, context, progress_callback).await?;
+    Ok(CompressResult { output_path: output_path.to_path_buf(), compression_type, dedupe, size_filtered: context.size_filtered(), memory, warnings: context.warnings() })
 }
 
-async fn decompress_tar_br_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    file_size: u64,
-    progress_callback: F
-) -> Result<()>
-where
-    F: FnMut(f64, String),
-{
-    let file = File::open(file_path)?;
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    let progress_reader = ProgressReader::new(file, file_size, filename, progress_callback);
-    let br_decoder = brotli::Decompressor::new(BufReader::new(progress_reader), 4096);
-    let mut archive = tar::Archive::new(br_decoder);
+// How many of the largest files Auto samples, and how much of each
+const AUTO_SAMPLE_FILES: usize = 32;
+const AUTO_SAMPLE_SIZE: u64 = 64 * 1024;
+
+// Pick a format for Auto, plus whether zip entries should be stored. Inputs that mostly
+// compress get a zstd tarball; ones that mostly don't (media, archives) get a stored
+// zip, since recompressing them gains nothing; a mix falls back to a regular zip, which
+// every system can open.
+fn choose_auto_format(files: &[PathBuf], options: &CompressOptions, context: &CompressContext) -> Result<(CompressionType, bool)> {
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    let mut entries: Vec<WalkEntry> = walk_sources(&sources, options, context)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && entry.size > 0)
+        .collect();
+    entries.sort_by(|a, b| b.size.cmp(&a.size));
     
-    std::fs::create_dir_all(output_dir)?;
-    archive.unpack(output_dir)?;
-    Ok(())
+    // Weighted by size, so one big video outweighs a pile of small text files
+    let mut compressible = 0u64;
+    let mut incompressible = 0u64;
+    for entry in entries.iter().take(AUTO_SAMPLE_FILES) {
+        let mut sample = Vec::new();
+        context.open_file(&entry.path)?.take(AUTO_SAMPLE_SIZE).read_to_end(&mut sample)?;
+        if compresses_well(&sample)? {
+            compressible += entry.size;
+        } else {
+            incompressible += entry.size;
+        }
+    }
+    
+    let total = (compressible + incompressible) as f64;
+    if total == 0.0 {
+        Ok((CompressionType::Zip, false))
+    } else if compressible as f64 >= total * 0.8 {
+        Ok((CompressionType::TarZst, false))
+    } else if incompressible as f64 >= total * 0.8 {
+        Ok((CompressionType::Zip, true))
+    } else {
+        Ok((CompressionType::Zip, false))
+    }
 }
 
-async fn decompress_gz_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    file_size: u64,
-    progress_callback: F
-) -> Result<()>
+async fn compress_with_format<F>(
+    files: &[PathBuf],
+    output_path: &Path,
+    compression_type: CompressionType,
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress_callback: F,
+) -> Result<DedupeSummary>
 where
-    F: FnMut(f64, String),
+    F: FnMut(f64, String) + Send,
 {
-    let input = File::open(file_path)?;
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    let progress_reader = ProgressReader::new(input, file_size, filename, progress_callback);
-    let mut decoder = flate2::read::GzDecoder::new(BufReader::new(progress_reader));
-    
-    std::fs::create_dir_all(output_dir)?;
+    if !compression_type.supports_multiple_files() && files.len() > 1 {
+        return Err(anyhow::anyhow!(
+            "Compression type {:?} does not support multiple files",
+            compression_type
+        ));
+    }
+
+    check_output_not_source(files, output_path)?;
     
-    // Try to get the original filename from the gzip header first
-    let output_name = if let Some(filename_bytes) = decoder.header().and_then(|h| h.filename()) {
-        // Convert bytes to string and create OsString
-        match std::str::from_utf8(filename_bytes) {
-            Ok(filename_str) => std::ffi::OsString::from(filename_str),
-            Err(_) => {
-                // If UTF-8 conversion fails, fall back to the compressed filename
-                fallback_filename_from_compressed(file_path)
-            }
+    // The tar crate refuses absolute names, and the single-file formats store no name
+    if options.absolute_paths && !matches!(compression_type, CompressionType::Zip | CompressionType::Cpio | CompressionType::CpioGz | CompressionType::Chunks) {
+        return Err(anyhow::anyhow!("Absolute paths can only be stored in Zip, Cpio and Chunks archives, not {:?}", compression_type));
+    }
+
+    if let Some(temp_dir) = &options.temp_dir {
+        if !temp_dir.is_dir() {
+            return Err(anyhow::anyhow!("Temp directory does not exist: {}", temp_dir.display()));
         }
-    } else {
-        // If no filename in header, try to infer from the compressed filename
-        fallback_filename_from_compressed(file_path)
-    };
+    }
+
+    if options.preflight && !options.skip_errors {
+        let unreadable = unreadable_sources(files, options, context)?;
+        if !unreadable.is_empty() {
+            return Err(anyhow::anyhow!(
+                "{} file(s) cannot be read:\n{}",
+                unreadable.len(),
+                unreadable.join("\n")
+            ));
+        }
+    }
+
+    // Everything is written to a scratch file beside the output and only renamed over it
+    // once complete, so a failure, cancel or crash never leaves a truncated archive or
+    // damages one that already exists. The scratch file is removed when dropped.
+    let output_dir = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let (temp_file, _) = TempFile::new_in(output_dir, SCRATCH_PREFIX)?;
+    let temp_path = temp_file.path().to_path_buf();
     
-    let output_path = output_dir.join(output_name);
-    let mut output = File::create(output_path)?;
+    let (entries, dedupe) = match write_format(files, &temp_path, compression_type, options, context, progress_callback).await {
+        Ok(written) => written,
+        Err(e) if is_cancelled(&options.cancel) => {
+            let bytes_written = std::fs::metadata(&temp_path).map(|metadata| metadata.len()).unwrap_or(0);
+            drop(temp_file);
+            let tally = &context.tally;
+            let (files_total, files_completed) = (tally.files_total.load(Ordering::SeqCst), tally.files_done.load(Ordering::SeqCst));
+            let files_total = if files_total == 0 { files.len() } else { files_total };
+            let report = CancelReport {
+                files_completed,
+                files_remaining: files_total.saturating_sub(files_completed),
+                bytes_written,
+                partial_output_cleaned: !temp_path.exists(),
+            };
+            return Err(e.context(report));
+        },
+        Err(e) => {
+            return Err(disk_full_error(e, || scratch_file_size(temp_file)));
+        },
+    };
     
-    std::io::copy(&mut decoder, &mut output)?;
-    Ok(())
+    options.check_cancelled()?;
+    options.enter_phase(OperationPhase::Finalizing);
+    if options.durable() {
+        File::open(&temp_path)?.sync_all()?;
+    }
+    temp_file.persist(output_path)?;
+    
+    if let Some(format) = options.emit_manifest {
+        write_manifest(output_path, &entries, format, options.manifest_hashes)?;
+    }
+    // The rename (and manifest) only last once the folder itself is on disk
+    if options.durable() {
+        sync_dir(output_dir)?;
+    }
+    Ok(dedupe)
 }
 
-fn fallback_filename_from_compressed(file_path: &Path) -> std::ffi::OsString {
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy();
+// Run every check that would otherwise only fail a compression part way through, and
+// return all the problems found (none when it is good to go), so they can be shown
+// together: the file count the format allows, sources that are missing or unreadable,
+// an output folder that is missing or can't be written, an output that is one of the
+// sources, and too little free space. The space needed is the size of the sources,
+// since nothing is known to compress until it has been tried, plus the scratch copy
+// written beside the output.
+pub fn validate_compression_request(
+    files: &[PathBuf],
+    output_path: &Path,
+    compression_type: &CompressionType,
+    options: &CompressOptions,
+) -> Vec<RequestProblem> {
+    let mut problems = Vec::new();
+    let mut problem = |path: Option<&Path>, message: String| problems.push(RequestProblem { path: path.map(Path::to_path_buf), message });
     
-    // Handle different compression formats
-    let (base_name, stripped) = if filename.ends_with(".gz") {
-        (&filename[..filename.len() - 3], true)
-    } else if filename.ends_with(".gzip") {
-        (&filename[..filename.len() - 5], true)
-    } else if filename.ends_with(".br") {
-        (&filename[..filename.len() - 3], true)
-    } else if filename.ends_with(".bz2") {
-        (&filename[..filename.len() - 4], true)
-    } else if filename.ends_with(".bzip2") {
-        (&filename[..filename.len() - 6], true)
-    } else {
-        (filename.as_ref(), false)
-    };
+    if files.is_empty() {
+        problem(None, "No files selected".to_string());
+    }
+    if !compression_type.supports_multiple_files() && files.len() > 1 {
+        problem(None, format!("{:?} compresses a single file, but {} were selected", compression_type, files.len()));
+    }
     
-    if stripped && !base_name.is_empty() {
-        // If we stripped a compression extension and there's still a base name
-        if base_name.contains('.') {
-            // Base name already has an extension, use it as-is
-            std::ffi::OsString::from(base_name)
-        } else {
-            // No extension in base name, assume it's a text file
-            // You could make this more sophisticated by analyzing file content
-            std::ffi::OsString::from(format!("{}.txt", base_name))
+    let existing: Vec<PathBuf> = files.iter()
+        .filter(|file| match std::fs::symlink_metadata(file) {
+            Ok(_) => true,
+            Err(e) => {
+                problem(Some(file.as_path()), format!("'{}' cannot be found: {}", file.display(), e));
+                false
+            },
+        })
+        .cloned()
+        .collect();
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(&existing);
+    let mut needed = 0u64;
+    let walked = CompressContext::new(options)
+        .and_then(|context| Ok((walk_sources(&sources, options, &context)?, context)));
+    match walked {
+        Ok((entries, context)) => {
+            for entry in entries.iter().filter(|entry| !entry.is_dir && !entry.special) {
+                needed += entry.size;
+                if let Err(e) = context.open_file(&entry.path) {
+                    problem(Some(entry.path.as_path()), format!("'{}' cannot be read: {}", entry.path.display(), e));
+                }
+            }
+        },
+        Err(e) => problem(None, e.to_string()),
+    }
+    
+    if let Err(e) = check_output_not_source(files, output_path) {
+        problem(Some(output_path), e.to_string());
+    }
+    if std::fs::metadata(output_path).is_ok_and(|metadata| metadata.permissions().readonly()) {
+        problem(Some(output_path), format!("'{}' already exists and is read-only", output_path.display()));
+    }
+    
+    let output_dir = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    if !output_dir.is_dir() {
+        problem(Some(output_dir), format!("The output folder '{}' does not exist", output_dir.display()));
+        return problems;
+    }
+    // The scratch file the archive is written to first is the real test
+    if let Err(e) = TempFile::new_in(output_dir, SCRATCH_PREFIX) {
+        problem(Some(output_dir), format!("Cannot write to '{}': {}", output_dir.display(), e));
+    }
+    if let Some(available) = available_space(output_dir) {
+        if needed > available {
+            problem(Some(output_dir), format!(
+                "'{}' has {} bytes free, but the archive may need up to {} bytes",
+                output_dir.display(), available, needed
+            ));
         }
-    } else {
-        // Fallback to file stem if we couldn't parse the format
-        file_path.file_stem().unwrap_or_default().to_os_string()
     }
+    problems
 }
 
-async fn decompress_br_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    file_size: u64,
-    progress_callback: F
-) -> Result<()>
+// Refuse an output path that is one of the sources, e.g. a relative output name that
+// resolved next to a file of the same name: the finished archive would be renamed over
+// the file it was made from
+fn check_output_not_source(files: &[PathBuf], output_path: &Path) -> Result<()> {
+    let Ok(output) = std::fs::canonicalize(output_path) else { return Ok(()) };
+    for file in files {
+        if std::fs::canonicalize(file).is_ok_and(|source| source == output) {
+            return Err(anyhow::anyhow!(
+                "The output '{}' is one of the files being compressed; choose another name",
+                output_path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Write the archive in the given format to `temp_path`, with whichever codec is
+// registered under its name
+async fn write_format<F>(
+    files: &[PathBuf],
+    temp_path: &Path,
+    compression_type: CompressionType,
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress_callback: F,
+) -> Result<(Vec<WalkEntry>, DedupeSummary)>
 where
-    F: FnMut(f64, String),
+    F: FnMut(f64, String) + Send,
 {
-    let input = File::open(file_path)?;
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    let progress_reader = ProgressReader::new(input, file_size, filename, progress_callback);
-    let mut decoder = brotli::Decompressor::new(BufReader::new(progress_reader), 4096);
-    
-    std::fs::create_dir_all(output_dir)?;
-    
-    // Use improved filename logic
-    let output_name = fallback_filename_from_compressed(file_path);
-    let output_path = output_dir.join(output_name);
-    let mut output = File::create(output_path)?;
-    
-    std::io::copy(&mut decoder, &mut output)?;
-    Ok(())
+    if let CompressionType::Auto = compression_type {
+        return Err(anyhow::anyhow!("Auto must be resolved to a format first"));
+    }
+    let name = compression_type.name();
+    let codec = codec::find_codec(&name).ok_or_else(|| anyhow::anyhow!("No codec registered as {}", name))?;
+    let output = codec.compress(files, temp_path, options, context, Box::new(progress_callback)).await?;
+    let entries = match output.entries {
+        Some(entries) => entries,
+        None if options.emit_manifest.is_some() => {
+            return Err(CompressionError::Unsupported { feature: format!("a manifest of a {} archive (the codec doesn't list its entries)", name) }.into());
+        },
+        None => Vec::new(),
+    };
+    Ok((entries, output.dedupe))
 }
 
-async fn decompress_bzip2_with_progress<F>(
-    file_path: &Path, 
-    output_dir: &Path, 
-    file_size: u64,
-    progress_callback: F
-) -> Result<()>
+// Write one of the formats implemented here; the built-in codecs in codec.rs call this
+pub async fn write_builtin_format<F>(
+    files: &[PathBuf],
+    output_path: &Path,
+    compression_type: &CompressionType,
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress_callback: F,
+) -> Result<(Vec<WalkEntry>, DedupeSummary)>
+where
+    F: FnMut(f64, String) + Send,
+{
+    let mut dedupe = DedupeSummary::default();
+    let entries = match compression_type {
+        CompressionType::Zip => {
+            let (entries, summary) = compress_zip_with_progress(files, output_path, options, context, progress_callback).await?;
+            dedupe = summary;
+            entries
+        },
+        CompressionType::TarGz => compress_tar_gz_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::TarBr => compress_tar_br_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::TarZst => compress_tar_zst_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::Chunks => compress_chunks_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::Cpio => compress_cpio_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::CpioGz => compress_cpio_gz_with_progress(files, output_path, options, context, progress_callback).await?,
+        CompressionType::Gz | CompressionType::Gzip => {
+            compress_gz_with_progress(&files[0], output_path, options, context, progress_callback).await?;
+            single_file_entries(&files[0])?
+        },
+        CompressionType::Br => {
+            compress_br_with_progress(&files[0], output_path, options, context, progress_callback).await?;
+            single_file_entries(&files[0])?
+        },
+        CompressionType::Bzip2 => {
+            compress_bzip2_with_progress(&files[0], output_path, options, context, progress_callback).await?;
+            single_file_entries(&files[0])?
+        },
+        CompressionType::Auto | CompressionType::Custom(_) => {
+            return Err(anyhow::anyhow!("{} is not a built-in format", compression_type.name()));
+        },
+    };
+    Ok((entries, dedupe))
+}
+
+async fn compress_zip_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<(Vec<WalkEntry>, DedupeSummary)>
 where
     F: FnMut(f64, String),
 {
-    let input = File::open(file_path)?;
-    let filename = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
-    let progress_reader = ProgressReader::new(input, file_size, filename, progress_callback);
-    let mut decoder = bzip2::read::BzDecoder::new(BufReader::new(progress_reader));
+    let file = File::create(output_path)
+        .with_context(|| format!("Failed to create output file: {}", output_path.display()))?;
     
-    std::fs::create_dir_all(output_dir)?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    let method = if options.store { zip::CompressionMethod::Stored } else { zip::CompressionMethod::Deflated };
+    let zip_options = FileOptions::default()
+        .compression_method(method)
+        .unix_permissions(ZIP_ENTRY_MODE)
+        .large_file(options.force_zip64);
+
+    let sources = source_names(files);
+    // Zip has no way to store FIFOs or device nodes
+    let entries: Vec<WalkEntry> = walk_sources(&sources, options, context)?
+        .into_iter()
+        .filter(|entry| {
+            if entry.special {
+                context.warn(format!("Skipped special file (not supported in zip): {}", entry.path.display()));
+            }
+            !entry.special
+        })
+        .collect();
+
+    let mut progress = Progress::new(entries.iter().map(|entry| entry.size).sum(), progress_callback);
+
+    // Only files that share their size with another can be duplicates, so only those
+    // are hashed
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    if options.dedupe {
+        for entry in entries.iter().filter(|entry| !entry.is_dir && entry.size > 0) {
+            *size_counts.entry(entry.size).or_default() += 1;
+        }
+    }
+    // Content hash to the entry name it was first added under, and the duplicates
+    // (entry name to the name of the first copy) left for copy_duplicates
+    let mut first_copies: HashMap<[u8; 32], String> = HashMap::new();
+    let mut duplicates: HashMap<String, String> = HashMap::new();
+
+    for entry in &entries {
+        // Zip only stores the files; folders are implied by their paths
+        if entry.is_dir {
+            continue;
+        }
+
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        
+        let line_ending = text_line_ending(entry, options, context)?;
+        // Converted text no longer matches its source, so it is never deduplicated
+        if line_ending.is_none() && size_counts.get(&entry.size).map_or(false, |count| *count > 1) {
+            let zip_path = zip_entry_path(&entry.archive_name, context);
+            let hash = hash_source(entry, options, context)?;
+            if let Some(first) = first_copies.get(&hash) {
+                duplicates.insert(zip_path, first.clone());
+                progress.add(entry.size);
+                context.file_done();
+                continue;
+            }
+            first_copies.insert(hash, zip_path);
+        }
+        let entry_options = match options.compatibility {
+            ZipCompat::Modern => zip_options,
+            // The writer is seekable, so sizes always go back into the local header;
+            // the only choice left is when to fall back to Zip64
+            ZipCompat::Legacy => zip_options.large_file(options.force_zip64 || entry.size > u32::MAX as u64),
+        };
+        
+        options.check_cancelled()?;
+        add_to_zip_with_progress(&mut zip, entry, &entry_options, options, context, line_ending, &mut progress).await?;
+        context.file_done();
+    }
+
+    zip.finish()?;
+    options.check_cancelled()?;
+    let dedupe = rewrite_zip_entries(output_path, &entries, &duplicates, options, context)?;
+    if options.preserve_attributes() {
+        set_dos_attributes(output_path)?;
+    }
+    // Last, since copying in the duplicates rewrites the archive
+    if options.force_zip64 {
+        add_zip64_end(output_path)?;
+    }
+    progress.complete();
+    Ok((entries, dedupe))
+}
+
+fn hash_source(entry: &WalkEntry, options: &CompressOptions, context: &CompressContext) -> Result<[u8; 32]> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    let mut file = CancellableReader { inner: context.open_file(&entry.path)?, cancel: options.cancel.clone() };
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+// The selected paths with the names every format gives them: each one under its
+// own name at the top, so selected folders sit side by side ("docs/...", "images/...")
+// instead of being merged or flattened. Two selections with the same name, such as
+// "docs" folders from two places, would merge into one folder, so the later ones are
+// numbered the way Explorer names copies: "docs (2)", "notes (2).txt".
+fn source_names(files: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    let mut taken = HashSet::new();
+    files.iter()
+        .map(|file_path| {
+            let name = file_path.file_name().unwrap_or_default();
+            let mut unique = PathBuf::from(name);
+            let mut counter = 1;
+            while !name.is_empty() && !taken.insert(unique.clone()) {
+                counter += 1;
+                // A folder's dot isn't an extension, so its number goes at the end
+                let path = Path::new(name);
+                let mut numbered = match (file_path.is_dir(), path.file_stem(), path.extension()) {
+                    (false, Some(stem), Some(_)) => stem.to_os_string(),
+                    _ => name.to_os_string(),
+                };
+                numbered.push(format!(" ({})", counter));
+                if let (false, Some(extension)) = (file_path.is_dir(), path.extension()) {
+                    numbered.push(".");
+                    numbered.push(extension);
+                }
+                unique = PathBuf::from(numbered);
+            }
+            (file_path.clone(), unique)
+        })
+        .collect()
+}
+
+// Zip has no way for two entries to share one copy of the data (pointing several
+// entries at the same bytes is how zip bombs are built, and unzip rejects it), so each
+// duplicate still gets its own copy. Once the archive is written without them it is
+// copied again in entry order: the other entries as they are, and each duplicate as
+// the first copy's compressed data, not compressed again, under its own name, time
+// and extra fields. The headers are written here, since the zip crate's raw copies
+// drop extra fields and the file type bits of the mode. For the same reason entries
+// deflated with a strategy (see add_to_zip_with_strategy) come out of the zip crate
+// without either, so when there are any every entry gets its headers written again too.
+fn rewrite_zip_entries<'a, I>(archive_path: &Path, entries: I, duplicates: &HashMap<String, String>, options: &CompressOptions, context: &CompressContext) -> Result<DedupeSummary>
+where
+    I: IntoIterator<Item = &'a WalkEntry>,
+{
+    let mut summary = DedupeSummary::default();
+    let restore_headers = options.deflate_strategy != DeflateStrategy::Default && !options.store;
+    if duplicates.is_empty() && !restore_headers {
+        return Ok(summary);
+    }
     
-    // Use improved filename logic
-    let output_name = fallback_filename_from_compressed(file_path);
-    let output_path = output_dir.join(output_name);
-    let mut output = File::create(output_path)?;
+    let mut source = BufReader::new(File::open(archive_path)?);
+    let source_len = source.get_ref().metadata()?.len();
+    let central = read_central_headers(&mut source)?;
+    let dir = archive_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let (temp_file, file) = TempFile::new_in(dir, SCRATCH_PREFIX)?;
+    let mut writer = BufWriter::new(file);
+    let (mut directory, mut offset, mut count) = (Vec::new(), 0u64, 0u64);
+    for entry in entries.into_iter().filter(|entry| !entry.is_dir) {
+        options.check_cancelled()?;
+        let name = zip_entry_path(&entry.archive_name, context);
+        let first = duplicates.get(&name);
+        let host = central.get(first.unwrap_or(&name).as_bytes())
+            .ok_or_else(|| anyhow::anyhow!("'{}' is missing from the archive", name))?;
+        let mut local = local_entry_at(&mut source, central_header_offset(host), source_len)?;
+        let data_offset = local.header_offset + local.header.len() as u64;
+        if first.is_some() || restore_headers {
+            let attributes = if options.preserve_attributes() { read_windows_attributes(&entry.path) } else { None };
+            let extra = zip_extra_fields(options, attributes)?;
+            local.header = renamed_local_header(&local, &name, zip_entry_time(&entry.path), &extra, options.force_zip64)?;
+            local.descriptor_len = 0;
+        }
+        if first.is_some() {
+            summary.duplicate_files += 1;
+            summary.bytes_saved += local.size;
+        }
+        
+        writer.write_all(&local.header)?;
+        source.seek(std::io::SeekFrom::Start(data_offset))?;
+        std::io::copy(&mut (&mut source).take(local.compressed_size + local.descriptor_len), &mut writer)?;
+        let mut central = rebuilt_central_header(&local, offset, Some(host))?;
+        // The external attributes: every file was added with ZIP_ENTRY_MODE
+        central[38..42].copy_from_slice(&((0o100000u32 | ZIP_ENTRY_MODE) << 16).to_le_bytes());
+        directory.extend_from_slice(&central);
+        offset += local.header.len() as u64 + local.compressed_size + local.descriptor_len;
+        count += 1;
+    }
+    writer.write_all(&directory)?;
+    write_end_of_directory(&mut writer, count, directory.len() as u64, offset)?;
+    writer.flush()?;
+    drop(writer);
+    drop(source);
+    temp_file.persist(archive_path)?;
+    Ok(summary)
+}
+
+// Every central directory header of a zip, by entry name
+fn read_central_headers(file: &mut BufReader<File>) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let directory = find_zip_directory(file)?
+        .ok_or_else(|| CompressionError::Corrupt { reason: "end of central directory not found".to_string() })?;
+    file.seek(std::io::SeekFrom::Start(directory.offset))?;
+    let mut headers = HashMap::new();
+    for _ in 0..directory.entries {
+        let mut header = vec![0u8; 46];
+        if file.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(CompressionError::Corrupt { reason: "damaged central directory".to_string() }.into());
+        }
+        let rest = u16_at(&header, 28) as usize + u16_at(&header, 30) as usize + u16_at(&header, 32) as usize;
+        header.resize(46 + rest, 0);
+        file.read_exact(&mut header[46..])?;
+        headers.insert(header[46..46 + u16_at(&header, 28) as usize].to_vec(), header);
+    }
+    Ok(headers)
+}
+
+// Where a central header's local header is. A full 32-bit offset means the real one
+// is in the Zip64 field, after whichever sizes are there too.
+fn central_header_offset(header: &[u8]) -> u64 {
+    let offset = u32_at(header, 42) as u64;
+    let extra_start = 46 + u16_at(header, 28) as usize;
+    let extra = &header[extra_start..extra_start + u16_at(header, 30) as usize];
+    match read_entry_metadata(extra, ZIP64_FIELD) {
+        Some(field) if offset == 0xFFFF_FFFF => {
+            let at = [24, 20].iter().filter(|size| u32_at(header, **size) == 0xFFFF_FFFF).count() * 8;
+            field.get(at..at + 8).map_or(offset, |value| u64_at(value, 0))
+        }
+        _ => offset,
+    }
+}
+
+fn local_entry_at(file: &mut BufReader<File>, offset: u64, file_len: u64) -> Result<LocalEntry> {
+    let mut fixed = [0u8; 30];
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    file.read_exact(&mut fixed)?;
+    let entry = if fixed[..4] == LOCAL_HEADER_SIGNATURE { read_local_entry(file, offset, &fixed, file_len)? } else { None };
+    entry.ok_or_else(|| CompressionError::Corrupt { reason: format!("no local header at offset {}", offset) }.into())
+}
+
+// A local header for an entry's data under another name, time and extra fields. The
+// version, flags and method stay; the sizes go in a Zip64 field when they don't fit 32
+// bits, or always with `force_zip64`.
+fn renamed_local_header(entry: &LocalEntry, name: &str, time: zip::DateTime, extra: &[u8], force_zip64: bool) -> Result<Vec<u8>> {
+    let zip64 = force_zip64 || entry.size >= 0xFFFF_FFFF || entry.compressed_size >= 0xFFFF_FFFF;
+    let mut fields = Vec::new();
+    if zip64 {
+        let mut sizes = entry.size.to_le_bytes().to_vec();
+        sizes.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        write_extra_field(&mut fields, ZIP64_FIELD, &sizes)?;
+    }
+    fields.extend_from_slice(extra);
+    let (size, compressed_size) = if zip64 { (u32::MAX, u32::MAX) } else { (entry.size as u32, entry.compressed_size as u32) };
+    let version = if zip64 { u16_at(&entry.header, 4).max(45) } else { u16_at(&entry.header, 4) };
+    // Bit 3 off: the sizes are here, not in a data descriptor (which isn't copied)
+    let flags = u16_at(&entry.header, 6) & !0x08;
     
-    std::io::copy(&mut decoder, &mut output)?;
-    Ok(())
+    let mut header = Vec::with_capacity(30 + name.len() + fields.len());
+    header.extend_from_slice(&LOCAL_HEADER_SIGNATURE);
+    header.extend_from_slice(&version.to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(&entry.header[8..10]);
+    header.extend_from_slice(&time.timepart().to_le_bytes());
+    header.extend_from_slice(&time.datepart().to_le_bytes());
+    header.extend_from_slice(&entry.crc.to_le_bytes());
+    header.extend_from_slice(&compressed_size.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&fields);
+    Ok(header)
 }
 
-#[cfg(feature = "rar-support")]
-async fn decompress_rar(file_path: &Path, output_dir: &Path) -> Result<()> {
-    use unrar::Archive;
+const ZIP_EOCD_SIG: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+const ZIP64_EOCD_SIG: [u8; 4] = [0x50, 0x4b, 0x06, 0x06];
+const ZIP64_LOCATOR_SIG: [u8; 4] = [0x50, 0x4b, 0x06, 0x07];
+const ZIP_EOCD_SIZE: usize = 22;
+const ZIP64_EOCD_SIZE: u64 = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+
+// Put a Zip64 end of central directory record and its locator in front of the plain
+// end record of a finished zip. The zip crate only writes them once the entry count or
+// an offset overflows. The plain record keeps its real values, which still fit, so
+// readers without Zip64 support can open the archive too.
+fn add_zip64_end(archive_path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(archive_path)?;
+    let len = file.metadata()?.len();
+    // The end record is followed by a comment of up to 64 KiB
+    let tail_len = len.min((ZIP_EOCD_SIZE + u16::MAX as usize + ZIP64_LOCATOR_SIZE) as u64) as usize;
+    file.seek(std::io::SeekFrom::Start(len - tail_len as u64))?;
+    let mut tail = vec![0; tail_len];
+    file.read_exact(&mut tail)?;
     
-    std::fs::create_dir_all(output_dir)?;
+    let eocd_at = (0..(tail_len + 1).saturating_sub(ZIP_EOCD_SIZE)).rev()
+        .find(|at| tail[*at..*at + 4] == ZIP_EOCD_SIG)
+        .ok_or_else(|| CompressionError::Corrupt { reason: "end of central directory not found".to_string() })?;
+    if eocd_at >= ZIP64_LOCATOR_SIZE && tail[eocd_at - ZIP64_LOCATOR_SIZE..eocd_at - ZIP64_LOCATOR_SIZE + 4] == ZIP64_LOCATOR_SIG {
+        return Ok(());
+    }
+    let end = tail[eocd_at..].to_vec();
+    let entries = u16::from_le_bytes([end[10], end[11]]) as u64;
+    let directory_size = u32::from_le_bytes([end[12], end[13], end[14], end[15]]) as u64;
+    let directory_offset = u32::from_le_bytes([end[16], end[17], end[18], end[19]]) as u64;
+    let zip64_end_offset = len - tail_len as u64 + eocd_at as u64;
     
-    // Convert to owned strings to avoid lifetime issues
-    let file_path_str = file_path.to_str()
-        .ok_or_else(|| anyhow::anyhow!("Invalid file path"))?
-        .to_string();
-    let output_dir_str = output_dir.to_str()
-        .ok_or_else(|| anyhow::anyhow!("Invalid output directory path"))?
-        .to_string();
+    let mut record = Vec::with_capacity(ZIP64_EOCD_SIZE as usize + ZIP64_LOCATOR_SIZE + end.len());
+    record.extend_from_slice(&ZIP64_EOCD_SIG);
+    // Size of the rest of the record
+    record.extend_from_slice(&(ZIP64_EOCD_SIZE - 12).to_le_bytes());
+    // Version made by and needed: 4.5 introduced Zip64
+    record.extend_from_slice(&45u16.to_le_bytes());
+    record.extend_from_slice(&45u16.to_le_bytes());
+    // This disk, and the disk the directory starts on
+    record.extend_from_slice(&0u32.to_le_bytes());
+    record.extend_from_slice(&0u32.to_le_bytes());
+    record.extend_from_slice(&entries.to_le_bytes());
+    record.extend_from_slice(&entries.to_le_bytes());
+    record.extend_from_slice(&directory_size.to_le_bytes());
+    record.extend_from_slice(&directory_offset.to_le_bytes());
     
-    // Handle the unrar specific error types using spawn_blocking for thread safety
-    tokio::task::spawn_blocking(move || {
-        Archive::new(file_path_str)
-            .extract_to(output_dir_str)
-            .map_err(|e| anyhow::anyhow!("RAR extraction failed: {:?}", e))?
-            .process()
-            .map_err(|e| anyhow::anyhow!("RAR processing failed: {:?}", e))?;
-        Ok::<(), anyhow::Error>(())
-    }).await??;
+    record.extend_from_slice(&ZIP64_LOCATOR_SIG);
+    record.extend_from_slice(&0u32.to_le_bytes());
+    record.extend_from_slice(&zip64_end_offset.to_le_bytes());
+    // Total number of disks
+    record.extend_from_slice(&1u32.to_le_bytes());
+    record.extend_from_slice(&end);
     
+    file.seek(std::io::SeekFrom::Start(zip64_end_offset))?;
+    file.write_all(&record)?;
+    file.sync_all()?;
     Ok(())
 }
 
-pub fn is_compressed_file(path: &Path) -> bool {
-    let file_name = path.file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or("");
+// Resumable compression jobs, for backups that run for hours. A job writes its archive
+// in parts, each a batch of entries in a file of its own in a folder beside the output,
+// and a journal there records every finished part with the entries in it. After an
+// interruption only the part that was being written is redone. Once every entry is in
+// a part the parts are joined into the output without compressing anything again (zip
+// entries are copied as they are; gzip members and zstd frames simply follow each
+// other) and the job's files are removed. Zip, TarGz and TarZst only; dedupe,
+// manifests and the zstd dictionary and memory settings don't apply to jobs.
 
-    if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") || file_name.ends_with(".tar.br") {
-        return true;
+const JOB_PART_FILES: usize = 1000;
+const JOB_PART_BYTES: u64 = 256 * 1024 * 1024;
+const JOB_JOURNAL: &str = "journal";
+const JOB_DIR_PREFIX: &str = ".tauzip-job-";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressJob {
+    pub id: String,
+    pub files: Vec<PathBuf>,
+    pub output_path: PathBuf,
+    pub compression_type: CompressionType,
+    pub options: CompressOptions,
+}
+
+impl CompressJob {
+    // Set up a job and remember it under its id; nothing is compressed until
+    // run_compress_job
+    pub fn create(files: Vec<PathBuf>, output_path: PathBuf, compression_type: CompressionType, options: CompressOptions) -> Result<Self> {
+        if !matches!(compression_type, CompressionType::Zip | CompressionType::TarGz | CompressionType::TarZst) {
+            return Err(CompressionError::Unsupported {
+                feature: format!("resumable jobs for {:?} (only Zip, TarGz and TarZst)", compression_type),
+            }.into());
+        }
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        check_output_not_source(&files, &output_path)?;
+        let job = Self { id: format!("{:x}-{:x}", nanos, std::process::id()), files, output_path, compression_type, options };
+        std::fs::create_dir_all(job.dir())?;
+        let record = job_record_path(&job.id);
+        if let Some(parent) = record.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&record, serde_json::to_vec_pretty(&job)?)?;
+        Ok(job)
     }
 
-    let extension = path.extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
+    // A job that was started earlier and hasn't finished
+    pub fn load(id: &str) -> Result<Self> {
+        // The id is also a file name
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(anyhow::anyhow!("Invalid job id: {}", id));
+        }
+        let record = std::fs::read(job_record_path(id))
+            .map_err(|_| anyhow::anyhow!("No unfinished job {}", id))?;
+        Ok(serde_json::from_slice(&record)?)
+    }
+
+    // Where the parts and the journal are kept, beside the output
+    fn dir(&self) -> PathBuf {
+        let parent = self.output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        parent.join(format!("{}{}", JOB_DIR_PREFIX, self.id))
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_dir_all(self.dir());
+        let _ = std::fs::remove_file(job_record_path(&self.id));
+    }
+}
+
+fn job_record_path(id: &str) -> PathBuf {
+    jobs_dir().join(format!("{}.json", id))
+}
+
+fn job_part_name(part: usize) -> String {
+    format!("part-{}", part)
+}
+
+// The journal of a job's finished parts: for each one a "f <name>" line per entry (the
+// name's bytes as a JSON array, so any name fits on a line, UTF-8 or not) and then a
+// "p <number>" line, written together once the part is safely on disk. Lines after the
+// last "p" are from a write that was cut off and are dropped.
+struct JobJournal {
+    file: File,
+    done: HashSet<Vec<u8>>,
+    parts: usize,
+}
+
+impl JobJournal {
+    fn open(job_dir: &Path) -> Result<Self> {
+        let path = job_dir.join(JOB_JOURNAL);
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        let mut done = HashSet::new();
+        let mut pending = Vec::new();
+        let mut parts = 0;
+        let mut offset = 0;
+        let mut complete = 0;
+        for line in contents.split_inclusive('\n') {
+            offset += line.len();
+            if !line.ends_with('\n') {
+                break;
+            }
+            let line = line.trim_end();
+            if let Some(name) = line.strip_prefix("f ") {
+                pending.push(serde_json::from_str::<Vec<u8>>(name)?);
+            } else if line.starts_with("p ") {
+                done.extend(pending.drain(..));
+                parts += 1;
+                complete = offset;
+            }
+        }
+        
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(&path)?;
+        file.set_len(complete as u64)?;
+        file.seek(std::io::SeekFrom::End(0))?;
+        Ok(Self { file, done, parts })
+    }
+
+    fn record_part(&mut self, names: impl Iterator<Item = Vec<u8>>) -> Result<()> {
+        let mut lines = String::new();
+        for name in names {
+            lines.push_str(&format!("f {}\n", serde_json::to_string(&name)?));
+        }
+        lines.push_str(&format!("p {}\n", self.parts));
+        self.file.write_all(lines.as_bytes())?;
+        self.file.sync_data()?;
+        self.parts += 1;
+        Ok(())
+    }
+}
+
+fn job_entry_name(entry: &WalkEntry) -> Vec<u8> {
+    name_bytes(&entry.archive_name)
+}
+
+// Run a job from wherever it got to and return the finished archive's path
+pub async fn run_compress_job<F>(job: &CompressJob, progress_callback: F) -> Result<PathBuf>
+where
+    F: FnMut(f64, String) + Send,
+{
+    let job_dir = job.dir();
+    std::fs::create_dir_all(&job_dir)?;
+    let options = &job.options;
+    let context = &CompressContext::new(options)?;
+    
+    let is_zip = matches!(job.compression_type, CompressionType::Zip);
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(&job.files);
+    // Zip only stores files, and nothing special
+    let entries: Vec<WalkEntry> = walk_sources(&sources, options, context)?
+        .into_iter()
+        .filter(|entry| !is_zip || (!entry.is_dir && !entry.special))
+        .collect();
+    
+    let mut journal = JobJournal::open(&job_dir)?;
+    let (done, pending): (Vec<&WalkEntry>, Vec<&WalkEntry>) = entries.iter()
+        .partition(|entry| journal.done.contains(&job_entry_name(entry)));
+    let mut progress = Progress::new(entries.iter().map(|entry| entry.size).sum(), progress_callback);
+    progress.done = done.iter().map(|entry| entry.size).sum();
+    
+    let mut batch_start = 0;
+    while batch_start < pending.len() {
+        // A part ends after JOB_PART_FILES entries, or once it holds JOB_PART_BYTES
+        let mut batch_end = batch_start;
+        let mut batch_bytes = 0;
+        while batch_end < pending.len() && batch_end - batch_start < JOB_PART_FILES && batch_bytes < JOB_PART_BYTES {
+            batch_bytes += pending[batch_end].size;
+            batch_end += 1;
+        }
+        let batch = &pending[batch_start..batch_end];
+        
+        let (temp_file, _) = TempFile::new_in(&job_dir, SCRATCH_PREFIX)?;
+        let written = write_job_part(&job.compression_type, batch, temp_file.path(), options, context, &mut progress).await
+            .and_then(|_| Ok(File::open(temp_file.path())?.sync_all()?));
+        // Parts already finished stay for the next resume; only this one is removed
+        if let Err(e) = written {
+            return Err(disk_full_error(e, || scratch_file_size(temp_file)));
+        }
+        temp_file.persist(&job_dir.join(job_part_name(journal.parts)))?;
+        journal.record_part(batch.iter().map(|entry| job_entry_name(entry)))?;
+        batch_start = batch_end;
+    }
+    
+    options.check_cancelled()?;
+    let output_dir = job.output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let (temp_file, output) = TempFile::new_in(output_dir, SCRATCH_PREFIX)?;
+    let joined = join_job_parts(&job.compression_type, &job_dir, journal.parts, output)
+        .and_then(|_| if is_zip && options.force_zip64 { add_zip64_end(temp_file.path()) } else { Ok(()) })
+        .and_then(|_| if options.durable() { Ok(File::open(temp_file.path())?.sync_all()?) } else { Ok(()) });
+    if let Err(e) = joined {
+        return Err(disk_full_error(e, || scratch_file_size(temp_file)));
+    }
+    temp_file.persist(&job.output_path)?;
+    if options.durable() {
+        sync_dir(output_dir)?;
+    }
+    drop(journal);
+    job.remove();
+    
+    progress.complete();
+    Ok(job.output_path.clone())
+}
+
+// Write one part of a job: a zip of the batch, or for the tarballs the batch's tar
+// entries compressed on their own without the end-of-archive blocks
+async fn write_job_part<F>(
+    compression_type: &CompressionType,
+    batch: &[&WalkEntry],
+    part_path: &Path,
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress: &mut Progress<F>,
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let file = BufWriter::new(File::create(part_path)?);
+    match compression_type {
+        CompressionType::Zip => {
+            let mut zip = ZipWriter::new(file);
+            let method = if options.store { zip::CompressionMethod::Stored } else { zip::CompressionMethod::Deflated };
+            let zip_options = FileOptions::default()
+                .compression_method(method)
+                .unix_permissions(ZIP_ENTRY_MODE)
+                .large_file(options.force_zip64);
+            for entry in batch {
+                progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+                
+                let entry_options = match options.compatibility {
+                    ZipCompat::Modern => zip_options,
+                    ZipCompat::Legacy => zip_options.large_file(options.force_zip64 || entry.size > u32::MAX as u64),
+                };
+                let line_ending = text_line_ending(entry, options, context)?;
+                options.check_cancelled()?;
+                add_to_zip_with_progress(&mut zip, entry, &entry_options, options, context, line_ending, progress).await?;
+                context.file_done();
+            }
+            zip.finish()?.flush()?;
+            rewrite_zip_entries(part_path, batch.iter().copied(), &HashMap::new(), options, context)?;
+            if options.preserve_attributes() {
+                set_dos_attributes(part_path)?;
+            }
+        },
+        CompressionType::TarGz if options.threads() > 1 => {
+            let encoder = deflate::ParallelGzWriter::new(file, options.threads(), 6, None)?;
+            write_tar_part(encoder, batch, options, context, progress).await?
+                .finish()?.flush()?;
+        },
+        CompressionType::TarGz => {
+            let encoder = GzEncoder::new(file, FlateCompression::default());
+            write_tar_part(encoder, batch, options, context, progress).await?
+                .finish()?.flush()?;
+        },
+        CompressionType::TarZst => {
+            let encoder = zstd::stream::write::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            write_tar_part(encoder, batch, options, context, progress).await?
+                .finish()?.flush()?;
+        },
+        _ => return Err(anyhow::anyhow!("Not a job format: {:?}", compression_type)),
+    }
+    Ok(())
+}
 
-    matches!(extension, "zip" | "gz" | "br" | "gzip" | "bzip2" | "bz2" | "rar")
-}
\ No newline at end of file
+async fn write_tar_part<W: Write, F>(
+    writer: W,
+    batch: &[&WalkEntry],
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress: &mut Progress<F>,
+) -> Result<W>
+where
+    F: FnMut(f64, String),
+{
+    let mut tar = TarBuilder::new(OpenEnded { inner: writer, closed: false });
+    for entry in batch {
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        
+        let line_ending = text_line_ending(entry, options, context)?;
+        options.check_cancelled()?;
+        add_to_tar_with_progress(&mut tar, entry, options, context, line_ending, progress).await?;
+        if !entry.is_dir {
+            context.file_done();
+        }
+    }
+    // The end-of-archive blocks go after the last part only, in join_job_parts
+    tar.get_mut().closed = true;
+    Ok(tar.into_inner()?.inner)
+}
+
+// Passes writes through until closed, then swallows them
+struct OpenEnded<W: Write> {
+    inner: W,
+    closed: bool,
+}
+
+impl<W: Write> Write for OpenEnded<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.closed {
+            return Ok(buf.len());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn join_job_parts(compression_type: &CompressionType, job_dir: &Path, parts: usize, output: File) -> Result<()> {
+    let mut writer = BufWriter::new(output);
+    if let CompressionType::Zip = compression_type {
+        let mut zip = ZipWriter::new(writer);
+        for part in 0..parts {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(job_dir.join(job_part_name(part)))?))?;
+            for index in 0..archive.len() {
+                zip.raw_copy_file(archive.by_index_raw(index)?)?;
+            }
+        }
+        zip.finish()?.flush()?;
+        return Ok(());
+    }
+    
+    for part in 0..parts {
+        std::io::copy(&mut File::open(job_dir.join(job_part_name(part)))?, &mut writer)?;
+    }
+    // A last member (or frame) of its own with the end-of-archive blocks
+    let end = [0u8; 1024];
+    match compression_type {
+        CompressionType::TarGz => {
+            let mut encoder = GzEncoder::new(&mut writer, FlateCompression::default());
+            encoder.write_all(&end)?;
+            encoder.finish()?;
+        },
+        _ => writer.write_all(&zstd::encode_all(&end[..], zstd::DEFAULT_COMPRESSION_LEVEL)?)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Prefix of the scratch file an archive is written to before it is renamed into place
+const SCRATCH_PREFIX: &str = ".tauzip-";
+
+// The single-file formats store exactly their one source
+fn single_file_entries(file_path: &Path) -> Result<Vec<WalkEntry>> {
+    Ok(vec![WalkEntry {
+        path: file_path.to_path_buf(),
+        archive_name: PathBuf::from(file_path.file_name().unwrap_or_default()),
+        is_dir: false,
+        size: std::fs::metadata(file_path)?.len(),
+        special: false,
+    }])
+}
+
+// Hex SHA-256 of everything `reader` yields, as written to manifests
+fn sha256_hex<R: Read>(mut reader: R) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Describe the files that went into the archive, from the very entry list it was built from
+fn write_manifest(output_path: &Path, entries: &[WalkEntry], format: ManifestFormat, with_hashes: bool) -> Result<()> {
+    let mut manifest = Vec::new();
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        let mtime = std::fs::metadata(&entry.path)?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let sha256 = if with_hashes && !entry.special {
+            Some(sha256_hex(File::open(&entry.path)?)?)
+        } else {
+            None
+        };
+        manifest.push(ManifestEntry {
+            path: entry.archive_name.to_string_lossy().replace('\\', "/"),
+            size: entry.size,
+            mtime,
+            sha256,
+        });
+    }
+    
+    let (extension, contents) = match format {
+        ManifestFormat::Json => ("json", serde_json::to_string_pretty(&manifest)?),
+        ManifestFormat::Txt => {
+            let lines: Vec<String> = manifest.iter()
+                .map(|entry| {
+                    let mut line = format!("{}\t{}\t{}", entry.path, entry.size, entry.mtime);
+                    if let Some(hash) = &entry.sha256 {
+                        line.push('\t');
+                        line.push_str(hash);
+                    }
+                    line
+                })
+                .collect();
+            ("txt", lines.join("\n") + "\n")
+        },
+    };
+    
+    let mut manifest_name = output_path.file_name().unwrap_or_default().to_os_string();
+    manifest_name.push(format!(".manifest.{}", extension));
+    std::fs::write(output_path.with_file_name(manifest_name), contents)?;
+    Ok(())
+}
+
+// The canonical path of a source as an entry name for absolute_paths, without the \\?\
+// prefix Windows canonicalize adds, so it reads "C:\Users\..." (or "\\server\share\..."
+// for a network share)
+fn absolute_entry_name(path: &Path) -> Result<PathBuf> {
+    let canonical = path.canonicalize()
+        .map_err(|e| anyhow::anyhow!("Failed to resolve '{}': {}", path.display(), e))?;
+    let text = canonical.to_string_lossy();
+    Ok(match text.strip_prefix(r"\\?\UNC\") {
+        Some(share) => PathBuf::from(format!(r"\\{}", share)),
+        None => PathBuf::from(text.strip_prefix(r"\\?\").unwrap_or(&text)),
+    })
+}
+
+// A file or directory found while walking the selection
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    // Name the entry gets inside the archive
+    pub archive_name: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    // A FIFO or device node kept for preserve_special; it has no content to read
+    pub special: bool,
+}
+
+// Expand the selected paths into a flat list of entries. Each source comes with the
+// name it should have in the archive; children are named below it.
+fn walk_sources(sources: &[(PathBuf, PathBuf)], options: &CompressOptions, context: &CompressContext) -> Result<Vec<WalkEntry>> {
+    if let (Some(min), Some(max)) = (options.min_size, options.max_size) {
+        if min > max {
+            return Err(anyhow::anyhow!("The minimum file size ({} bytes) is larger than the maximum ({} bytes)", min, max));
+        }
+    }
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    for (path, archive_name) in sources {
+        // ContentsOnly drops the selected folder's own name, so its children
+        // end up where the folder itself would have been
+        let archive_name = if options.absolute_paths {
+            absolute_entry_name(path)?
+        } else if options.folder_mode == FolderMode::ContentsOnly && path.is_dir() {
+            archive_name.parent().map(Path::to_path_buf).unwrap_or_default()
+        } else {
+            // source_names numbers a selection whose name an earlier one already has
+            if path.file_name().is_some_and(|name| name != archive_name.as_os_str()) {
+                context.warn(format!("More than one selection is named '{}'; {} is stored as '{}'",
+                    path.file_name().unwrap_or_default().to_string_lossy(), path.display(), archive_name.display()));
+            }
+            archive_name.clone()
+        };
+        walk_path(path, &archive_name, options, context, &mut visited, &mut entries)?;
+    }
+    options.report_scan(entries.len());
+    
+    // The archive being written may sit inside a selected folder
+    entries.retain(|entry| {
+        let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+        !(name.starts_with(SCRATCH_PREFIX) && name.ends_with(".tmp"))
+    });
+    
+    if options.min_size.is_some() || options.max_size.is_some() {
+        let mut filtered = SizeFilterSummary::default();
+        entries.retain(|entry| {
+            let keep = entry.is_dir || entry.special || options.size_allowed(entry.size);
+            if !keep {
+                filtered.skipped_files += 1;
+                filtered.skipped_bytes += entry.size;
+            }
+            keep
+        });
+        // Stored rather than added, since one compression may walk the sources more than once
+        context.tally.size_filtered_files.store(filtered.skipped_files, Ordering::SeqCst);
+        context.tally.size_filtered_bytes.store(filtered.skipped_bytes, Ordering::SeqCst);
+        if !options.allow_empty && filtered.skipped_files > 0 && entries.iter().all(|entry| entry.is_dir) {
+            return Err(anyhow::anyhow!("Nothing to archive: every file is outside the size limits"));
+        }
+    }
+    
+    if options.newer_than.is_some() && !options.allow_empty && entries.iter().all(|entry| entry.is_dir) {
+        return Err(anyhow::anyhow!("Nothing to archive: no file was modified after the given time"));
+    }
+    
+    if options.skip_errors {
+        entries.retain(|entry| match context.open_file(&entry.path) {
+            Err(e) if !entry.is_dir && !entry.special => {
+                context.warn(format!("Skipped unreadable file {}: {}", entry.path.display(), e));
+                false
+            },
+            _ => true,
+        });
+    }
+    
+    context.tally.files_total.store(entries.iter().filter(|entry| !entry.is_dir).count(), Ordering::SeqCst);
+    sort_entries(&mut entries, options.entry_order);
+    Ok(entries)
+}
+
+fn sort_entries(entries: &mut [WalkEntry], order: EntryOrder) {
+    if order == EntryOrder::AsGiven {
+        return;
+    }
+    
+    let extension = |entry: &WalkEntry| entry.archive_name.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    // Stable, so entries that compare equal keep the order they were walked in
+    entries.sort_by(|a, b| {
+        b.is_dir.cmp(&a.is_dir).then_with(|| {
+            if a.is_dir {
+                return a.archive_name.cmp(&b.archive_name);
+            }
+            match order {
+                EntryOrder::AsGiven => std::cmp::Ordering::Equal,
+                EntryOrder::PathAsc => a.archive_name.cmp(&b.archive_name),
+                // Ties go by path, so the same inputs always give the same archive
+                EntryOrder::SizeAsc => a.size.cmp(&b.size)
+                    .then_with(|| a.archive_name.cmp(&b.archive_name)),
+                EntryOrder::SizeDesc => b.size.cmp(&a.size)
+                    .then_with(|| a.archive_name.cmp(&b.archive_name)),
+                EntryOrder::ExtensionGrouped => extension(a).cmp(&extension(b))
+                    .then_with(|| a.archive_name.cmp(&b.archive_name)),
+            }
+        })
+    });
+}
+
+// Every selected file (including those inside selected folders) that can't be opened
+// for reading, as "path: reason"
+fn unreadable_sources(files: &[PathBuf], options: &CompressOptions, context: &CompressContext) -> Result<Vec<String>> {
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    
+    Ok(walk_sources(&sources, options, context)?
+        .iter()
+        .filter(|entry| !entry.is_dir && !entry.special)
+        .filter_map(|entry| context.open_file(&entry.path).err().map(|e| format!("{}: {}", entry.path.display(), e)))
+        .collect())
+}
+
+fn walk_path(
+    path: &Path,
+    archive_name: &Path,
+    options: &CompressOptions,
+    context: &CompressContext,
+    visited: &mut HashSet<DirKey>,
+    entries: &mut Vec<WalkEntry>,
+) -> Result<()> {
+    // Reading a FIFO blocks until something writes to it, and device nodes can be
+    // endless, so anything that isn't a regular file or folder is never read
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if is_special_file(&metadata) {
+            if options.preserve_special && !is_socket(&metadata) {
+                entries.push(WalkEntry {
+                    path: path.to_path_buf(),
+                    archive_name: archive_name.to_path_buf(),
+                    is_dir: false,
+                    size: 0,
+                    special: true,
+                });
+            } else {
+                context.warn(format!("Skipped special file (device node, FIFO or socket): {}", path.display()));
+            }
+            return Ok(());
+        }
+    }
+    
+    if path.is_file() {
+        let metadata = std::fs::metadata(path)?;
+        if let Some(newer_than) = options.newer_than {
+            if metadata.modified()? <= newer_than {
+                return Ok(());
+            }
+        }
+        entries.push(WalkEntry {
+            path: path.to_path_buf(),
+            archive_name: archive_name.to_path_buf(),
+            is_dir: false,
+            size: metadata.len(),
+            special: false,
+        });
+    } else if path.is_dir() {
+        // Following symlinks or junctions can lead back to a directory we are already
+        // inside, so every directory is only walked once
+        if !visited.insert(dir_key(path)?) {
+            context.warn(format!("Skipped folder already archived (symlink cycle?): {}", path.display()));
+            return Ok(());
+        }
+
+        // A folder archived as ContentsOnly at the root has no entry of its own
+        if !archive_name.as_os_str().is_empty() {
+            entries.push(WalkEntry {
+                path: path.to_path_buf(),
+                archive_name: archive_name.to_path_buf(),
+                is_dir: true,
+                size: 0,
+                special: false,
+            });
+        }
+
+        for entry in std::fs::read_dir(path)? {
+            options.check_cancelled()?;
+            let entry = entry?;
+            let child = entry.path();
+
+            #[cfg(target_os = "windows")]
+            if !options.follow_junctions && is_reparse_link(&child) {
+                context.warn(format!("Skipped junction/symlink: {}", child.display()));
+                continue;
+            }
+
+            walk_path(&child, &archive_name.join(entry.file_name()), options, context, visited, entries)?;
+            if entries.len() % SCAN_REPORT_INTERVAL == 0 {
+                options.report_scan(entries.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Identifies a directory regardless of which path (or link) was used to reach it
+#[cfg(unix)]
+type DirKey = (u64, u64);
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+#[cfg(unix)]
+fn dir_key(path: &Path) -> Result<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_key(path: &Path) -> Result<DirKey> {
+    Ok(std::fs::canonicalize(path)?)
+}
+
+// Junctions and symlinks are name-surrogate reparse points, which Rust reports as symlinks.
+// Other reparse points such as cloud placeholder files are left alone.
+#[cfg(target_os = "windows")]
+fn is_reparse_link(path: &Path) -> bool {
+    std::fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+// Split the inputs into named groups, in the order each group is first seen. With
+// GroupBy::None everything is one group with an empty name.
+pub fn group_sources(files: &[PathBuf], group_by: GroupBy) -> Vec<(String, Vec<PathBuf>)> {
+    let mut groups: Vec<(String, Vec<PathBuf>)> = Vec::new();
+    
+    for file in files {
+        let key = match group_by {
+            GroupBy::None => String::new(),
+            GroupBy::Parent => file.parent()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            GroupBy::Extension => match file.extension() {
+                Some(ext) if file.is_file() => ext.to_string_lossy().to_lowercase(),
+                _ => "other".to_string(),
+            },
+        };
+        
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, members)) => members.push(file.clone()),
+            None => groups.push((key, vec![file.clone()])),
+        }
+    }
+    
+    // Name parent groups after the folder itself rather than its full path
+    if group_by == GroupBy::Parent {
+        for (name, _) in groups.iter_mut() {
+            let folder = Path::new(name.as_str()).file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| "root".to_string());
+            *name = folder;
+        }
+    }
+    groups
+}
+
+// Output path for one group: the group name goes before the extension, so
+// "backup.zip" becomes "backup-photos.zip"
+pub fn group_output_path(output_path: &Path, group: &str, compression_type: &CompressionType) -> PathBuf {
+    let file_name = output_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let extension = compression_type.extension();
+    let stem = if !extension.is_empty() && file_name.to_lowercase().ends_with(&extension) {
+        &file_name[..file_name.len() - extension.len()]
+    } else {
+        file_name.as_str()
+    };
+    get_unique_name(&output_path.with_file_name(format!("{}-{}{}", stem, group, extension)))
+}
+
+// Pick an output path beside the sources when the user did not choose one:
+// a single input keeps its own name (`notes.txt.gz`, `photos.zip`), several inputs
+// are named after their common parent directory. Existing files are never overwritten.
+pub fn quick_output_path(files: &[PathBuf], compression_type: &CompressionType) -> Result<PathBuf> {
+    let first = files.first().ok_or_else(|| anyhow::anyhow!("No files to compress"))?;
+    
+    let (parent, base_name) = if files.len() == 1 {
+        let parent = first.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
+        let base_name = if compression_type.supports_multiple_files() && first.is_file() {
+            first.file_stem().unwrap_or_default().to_string_lossy().to_string()
+        } else {
+            first.file_name().unwrap_or_default().to_string_lossy().to_string()
+        };
+        (parent, base_name)
+    } else {
+        let parent = find_common_base_dir(files).unwrap_or_else(|| PathBuf::from("."));
+        let base_name = std::fs::canonicalize(&parent)
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            .unwrap_or_else(|| "archive".to_string());
+        (parent, base_name)
+    };
+    
+    let output_path = parent.join(format!("{}{}", base_name, compression_type.extension()));
+    Ok(get_unique_name(&output_path))
+}
+
+fn find_common_base_dir(files: &[PathBuf]) -> Option<PathBuf> {
+    if files.is_empty() {
+        return None;
+    }
+
+    // Start with the first file's parent directory
+    let mut common_base = files[0].parent()?.to_path_buf();
+
+    // Find the common ancestor of all file paths
+    for file in &files[1..] {
+        if let Some(file_parent) = file.parent() {
+            // Find the common path between current common_base and this file's parent
+            if let Some(new_common) = find_common_path(&common_base, file_parent) {
+                common_base = new_common;
+            } else {
+                // No common path found, use current directory
+                return Some(PathBuf::from("."));
+            }
+        }
+    }
+
+    Some(common_base)
+}
+
+fn find_common_path(path1: &Path, path2: &Path) -> Option<PathBuf> {
+    let components1: Vec<_> = path1.components().collect();
+    let components2: Vec<_> = path2.components().collect();
+    
+    let mut common_components = Vec::new();
+    
+    for (comp1, comp2) in components1.iter().zip(components2.iter()) {
+        if comp1 == comp2 {
+            common_components.push(comp1.as_os_str());
+        } else {
+            break;
+        }
+    }
+    
+    if common_components.is_empty() {
+        None
+    } else {
+        // Reconstruct the path from components
+        let mut result = PathBuf::new();
+        for component in common_components {
+            result.push(component);
+        }
+        Some(result)
+    }
+}
+
+fn add_to_zip_sync_with_progress<F>(
+    zip: &mut ZipWriter<BufWriter<File>>,
+    entry: &WalkEntry,
+    options: &FileOptions,
+    compress_options: &CompressOptions,
+    context: &CompressContext,
+    line_ending: Option<LineEnding>,
+    progress: &mut Progress<F>,
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    // Ensure we use forward slashes for zip paths (cross-platform compatibility)
+    let zip_path = zip_entry_path(&entry.archive_name, context);
+    let options = &options.last_modified_time(zip_entry_time(&entry.path));
+    
+    let attributes = if compress_options.preserve_attributes() {
+        read_windows_attributes(&entry.path)
+    } else {
+        None
+    };
+    
+    // Entries of 4 GiB and up would need Zip64 in the scratch archive
+    let with_strategy = compress_options.deflate_strategy != DeflateStrategy::Default && !compress_options.store;
+    if with_strategy && entry.size < u32::MAX as u64 {
+        add_to_zip_with_strategy(zip, entry, &zip_path, attributes, compress_options, context, line_ending)?;
+        progress.add(entry.size);
+        return Ok(());
+    } else if with_strategy {
+        context.warn(format!("{} is too large for a deflate strategy, so it used the default one", entry.path.display()));
+    }
+    
+    if compress_options.entry_metadata.is_some() || attributes.is_some() {
+        zip.start_file_with_extra_data(&zip_path, *options)?;
+        if let Some(metadata) = &compress_options.entry_metadata {
+            set_entry_metadata(zip, metadata)?;
+        }
+        if let Some(attributes) = attributes {
+            write_extra_field(zip, ATTRIBUTES_FIELD_ID, &attributes.to_le_bytes())?;
+        }
+        zip.end_extra_data()?;
+    } else {
+        zip.start_file(&zip_path, *options)?;
+    }
+    
+    // Progress counts source bytes, so converted text still adds up to its file size
+    let file = CancellableReader { inner: context.open_file(&entry.path)?, cancel: compress_options.cancel.clone() };
+    let mut file = ProgressReader::new(file, progress);
+    match line_ending {
+        Some(ending) => std::io::copy(&mut LineEndingReader::new(BufReader::new(file), ending), zip)?,
+        None => std::io::copy(&mut file, zip)?,
+    };
+    Ok(())
+}
+
+// Unix mode of every file entry written to a zip
+const ZIP_ENTRY_MODE: u32 = 0o755;
+
+// A zip entry's modification time: its source file's, in the zone-less fields zip has.
+// Written as UTC, the way dos_time_to_unix reads it back. Times zip can't hold (before
+// 1980) become 1980-01-01.
+fn zip_entry_time(path: &Path) -> zip::DateTime {
+    let secs = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs() as i64);
+    // Howard Hinnant's civil_from_days, the inverse of dos_time_to_unix's days_from_civil
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    zip::DateTime::from_date_and_time(
+        year as u16,
+        month as u8,
+        day as u8,
+        (time_of_day / 3600) as u8,
+        (time_of_day % 3600 / 60) as u8,
+        (time_of_day % 60) as u8,
+    ).unwrap_or_default()
+}
+
+async fn add_to_zip_with_progress<F>(
+    zip: &mut ZipWriter<BufWriter<File>>,
+    entry: &WalkEntry,
+    options: &FileOptions,
+    compress_options: &CompressOptions,
+    context: &CompressContext,
+    line_ending: Option<LineEnding>,
+    progress: &mut Progress<F>,
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    add_to_zip_sync_with_progress(zip, entry, options, compress_options, context, line_ending, progress)
+}
+
+// zip 0.6 only deflates with its own settings, so the entry is deflated with the
+// strategy into a one-entry scratch zip and copied in from there (see one_entry_zip).
+// The copy loses the extra fields; rewrite_zip_entries puts them back.
+fn add_to_zip_with_strategy(
+    zip: &mut ZipWriter<BufWriter<File>>,
+    entry: &WalkEntry,
+    zip_path: &str,
+    attributes: Option<u32>,
+    compress_options: &CompressOptions,
+    context: &CompressContext,
+    line_ending: Option<LineEnding>,
+) -> Result<()> {
+    let extra = zip_extra_fields(compress_options, attributes)?;
+    let temp_dir = compress_options.scratch_dir();
+    
+    let file = CancellableReader { inner: context.open_file(&entry.path)?, cancel: compress_options.cancel.clone() };
+    let source: Box<dyn Read> = match line_ending {
+        Some(ending) => Box::new(LineEndingReader::new(BufReader::new(file), ending)),
+        None => Box::new(file),
+    };
+    let temp_file = one_entry_zip(&temp_dir, zip_path, zip_entry_time(&entry.path), 8, &extra, |scratch| {
+        let mut reader = flate2::CrcReader::new(source);
+        let mut encoder = deflate::StrategyEncoder::new(scratch, 6, compress_options.deflate_strategy);
+        let size = std::io::copy(&mut reader, &mut encoder)?;
+        Ok((encoder.finish()?, reader.crc().sum(), size))
+    })?;
+    
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(temp_file.path())?))?;
+    zip.raw_copy_file_rename(archive.by_index_raw(0)?, zip_path)?;
+    Ok(())
+}
+
+// The extra fields a file entry gets: the archive's entry_metadata and the file's
+// Windows attributes
+fn zip_extra_fields(compress_options: &CompressOptions, attributes: Option<u32>) -> Result<Vec<u8>> {
+    let mut extra = Vec::new();
+    if let Some(metadata) = &compress_options.entry_metadata {
+        set_entry_metadata(&mut extra, metadata)?;
+    }
+    if let Some(attributes) = attributes {
+        write_extra_field(&mut extra, ATTRIBUTES_FIELD_ID, &attributes.to_le_bytes())?;
+    }
+    Ok(extra)
+}
+
+// zip 0.6 can't take data that is already compressed, but it can copy an entry from
+// another archive, keeping its time and mode (not its extra fields). So such an entry
+// is written as a one-entry scratch zip in `temp_dir`, headers and all, for
+// raw_copy_file_rename. `data` writes the compressed bytes (with `method`) and returns
+// the writer, the CRC and the uncompressed size.
+fn one_entry_zip<F>(temp_dir: &Path, zip_path: &str, time: zip::DateTime, method: u16, extra: &[u8], data: F) -> Result<TempFile>
+where
+    F: FnOnce(BufWriter<File>) -> Result<(BufWriter<File>, u32, u64)>,
+{
+    let (temp_file, file) = TempFile::new_in(temp_dir, SCRATCH_PREFIX)?;
+    let mut scratch = BufWriter::new(file);
+    
+    // Local header; CRC and sizes are filled in once the data is written
+    let name = zip_path.as_bytes();
+    // Stored and deflated need 2.0; bzip2, LZMA and zstd more, up to 6.3
+    let version: u16 = if method == 0 || method == 8 { 20 } else { 63 };
+    let mut header = Vec::with_capacity(30 + name.len() + extra.len());
+    header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+    header.extend_from_slice(&version.to_le_bytes());
+    // Bit 11: the name is UTF-8
+    header.extend_from_slice(&(1u16 << 11).to_le_bytes());
+    header.extend_from_slice(&method.to_le_bytes());
+    header.extend_from_slice(&time.timepart().to_le_bytes());
+    header.extend_from_slice(&time.datepart().to_le_bytes());
+    header.extend_from_slice(&[0u8; 12]);
+    header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    header.extend_from_slice(name);
+    header.extend_from_slice(extra);
+    scratch.write_all(&header)?;
+    
+    let (mut scratch, crc, size) = data(scratch)?;
+    let data_end = scratch.stream_position()?;
+    let compressed_size = data_end - header.len() as u64;
+    // Line ending conversion can push an entry past the limit checked up front
+    if compressed_size >= u32::MAX as u64 || size >= u32::MAX as u64 {
+        return Err(anyhow::anyhow!("'{}' is too large to copy in without Zip64", zip_path));
+    }
+    let sizes = [crc, compressed_size as u32, size as u32];
+    
+    // Central directory: made by Unix, with the mode regular entries get
+    let mut central = Vec::with_capacity(46 + name.len() + extra.len());
+    central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+    central.extend_from_slice(&((3u16 << 8) | version).to_le_bytes());
+    central.extend_from_slice(&header[4..14]);
+    for value in sizes {
+        central.extend_from_slice(&value.to_le_bytes());
+    }
+    central.extend_from_slice(&header[26..30]);
+    // Comment length, disk number, internal attributes
+    central.extend_from_slice(&[0u8; 6]);
+    central.extend_from_slice(&((0o100000u32 | ZIP_ENTRY_MODE) << 16).to_le_bytes());
+    central.extend_from_slice(&0u32.to_le_bytes());
+    central.extend_from_slice(name);
+    central.extend_from_slice(extra);
+    scratch.write_all(&central)?;
+    
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&[0u8; 4]);
+    end.extend_from_slice(&1u16.to_le_bytes());
+    end.extend_from_slice(&1u16.to_le_bytes());
+    end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    end.extend_from_slice(&(data_end as u32).to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    scratch.write_all(&end)?;
+    
+    scratch.seek(std::io::SeekFrom::Start(14))?;
+    for value in sizes {
+        scratch.write_all(&value.to_le_bytes())?;
+    }
+    scratch.flush()?;
+    Ok(temp_file)
+}
+
+fn set_entry_metadata<W: Write>(writer: &mut W, metadata: &EntryMetadata) -> Result<()> {
+    let data = metadata.value.as_bytes();
+    // The block length is a u16 and the whole extra area must fit in 64 KiB
+    if data.len() > u16::MAX as usize - 4 {
+        return Err(anyhow::anyhow!("Entry metadata is too large ({} bytes)", data.len()));
+    }
+    write_extra_field(writer, metadata.field_id, data)
+}
+
+// Write one extra field block (id, length, data) for the entry that was just started
+fn write_extra_field<W: Write>(writer: &mut W, field_id: u16, data: &[u8]) -> Result<()> {
+    writer.write_all(&field_id.to_le_bytes())?;
+    writer.write_all(&(data.len() as u16).to_le_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+// zip 0.6 always writes unix-style external attributes and never exposes the DOS
+// attribute byte, so Windows attributes travel in a private extra field (a
+// little-endian u32), which is what extraction reads. set_dos_attributes copies them
+// into the DOS byte of the finished archive for other tools.
+const ATTRIBUTES_FIELD_ID: u16 = 0x5A54;
+
+// Put each entry's Windows attributes (its ATTRIBUTES_FIELD_ID field) in the low byte of
+// its central directory external attributes, where Explorer, 7-Zip and Info-ZIP look
+// for them. Only those bytes change, in place, so nothing moves.
+fn set_dos_attributes(archive_path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(archive_path)?;
+    let directory = find_zip_directory(&mut file)?
+        .ok_or_else(|| CompressionError::Corrupt { reason: "end of central directory not found".to_string() })?;
+    
+    let mut patches = Vec::new();
+    let mut reader = BufReader::new(&file);
+    reader.seek(std::io::SeekFrom::Start(directory.offset))?;
+    let mut position = directory.offset;
+    for _ in 0..directory.entries {
+        let mut header = vec![0u8; 46];
+        if reader.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(CompressionError::Corrupt { reason: "damaged central directory".to_string() }.into());
+        }
+        let (name_len, extra_len) = (u16_at(&header, 28) as usize, u16_at(&header, 30) as usize);
+        header.resize(46 + name_len + extra_len + u16_at(&header, 32) as usize, 0);
+        reader.read_exact(&mut header[46..])?;
+        
+        let attributes = read_entry_metadata(&header[46 + name_len..46 + name_len + extra_len], ATTRIBUTES_FIELD_ID)
+            .and_then(|data| <[u8; 4]>::try_from(data).ok())
+            .map(u32::from_le_bytes);
+        if let Some(attributes) = attributes {
+            let external = (u32_at(&header, 38) & !0xFF) | (attributes & 0xFF);
+            patches.push((position + 38, external));
+        }
+        position += header.len() as u64;
+    }
+    drop(reader);
+    
+    for (at, external) in patches {
+        file.seek(std::io::SeekFrom::Start(at))?;
+        file.write_all(&external.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Read-only, hidden, system and archive
+#[cfg(windows)]
+const PRESERVED_ATTRIBUTES: u32 = 0x1 | 0x2 | 0x4 | 0x20;
+
+#[cfg(windows)]
+fn read_windows_attributes(path: &Path) -> Option<u32> {
+    use std::os::windows::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|metadata| metadata.file_attributes() & PRESERVED_ATTRIBUTES)
+}
+
+#[cfg(not(windows))]
+fn read_windows_attributes(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(windows)]
+fn apply_windows_attributes(path: &Path, attributes: u32) -> Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{GetFileAttributesW, SetFileAttributesW, INVALID_FILE_ATTRIBUTES};
+    
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    unsafe {
+        let current = GetFileAttributesW(wide_path.as_ptr());
+        if current == INVALID_FILE_ATTRIBUTES {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let updated = (current & !PRESERVED_ATTRIBUTES) | (attributes & PRESERVED_ATTRIBUTES);
+        if SetFileAttributesW(wide_path.as_ptr(), updated) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn apply_windows_attributes(_path: &Path, _attributes: u32) -> Result<()> {
+    Ok(())
+}
+
+// Find the block with the given id in a zip extra field area
+fn read_entry_metadata(extra_data: &[u8], field_id: u16) -> Option<&[u8]> {
+    let mut rest = extra_data;
+    while rest.len() >= 4 {
+        let id = u16::from_le_bytes([rest[0], rest[1]]);
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let data = rest.get(4..4 + len)?;
+        if id == field_id {
+            return Some(data);
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+async fn compress_tar_gz_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::create(output_path)?;
+    if options.threads() > 1 {
+        let gz_writer = deflate::ParallelGzWriter::new(BufWriter::new(file), options.threads(), 6, None)?;
+        let mut tar = TarBuilder::new(gz_writer);
+        let entries = append_tar_entries(&mut tar, files, options, context, progress_callback).await?;
+        tar.into_inner()?.finish()?.flush()?;
+        return Ok(entries);
+    }
+    let gz_encoder = GzEncoder::new(BufWriter::new(file), FlateCompression::default());
+    let mut tar = TarBuilder::new(gz_encoder);
+
+    let entries = append_tar_entries(&mut tar, files, options, context, progress_callback).await?;
+    tar.finish()?;
+    Ok(entries)
+}
+
+async fn compress_tar_br_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let memory = fit_codec_memory(&CompressionType::TarBr, options.memory_limit)?;
+    let file = File::create(output_path)?;
+    let br_encoder = brotli::CompressorWriter::new(BufWriter::new(file), 4096, BROTLI_QUALITY, memory.brotli_lgwin.unwrap_or(BROTLI_LGWIN));
+    let mut tar = TarBuilder::new(br_encoder);
+
+    let entries = append_tar_entries(&mut tar, files, options, context, progress_callback).await?;
+    tar.finish()?;
+    Ok(entries)
+}
+
+async fn append_tar_entries<W: Write, F>(
+    tar: &mut TarBuilder<W>,
+    files: &[PathBuf],
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress_callback: F,
+) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    // For tar files, each selected item is stored under its own name
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    let entries = walk_sources(&sources, options, context)?;
+
+    let mut progress = Progress::new(entries.iter().map(|entry| entry.size).sum(), progress_callback);
+    for entry in &entries {
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        
+        let line_ending = text_line_ending(entry, options, context)?;
+        options.check_cancelled()?;
+        add_to_tar_with_progress(tar, entry, options, context, line_ending, &mut progress).await?;
+        if !entry.is_dir {
+            context.file_done();
+        }
+    }
+
+    progress.complete();
+    Ok(entries)
+}
+
+// Add files to the end of an existing uncompressed .tar without rewriting what is
+// already there: the new entries overwrite the end-of-archive blocks and a new end is
+// written after them. A compressed tarball would have to be recompressed, so those are
+// refused. If anything fails partway, the archive is cut back to its old end.
+pub async fn append_to_tar<F>(archive_path: &Path, files: &[PathBuf], options: &CompressOptions, progress_callback: F) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let file_name = archive_path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    if !is_tar_name(&normalized_name) {
+        return Err(anyhow::anyhow!("Not a tar archive: {}", archive_path.display()));
+    }
+    if !normalized_name.ends_with(".tar") {
+        return Err(CompressionError::Unsupported {
+            feature: "appending to a compressed tar (the whole archive would have to be recompressed)".to_string(),
+        }.into());
+    }
+    
+    let end = tar_data_end(archive_path)?;
+    let context = &CompressContext::new(options)?;
+    
+    let mut file = OpenOptions::new().read(true).write(true).open(archive_path)?;
+    file.seek(std::io::SeekFrom::Start(end))?;
+    let mut tar = TarBuilder::new(BufWriter::new(file));
+    let appended = match append_tar_entries(&mut tar, files, options, context, progress_callback).await {
+        Ok(_) => finish_tar_append(tar),
+        // The builder writes its end blocks when dropped, so it has to go before the
+        // archive is cut back below
+        Err(e) => {
+            drop(tar);
+            Err(e)
+        },
+    };
+    
+    let mut file = OpenOptions::new().write(true).open(archive_path)?;
+    match appended {
+        // Padding after the old end marker would otherwise trail the new one
+        Ok(new_end) => {
+            file.set_len(new_end)?;
+            Ok(())
+        },
+        // Drop whatever was half written and put the end-of-archive blocks back
+        Err(e) => {
+            file.set_len(end)?;
+            file.seek(std::io::SeekFrom::Start(end))?;
+            file.write_all(&[0u8; 1024])?;
+            Err(e)
+        },
+    }
+}
+
+// Write the end-of-archive blocks; returns where the archive now ends
+fn finish_tar_append(tar: TarBuilder<BufWriter<File>>) -> Result<u64> {
+    let mut file = tar.into_inner()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(file.stream_position()?)
+}
+
+// Offset just past the data of a tar's last entry, where its end-of-archive blocks start
+fn tar_data_end(archive_path: &Path) -> Result<u64> {
+    let mut archive = tar::Archive::new(File::open(archive_path)?);
+    let mut end = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let size = entry.header().entry_size()?;
+        end = entry.raw_file_position() + size.div_ceil(512) * 512;
+    }
+    Ok(end)
+}
+
+async fn add_to_tar_with_progress<W: Write, F: FnMut(f64, String)>(
+    tar: &mut TarBuilder<W>,
+    entry: &WalkEntry,
+    options: &CompressOptions,
+    context: &CompressContext,
+    line_ending: Option<LineEnding>,
+    progress: &mut Progress<F>,
+) -> Result<()> {
+    let archive_name = match entry.archive_name.strip_prefix(".") {
+        Ok(stripped) if options.normalize_paths() && !stripped.as_os_str().is_empty() => stripped,
+        _ => entry.archive_name.as_path(),
+    };
+    match line_ending {
+        Some(ending) => {
+            // The header needs the size up front, so run the conversion once just to measure it
+            let size = std::io::copy(&mut LineEndingReader::new(BufReader::new(context.open_file(&entry.path)?), ending), &mut std::io::sink())?;
+            
+            let mut header = tar_header(options.tar_format, &std::fs::metadata(&entry.path)?);
+            header.set_size(size);
+            // Progress counts the source bytes of the second pass
+            let source = ProgressReader::new(context.open_file(&entry.path)?, progress);
+            append_tar_entry(tar, header, archive_name, options.tar_format, LineEndingReader::new(BufReader::new(source), ending))?;
+        },
+        // A FIFO or device node is just its header: the type and device numbers come
+        // from the metadata
+        None if entry.special => {
+            let mut header = tar_header(options.tar_format, &std::fs::metadata(&entry.path)?);
+            header.set_size(0);
+            append_tar_entry(tar, header, archive_name, options.tar_format, std::io::empty())?;
+        },
+        // Directories only get their own header here; their contents are separate entries
+        None if entry.is_dir => {
+            let header = tar_header(options.tar_format, &std::fs::metadata(&entry.path)?);
+            append_tar_entry(tar, header, archive_name, options.tar_format, std::io::empty())?;
+        },
+        None => {
+            let header = tar_header(options.tar_format, &std::fs::metadata(&entry.path)?);
+            append_tar_entry(tar, header, archive_name, options.tar_format, ProgressReader::new(context.open_file(&entry.path)?, progress))?;
+        },
+    }
+    Ok(())
+}
+
+fn tar_header(format: TarFormat, metadata: &std::fs::Metadata) -> tar::Header {
+    let mut header = match format {
+        TarFormat::Ustar | TarFormat::Pax => tar::Header::new_ustar(),
+        TarFormat::Gnu => tar::Header::new_gnu(),
+    };
+    header.set_metadata(metadata);
+    header
+}
+
+// A zip entry name. Zip names are UTF-8 (the zip crate only takes them as Strings), so
+// a Unix name that isn't can only go in with U+FFFD for the bytes that don't decode;
+// say so rather than change it quietly. Tar and cpio keep such names exactly.
+fn zip_entry_path(name: &Path, context: &CompressContext) -> String {
+    if name.to_str().is_none() {
+        context.warn(format!("'{}' is not valid UTF-8, so zip can't store its name exactly; a tar or cpio archive would", name.display()));
+    }
+    name.to_string_lossy().replace('\\', "/")
+}
+
+// Write one entry, recording a name or size the header can't hold the way the format
+// does it, or failing for Ustar, which has no way to
+fn append_tar_entry<W: Write, R: Read>(tar: &mut TarBuilder<W>, mut header: tar::Header, name: &Path, format: TarFormat, data: R) -> Result<()> {
+    // The tar crate adds GNU long-name records itself
+    if format == TarFormat::Gnu {
+        tar.append_data(&mut header, name, data)?;
+        return Ok(());
+    }
+    
+    let size = header.size()?;
+    let name_fits = header.set_path(name).is_ok();
+    let mut extensions: Vec<(&str, Vec<u8>)> = Vec::new();
+    
+    if !name_fits {
+        if format == TarFormat::Ustar {
+            return Err(anyhow::anyhow!("'{}' is too long for a ustar tar; use the Pax or Gnu format", name.display()));
+        }
+        // The name's own bytes, like GNU tar writes them, even when they aren't UTF-8
+        let full_name = name_bytes(name);
+        // Readers without pax support still get the end of the name, not cut inside
+        // a UTF-8 sequence
+        let mut cut = full_name.len().saturating_sub(99);
+        while cut < full_name.len() && full_name[cut] & 0xC0 == 0x80 {
+            cut += 1;
+        }
+        let tail = &full_name[cut..];
+        header.set_path(path_from_bytes(&tail[tail.iter().take_while(|b| **b == b'/').count()..]))?;
+        extensions.push(("path", full_name));
+    }
+    if size > USTAR_MAX_SIZE {
+        if format == TarFormat::Ustar {
+            return Err(anyhow::anyhow!("'{}' is larger than the 8 GiB a ustar tar allows; use the Pax or Gnu format", name.display()));
+        }
+        extensions.push(("size", size.to_string().into_bytes()));
+    }
+    
+    if !extensions.is_empty() {
+        tar.append_pax_extensions(extensions.iter().map(|(key, value)| (*key, value.as_slice())))?;
+    }
+    header.set_cksum();
+    tar.append(&header, data)?;
+    Ok(())
+}
+
+async fn compress_tar_zst_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::create(output_path)?;
+    // zstd writes the dictionary's id into the frame header by default, which is what
+    // lets extraction ask for the right one
+    let mut zst_encoder = match &options.zstd_dictionary {
+        Some(dictionary_path) => {
+            let dictionary = load_zstd_dictionary(dictionary_path)?;
+            zstd::stream::write::Encoder::with_dictionary(BufWriter::new(file), zstd::DEFAULT_COMPRESSION_LEVEL, &dictionary)?
+        }
+        None => zstd::stream::write::Encoder::new(BufWriter::new(file), zstd::DEFAULT_COMPRESSION_LEVEL)?,
+    };
+    // Without a limit zstd keeps choosing the window from the input size
+    if options.memory_limit.is_some() {
+        let memory = fit_codec_memory(&CompressionType::TarZst, options.memory_limit)?;
+        zst_encoder.window_log(memory.zstd_window_log.unwrap_or(ZSTD_WINDOW_LOG))?;
+    }
+    let mut tar = TarBuilder::new(zst_encoder);
+
+    let entries = append_tar_entries(&mut tar, files, options, context, progress_callback).await?;
+    tar.into_inner()?.finish()?.flush()?;
+    Ok(entries)
+}
+
+// Store the files' chunks in the store beside the output, then write the manifest to
+// the output itself. Chunks already in the store from earlier backups are reused.
+async fn compress_chunks_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let store = ChunkStore::create_beside(output_path)?;
+    
+    // Named like tar entries: each selected item under its own name
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    // A backup of a folder that holds its own chunk store must not take in the store
+    let entries: Vec<WalkEntry> = walk_sources(&sources, options, context)?
+        .into_iter()
+        .filter(|entry| {
+            if entry.special {
+                context.warn(format!("Skipped special file (not supported in chunk backups): {}", entry.path.display()));
+            }
+            !entry.special && !entry.path.components().any(|component| component.as_os_str() == STORE_DIR)
+        })
+        .collect();
+    
+    let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut progress = Progress::new(total_size, progress_callback);
+    let mut manifest = ChunkManifest::new();
+    
+    for entry in &entries {
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        
+        options.check_cancelled()?;
+        let mtime = std::fs::metadata(&entry.path)?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let chunks = if entry.is_dir {
+            Vec::new()
+        } else {
+            let file = CancellableReader { inner: context.open_file(&entry.path)?, cancel: options.cancel.clone() };
+            let (chunks, _) = store.put_stream(ProgressReader::new(file, &mut progress))?;
+            context.file_done();
+            chunks
+        };
+        manifest.entries.push(ChunkEntry {
+            name: entry.archive_name.to_string_lossy().replace('\\', "/"),
+            is_dir: entry.is_dir,
+            size: entry.size,
+            mtime,
+            chunks,
+        });
+    }
+    
+    manifest.write(output_path)?;
+    progress.complete();
+    Ok(entries)
+}
+
+async fn compress_cpio_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::create(output_path)?;
+    let mut cpio = CpioWriter::new(BufWriter::new(file));
+
+    let entries = append_cpio_entries(&mut cpio, files, options, context, progress_callback).await?;
+    cpio.finish()?.flush()?;
+    Ok(entries)
+}
+
+async fn compress_cpio_gz_with_progress<F>(files: &[PathBuf], output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::create(output_path)?;
+    let gz_encoder = GzEncoder::new(BufWriter::new(file), FlateCompression::default());
+    let mut cpio = CpioWriter::new(gz_encoder);
+
+    let entries = append_cpio_entries(&mut cpio, files, options, context, progress_callback).await?;
+    cpio.finish()?.finish()?.flush()?;
+    Ok(entries)
+}
+
+async fn append_cpio_entries<W: Write, F>(
+    cpio: &mut CpioWriter<W>,
+    files: &[PathBuf],
+    options: &CompressOptions,
+    context: &CompressContext,
+    progress_callback: F,
+) -> Result<Vec<WalkEntry>>
+where
+    F: FnMut(f64, String),
+{
+    // Named like tar entries: each selected item under its own name
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    // Device nodes, FIFOs and sockets have no content that could be archived
+    let entries: Vec<WalkEntry> = walk_sources(&sources, options, context)?
+        .into_iter()
+        .filter(|entry| match std::fs::metadata(&entry.path) {
+            Ok(metadata) if is_special_file(&metadata) => {
+                context.warn(format!("Skipped special file (device node, FIFO or socket): {}", entry.path.display()));
+                false
+            },
+            _ => true,
+        })
+        .collect();
+
+    let mut progress = Progress::new(entries.iter().map(|entry| entry.size).sum(), progress_callback);
+    for entry in &entries {
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        
+        options.check_cancelled()?;
+        let metadata = std::fs::metadata(&entry.path)?;
+        let header = cpio_header(&entry.archive_name, &metadata);
+        if entry.is_dir {
+            cpio.append(&header, &mut std::io::empty())?;
+        } else {
+            cpio.append(&header, &mut BufReader::new(ProgressReader::new(context.open_file(&entry.path)?, &mut progress)))?;
+            context.file_done();
+        }
+    }
+
+    progress.complete();
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn cpio_header(archive_name: &Path, metadata: &std::fs::Metadata) -> CpioHeader {
+    use std::os::unix::fs::MetadataExt;
+    CpioHeader {
+        name: name_bytes(archive_name),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime() as u32,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+    }
+}
+
+#[cfg(not(unix))]
+fn cpio_header(archive_name: &Path, metadata: &std::fs::Metadata) -> CpioHeader {
+    let mtime = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    let (mode, size) = if metadata.is_dir() {
+        (crate::cpio::MODE_DIR | 0o755, 0)
+    } else {
+        (crate::cpio::MODE_FILE | 0o644, metadata.len())
+    };
+    CpioHeader {
+        name: name_bytes(archive_name),
+        mode,
+        uid: 0,
+        gid: 0,
+        mtime,
+        size,
+    }
+}
+
+#[cfg(unix)]
+fn is_special_file(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = metadata.file_type();
+    file_type.is_block_device() || file_type.is_char_device() || file_type.is_fifo() || file_type.is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_special_file(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_socket(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_socket(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+// The line ending to convert this entry to, if it is a text file and normalizing is on
+fn text_line_ending(entry: &WalkEntry, options: &CompressOptions, context: &CompressContext) -> Result<Option<LineEnding>> {
+    let (Some(ending), Some(matcher)) = (options.normalize_text, &context.text_matcher) else { return Ok(None) };
+    if entry.is_dir || entry.special {
+        return Ok(None);
+    }
+    
+    if !matcher.is_match(entry.path.file_name().unwrap_or_default()) {
+        return Ok(None);
+    }
+    
+    // Same heuristic as git: a NUL byte in the first 8000 bytes means binary
+    let mut head = Vec::new();
+    context.open_file(&entry.path)?.take(8000).read_to_end(&mut head)?;
+    Ok((!head.contains(&0)).then_some(ending))
+}
+
+// The file names normalize_text converts: text_globs, or the built-in list when that
+// is empty. Matched like ExtractOptions::pattern, but ignoring ASCII case.
+fn text_glob_set(options: &CompressOptions) -> Result<globset::GlobSet> {
+    let globs: Vec<&str> = if options.text_globs.is_empty() {
+        DEFAULT_TEXT_GLOBS.to_vec()
+    } else {
+        options.text_globs.iter().map(String::as_str).collect()
+    };
+    let mut set = globset::GlobSetBuilder::new();
+    for glob in globs {
+        set.add(globset::GlobBuilder::new(glob)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid text glob '{}'", glob))?);
+    }
+    Ok(set.build()?)
+}
+
+// Rewrites CRLF, CR and LF line breaks to one line ending as the data streams through
+struct LineEndingReader<R> {
+    inner: R,
+    ending: LineEnding,
+    pending: Vec<u8>,
+    pos: usize,
+    // A CR ended the previous chunk, so a leading LF belongs to it
+    last_was_cr: bool,
+}
+
+impl<R: Read> LineEndingReader<R> {
+    fn new(inner: R, ending: LineEnding) -> Self {
+        Self {
+            inner,
+            ending,
+            pending: Vec::new(),
+            pos: 0,
+            last_was_cr: false,
+        }
+    }
+}
+
+impl<R: Read> Read for LineEndingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.pending.len() {
+            let mut chunk = [0u8; 8192];
+            let bytes = self.inner.read(&mut chunk)?;
+            if bytes == 0 {
+                return Ok(0);
+            }
+            
+            self.pending.clear();
+            self.pos = 0;
+            for &byte in &chunk[..bytes] {
+                match byte {
+                    b'\r' => self.pending.extend_from_slice(self.ending.as_bytes()),
+                    b'\n' if self.last_was_cr => {},
+                    b'\n' => self.pending.extend_from_slice(self.ending.as_bytes()),
+                    _ => self.pending.push(byte),
+                }
+                self.last_was_cr = byte == b'\r';
+            }
+        }
+        
+        let bytes = buf.len().min(self.pending.len() - self.pos);
+        buf[..bytes].copy_from_slice(&self.pending[self.pos..self.pos + bytes]);
+        self.pos += bytes;
+        Ok(bytes)
+    }
+}
+
+// Byte progress of one operation, shared by every format. Sources or sinks are wrapped
+// in a ProgressReader or ProgressWriter, which count the bytes passing through, and the
+// callback gets that count as a percentage of `total` with the current file name. While
+// bytes flow, reports are at least PROGRESS_INTERVAL apart so fast codecs don't flood
+// the frontend with events; starting a file and completing always report.
+struct Progress<F> {
+    callback: F,
+    total: u64,
+    done: u64,
+    // Where the current file began
+    entry_start: u64,
+    filename: String,
+    last_report: Option<std::time::Instant>,
+    // Mirrors `done` for whoever started the operation, summed over nested extractions
+    shared: Option<Arc<AtomicU64>>,
+}
+
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+impl<F: FnMut(f64, String)> Progress<F> {
+    fn new(total: u64, callback: F) -> Self {
+        Self {
+            callback,
+            total,
+            done: 0,
+            entry_start: 0,
+            filename: String::new(),
+            last_report: None,
+            shared: None,
+        }
+    }
+    
+    fn counting(mut self, shared: Option<Arc<AtomicU64>>) -> Self {
+        self.shared = shared;
+        self
+    }
+    
+    fn percent(&self) -> f64 {
+        if self.total > 0 {
+            (self.done as f64 / self.total as f64 * 100.0).min(100.0)
+        } else {
+            0.0
+        }
+    }
+    
+    // Move on to another file, reporting straight away
+    fn start(&mut self, filename: String) {
+        self.entry_start = self.done;
+        self.filename = filename;
+        self.report();
+    }
+    
+    // Count the current file at its full size, however much of it went through a
+    // wrapper (it was skipped, failed, or wasn't the size its listing said)
+    fn entry_done(&mut self, size: u64) {
+        let done = self.entry_start + size;
+        if let Some(shared) = &self.shared {
+            if done >= self.done {
+                shared.fetch_add(done - self.done, Ordering::SeqCst);
+            } else {
+                shared.fetch_sub(self.done - done, Ordering::SeqCst);
+            }
+        }
+        self.done = done;
+    }
+    
+    // Count bytes done; the wrappers call this, and loops use it directly for entries
+    // that are skipped or copied without being read through a wrapper
+    fn add(&mut self, bytes: u64) {
+        self.done += bytes;
+        if let Some(shared) = &self.shared {
+            shared.fetch_add(bytes, Ordering::SeqCst);
+        }
+        // Reaching the total always reports, so a single stream ends on 100%
+        let due = self.done >= self.total || match self.last_report {
+            Some(at) => at.elapsed() >= PROGRESS_INTERVAL,
+            None => true,
+        };
+        if due {
+            self.report();
+        }
+    }
+    
+    fn report(&mut self) {
+        self.last_report = Some(std::time::Instant::now());
+        let (percent, filename) = (self.percent(), self.filename.clone());
+        (self.callback)(percent, filename);
+    }
+    
+    fn complete(&mut self) {
+        (self.callback)(100.0, "Complete".to_string());
+    }
+}
+
+// Counts the bytes read through it into a Progress
+struct ProgressReader<'a, R, F> {
+    inner: R,
+    progress: &'a mut Progress<F>,
+}
+
+impl<'a, R: Read, F: FnMut(f64, String)> ProgressReader<'a, R, F> {
+    fn new(inner: R, progress: &'a mut Progress<F>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read, F: FnMut(f64, String)> Read for ProgressReader<'_, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.progress.add(bytes as u64);
+        Ok(bytes)
+    }
+}
+
+// Counts the bytes written through it into a Progress
+struct ProgressWriter<'a, W, F> {
+    inner: W,
+    progress: &'a mut Progress<F>,
+}
+
+impl<'a, W: Write, F: FnMut(f64, String)> ProgressWriter<'a, W, F> {
+    fn new(inner: W, progress: &'a mut Progress<F>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<W: Write, F: FnMut(f64, String)> Write for ProgressWriter<'_, W, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes = self.inner.write(buf)?;
+        self.progress.add(bytes as u64);
+        Ok(bytes)
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Progress for a single-file format: the source's bytes, under its name
+fn single_file_progress<F: FnMut(f64, String)>(file_path: &Path, progress_callback: F) -> Result<Progress<F>> {
+    let mut progress = Progress::new(std::fs::metadata(file_path)?.len(), progress_callback);
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    Ok(progress)
+}
+
+async fn compress_gz_with_progress<F>(file_path: &Path, output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let output = BufWriter::new(File::create(output_path)?);
+    let name = file_path.file_name().and_then(|name| name.to_str());
+    let mut progress = single_file_progress(file_path, progress_callback)?;
+    {
+        let mut reader = ProgressReader::new(open_source(file_path, options, context)?, &mut progress);
+        if options.deflate_strategy != DeflateStrategy::Default {
+            write_gz_with_strategy(name, reader, output, options.deflate_strategy)?;
+        } else if options.threads() > 1 {
+            let mut writer = deflate::ParallelGzWriter::new(output, options.threads(), 6, name)?;
+            std::io::copy(&mut reader, &mut writer)?;
+            writer.finish()?.flush()?;
+        } else {
+            // Store the original filename in the gzip header when there is one
+            let builder = match name {
+                Some(name) => GzBuilder::new().filename(name),
+                None => GzBuilder::new(),
+            };
+            let mut encoder = builder.write(output, FlateCompression::default());
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    progress.complete();
+    Ok(())
+}
+
+// Same stream GzBuilder writes (name in the header, no timestamp, unknown OS), with the
+// deflate part from StrategyEncoder
+fn write_gz_with_strategy<R: Read, W: Write>(name: Option<&str>, source: R, mut output: W, strategy: DeflateStrategy) -> Result<()> {
+    deflate::write_gzip_header(&mut output, name)?;
+    
+    let mut reader = flate2::CrcReader::new(source);
+    let mut encoder = deflate::StrategyEncoder::new(output, 6, strategy);
+    std::io::copy(&mut reader, &mut encoder)?;
+    let mut output = encoder.finish()?;
+    output.write_all(&reader.crc().sum().to_le_bytes())?;
+    output.write_all(&reader.crc().amount().to_le_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+async fn compress_br_with_progress<F>(file_path: &Path, output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let output = BufWriter::new(File::create(output_path)?);
+    let memory = fit_codec_memory(&CompressionType::Br, options.memory_limit)?;
+    let mut progress = single_file_progress(file_path, progress_callback)?;
+    {
+        let mut reader = ProgressReader::new(open_source(file_path, options, context)?, &mut progress);
+        let mut encoder = brotli::CompressorWriter::new(output, 4096, BROTLI_QUALITY, memory.brotli_lgwin.unwrap_or(BROTLI_LGWIN));
+        std::io::copy(&mut reader, &mut encoder)?;
+        encoder.flush()?;
+    }
+    progress.complete();
+    Ok(())
+}
+
+async fn compress_bzip2_with_progress<F>(file_path: &Path, output_path: &Path, options: &CompressOptions, context: &CompressContext, progress_callback: F) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let output = BufWriter::new(File::create(output_path)?);
+    let memory = fit_codec_memory(&CompressionType::Bzip2, options.memory_limit)?;
+    let level = bzip2::Compression::new(memory.bzip2_level.unwrap_or(BZIP2_LEVEL));
+    let mut progress = single_file_progress(file_path, progress_callback)?;
+    {
+        let mut reader = ProgressReader::new(open_source(file_path, options, context)?, &mut progress);
+        let mut encoder = bzip2::write::BzEncoder::new(output, level);
+        std::io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.flush()?;
+    }
+    progress.complete();
+    Ok(())
+}
+
+// Reader for a single-file compression source. With `use_mmap` the file is memory mapped,
+// which saves the kernel-to-user copy of buffered reads; that matters most for the fast
+// codecs, while for gzip/bzip2 the encoder itself dominates and the difference is small.
+// Falls back to buffered reads if the map can't be created or doesn't fit the address space.
+fn open_source(file_path: &Path, options: &CompressOptions, context: &CompressContext) -> Result<Box<dyn Read>> {
+    let file = context.open_file(file_path)?;
+    let cancel = options.cancel.clone();
+    
+    if options.use_mmap {
+        let len = file.file().metadata()?.len();
+        // Empty files can't be mapped on every platform
+        if len > 0 && len <= usize::MAX as u64 {
+            // SAFETY: the map is read-only; if another process truncates the file while
+            // we read it the OS may fault, the same risk every mmap-based tool accepts.
+            match unsafe { memmap2::Mmap::map(file.file()) } {
+                Ok(map) => return Ok(Box::new(CancellableReader { inner: std::io::Cursor::new(map), cancel })),
+                Err(e) => context.warn(format!("Memory mapping failed, so buffered reads were used: {}", e)),
+            }
+        }
+    }
+    
+    Ok(Box::new(CancellableReader { inner: BufReader::new(file), cancel }))
+}
+
+// Standard decompression without progress (backward compatibility)
+pub async fn decompress_files(file_path: &Path, output_dir: &Path) -> Result<ExtractSummary> {
+    decompress_files_with_progress(file_path, output_dir, &ExtractOptions::default(), |_, _| {}).await
+}
+
+// Nested archives may not grow the output past this multiple of the outer archive's size.
+// A real download rarely gets anywhere near it; layered zip bombs blow through it quickly.
+const MAX_NESTED_EXPANSION_RATIO: u64 = 1000;
+
+// New decompression function with filename-aware progress callback
+pub async fn decompress_files_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path,
+    options: &ExtractOptions,
+    mut progress_callback: F
+) -> Result<ExtractSummary> 
+where
+    F: FnMut(f64, String) + Send,
+{
+    let merge_tally = match &options.merge_into {
+        Some(merge_dir) if !merge_dir.is_dir() => {
+            return Err(anyhow::anyhow!("Folder to merge into does not exist: {}", merge_dir.display()));
+        }
+        Some(_) => Some(Arc::new(MergeTally::default())),
+        None => None,
+    };
+    let output_dir = options.merge_into.as_deref().unwrap_or(output_dir);
+    // Entries are checked against where the folder really is, so a link given as the
+    // output (or anywhere above it) is followed once, here, and not per entry
+    std::fs::create_dir_all(output_dir)
+        .map_err(|e| anyhow::anyhow!("Failed to create output folder '{}': {}", output_dir.display(), e))?;
+    let output_dir = &real_dir(output_dir)?;
+    let log = options.extraction_log.as_deref()
+        .map(|path| ExtractionLog::open(path, file_path, output_dir).map(Arc::new))
+        .transpose()?;
+    let case_folds = cfg!(windows).then(|| Arc::new(CaseFolds::default()));
+    let warnings = Arc::new(Warnings::default());
+    // What a disk-full failure reports as written is as far as progress got; the
+    // unfinished file itself is removed where it is written
+    let progress_bytes = Arc::new(AtomicU64::new(0));
+    let bytes_written = || progress_bytes.load(Ordering::SeqCst);
+    let options = &ExtractOptions {
+        merge_tally: merge_tally.clone(),
+        log,
+        case_folds: case_folds.clone(),
+        warnings: Some(warnings.clone()),
+        progress_bytes: Some(progress_bytes.clone()),
+        ..options.clone()
+    };
+    
+    let mut summary = decompress_archive_with_progress(file_path, output_dir, options, &mut progress_callback).await
+        .map_err(|e| disk_full_error(e, bytes_written))?;
+    
+    let size_limit = std::fs::metadata(file_path)?.len().saturating_mul(MAX_NESTED_EXPANSION_RATIO);
+    let mut unwrapped = Vec::new();
+    
+    for _ in 0..options.recursive_extract {
+        let Some(nested) = single_nested_archive(output_dir)? else { break };
+        
+        // An archive that contains itself would otherwise unwrap until the depth runs out
+        let nested_key = (nested.file_name().unwrap_or_default().to_os_string(), std::fs::metadata(&nested)?.len());
+        if unwrapped.contains(&nested_key) {
+            options.warn(format!("Not unwrapping '{}': it repeats an archive already unwrapped", nested.display()));
+            break;
+        }
+        
+        if directory_size(output_dir) > size_limit {
+            options.warn(format!("Not unwrapping '{}': extracted data is already too large", nested.display()));
+            break;
+        }
+        
+        let nested_summary = decompress_archive_with_progress(&nested, output_dir, options, &mut progress_callback).await
+            .map_err(|e| disk_full_error(e, bytes_written))?;
+        summary.failed_entries.extend(nested_summary.failed_entries);
+        std::fs::remove_file(&nested)?;
+        unwrapped.push(nested_key);
+    }
+    
+    // Last, since every file written into the folder bumps its time again
+    if let Err(e) = apply_dir_timestamp(file_path, output_dir, options.dir_timestamp, options.zstd_dictionary.as_deref()) {
+        options.warn(format!("Could not set the time of {}: {}", output_dir.display(), e));
+    }
+    summary.merged = merge_tally.map(|tally| MergeCounts {
+        added: tally.added.load(Ordering::SeqCst),
+        overwritten: tally.overwritten.load(Ordering::SeqCst),
+        skipped: tally.skipped.load(Ordering::SeqCst),
+    });
+    if let Some(case_folds) = case_folds {
+        summary.case_collisions = std::mem::take(&mut *case_folds.collisions.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+    summary.warnings = warnings.take();
+    Ok(summary)
+}
+
+fn apply_dir_timestamp(file_path: &Path, output_dir: &Path, dir_timestamp: DirTimestamp, zstd_dictionary: Option<&Path>) -> Result<()> {
+    let time = match dir_timestamp {
+        DirTimestamp::Now => return Ok(()),
+        DirTimestamp::FromArchive => std::fs::metadata(file_path)?.modified()?,
+        DirTimestamp::FromNewestEntry => match newest_entry_time(file_path, zstd_dictionary)? {
+            Some(time) => time,
+            None => match newest_file_time(output_dir) {
+                Some(time) => time,
+                None => return Ok(()),
+            },
+        },
+    };
+    set_dir_modified(output_dir, time)
+}
+
+// The latest modification time stored in a zip or tar archive's entries
+fn newest_entry_time(file_path: &Path, zstd_dictionary: Option<&Path>) -> Result<Option<std::time::SystemTime>> {
+    let file_name = file_path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    
+    let newest_secs = if normalized_name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+        let mut newest = None;
+        for i in 0..archive.len() {
+            let secs = dos_time_to_unix(archive.by_index_raw(i)?.last_modified());
+            newest = newest.max(Some(secs));
+        }
+        newest
+    } else if is_tar_name(&normalized_name) {
+        let mut archive = tar::Archive::new(open_tar_reader(file_path, zstd_dictionary)?);
+        let mut newest = None;
+        for entry in archive.entries()? {
+            newest = newest.max(Some(entry?.header().mtime()?));
+        }
+        newest
+    } else {
+        return Ok(None);
+    };
+    Ok(newest_secs.map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)))
+}
+
+// Zip stores local time without a zone; it is read as UTC, which is close enough for sorting
+fn dos_time_to_unix(time: zip::DateTime) -> u64 {
+    // Days since the epoch for a proleptic Gregorian date (Howard Hinnant's days_from_civil)
+    let (year, month, day) = (time.year() as i64, time.month() as i64, time.day() as i64);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    
+    let secs = days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    secs.max(0) as u64
+}
+
+fn newest_file_time(dir: &Path) -> Option<std::time::SystemTime> {
+    std::fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if metadata.is_dir() {
+                newest_file_time(&entry.path())
+            } else {
+                metadata.modified().ok()
+            }
+        })
+        .max()
+}
+
+// The folders an extraction wrote into, with the time the archive stored for each (in
+// seconds since the epoch). Writing a file into a folder moves the folder's time to
+// now, so the times are only put back once every entry is out, deepest folders first
+// so setting a child doesn't disturb its parent again. Folders with no entry of their
+// own get the time of their newest child.
+#[derive(Default)]
+struct DirTimes {
+    dirs: HashMap<String, Option<u64>>,
+}
+
+impl DirTimes {
+    fn record(&mut self, name: &str, is_dir: bool, time: u64) {
+        let name = name.replace('\\', "/");
+        let name = name.trim_start_matches("./").trim_matches('/');
+        // Names that climb out are refused by the extraction itself
+        if name.is_empty() || name.split('/').any(|component| component == "..") {
+            return;
+        }
+        
+        if is_dir {
+            self.dirs.insert(name.to_string(), Some(time));
+        }
+        let mut parent = name;
+        while let Some((ancestor, _)) = parent.rsplit_once('/') {
+            self.dirs.entry(ancestor.to_string()).or_insert(None);
+            parent = ancestor;
+        }
+    }
+    
+    fn restore(self, output_dir: &Path, options: &ExtractOptions) {
+        let mut dirs: Vec<(String, Option<u64>)> = self.dirs.into_iter().collect();
+        dirs.sort_by_key(|(name, _)| std::cmp::Reverse(name.split('/').count()));
+        for (name, time) in dirs {
+            let dir = output_dir.join(&name);
+            if !dir.is_dir() {
+                continue;
+            }
+            let time = match time {
+                Some(secs) => Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+                None => newest_child_time(&dir),
+            };
+            if let Some(time) = time {
+                if let Err(e) = set_dir_modified(&dir, time) {
+                    options.warn(format!("Could not set the time of {}: {}", dir.display(), e));
+                }
+            }
+        }
+    }
+}
+
+// Unlike newest_file_time this looks at folders too, whose times are already restored
+fn newest_child_time(dir: &Path) -> Option<std::time::SystemTime> {
+    std::fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
+
+#[cfg(not(windows))]
+fn set_dir_modified(dir: &Path, time: std::time::SystemTime) -> Result<()> {
+    File::open(dir)?.set_modified(time)?;
+    Ok(())
+}
+
+// Directories can only be opened with backup semantics, and only need attribute access
+#[cfg(windows)]
+fn set_dir_modified(dir: &Path, time: std::time::SystemTime) -> Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Storage::FileSystem::{FILE_FLAG_BACKUP_SEMANTICS, FILE_WRITE_ATTRIBUTES};
+    
+    OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(dir)?
+        .set_modified(time)?;
+    Ok(())
+}
+
+// The extracted archive when an extraction produced exactly one file and it is an archive
+fn single_nested_archive(output_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut entries = std::fs::read_dir(output_dir)?;
+    let (Some(first), None) = (entries.next(), entries.next()) else { return Ok(None) };
+    let path = first?.path();
+    
+    Ok((path.is_file() && is_compressed_file(&path)).then_some(path))
+}
+
+fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else { return 0 };
+    
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            Ok(file_type) if file_type.is_file() => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+async fn decompress_archive_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path,
+    options: &ExtractOptions,
+    mut progress_callback: F
+) -> Result<ExtractSummary> 
+where
+    F: FnMut(f64, String) + Send,
+{
+    let extension = file_path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+
+    // Get file size for progress calculation
+    let file_size = std::fs::metadata(file_path)?.len();
+    
+    let archive_name = file_path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let normalized_name = normalize_archive_name(file_name);
+
+    if is_tar_name(&normalized_name) {
+        decompress_tar_with_progress(file_path, &normalized_name, output_dir, file_size, options, move |progress, _| {
+            progress_callback(progress, archive_name.clone())
+        }).await
+    } else if is_cpio_name(&normalized_name) {
+        decompress_cpio_with_progress(file_path, &normalized_name, output_dir, file_size, options, move |progress, _| {
+            progress_callback(progress, archive_name.clone())
+        }).await
+    } else {
+        match extension {
+            "zip" => decompress_zip_with_progress(file_path, output_dir, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await,
+            "gz" | "gzip" => decompress_gz_with_progress(file_path, output_dir, file_size, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await.map(|_| ExtractSummary::default()),
+            "br" => decompress_br_with_progress(file_path, output_dir, file_size, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await.map(|_| ExtractSummary::default()),
+            "bz2" | "bzip2" => decompress_bzip2_with_progress(file_path, output_dir, file_size, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await.map(|_| ExtractSummary::default()),
+            "7z" => decompress_7z_with_progress(file_path, output_dir, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await,
+            "iso" => decompress_iso_with_progress(file_path, output_dir, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await,
+            "tzchunks" => decompress_chunks_with_progress(file_path, output_dir, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await,
+            // Any disk of a spanned zip extracts the whole set from its last disk
+            ext if is_zip_disk_part(ext) => decompress_zip_with_progress(&file_path.with_extension("zip"), output_dir, options, move |progress, _| {
+                progress_callback(progress, archive_name.clone())
+            }).await,
+            #[cfg(feature = "rar-support")]
+            "rar" => decompress_rar(file_path, output_dir).await.map(|_| ExtractSummary::default()),
+            _ => match codec::custom_codec_for_file(file_path) {
+                Some(codec) => codec.decompress(file_path, output_dir, options, Box::new(move |progress, _| {
+                    progress_callback(progress, archive_name.clone())
+                })).await,
+                None => Err(anyhow::anyhow!("Unsupported file format: {}", extension)),
+            },
+        }
+    }
+}
+
+// Whether extraction progress for this archive is an estimate. Tarballs and single-stream
+// files (.gz, .br, .bz2) don't record their uncompressed size up front, so their progress
+// is compressed bytes read over the compressed file size: monotonic and it ends at 100%,
+// but it doesn't track output bytes exactly.
+pub fn progress_is_estimated(file_path: &Path) -> bool {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    
+    is_tar_name(&normalized_name)
+        || is_cpio_name(&normalized_name)
+        || [".gz", ".gzip", ".br", ".bz2", ".bzip2"].iter().any(|ext| normalized_name.ends_with(ext))
+}
+
+async fn decompress_zip_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path, 
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    // A spanned zip is read from its disks joined into one
+    let stitched = stitch_spanned_zip(file_path, &options.scratch_dir())?;
+    let archive_path = stitched.as_ref().map_or(file_path, |temp_file| temp_file.path());
+    check_zip_features(archive_path)?;
+    let file = File::open(archive_path)?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    
+    std::fs::create_dir_all(output_dir)?;
+
+    // Entries left out by the pattern are never read, and progress only counts the rest
+    let pattern = EntryPattern::new(options)?;
+    let mut selected = Vec::new();
+    let mut total_size = 0u64;
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if pattern.matches(&zip_entry_name(&file)) {
+            total_size += file.size();
+            selected.push(i);
+        }
+    }
+    let mut progress = Progress::new(total_size, progress_callback).counting(options.progress_bytes.clone());
+    
+    let mut summary = ExtractSummary::default();
+    let mut journal = options.resume.then(|| ExtractJournal::open(file_path, output_dir)).transpose()?;
+    let overwrite_options = ExtractOptions { conflict_policy: ConflictPolicy::Overwrite, ..options.clone() };
+    
+    let mut dir_times = DirTimes::default();
+    for &i in &selected {
+        let (name, is_file, size, crc32) = {
+            let file = archive.by_index_raw(i)?;
+            dir_times.record(&zip_entry_name(&file), zip_entry_is_dir(&file), dos_time_to_unix(file.last_modified()));
+            (zip_entry_name(&file), !zip_entry_is_dir(&file), file.size(), file.crc32())
+        };
+        check_entry_depth(&name, options)?;
+        progress.start(name.rsplit('/').next().unwrap_or_default().to_string());
+        let mut entry_options = options;
+        if let Some(journal) = journal.as_mut().filter(|_| is_file) {
+            let target = entry_target_path(output_dir, Path::new(&name), options);
+            if journal.is_done(&name) && target.as_deref().is_some_and(|target| file_is_intact(target, size, Some(crc32))) {
+                progress.entry_done(size);
+                continue;
+            }
+            entry_options = journal.start(&name, target.as_deref(), options, &overwrite_options)?;
+        }
+        
+        let extracted = extract_zip_entry(&mut archive, i, output_dir, entry_options, &mut progress);
+        progress.entry_done(size);
+        if let Err(e) = extracted {
+            // Password problems affect every entry, so they always stop the extraction
+            if !options.continue_on_error || e.downcast_ref::<CompressionError>().is_some() {
+                return Err(e);
+            }
+            summary.record_failure(options, name, e);
+        } else if let Some(journal) = journal.as_mut().filter(|_| is_file) {
+            journal.record(&name)?;
+        }
+    }
+
+    if !options.flatten {
+        dir_times.restore(output_dir, options);
+    }
+    if let Some(journal) = journal.filter(|_| summary.failed_entries.is_empty()) {
+        journal.finish();
+    }
+    progress.complete();
+    Ok(summary)
+}
+
+// Name of the journal `resume` keeps in the output folder
+const JOURNAL_NAME: &str = ".tauzip-progress";
+
+// What one extraction of an archive has written: a header line that identifies the
+// archive, then an "s <name>" line as each file is started and a "d <name>" line once
+// it is completely written (the name as a JSON string, so any name fits on a line).
+// Entries are only skipped when their file is also still intact on disk.
+struct ExtractJournal {
+    path: PathBuf,
+    file: File,
+    started: HashSet<String>,
+    done: HashSet<String>,
+}
+
+impl ExtractJournal {
+    fn open(archive_path: &Path, output_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(output_dir)?;
+        let path = output_dir.join(JOURNAL_NAME);
+        let header = journal_header(archive_path)?;
+        
+        // A journal left by a different (or since modified) archive doesn't count, and
+        // a line cut off by the interruption is dropped
+        let mut started = HashSet::new();
+        let mut done = HashSet::new();
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            let mut lines = contents.lines();
+            if lines.next() == Some(header.as_str()) {
+                for line in lines {
+                    let Some((tag, name)) = line.split_once(' ') else { continue };
+                    let Ok(name) = serde_json::from_str::<String>(name) else { continue };
+                    if tag == "d" {
+                        done.insert(name.clone());
+                    }
+                    started.insert(name);
+                }
+            }
+        }
+        
+        let file = if started.is_empty() {
+            let mut file = File::create(&path)?;
+            writeln!(file, "{}", header)?;
+            file
+        } else {
+            OpenOptions::new().append(true).open(&path)?
+        };
+        Ok(Self { path, file, started, done })
+    }
+    
+    fn is_done(&self, name: &str) -> bool {
+        self.done.contains(name)
+    }
+    
+    // Picks the options to write a file with. One an earlier run started writing belongs
+    // to us, so it is overwritten rather than renamed or skipped. Any other file already
+    // at the target goes by the conflict policy and isn't claimed, so a file the user
+    // had there before is never taken for ours on the next run.
+    fn start<'a>(&mut self, name: &str, target: Option<&Path>, options: &'a ExtractOptions, overwrite_options: &'a ExtractOptions) -> Result<&'a ExtractOptions> {
+        if self.started.contains(name) {
+            return Ok(overwrite_options);
+        }
+        if !target.is_some_and(|target| target.symlink_metadata().is_ok()) {
+            writeln!(self.file, "s {}", serde_json::to_string(name)?)?;
+            self.started.insert(name.to_string());
+        }
+        Ok(options)
+    }
+    
+    // Only files this journal claimed are recorded as done
+    fn record(&mut self, name: &str) -> Result<()> {
+        if self.started.contains(name) {
+            writeln!(self.file, "d {}", serde_json::to_string(name)?)?;
+        }
+        Ok(())
+    }
+    
+    fn finish(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn journal_header(archive_path: &Path) -> Result<String> {
+    let metadata = std::fs::metadata(archive_path)?;
+    let modified = metadata.modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let archive_path = std::fs::canonicalize(archive_path)?;
+    Ok(format!("{}\t{}\t{}", archive_path.display(), metadata.len(), modified))
+}
+
+// The output folder of an earlier, interrupted extraction of this archive: a sibling
+// folder holding a journal for it
+pub fn find_resumable_output_dir(archive_path: &Path) -> Option<PathBuf> {
+    let header = journal_header(archive_path).ok()?;
+    let parent = archive_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    std::fs::read_dir(parent).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .find(|dir| {
+            std::fs::read_to_string(dir.join(JOURNAL_NAME))
+                .is_ok_and(|contents| contents.lines().next() == Some(header.as_str()))
+        })
+}
+
+// Whether a file from an earlier run matches its entry: same size and, when the
+// archive records one (zip), the same CRC-32
+fn file_is_intact(path: &Path, size: u64, crc32: Option<u32>) -> bool {
+    if std::fs::metadata(path).map(|metadata| metadata.len()).ok() != Some(size) {
+        return false;
+    }
+    let Some(expected) = crc32 else { return true };
+    
+    let Ok(mut file) = File::open(path) else { return false };
+    let mut crc = flate2::Crc::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => return crc.sum() == expected,
+            Ok(read) => crc.update(&buffer[..read]),
+            Err(_) => return false,
+        }
+    }
+}
+
+fn extract_zip_entry<R: Read + std::io::Seek, F: FnMut(f64, String)>(
+    archive: &mut zip::ZipArchive<R>,
+    index: usize,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    progress: &mut Progress<F>,
+) -> Result<()> {
+    // The zip crate can't decode Deflate64, so those entries are opened raw and
+    // decompressed here instead
+    let deflate64 = is_deflate64(archive.by_index_raw(index)?.compression());
+    let mut file = if deflate64 {
+        // The raw data of an encrypted entry would still need decrypting
+        if let Err(zip::result::ZipError::UnsupportedArchive(msg)) = archive.by_index(index) {
+            if msg == zip::result::ZipError::PASSWORD_REQUIRED {
+                return Err(anyhow::anyhow!("Encrypted Deflate64 entries are not supported"));
+            }
+        }
+        archive.by_index_raw(index)?
+    } else {
+        match &options.password {
+            Some(password) => archive.by_index_decrypt(index, password.as_bytes())?
+                .map_err(|_| CompressionError::WrongPassword)?,
+            None => archive.by_index(index).map_err(|e| match e {
+                zip::result::ZipError::UnsupportedArchive(msg) if msg == zip::result::ZipError::PASSWORD_REQUIRED => {
+                    anyhow::Error::new(CompressionError::NeedsPassword)
+                },
+                e => e.into(),
+            })?,
+        }
+    };
+
+    let name = zip_entry_name(&file);
+    let outpath = if zip_entry_is_dir(&file) {
+        // Flat extraction has no use for directory entries
+        if options.flatten {
+            return Ok(());
+        }
+        let outpath = match entry_target_path(output_dir, Path::new(&name), options) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        create_entry_dir(output_dir, &outpath, options)?;
+        outpath
+    } else {
+        let outpath = match entry_output_path(output_dir, Path::new(&name), options) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(p) = outpath.parent() {
+            create_entry_dir(output_dir, p, options)?;
+        }
+        let outfile = File::create(&outpath)?;
+        let copied = if deflate64 {
+            let (expected_crc, expected_size) = (file.crc32(), file.size());
+            let decoder = deflate64::Deflate64Decoder::new(&mut file);
+            let mut checked = CrcCheckReader { inner: decoder, crc: flate2::Crc::new(), len: 0, expected_crc, expected_size };
+            write_entry_data(&mut ProgressReader::new(&mut checked, progress), outfile, options.preserve_sparse())
+        } else {
+            write_entry_data(&mut ProgressReader::new(&mut file, progress), outfile, options.preserve_sparse())
+        };
+        if let Err(e) = copied {
+            // Don't leave a truncated file behind (the file is closed by now)
+            let _ = std::fs::remove_file(&outpath);
+            return Err(e.into());
+        }
+        outpath
+    };
+
+    // Set file permissions if available
+    #[cfg(unix)]
+    if let Some(mode) = file.unix_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(mode))?;
+    }
+    
+    // Last, since a read-only file can't be touched afterwards
+    if options.preserve_attributes() && !zip_entry_is_dir(&file) {
+        let attributes = read_entry_metadata(file.extra_data(), ATTRIBUTES_FIELD_ID)
+            .and_then(|data| <[u8; 4]>::try_from(data).ok())
+            .map(u32::from_le_bytes);
+        if let Some(attributes) = attributes {
+            apply_windows_attributes(&outpath, attributes)?;
+        }
+    }
+    Ok(())
+}
+
+// Highest "version needed to extract" we can handle (6.3: LZMA, PPMd, ...)
+const MAX_ZIP_VERSION: u16 = 63;
+// General purpose flags
+const ZIP_FLAG_STRONG_ENCRYPTION: u16 = 1 << 6;
+const ZIP_FLAG_ENCRYPTED_DIRECTORY: u16 = 1 << 13;
+
+// Where the central directory is, from the end of central directory record
+pub struct ZipDirectory {
+    pub disk: u32,
+    // Disk the central directory starts on
+    pub directory_disk: u32,
+    pub entries: u64,
+    pub offset: u64,
+}
+
+// Find the end of central directory record (and its Zip64 version, if any). None when
+// there is no end record at all.
+pub fn find_zip_directory<R: Read + Seek>(file: &mut R) -> Result<Option<ZipDirectory>> {
+    let file_len = file.seek(std::io::SeekFrom::End(0))?;
+    
+    // The end record is 22 bytes plus a comment of up to 64 KiB
+    let tail_len = file_len.min(22 + u16::MAX as u64);
+    file.seek(std::io::SeekFrom::Start(file_len - tail_len))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let Some(eocd) = (0..tail.len().saturating_sub(21)).rev().find(|&i| tail[i..i + 4] == [0x50, 0x4b, 0x05, 0x06]) else {
+        return Ok(None);
+    };
+    
+    let record = &tail[eocd..];
+    let mut directory = ZipDirectory {
+        disk: u16_at(record, 4) as u32,
+        directory_disk: u16_at(record, 6) as u32,
+        entries: u16_at(record, 10) as u64,
+        offset: u32_at(record, 16) as u64,
+    };
+    
+    // Zip64: a locator right before the end record points at the 64-bit version
+    if directory.entries == 0xFFFF || directory.offset == 0xFFFF_FFFF {
+        let locator = eocd.checked_sub(20).map(|at| &tail[at..eocd]);
+        if let Some(locator) = locator.filter(|locator| locator[..4] == [0x50, 0x4b, 0x06, 0x07]) {
+            let mut zip64 = [0u8; 56];
+            file.seek(std::io::SeekFrom::Start(u64_at(locator, 8)))?;
+            file.read_exact(&mut zip64)?;
+            directory.disk = u32_at(&zip64, 16);
+            directory.directory_disk = u32_at(&zip64, 20);
+            directory.entries = u64_at(&zip64, 32);
+            directory.offset = u64_at(&zip64, 48);
+        }
+    }
+    Ok(Some(directory))
+}
+
+// Old zips split across floppies or CDs come as name.z01, name.z02, ..., name.zip, the
+// .zip being the last disk and holding the central directory. Every disk counts its
+// offsets from its own start, and the first begins with a split signature. Joined end
+// to end without the signature they make one ordinary zip once the central directory
+// points into the whole, so that is written to a scratch file in `scratch_dir`. None
+// when the archive isn't spanned.
+fn stitch_spanned_zip(file_path: &Path, scratch_dir: &Path) -> Result<Option<TempFile>> {
+    let directory = match find_zip_directory(&mut BufReader::new(File::open(file_path)?))? {
+        Some(directory) if directory.disk != 0 => directory,
+        _ => return Ok(None),
+    };
+    let stem = file_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let parts: Vec<PathBuf> = (1..=directory.disk)
+        .map(|disk| file_path.with_file_name(format!("{}.z{:02}", stem, disk)))
+        .chain(std::iter::once(file_path.to_path_buf()))
+        .collect();
+    let missing: Vec<String> = parts.iter().enumerate()
+        .filter(|(_, part)| !part.is_file())
+        .map(|(disk, part)| format!("disk {} ({})", disk + 1, part.file_name().unwrap_or_default().to_string_lossy()))
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "This zip is spanned across {} disks, and these are missing from its folder: {}",
+            parts.len(),
+            missing.join(", ")
+        ));
+    }
+    let needed: u64 = parts.iter().filter_map(|part| std::fs::metadata(part).ok()).map(|metadata| metadata.len()).sum();
+    if let Some(available) = available_space(scratch_dir).filter(|available| *available < needed) {
+        return Err(anyhow::anyhow!(
+            "Joining the disks of this spanned zip needs {} bytes in '{}', which has {} free; choose a temp folder with more room",
+            needed, scratch_dir.display(), available
+        ));
+    }
+    
+    let (temp_file, mut stitched) = TempFile::new_in(scratch_dir, SCRATCH_PREFIX)?;
+    // Where each disk's offset 0 falls in the stitched file. The first disk's offsets
+    // count the signature that is left out, so its start is before the file's.
+    let mut disk_starts = Vec::with_capacity(parts.len());
+    let mut len = 0u64;
+    for (disk, part) in parts.iter().enumerate() {
+        let mut reader = File::open(part)?;
+        let mut start = len as i64;
+        if disk == 0 {
+            let mut signature = [0u8; 4];
+            reader.read_exact(&mut signature)?;
+            if signature == DATA_DESCRIPTOR_SIGNATURE {
+                start -= 4;
+            } else {
+                reader.rewind()?;
+            }
+        }
+        disk_starts.push(start);
+        len += std::io::copy(&mut reader, &mut stitched)?;
+    }
+    
+    let directory_start = absolute_disk_offset(&disk_starts, len, directory.directory_disk, directory.offset)?;
+    let mut central = Vec::new();
+    {
+        let mut reader = BufReader::new(&stitched);
+        reader.seek(std::io::SeekFrom::Start(directory_start))?;
+        for _ in 0..directory.entries {
+            let mut header = vec![0u8; 46];
+            if reader.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+                return Err(CompressionError::Corrupt { reason: "damaged central directory".to_string() }.into());
+            }
+            let rest = u16_at(&header, 28) as usize + u16_at(&header, 30) as usize + u16_at(&header, 32) as usize;
+            header.resize(46 + rest, 0);
+            reader.read_exact(&mut header[46..])?;
+            rebase_central_header(&mut header, &disk_starts, len)?;
+            central.extend_from_slice(&header);
+        }
+    }
+    
+    // The new directory replaces the old one and everything after it
+    stitched.set_len(directory_start)?;
+    stitched.seek(std::io::SeekFrom::End(0))?;
+    let mut writer = BufWriter::new(&stitched);
+    writer.write_all(&central)?;
+    write_end_of_directory(&mut writer, directory.entries, central.len() as u64, directory_start)?;
+    writer.flush()?;
+    Ok(Some(temp_file))
+}
+
+// Offset `offset` on `disk` as a position in the stitched file of `len` bytes
+fn absolute_disk_offset(disk_starts: &[i64], len: u64, disk: u32, offset: u64) -> Result<u64> {
+    disk_starts.get(disk as usize)
+        .map(|start| start + offset as i64)
+        .filter(|position| *position >= 0 && *position as u64 <= len)
+        .map(|position| position as u64)
+        .ok_or_else(|| CompressionError::Corrupt { reason: format!("offset {} on disk {} is outside the archive", offset, disk + 1) }.into())
+}
+
+// Point a spanned zip's central header at its entry in the stitched file: disk 0, and
+// the offset from the start of the whole. Either value may be in the Zip64 extra field
+// instead, after whichever sizes are there too.
+fn rebase_central_header(header: &mut [u8], disk_starts: &[i64], len: u64) -> Result<()> {
+    let mut disk = u16_at(header, 34) as u32;
+    let mut offset = u32_at(header, 42) as u64;
+    let (mut disk_at, mut offset_at) = (None, None);
+    
+    let extra_end = 46 + u16_at(header, 28) as usize + u16_at(header, 30) as usize;
+    let mut at = 46 + u16_at(header, 28) as usize;
+    while at + 4 <= extra_end {
+        let size = u16_at(header, at + 2) as usize;
+        if at + 4 + size > extra_end {
+            break;
+        }
+        if u16_at(header, at) == ZIP64_FIELD {
+            let end = at + 4 + size;
+            let mut field = at + 4;
+            for size_field in [24, 20] {
+                if u32_at(header, size_field) == 0xFFFF_FFFF {
+                    field += 8;
+                }
+            }
+            if offset == 0xFFFF_FFFF && field + 8 <= end {
+                offset = u64_at(header, field);
+                offset_at = Some(field);
+                field += 8;
+            }
+            if disk == 0xFFFF && field + 4 <= end {
+                disk = u32_at(header, field);
+                disk_at = Some(field);
+            }
+            break;
+        }
+        at += 4 + size;
+    }
+    
+    let rebased = absolute_disk_offset(disk_starts, len, disk, offset)?;
+    match offset_at {
+        Some(field) => header[field..field + 8].copy_from_slice(&rebased.to_le_bytes()),
+        None if rebased < 0xFFFF_FFFF => header[42..46].copy_from_slice(&(rebased as u32).to_le_bytes()),
+        None => return Err(CompressionError::Unsupported { feature: "disk spanning past 4 GiB without Zip64".to_string() }.into()),
+    }
+    match disk_at {
+        Some(field) => header[field..field + 4].copy_from_slice(&0u32.to_le_bytes()),
+        None => header[34..36].copy_from_slice(&0u16.to_le_bytes()),
+    }
+    Ok(())
+}
+
+// Little-endian fields of zip records
+pub fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+pub fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+pub fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap_or_default())
+}
+
+// Read the end of central directory and every central directory header ourselves, since
+// the zip crate fails on these cases with vague or misleading messages. Returns an
+// Unsupported error naming the first feature we can't handle.
+fn check_zip_features(file_path: &Path) -> Result<()> {
+    let unsupported = |feature: String| -> anyhow::Error {
+        CompressionError::Unsupported { feature }.into()
+    };
+    
+    let mut file = BufReader::new(File::open(file_path)?);
+    let Some(directory) = find_zip_directory(&mut file)? else {
+        // Not our problem to diagnose; the zip crate reports a missing end record itself
+        return Ok(());
+    };
+    
+    file.seek(std::io::SeekFrom::Start(directory.offset))?;
+    for _ in 0..directory.entries {
+        let mut header = [0u8; 46];
+        if file.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            // A damaged directory is left for the zip crate to report
+            return Ok(());
+        }
+        let version_needed = u16_at(&header, 6) & 0xFF;
+        let flags = u16_at(&header, 8);
+        let method = u16_at(&header, 10);
+        
+        if flags & ZIP_FLAG_ENCRYPTED_DIRECTORY != 0 {
+            return Err(unsupported("central directory encryption".to_string()));
+        }
+        if flags & ZIP_FLAG_STRONG_ENCRYPTION != 0 {
+            return Err(unsupported("strong encryption".to_string()));
+        }
+        // Stored, Deflate, Deflate64, Bzip2, Zstd and AES (which wraps one of these)
+        if !matches!(method, 0 | 8 | 9 | 12 | 93 | 99) {
+            return Err(unsupported(format!("compression method {} ({})", method, zip_method_name(method))));
+        }
+        if version_needed > MAX_ZIP_VERSION {
+            return Err(unsupported(format!("zip {}.{} features", version_needed / 10, version_needed % 10)));
+        }
+        
+        let skip = u16_at(&header, 28) as i64 + u16_at(&header, 30) as i64 + u16_at(&header, 32) as i64;
+        file.seek_relative(skip)?;
+    }
+    Ok(())
+}
+
+// Guess which tool wrote a zip from what tools leave in the central directory: the
+// version-made-by field, the extra fields they add, and the flags and names they use.
+// Only a guess: plenty of tools write the same plain headers, and any tool can write
+// what another one does.
+pub fn detect_creator(file_path: &Path) -> Result<CreatorGuess> {
+    let mut file = BufReader::new(File::open(file_path)?);
+    let directory = find_zip_directory(&mut file)?
+        .ok_or_else(|| anyhow::anyhow!("Not a zip archive (no end of central directory record)"))?;
+    
+    let mut version_made_by = None;
+    let mut extra_ids = std::collections::BTreeSet::new();
+    let mut methods = std::collections::BTreeSet::new();
+    let (mut utf8_names, mut descriptors, mut backslashes, mut mac_resources) = (false, false, false, false);
+    file.seek(std::io::SeekFrom::Start(directory.offset))?;
+    for _ in 0..directory.entries {
+        let mut header = [0u8; 46];
+        if file.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(CompressionError::Corrupt { reason: "damaged central directory".to_string() }.into());
+        }
+        version_made_by.get_or_insert(u16_at(&header, 4));
+        let flags = u16_at(&header, 8);
+        utf8_names |= flags & (1 << 11) != 0;
+        descriptors |= flags & 0x08 != 0;
+        methods.insert(u16_at(&header, 10));
+        
+        let name_len = u16_at(&header, 28) as usize;
+        let mut rest = vec![0u8; name_len + u16_at(&header, 30) as usize];
+        file.read_exact(&mut rest)?;
+        let name = &rest[..name_len];
+        backslashes |= name.contains(&b'\\');
+        mac_resources |= name.starts_with(b"__MACOSX/");
+        let mut extra = &rest[name_len..];
+        while extra.len() >= 4 {
+            extra_ids.insert(u16_at(extra, 0));
+            let len = 4 + u16_at(extra, 2) as usize;
+            if len > extra.len() {
+                break;
+            }
+            extra = &extra[len..];
+        }
+        file.seek_relative(u16_at(&header, 32) as i64)?;
+    }
+    
+    let version_made_by = version_made_by.unwrap_or(0);
+    let host = (version_made_by >> 8) as u8;
+    let spec = (version_made_by & 0xFF) as u8;
+    let has = |id: u16| extra_ids.contains(&id);
+    let info_zip_fields = has(0x5455) || has(0x7875) || has(0x5855) || has(UNICODE_PATH_FIELD);
+    
+    let creator = if has(ATTRIBUTES_FIELD_ID) {
+        "TauZip"
+    } else if has(0xD935) {
+        "Android build tools (zipalign or apksigner)"
+    } else if has(0xCAFE) {
+        "Java jar tool"
+    } else if mac_resources {
+        "macOS Archive Utility (Finder's Compress)"
+    } else if spec == 63 {
+        "7-Zip"
+    } else if spec == 46 && host == 3 && !info_zip_fields {
+        "the Rust zip crate (TauZip and others)"
+    } else if has(ZIP_AES_FIELD) {
+        "WinZip, or another tool using its AES encryption (7-Zip, WinRAR)"
+    } else if info_zip_fields {
+        "Info-ZIP zip, or a tool built on it"
+    } else if has(0x000A) {
+        "a Windows archiver that stores NTFS times (WinRAR, WinZip or 7-Zip)"
+    } else if host == 0 && spec == 20 && extra_ids.is_empty() {
+        "Windows Explorer (Send to > Compressed folder), or a .NET or Python zip library"
+    } else if host == 3 && extra_ids.is_empty() {
+        "a zip library such as Python's zipfile"
+    } else {
+        "Unknown"
+    };
+    
+    let mut evidence = vec![format!("version made by {}.{} on {}", spec / 10, spec % 10, zip_host_name(host))];
+    evidence.extend(extra_ids.iter().map(|id| format!("extra field 0x{:04X} ({})", id, zip_extra_field_name(*id))));
+    let method_names: Vec<String> = methods.iter().map(|method| zip_method_name(*method)).collect();
+    if !method_names.is_empty() {
+        evidence.push(format!("methods: {}", method_names.join(", ")));
+    }
+    if descriptors {
+        evidence.push("sizes in data descriptors (written while streaming)".to_string());
+    }
+    if utf8_names {
+        evidence.push("names flagged as UTF-8".to_string());
+    }
+    if backslashes {
+        evidence.push("backslashes in names".to_string());
+    }
+    if mac_resources {
+        evidence.push("a __MACOSX resource fork folder".to_string());
+    }
+    
+    Ok(CreatorGuess {
+        creator: creator.to_string(),
+        version_made_by,
+        host: zip_host_name(host).to_string(),
+        evidence,
+    })
+}
+
+// Host systems of the version-made-by field, from APPNOTE 4.4.2
+fn zip_host_name(host: u8) -> &'static str {
+    match host {
+        0 => "MS-DOS/FAT",
+        1 => "Amiga",
+        2 => "OpenVMS",
+        3 => "Unix",
+        4 => "VM/CMS",
+        5 => "Atari ST",
+        6 => "OS/2 HPFS",
+        7 => "Macintosh",
+        8 => "Z-System",
+        9 => "CP/M",
+        10 => "Windows NTFS",
+        11 => "MVS",
+        12 => "VSE",
+        13 => "Acorn RISC OS",
+        14 => "VFAT",
+        15 => "alternate MVS",
+        16 => "BeOS",
+        17 => "Tandem",
+        18 => "OS/400",
+        19 => "macOS",
+        _ => "unknown",
+    }
+}
+
+fn zip_extra_field_name(id: u16) -> &'static str {
+    match id {
+        0x0001 => "Zip64",
+        0x000A => "NTFS times",
+        0x0017 => "strong encryption",
+        0x5455 => "extended timestamp",
+        0x5855 | 0x7855 | 0x7875 => "Unix owner",
+        0x6375 => "Unicode comment",
+        UNICODE_PATH_FIELD => "Unicode path",
+        ZIP_AES_FIELD => "AES encryption",
+        ATTRIBUTES_FIELD_ID => "TauZip Windows attributes",
+        0xCAFE => "jar marker",
+        0xD935 => "Android alignment",
+        _ => "unknown",
+    }
+}
+
+// Names for the method numbers of the zip spec (APPNOTE 4.4.5) that turn up in practice
+fn zip_method_name(method: u16) -> String {
+    let name = match method {
+        0 => "Stored",
+        1 => "Shrink",
+        2..=5 => "Reduce",
+        6 => "Implode",
+        8 => "Deflate",
+        9 => "Deflate64",
+        10 => "PKWARE DCL Implode",
+        12 => "Bzip2",
+        14 => "LZMA",
+        18 => "IBM TERSE",
+        19 => "IBM LZ77",
+        93 => "Zstd",
+        95 => "XZ",
+        96 => "JPEG",
+        97 => "WavPack",
+        98 => "PPMd",
+        ZIP_METHOD_AES => "AES",
+        _ => return format!("Method {}", method),
+    };
+    name.to_string()
+}
+
+// Copy an entry's data into a freshly created file, leaving holes for zero runs if asked
+fn write_entry_data<R: Read + ?Sized>(reader: &mut R, mut outfile: File, sparse: bool) -> std::io::Result<u64> {
+    if !sparse {
+        return std::io::copy(reader, &mut outfile);
+    }
+    let mut writer = SparseWriter::new(outfile);
+    let copied = std::io::copy(reader, &mut writer)?;
+    writer.finish()?;
+    Ok(copied)
+}
+
+// The spec only allows forward slashes in zip entry names, and TauZip writes nothing
+// else, but archives made by some Windows tools use backslashes. Names are read with
+// either, so those entries land in folders (and folder entries are seen as folders)
+// instead of becoming files with backslashes in their names.
+fn zip_entry_name(file: &zip::read::ZipFile<'_>) -> String {
+    unicode_path(file.extra_data(), file.name_raw()).unwrap_or_else(|| file.name().to_string()).replace('\\', "/")
+}
+
+// Info-ZIP Unicode Path extra field
+const UNICODE_PATH_FIELD: u16 = 0x7075;
+
+// Tools writing names in a legacy code page can add the real name in UTF-8 as a Unicode
+// Path extra field: version 1, the CRC-32 of the name field it stands for, then the
+// name. A CRC that doesn't match means the entry was renamed by a tool that didn't
+// know the field, so the name field is used after all (decoded as CP437 by the zip
+// crate unless it is flagged as UTF-8).
+fn unicode_path(extra_data: &[u8], name_raw: &[u8]) -> Option<String> {
+    let data = read_entry_metadata(extra_data, UNICODE_PATH_FIELD)?;
+    if data.len() < 5 || data[0] != 1 {
+        return None;
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(name_raw);
+    if crc.sum() != u32::from_le_bytes([data[1], data[2], data[3], data[4]]) {
+        return None;
+    }
+    String::from_utf8(data[5..].to_vec()).ok()
+}
+
+fn zip_entry_is_dir(file: &zip::read::ZipFile<'_>) -> bool {
+    file.name().ends_with(['/', '\\'])
+}
+
+// Checks what a reader gives against the CRC-32 and size it should have once it runs
+// out, the way the zip crate checks the entries it decodes itself
+struct CrcCheckReader<R> {
+    inner: R,
+    crc: flate2::Crc,
+    len: u64,
+    expected_crc: u32,
+    expected_size: u64,
+}
+
+impl<R: Read> Read for CrcCheckReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        self.len += read as u64;
+        if read == 0 && !buf.is_empty() && (self.crc.sum() != self.expected_crc || self.len != self.expected_size) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid checksum"));
+        }
+        Ok(read)
+    }
+}
+
+// zip 0.6 reports method 9 (Deflate64) as an unsupported method
+#[allow(deprecated)]
+fn is_deflate64(method: zip::CompressionMethod) -> bool {
+    method == zip::CompressionMethod::Unsupported(9)
+}
+
+async fn decompress_tar_with_progress<F>(
+    file_path: &Path, 
+    normalized_name: &str,
+    output_dir: &Path, 
+    file_size: u64,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::open(file_path)?;
+    let mut progress = Progress::new(file_size, progress_callback).counting(options.progress_bytes.clone());
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    let progress_reader = ProgressReader::new(file, &mut progress);
+    let memory_limit = options.decompress_memory_limit();
+    check_decode_memory(file_path, normalized_name, memory_limit)?;
+    let decoder: Box<dyn Read + '_> = match zstd_dictionary_for(file_path, normalized_name, options.zstd_dictionary.as_deref())? {
+        Some(dictionary) => {
+            let mut decoder = zstd::stream::read::Decoder::with_dictionary(BufReader::new(progress_reader), &dictionary)?;
+            decoder.window_log_max(zstd_window_log_max(memory_limit))?;
+            Box::new(decoder)
+        }
+        None => tar_decoder(normalized_name, BufReader::new(progress_reader), memory_limit)?,
+    };
+    let mut archive = tar::Archive::new(decoder);
+    
+    std::fs::create_dir_all(output_dir)?;
+    let mut journal = options.resume.then(|| ExtractJournal::open(file_path, output_dir)).transpose()?;
+    let summary = unpack_tar_archive(&mut archive, output_dir, options, journal.as_mut())?;
+    if let Some(journal) = journal.filter(|_| summary.failed_entries.is_empty()) {
+        journal.finish();
+    }
+    Ok(summary)
+}
+
+async fn decompress_cpio_with_progress<F>(
+    file_path: &Path,
+    normalized_name: &str,
+    output_dir: &Path,
+    file_size: u64,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    let file = File::open(file_path)?;
+    let mut progress = Progress::new(file_size, progress_callback).counting(options.progress_bytes.clone());
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    let progress_reader = BufReader::new(ProgressReader::new(file, &mut progress));
+    let reader: Box<dyn Read + '_> = if normalized_name.ends_with(".gz") {
+        Box::new(flate2::read::GzDecoder::new(progress_reader))
+    } else {
+        Box::new(progress_reader)
+    };
+    let mut cpio = CpioReader::new(reader);
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    let pattern = EntryPattern::new(options)?;
+    let mut summary = ExtractSummary::default();
+    while let Some(header) = cpio.next_header()? {
+        check_entry_depth(&header.name(), options)?;
+        if !pattern.matches(&header.name()) {
+            cpio.copy_data(&header, &mut std::io::sink())?;
+            continue;
+        }
+        // Device nodes, FIFOs, sockets and symlinks aren't recreated
+        if !header.is_dir() && !header.is_file() {
+            cpio.copy_data(&header, &mut std::io::sink())?;
+            summary.record_failure(options, header.name().to_string(), anyhow::anyhow!("special file (mode {:o}) was skipped", header.mode));
+            continue;
+        }
+        
+        if let Err(e) = unpack_cpio_entry(&mut cpio, &header, output_dir, options) {
+            if !options.continue_on_error {
+                return Err(e);
+            }
+            summary.record_failure(options, header.name().to_string(), e);
+        }
+    }
+    Ok(summary)
+}
+
+fn unpack_cpio_entry<R: Read>(cpio: &mut CpioReader<R>, header: &CpioHeader, output_dir: &Path, options: &ExtractOptions) -> Result<()> {
+    if header.is_dir() {
+        cpio.copy_data(header, &mut std::io::sink())?;
+        if let (false, Some(path)) = (options.flatten, entry_target_path(output_dir, &header.path(), options)) {
+            create_entry_dir(output_dir, &path, options)?;
+        }
+        return Ok(());
+    }
+    
+    let outpath = match entry_output_path(output_dir, &header.path(), options) {
+        Some(path) => path,
+        None => {
+            cpio.copy_data(header, &mut std::io::sink())?;
+            return Ok(());
+        },
+    };
+    if let Some(p) = outpath.parent() {
+        create_entry_dir(output_dir, p, options)?;
+    }
+    let outfile = File::create(&outpath)?;
+    let copied = if options.preserve_sparse() {
+        let mut writer = SparseWriter::new(outfile);
+        cpio.copy_data(header, &mut writer).and_then(|_| Ok(writer.finish().map(|_| ())?))
+    } else {
+        let mut outfile = outfile;
+        cpio.copy_data(header, &mut outfile).map(|_| ())
+    };
+    if let Err(e) = copied {
+        // Don't leave a truncated file behind (the file is closed by now)
+        let _ = std::fs::remove_file(&outpath);
+        return Err(e);
+    }
+    
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&outpath, std::fs::Permissions::from_mode(header.mode & 0o7777))?;
+    }
+    Ok(())
+}
+
+fn unpack_tar_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    mut journal: Option<&mut ExtractJournal>,
+) -> Result<ExtractSummary> {
+    let mut summary = ExtractSummary::default();
+    let pattern = EntryPattern::new(options)?;
+    // The tar crate's own unpack can't check each path's depth or pattern first, apply
+    // the conflict policy of a merge, or log each file
+    if !options.flatten && !options.continue_on_error && journal.is_none() && options.max_depth() == 0 && pattern.is_all()
+        && options.merge_into.is_none() && options.log.is_none() && options.case_folds.is_none() {
+        archive.unpack(output_dir)?;
+        return Ok(summary);
+    }
+
+    // Walk the entries ourselves so each one can be handled (or fail) on its own
+    let overwrite_options = ExtractOptions { conflict_policy: ConflictPolicy::Overwrite, ..options.clone() };
+    let mut dir_times = DirTimes::default();
+    for entry in archive.entries()? {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if options.continue_on_error => {
+                // A broken header means the rest of the stream can't be located
+                summary.record_failure(options, "(remaining entries)".to_string(), e.into());
+                break;
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        let name = entry.path()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default();
+        check_entry_depth(&name, options)?;
+        // The tar reader skips over the data of an entry that isn't unpacked
+        if !pattern.matches(&name) {
+            continue;
+        }
+        dir_times.record(&name, entry.header().entry_type().is_dir(), entry.header().mtime().unwrap_or(0));
+        let is_file = entry.header().entry_type().is_file();
+        let mut entry_options = options;
+        if let Some(journal) = journal.as_deref_mut().filter(|_| is_file) {
+            // Tar keeps no checksum of the data, so the size has to do
+            let target = entry_target_path(output_dir, Path::new(&name), options);
+            if journal.is_done(&name) && target.as_deref().is_some_and(|target| file_is_intact(target, entry.size(), None)) {
+                continue;
+            }
+            entry_options = journal.start(&name, target.as_deref(), options, &overwrite_options)?;
+        }
+        
+        if let Err(e) = unpack_tar_entry(&mut entry, output_dir, entry_options) {
+            if !options.continue_on_error {
+                return Err(e);
+            }
+            summary.record_failure(options, name, e);
+        } else if let Some(journal) = journal.as_deref_mut().filter(|_| is_file) {
+            journal.record(&name)?;
+        }
+    }
+    if !options.flatten {
+        dir_times.restore(output_dir, options);
+    }
+    Ok(summary)
+}
+
+fn unpack_tar_entry<R: Read>(entry: &mut tar::Entry<'_, R>, output_dir: &Path, options: &ExtractOptions) -> Result<()> {
+    // unpack_in replaces whatever is already there, so unless that is the policy anyway,
+    // files are placed here and the conflict policy applies to each one. So do a merge,
+    // and Windows with its case-insensitive names.
+    let placing = options.conflict_policy != ConflictPolicy::Overwrite
+        || options.merge_into.is_some()
+        || options.case_folds.is_some();
+    if !options.flatten && !placing {
+        let is_file = entry.header().entry_type().is_file();
+        let entry_path = entry.path()?.into_owned();
+        check_entry_dirs(output_dir, &entry_path, options)?;
+        let target = entry_target_path(output_dir, &entry_path, options).filter(|_| is_file);
+        let existed = target.as_ref().is_some_and(|target| target.exists());
+        if let Err(e) = entry.unpack_in(output_dir) {
+            // Don't leave a truncated file behind, e.g. when the disk filled up
+            if let Some(target) = &target {
+                let _ = std::fs::remove_file(target);
+            }
+            return Err(e.into());
+        }
+        if let Some(target) = &target {
+            let placement = if existed { Placement::Overwritten } else { Placement::Added };
+            options.log_entry(placement, &entry_path.to_string_lossy(), target);
+        }
+        return Ok(());
+    }
+
+    // Flat extraction drops the directory components, and directories themselves
+    if !entry.header().entry_type().is_file() {
+        if !options.flatten {
+            check_entry_dirs(output_dir, &entry.path()?.into_owned(), options)?;
+            entry.unpack_in(output_dir)?;
+        }
+        return Ok(());
+    }
+
+    let entry_path = entry.path()?.into_owned();
+    if let Some(outpath) = entry_output_path(output_dir, &entry_path, options) {
+        if placing {
+            // unpack_in makes sure a link unpacked earlier can't carry the file out of
+            // the folder; unpack doesn't, so check the real parent here
+            let parent = outpath.parent().unwrap_or(output_dir);
+            create_entry_dir(output_dir, parent, options)?;
+            if !parent.canonicalize()?.starts_with(output_dir.canonicalize()?) {
+                return Err(CompressionError::SuspiciousArchive {
+                    reason: format!("'{}' would be written outside the folder", entry_path.display()),
+                }.into());
+            }
+        }
+        if let Err(e) = entry.unpack(&outpath) {
+            let _ = std::fs::remove_file(&outpath);
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+// Work out where an archive entry should be written, applying flatten and the conflict policy.
+// Returns None when the entry should be skipped.
+fn entry_output_path(output_dir: &Path, entry_path: &Path, options: &ExtractOptions) -> Option<PathBuf> {
+    let target = entry_target_path(output_dir, entry_path, options)?;
+    let name = entry_path.to_string_lossy();
+    if let Some(case_folds) = &options.case_folds {
+        case_folds.check(&target, &name);
+    }
+    if options.merge_tally.is_none() && options.log.is_none() && options.case_folds.is_none() {
+        return resolve_conflict(target, options.conflict_policy);
+    }
+    let existed = target.exists();
+    let resolved = resolve_conflict(target.clone(), options.conflict_policy);
+    if let (Some(case_folds), Some(path)) = (&options.case_folds, &resolved) {
+        case_folds.record(path, &name);
+    }
+    let placement = match &resolved {
+        None => Placement::Skipped,
+        Some(path) if *path != target => Placement::Renamed,
+        Some(_) if existed => Placement::Overwritten,
+        Some(_) => Placement::Added,
+    };
+    if let Some(tally) = &options.merge_tally {
+        let counter = match placement {
+            Placement::Skipped => &tally.skipped,
+            Placement::Overwritten => &tally.overwritten,
+            Placement::Added | Placement::Renamed => &tally.added,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+    options.log_entry(placement, &name, resolved.as_deref().unwrap_or(&target));
+    resolved
+}
+
+// Where an entry belongs, before any conflict with an existing file is resolved
+fn entry_target_path(output_dir: &Path, entry_path: &Path, options: &ExtractOptions) -> Option<PathBuf> {
+    if options.restore_absolute && !options.flatten {
+        if let Some(path) = absolute_entry_target(&entry_path.to_string_lossy()) {
+            options.warn(format!("'{}' restored to its absolute path", entry_path.display()));
+            return Some(path);
+        }
+    }
+    let entry_path = match entry_path.to_str() {
+        Some(name) => sanitize_entry_path(name, options)?,
+        None => sanitize_raw_entry_path(entry_path, options)?,
+    };
+    if options.flatten {
+        Some(output_dir.join(entry_path.file_name()?))
+    } else {
+        Some(output_dir.join(entry_path))
+    }
+}
+
+// Create a folder an entry goes in, refusing to pass through one that is already there
+// as a link (see ExtractOptions::allow_symlink_dirs)
+fn create_entry_dir(output_dir: &Path, dir: &Path, options: &ExtractOptions) -> Result<()> {
+    check_symlink_dirs(output_dir, dir, options)?;
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+// check_symlink_dirs for the folders above an entry that the tar crate will create
+fn check_entry_dirs(output_dir: &Path, entry_path: &Path, options: &ExtractOptions) -> Result<()> {
+    match entry_target_path(output_dir, entry_path, options) {
+        Some(target) => check_symlink_dirs(output_dir, target.parent().unwrap_or(output_dir), options),
+        None => Ok(()),
+    }
+}
+
+// Every existing folder from output_dir down to `dir` must be a real folder. output_dir
+// itself was resolved up front, so only what is below it is looked at.
+fn check_symlink_dirs(output_dir: &Path, dir: &Path, options: &ExtractOptions) -> Result<()> {
+    if options.allow_symlink_dirs {
+        return Ok(());
+    }
+    let Ok(relative) = dir.strip_prefix(output_dir) else { return Ok(()) };
+    let mut current = output_dir.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(CompressionError::SuspiciousArchive {
+                    reason: format!("'{}' is a link, not a folder; set allow_symlink_dirs to extract through it", current.display()),
+                }.into());
+            },
+            Ok(_) => {},
+            // Nothing further down exists yet
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+// Fail the whole extraction for an entry nested deeper than options.max_depth allows.
+// Thousands of levels can exceed filesystem path limits and trip up other tools, and
+// no real archive needs them.
+fn check_entry_depth(name: &str, options: &ExtractOptions) -> Result<()> {
+    let max_depth = options.max_depth();
+    let depth = name.split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .count();
+    if max_depth > 0 && depth > max_depth {
+        return Err(CompressionError::SuspiciousArchive {
+            reason: format!("'{}' is nested {} levels deep (the limit is {})", name, depth, max_depth),
+        }.into());
+    }
+    Ok(())
+}
+
+// ExtractOptions::pattern, compiled once per archive. Names are matched with forward
+// slashes and without a leading "./", so "docs/*" matches tar's "./docs/a.txt" too.
+struct EntryPattern(Option<globset::GlobMatcher>);
+
+impl EntryPattern {
+    fn new(options: &ExtractOptions) -> Result<Self> {
+        let matcher = match &options.pattern {
+            Some(pattern) => Some(globset::Glob::new(pattern)
+                .with_context(|| format!("Invalid pattern '{}'", pattern))?
+                .compile_matcher()),
+            None => None,
+        };
+        Ok(Self(matcher))
+    }
+    
+    // No pattern set, so every entry is extracted
+    fn is_all(&self) -> bool {
+        self.0.is_none()
+    }
+    
+    fn matches(&self, name: &str) -> bool {
+        let Some(matcher) = &self.0 else { return true };
+        let name = name.replace('\\', "/");
+        matcher.is_match(name.trim_start_matches("./"))
+    }
+}
+
+// An entry name that is an absolute path on this system, for restore_absolute. None for
+// a relative name, one that is only absolute elsewhere, or one that climbs with "..".
+fn absolute_entry_target(name: &str) -> Option<PathBuf> {
+    let normalized = name.replace('\\', "/");
+    if normalized.split('/').any(|component| component == "..") {
+        return None;
+    }
+    let path = PathBuf::from(if cfg!(windows) { normalized.replace('/', "\\") } else { normalized });
+    if !path.is_absolute() {
+        return None;
+    }
+    Some(path)
+}
+
+// Turn an entry name into a path that stays inside the output dir. Names that climb out
+// with ".." are refused (zip-slip); absolute names like "/home/user/file" or
+// "C:\Users\file" from malformed archives lose their root and are extracted relative
+// to the output dir instead.
+fn sanitize_entry_path(name: &str, options: &ExtractOptions) -> Option<PathBuf> {
+    let normalized = name.replace('\\', "/");
+    let mut components = normalized.split('/').peekable();
+    let mut stripped_root = false;
+    
+    if let Some(first) = components.peek() {
+        let bytes = first.as_bytes();
+        if bytes.len() == 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+            components.next();
+            stripped_root = true;
+        }
+    }
+    
+    let mut path = PathBuf::new();
+    for component in components {
+        match component {
+            // A leading slash shows up as an empty first component
+            "" if path.as_os_str().is_empty() => stripped_root = true,
+            "" | "." => {},
+            ".." => {
+                options.warn(format!("Refused '{}': it would be written outside the folder", name));
+                return None;
+            },
+            _ => path.push(component),
+        }
+    }
+    
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    if stripped_root {
+        options.warn(format!("'{}' has an absolute path; extracted as '{}'", name, path.display()));
+    }
+    Some(path)
+}
+
+// sanitize_entry_path for a name that isn't UTF-8, which tar and cpio entries can have
+// on Unix. It is split on slashes as bytes, so the file gets the archive's name byte for
+// byte rather than one with U+FFFD in it.
+#[cfg(unix)]
+fn sanitize_raw_entry_path(name: &Path, options: &ExtractOptions) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut path = PathBuf::new();
+    for component in name.as_os_str().as_bytes().split(|b| *b == b'/' || *b == b'\\') {
+        match component {
+            b"" | b"." => {},
+            b".." => {
+                options.warn(format!("Refused '{}': it would be written outside the folder", name.display()));
+                return None;
+            },
+            _ => path.push(std::ffi::OsStr::from_bytes(component)),
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+#[cfg(not(unix))]
+fn sanitize_raw_entry_path(name: &Path, options: &ExtractOptions) -> Option<PathBuf> {
+    sanitize_entry_path(&name.to_string_lossy(), options)
+}
+
+fn resolve_conflict(path: PathBuf, policy: ConflictPolicy) -> Option<PathBuf> {
+    if !path.exists() {
+        return Some(path);
+    }
+
+    match policy {
+        ConflictPolicy::Overwrite => Some(path),
+        ConflictPolicy::Skip => None,
+        ConflictPolicy::Rename => Some(get_unique_name(&path)),
+    }
+}
+
+async fn decompress_gz_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path, 
+    file_size: u64,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let input = File::open(file_path)?;
+    let mut progress = Progress::new(file_size, progress_callback).counting(options.progress_bytes.clone());
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    let progress_reader = ProgressReader::new(input, &mut progress);
+    let mut decoder = flate2::read::GzDecoder::new(BufReader::new(progress_reader));
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    // Try to get the original filename from the gzip header first
+    let output_name = if let Some(filename_bytes) = decoder.header().and_then(|h| h.filename()) {
+        // Convert bytes to string and create OsString
+        match std::str::from_utf8(filename_bytes) {
+            Ok(filename_str) => std::ffi::OsString::from(filename_str),
+            Err(_) => {
+                // If UTF-8 conversion fails, fall back to the compressed filename
+                fallback_filename_from_compressed(file_path)
+            }
+        }
+    } else {
+        // If no filename in header, try to infer from the compressed filename
+        fallback_filename_from_compressed(file_path)
+    };
+    
+    let output_path = output_dir.join(output_name);
+    let mut output = ChecksumWriter { inner: File::create(&output_path)?, crc: flate2::Crc::new() };
+    options.log_entry(Placement::Added, &output_path.file_name().unwrap_or_default().to_string_lossy(), &output_path);
+    
+    let verified = match copy_decoded(&mut decoder, &mut output) {
+        Ok(_) if options.verify() => check_gz_trailer(file_path, decoder.into_inner(), &output.crc),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    
+    match verified {
+        // A failed write (a full disk, say) is never kept, verified or not
+        Err(e) if options.verify() || e.downcast_ref::<CompressionError>().is_none() => {
+            drop(output);
+            let _ = std::fs::remove_file(&output_path);
+            Err(e)
+        },
+        Err(e) => {
+            options.warn(format!("Keeping possibly incomplete output {}: {}", output_path.display(), e));
+            Ok(())
+        },
+        Ok(()) => Ok(()),
+    }
+}
+
+// Compare what was decompressed with the CRC-32 and length in the gzip trailer. flate2
+// checks these itself once it reaches the trailer, but a file cut short inside the
+// deflate data can look like a clean end of stream.
+fn check_gz_trailer<R: std::io::BufRead>(file_path: &Path, mut rest: R, crc: &flate2::Crc) -> Result<()> {
+    // More data after the first member means its trailer was reached and already checked
+    if !rest.fill_buf()?.is_empty() {
+        return Ok(());
+    }
+    
+    let mut file = File::open(file_path)?;
+    let mut trailer = [0u8; 8];
+    file.seek(std::io::SeekFrom::End(-8))?;
+    file.read_exact(&mut trailer)?;
+    let expected_crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let expected_len = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    
+    if crc.sum() != expected_crc || crc.amount() != expected_len {
+        return Err(CompressionError::Corrupt {
+            reason: "the gzip data doesn't match its trailer (truncated or damaged file)".to_string(),
+        }.into());
+    }
+    Ok(())
+}
+
+// Copy decompressed data to `writer`. Only a failure to read is the archive's fault
+// (CompressionError::Corrupt); a failure to write passes through as it is.
+fn copy_decoded<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u64> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut copied = 0u64;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(CompressionError::Corrupt { reason: e.to_string() }.into()),
+        };
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+}
+
+// Passes writes through while keeping a CRC-32 of everything written
+struct ChecksumWriter<W> {
+    inner: W,
+    crc: flate2::Crc,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn fallback_filename_from_compressed(file_path: &Path) -> std::ffi::OsString {
+    let filename = file_path.file_name().unwrap_or_default().to_string_lossy();
+    
+    // Handle different compression formats
+    let (base_name, stripped) = if filename.ends_with(".gz") {
+        (&filename[..filename.len() - 3], true)
+    } else if filename.ends_with(".gzip") {
+        (&filename[..filename.len() - 5], true)
+    } else if filename.ends_with(".br") {
+        (&filename[..filename.len() - 3], true)
+    } else if filename.ends_with(".bz2") {
+        (&filename[..filename.len() - 4], true)
+    } else if filename.ends_with(".bzip2") {
+        (&filename[..filename.len() - 6], true)
+    } else {
+        (filename.as_ref(), false)
+    };
+    
+    if stripped && !base_name.is_empty() {
+        // If we stripped a compression extension and there's still a base name
+        if base_name.contains('.') {
+            // Base name already has an extension, use it as-is
+            std::ffi::OsString::from(base_name)
+        } else {
+            // No extension in base name, assume it's a text file
+            // You could make this more sophisticated by analyzing file content
+            std::ffi::OsString::from(format!("{}.txt", base_name))
+        }
+    } else {
+        // Fallback to file stem if we couldn't parse the format
+        file_path.file_stem().unwrap_or_default().to_os_string()
+    }
+}
+
+async fn decompress_br_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path, 
+    file_size: u64,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let input = File::open(file_path)?;
+    let mut progress = Progress::new(file_size, progress_callback).counting(options.progress_bytes.clone());
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    let progress_reader = ProgressReader::new(input, &mut progress);
+    let mut decoder = brotli::Decompressor::new(BufReader::new(progress_reader), 4096);
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    // Use improved filename logic
+    let output_name = fallback_filename_from_compressed(file_path);
+    let output_path = output_dir.join(output_name);
+    let mut output = File::create(&output_path)?;
+    options.log_entry(Placement::Added, &output_path.file_name().unwrap_or_default().to_string_lossy(), &output_path);
+    
+    std::io::copy(&mut decoder, &mut output)?;
+    Ok(())
+}
+
+async fn decompress_bzip2_with_progress<F>(
+    file_path: &Path, 
+    output_dir: &Path, 
+    file_size: u64,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    let input = File::open(file_path)?;
+    let mut progress = Progress::new(file_size, progress_callback).counting(options.progress_bytes.clone());
+    progress.start(file_path.file_name().unwrap_or_default().to_string_lossy().to_string());
+    let progress_reader = ProgressReader::new(input, &mut progress);
+    let mut decoder = bzip2::read::BzDecoder::new(BufReader::new(progress_reader));
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    // Use improved filename logic
+    let output_name = fallback_filename_from_compressed(file_path);
+    let output_path = output_dir.join(output_name);
+    let mut output = File::create(&output_path)?;
+    options.log_entry(Placement::Added, &output_path.file_name().unwrap_or_default().to_string_lossy(), &output_path);
+    
+    std::io::copy(&mut decoder, &mut output)?;
+    Ok(())
+}
+
+async fn decompress_7z_with_progress<F>(
+    file_path: &Path,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    let password = options.password.as_deref()
+        .map(sevenz_rust::Password::from)
+        .unwrap_or_else(sevenz_rust::Password::empty);
+    
+    // Opening parses the headers, so archives with encrypted headers fail here
+    // with PasswordRequired before any content is listed or written
+    let mut reader = sevenz_rust::SevenZReader::open(file_path, password).map_err(map_7z_error)?;
+    for entry in &reader.archive().files {
+        check_entry_depth(entry.name(), options)?;
+    }
+    let pattern = EntryPattern::new(options)?;
+    let total_size = reader.archive().files.iter().filter(|entry| pattern.matches(entry.name())).map(|entry| entry.size).sum();
+    let mut progress = Progress::new(total_size, progress_callback).counting(options.progress_bytes.clone());
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    let mut summary = ExtractSummary::default();
+    reader.for_each_entries(|entry, entry_reader| {
+        // Handed back as plain I/O errors, so sevenz_rust can call a failure to decode
+        // an encrypted folder MaybeBadPassword
+        let io_error = sevenz_rust::Error::io;
+        
+        // Entries in a solid block are decompressed on the way to later ones anyway
+        if !pattern.matches(entry.name()) {
+            std::io::copy(entry_reader, &mut std::io::sink()).map_err(io_error)?;
+            return Ok(true);
+        }
+        
+        progress.start(entry.name().rsplit('/').next().unwrap_or_default().to_string());
+        
+        if entry.is_directory() {
+            if let (false, Some(path)) = (options.flatten, entry_target_path(output_dir, Path::new(entry.name()), options)) {
+                create_entry_dir(output_dir, &path, options)
+                    .map_err(|e| sevenz_rust::Error::Other(e.to_string().into()))?;
+            }
+            return Ok(true);
+        }
+        
+        let mut entry_reader = ReadFailure { inner: entry_reader, failed: false };
+        let written = match entry_output_path(output_dir, Path::new(entry.name()), options) {
+            Some(outpath) => write_7z_entry(output_dir, &outpath, &mut ProgressReader::new(&mut entry_reader, &mut progress), options),
+            None => Ok(0),
+        };
+        if let Err(e) = written {
+            // With a password, data that doesn't decode means it was the wrong one
+            if entry_reader.failed && options.password.is_some() {
+                return Err(io_error(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())));
+            }
+            if !options.continue_on_error {
+                return Err(sevenz_rust::Error::Other(e.to_string().into()));
+            }
+            summary.record_failure(options, entry.name().to_string(), e);
+        }
+        // Skipped or failed entries still have to be read through in a solid block
+        std::io::copy(&mut entry_reader, &mut std::io::sink()).map_err(io_error)?;
+        progress.entry_done(entry.size);
+        Ok(true)
+    }).map_err(map_7z_error)?;
+    
+    progress.complete();
+    Ok(summary)
+}
+
+async fn decompress_iso_with_progress<F>(
+    file_path: &Path,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    let mut image = IsoImage::open(BufReader::new(File::open(file_path)?))?;
+    let pattern = EntryPattern::new(options)?;
+    let entries: Vec<IsoEntry> = image.entries()?
+        .into_iter()
+        .filter(|entry| pattern.matches(&entry.path.to_string_lossy()))
+        .collect();
+    for name in image.skipped() {
+        options.warn(format!("Skipped ISO entry with unusable name: {:?}", name));
+    }
+    let mut progress = Progress::new(entries.iter().map(IsoEntry::size).sum(), progress_callback).counting(options.progress_bytes.clone());
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    let mut summary = ExtractSummary::default();
+    for entry in &entries {
+        check_entry_depth(&entry.path.to_string_lossy(), options)?;
+        if entry.is_dir {
+            if !options.flatten {
+                create_entry_dir(output_dir, &output_dir.join(&entry.path), options)?;
+            }
+            continue;
+        }
+        
+        progress.start(entry.path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        let written = match entry_output_path(output_dir, &entry.path, options) {
+            Some(outpath) => write_iso_entry(&mut image, entry, output_dir, &outpath, options, &mut progress),
+            None => Ok(()),
+        };
+        progress.entry_done(entry.size());
+        if let Err(e) = written {
+            if !options.continue_on_error {
+                return Err(e);
+            }
+            summary.record_failure(options, entry.path.display().to_string(), e);
+        }
+    }
+    
+    progress.complete();
+    Ok(summary)
+}
+
+async fn decompress_chunks_with_progress<F>(
+    file_path: &Path,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    progress_callback: F
+) -> Result<ExtractSummary>
+where
+    F: FnMut(f64, String),
+{
+    let manifest = ChunkManifest::read(file_path)?;
+    let store = ChunkStore::open_beside(file_path)?;
+    for entry in &manifest.entries {
+        check_entry_depth(&entry.name, options)?;
+    }
+    let pattern = EntryPattern::new(options)?;
+    let entries: Vec<&ChunkEntry> = manifest.entries.iter()
+        .filter(|entry| pattern.matches(&entry.name))
+        .collect();
+    let mut progress = Progress::new(entries.iter().map(|entry| entry.size).sum(), progress_callback).counting(options.progress_bytes.clone());
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    let mut summary = ExtractSummary::default();
+    for entry in &entries {
+        if entry.is_dir {
+            if let (false, Some(path)) = (options.flatten, entry_target_path(output_dir, Path::new(&entry.name), options)) {
+                create_entry_dir(output_dir, &path, options)?;
+            }
+            continue;
+        }
+        
+        progress.start(entry.name.rsplit('/').next().unwrap_or_default().to_string());
+        let written = match entry_output_path(output_dir, Path::new(&entry.name), options) {
+            Some(outpath) => write_chunk_entry(&store, entry, output_dir, &outpath, options, &mut progress),
+            None => Ok(()),
+        };
+        progress.entry_done(entry.size);
+        if let Err(e) = written {
+            // A damaged store affects every later entry too
+            if !options.continue_on_error || e.downcast_ref::<CompressionError>().is_some() {
+                return Err(e);
+            }
+            summary.record_failure(options, entry.name.clone(), e);
+        }
+    }
+    
+    progress.complete();
+    Ok(summary)
+}
+
+fn write_chunk_entry<F: FnMut(f64, String)>(store: &ChunkStore, entry: &ChunkEntry, output_dir: &Path, outpath: &Path, options: &ExtractOptions, progress: &mut Progress<F>) -> Result<()> {
+    if let Some(p) = outpath.parent() {
+        create_entry_dir(output_dir, p, options)?;
+    }
+    let mut outfile = BufWriter::new(File::create(outpath)?);
+    store.copy_chunks(&entry.chunks, &mut ProgressWriter::new(&mut outfile, progress))?;
+    outfile.flush()?;
+    if let Some(time) = std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(entry.mtime)) {
+        outfile.get_ref().set_modified(time)?;
+    }
+    Ok(())
+}
+
+fn write_iso_entry<R: Read + std::io::Seek, F: FnMut(f64, String)>(image: &mut IsoImage<R>, entry: &IsoEntry, output_dir: &Path, outpath: &Path, options: &ExtractOptions, progress: &mut Progress<F>) -> Result<()> {
+    if let Some(p) = outpath.parent() {
+        create_entry_dir(output_dir, p, options)?;
+    }
+    let outfile = File::create(outpath)?;
+    let copied = if options.preserve_sparse() {
+        let mut writer = SparseWriter::new(outfile);
+        image.copy_entry(entry, &mut ProgressWriter::new(&mut writer, progress)).and_then(|_| writer.finish().map(|_| ()))
+    } else {
+        let mut outfile = outfile;
+        image.copy_entry(entry, &mut ProgressWriter::new(&mut outfile, progress)).map(|_| ())
+    };
+    if let Err(e) = copied {
+        // Don't leave a truncated file behind (the file is closed by now)
+        let _ = std::fs::remove_file(outpath);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn write_7z_entry(output_dir: &Path, outpath: &Path, entry_reader: &mut dyn Read, options: &ExtractOptions) -> Result<u64> {
+    if let Some(p) = outpath.parent() {
+        create_entry_dir(output_dir, p, options)?;
+    }
+    let copied = write_entry_data(entry_reader, File::create(outpath)?, options.preserve_sparse());
+    if copied.is_err() {
+        // Don't leave a truncated file behind (the file is closed by now)
+        let _ = std::fs::remove_file(outpath);
+    }
+    Ok(copied?)
+}
+
+// Notes whether reading failed, to tell a 7z entry that didn't decode from one that
+// couldn't be written
+struct ReadFailure<R> {
+    inner: R,
+    failed: bool,
+}
+
+impl<R: Read> Read for ReadFailure<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf);
+        self.failed |= read.is_err();
+        read
+    }
+}
+
+fn map_7z_error(e: sevenz_rust::Error) -> anyhow::Error {
+    match e {
+        sevenz_rust::Error::PasswordRequired => CompressionError::NeedsPassword.into(),
+        sevenz_rust::Error::MaybeBadPassword(_) => CompressionError::WrongPassword.into(),
+        e => anyhow::anyhow!("7z extraction failed: {}", e),
+    }
+}
+
+#[cfg(feature = "rar-support")]
+async fn decompress_rar(file_path: &Path, output_dir: &Path) -> Result<()> {
+    use unrar::Archive;
+    
+    std::fs::create_dir_all(output_dir)?;
+    
+    // Convert to owned strings to avoid lifetime issues
+    let file_path_str = file_path.to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid file path"))?
+        .to_string();
+    let output_dir_str = output_dir.to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid output directory path"))?
+        .to_string();
+    
+    // Handle the unrar specific error types using spawn_blocking for thread safety
+    tokio::task::spawn_blocking(move || {
+        Archive::new(file_path_str)
+            .extract_to(output_dir_str)
+            .map_err(|e| anyhow::anyhow!("RAR extraction failed: {:?}", e))?
+            .process()
+            .map_err(|e| anyhow::anyhow!("RAR processing failed: {:?}", e))?;
+        Ok::<(), anyhow::Error>(())
+    }).await??;
+    
+    Ok(())
+}
+
+// Uncompressed size of an archive, as far as headers tell without decompressing: the entry
+// sizes for zip, 7z and ISO, the gzip trailer for .gz streams. Anything else (and anything
+// unreadable) counts as its file size, a fair proxy when only used for weighting.
+pub fn get_archive_size(file_path: &Path) -> u64 {
+    let file_size = std::fs::metadata(file_path).map(|m| m.len()).unwrap_or(0);
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    
+    let header_size = || -> Result<u64> {
+        if normalized_name.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+            let mut total = 0;
+            for i in 0..archive.len() {
+                total += archive.by_index_raw(i)?.size();
+            }
+            Ok(total)
+        } else if normalized_name.ends_with(".7z") {
+            let reader = sevenz_rust::SevenZReader::open(file_path, sevenz_rust::Password::empty()).map_err(map_7z_error)?;
+            Ok(reader.archive().files.iter().map(|entry| entry.size).sum())
+        } else if normalized_name.ends_with(".iso") {
+            let entries = IsoImage::open(BufReader::new(File::open(file_path)?))?.entries()?;
+            Ok(entries.iter().map(IsoEntry::size).sum())
+        } else if normalized_name.ends_with(".tzchunks") {
+            Ok(ChunkManifest::read(file_path)?.entries.iter().map(|entry| entry.size).sum())
+        } else if normalized_name.ends_with(".gz") || normalized_name.ends_with(".gzip") {
+            // The trailer holds the size modulo 4 GiB; a wrapped value shows up as too small
+            let mut file = File::open(file_path)?;
+            file.seek(std::io::SeekFrom::End(-4))?;
+            let mut trailer = [0u8; 4];
+            file.read_exact(&mut trailer)?;
+            Ok(u32::from_le_bytes(trailer) as u64)
+        } else {
+            Ok(0)
+        }
+    };
+    
+    header_size().ok().filter(|size| *size >= file_size).unwrap_or(file_size)
+}
+
+// List the entries of an archive without extracting it. When `metadata_field` is given,
+// the matching custom extra field of each zip entry is returned as well.
+// Tar entry names lose a leading "./" when `normalize_paths` is set. A .tar.zst
+// compressed with a dictionary needs `zstd_dictionary`.
+pub fn list_archive_contents(file_path: &Path, metadata_field: Option<u16>, normalize_paths: bool, zstd_dictionary: Option<&Path>) -> Result<Vec<ArchiveEntry>> {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    
+    if file_name.ends_with(".zip") {
+        // A spanned zip's last disk points into the others, so read the joined set
+        let stitched = stitch_spanned_zip(file_path, &std::env::temp_dir())?;
+        let file_path = stitched.as_ref().map_or(file_path, |temp_file| temp_file.path());
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+        let mut headers = File::open(file_path)?;
+        let mut entries = Vec::with_capacity(archive.len());
+        for i in 0..archive.len() {
+            // Raw access works for encrypted entries too, since nothing is decompressed
+            let file = archive.by_index_raw(i)?;
+            entries.push(zip_archive_entry(&file, metadata_field, &mut headers)?);
+        }
+        return Ok(entries);
+    }
+    
+    if file_name.ends_with(".tzchunks") {
+        return Ok(ChunkManifest::read(file_path)?.entries.into_iter()
+            .map(|entry| ArchiveEntry {
+                name: entry.name,
+                size: entry.size,
+                is_dir: entry.is_dir,
+                metadata: None,
+                method: "Zstd".to_string(),
+                encrypted: false,
+            })
+            .collect());
+    }
+    
+    let method = tar_stream_method(file_name);
+    let reader = open_tar_reader(file_path, zstd_dictionary)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if normalize_paths && is_tar_root(&name) {
+            continue;
+        }
+        entries.push(ArchiveEntry {
+            name: tar_entry_name(name, normalize_paths),
+            size: entry.header().size()?,
+            is_dir: entry.header().entry_type().is_dir(),
+            metadata: None,
+            method: method.to_string(),
+            encrypted: false,
+        });
+    }
+    Ok(entries)
+}
+
+// The archive's entries as a tree under a root named after the archive, folders first
+// and then by name. Built from the same header scan as list_archive_contents; folders
+// that only appear in paths get a node too.
+pub fn list_archive_tree(file_path: &Path) -> Result<TreeNode> {
+    let entries = list_archive_contents(file_path, None, true, None)?;
+    Ok(entry_tree(file_path, &entries))
+}
+
+fn entry_tree(file_path: &Path, entries: &[ArchiveEntry]) -> TreeNode {
+    let mut root = TreeBuilder { is_dir: true, ..TreeBuilder::default() };
+    for entry in entries {
+        let parts: Vec<&str> = entry.name.split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+        root.insert(&parts, entry.is_dir, entry.size);
+    }
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    root.build(name)
+}
+
+// Look at an archive's layout from its listing, before anything is extracted
+pub fn inspect_archive(file_path: &Path, threshold: Option<usize>) -> Result<ArchiveInspection> {
+    let entries = list_archive_contents(file_path, None, true, None)?;
+    let tree = entry_tree(file_path, &entries);
+    let common_root = match tree.children.as_slice() {
+        [only] if only.is_dir => Some(only.name.clone()),
+        _ => None,
+    };
+    let top_level_items = tree.children.len();
+    Ok(ArchiveInspection {
+        entry_count: entries.len(),
+        top_level_items,
+        many_loose_items: common_root.is_none() && top_level_items > threshold.unwrap_or(DEFAULT_LOOSE_ITEMS_THRESHOLD),
+        common_root,
+    })
+}
+
+// Time decoding the first ESTIMATE_PROBE_BYTES of an archive and scale that up to the
+// whole of it. Nothing is written. Zip records every entry's size, so its rate is
+// applied to the real total; for streams the compressed bytes the probe took stand in
+// for its share of the file.
+pub fn estimate_extraction(file_path: &Path) -> Result<ExtractionEstimate> {
+    let format = sniff_format(file_path)?
+        .ok_or_else(|| anyhow::anyhow!("Not a recognized archive: {}", file_path.display()))?;
+    let file_size = std::fs::metadata(file_path)?.len();
+    let started = std::time::Instant::now();
+    
+    match format {
+        "zip" => {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+            let mut total = 0u64;
+            for i in 0..archive.len() {
+                total += archive.by_index_raw(i)?.size();
+            }
+            let mut probed = 0u64;
+            for i in 0..archive.len() {
+                if probed >= ESTIMATE_PROBE_BYTES {
+                    break;
+                }
+                // Encrypted entries can't be decoded without the password
+                let Ok(entry) = archive.by_index(i) else { continue };
+                probed += std::io::copy(&mut entry.take(ESTIMATE_PROBE_BYTES - probed), &mut std::io::sink())?;
+            }
+            Ok(ExtractionEstimate {
+                uncompressed_size: total,
+                size_estimated: false,
+                seconds: scale_probe(started.elapsed(), probed, total),
+            })
+        },
+        // Stored as they are, so extracting is mostly reading
+        "tar" | "cpio" | "iso" => {
+            let read = std::io::copy(&mut File::open(file_path)?.take(ESTIMATE_PROBE_BYTES), &mut std::io::sink())?;
+            Ok(ExtractionEstimate {
+                uncompressed_size: file_size,
+                size_estimated: false,
+                seconds: scale_probe(started.elapsed(), read, file_size),
+            })
+        },
+        "tar.gz" | "tar.bz2" | "tar.xz" | "tar.zst" | "tar.br" | "gzip" | "cpio.gz" | "bzip2" | "brotli" => {
+            let mut counter = CountingReader { inner: File::open(file_path)?, count: 0 };
+            let decoded = {
+                let reader = BufReader::new(&mut counter);
+                let decoder: Box<dyn Read + '_> = match format {
+                    "gzip" | "cpio.gz" => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+                    "bzip2" => Box::new(bzip2::read::BzDecoder::new(reader)),
+                    "brotli" => Box::new(brotli::Decompressor::new(reader, 4096)),
+                    _ => tar_decoder(&format!(".{}", format), reader, DEFAULT_DECOMPRESS_MEMORY_LIMIT)?,
+                };
+                std::io::copy(&mut decoder.take(ESTIMATE_PROBE_BYTES), &mut std::io::sink())?
+            };
+            let elapsed = started.elapsed();
+            // A stream that ended within the probe was decoded whole
+            if decoded < ESTIMATE_PROBE_BYTES || counter.count == 0 {
+                return Ok(ExtractionEstimate { uncompressed_size: decoded, size_estimated: false, seconds: elapsed.as_secs_f64() });
+            }
+            let scale = file_size as f64 / counter.count as f64;
+            Ok(ExtractionEstimate {
+                uncompressed_size: (decoded as f64 * scale) as u64,
+                size_estimated: true,
+                seconds: elapsed.as_secs_f64() * scale,
+            })
+        },
+        _ => Err(CompressionError::Unsupported { feature: format!("estimating {} extraction", format) }.into()),
+    }
+}
+
+// Seconds for `total` bytes at the rate `probed` bytes took
+fn scale_probe(elapsed: std::time::Duration, probed: u64, total: u64) -> f64 {
+    if probed == 0 {
+        return 0.0;
+    }
+    elapsed.as_secs_f64() * total as f64 / probed as f64
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.count += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+#[derive(Default)]
+struct TreeBuilder {
+    is_dir: bool,
+    size: u64,
+    children: BTreeMap<String, TreeBuilder>,
+}
+
+impl TreeBuilder {
+    fn insert(&mut self, parts: &[&str], is_dir: bool, size: u64) {
+        let Some((first, rest)) = parts.split_first() else { return };
+        let child = self.children.entry(first.to_string()).or_default();
+        if rest.is_empty() {
+            child.is_dir |= is_dir;
+            child.size = size;
+        } else {
+            child.is_dir = true;
+            child.insert(rest, is_dir, size);
+        }
+    }
+
+    fn build(self, name: String) -> TreeNode {
+        let mut children: Vec<TreeNode> = self.children.into_iter()
+            .map(|(name, child)| child.build(name))
+            .collect();
+        // Stable, so each group stays sorted by name
+        children.sort_by_key(|child| !child.is_dir);
+        let size = if self.is_dir { children.iter().map(|child| child.size).sum() } else { self.size };
+        TreeNode { name, is_dir: self.is_dir, size, children }
+    }
+}
+
+// Some tar tools put "./" in front of every entry, plus an entry for "./" itself
+fn zip_archive_entry<R: Read + Seek>(file: &zip::read::ZipFile<'_>, metadata_field: Option<u16>, headers: &mut R) -> Result<ArchiveEntry> {
+    let metadata = metadata_field
+        .and_then(|field_id| read_entry_metadata(file.extra_data(), field_id))
+        .map(|data| String::from_utf8_lossy(data).to_string());
+    #[allow(deprecated)]
+    let code = file.compression().to_u16();
+    let method = zip_entry_method(code, file.extra_data());
+    
+    // The zip crate doesn't expose the general purpose flags, so bit 0 (encrypted) is
+    // read from the local header
+    let mut flags = [0u8; 2];
+    headers.seek(std::io::SeekFrom::Start(file.header_start() + 6))?;
+    headers.read_exact(&mut flags)?;
+    let encrypted = u16::from_le_bytes(flags) & 1 != 0 || code == ZIP_METHOD_AES;
+    
+    Ok(ArchiveEntry {
+        name: zip_entry_name(file),
+        size: file.size(),
+        is_dir: zip_entry_is_dir(file),
+        metadata,
+        method,
+        encrypted,
+    })
+}
+
+// The method's name, and for AES the name of the method underneath too
+fn zip_entry_method(code: u16, extra_data: &[u8]) -> String {
+    if code != ZIP_METHOD_AES {
+        return zip_method_name(code);
+    }
+    // Version (2), vendor "AE" (2), strength (1), then the method underneath
+    let inner = read_entry_metadata(extra_data, ZIP_AES_FIELD)
+        .filter(|data| data.len() >= 7)
+        .map(|data| zip_method_name(u16::from_le_bytes([data[5], data[6]])))
+        .unwrap_or_else(|| "?".to_string());
+    format!("AES ({})", inner)
+}
+
+// The listing entry for a central directory header, the same as zip_archive_entry makes
+// from the zip crate's view of the entry
+fn central_archive_entry(header: &[u8], metadata_field: Option<u16>) -> ArchiveEntry {
+    let flags = u16_at(header, 8);
+    let code = u16_at(header, 10);
+    let name_len = u16_at(header, 28) as usize;
+    let name_raw = &header[46..46 + name_len];
+    let extra = &header[46 + name_len..46 + name_len + u16_at(header, 30) as usize];
+    
+    let mut size = u32_at(header, 24) as u64;
+    if size == 0xFFFF_FFFF {
+        // The uncompressed size comes first in the Zip64 field
+        if let Some(field) = read_entry_metadata(extra, ZIP64_FIELD).filter(|field| field.len() >= 8) {
+            size = u64_at(field, 0);
+        }
+    }
+    // Bit 11: the name is UTF-8; otherwise it is CP437, as the zip crate reads it
+    let name = unicode_path(extra, name_raw)
+        .unwrap_or_else(|| if flags & (1 << 11) != 0 { String::from_utf8_lossy(name_raw).to_string() } else { cp437_name(name_raw) })
+        .replace('\\', "/");
+    ArchiveEntry {
+        is_dir: name.ends_with('/'),
+        metadata: metadata_field
+            .and_then(|field_id| read_entry_metadata(extra, field_id))
+            .map(|data| String::from_utf8_lossy(data).to_string()),
+        method: zip_entry_method(code, extra),
+        encrypted: flags & 1 != 0 || code == ZIP_METHOD_AES,
+        name,
+        size,
+    }
+}
+
+// The upper half of code page 437; the lower half is ASCII
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+fn cp437_name(name_raw: &[u8]) -> String {
+    name_raw.iter()
+        .map(|&byte| if byte < 0x80 { byte as char } else { CP437_HIGH.chars().nth(byte as usize - 0x80).unwrap_or('?') })
+        .collect()
+}
+
+// Entries `offset..offset + limit` of a zip, read from the central directory alone.
+// The headers before the page are stepped over without being parsed and nothing after
+// it is read, so no more than a page of entries is ever held.
+fn zip_page(file_path: &Path, offset: usize, limit: usize, metadata_field: Option<u16>) -> Result<ArchivePage> {
+    let mut file = BufReader::new(File::open(file_path)?);
+    let directory = find_zip_directory(&mut file)?
+        .ok_or_else(|| CompressionError::Corrupt { reason: "end of central directory not found".to_string() })?;
+    let total = directory.entries as usize;
+    let end = offset.saturating_add(limit).min(total);
+    
+    file.seek(std::io::SeekFrom::Start(directory.offset))?;
+    let mut entries = Vec::with_capacity(end.saturating_sub(offset));
+    for index in 0..end {
+        let mut header = vec![0u8; 46];
+        if file.read_exact(&mut header).is_err() || header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(CompressionError::Corrupt { reason: "damaged central directory".to_string() }.into());
+        }
+        let rest = u16_at(&header, 28) as usize + u16_at(&header, 30) as usize + u16_at(&header, 32) as usize;
+        if index < offset {
+            file.seek_relative(rest as i64)?;
+            continue;
+        }
+        header.resize(46 + rest, 0);
+        file.read_exact(&mut header[46..])?;
+        entries.push(central_archive_entry(&header, metadata_field));
+    }
+    Ok(ArchivePage { entries, total: Some(total), has_more: end < total })
+}
+
+const ZIP_METHOD_AES: u16 = 99;
+const ZIP_AES_FIELD: u16 = 0x9901;
+
+// What every entry of a tarball is compressed with: the stream around the whole tar
+fn tar_stream_method(file_name: &str) -> &'static str {
+    let normalized_name = normalize_archive_name(file_name);
+    if normalized_name.ends_with(".tar.gz") {
+        "Gzip"
+    } else if normalized_name.ends_with(".tar.br") {
+        "Brotli"
+    } else if normalized_name.ends_with(".tar.bz2") {
+        "Bzip2"
+    } else if normalized_name.ends_with(".tar.xz") {
+        "XZ"
+    } else if normalized_name.ends_with(".tar.zst") {
+        "Zstd"
+    } else {
+        "Stored"
+    }
+}
+
+fn tar_entry_name(name: String, normalize_paths: bool) -> String {
+    match name.strip_prefix("./") {
+        Some(stripped) if normalize_paths && !stripped.is_empty() => stripped.to_string(),
+        _ => name,
+    }
+}
+
+fn is_tar_root(name: &str) -> bool {
+    name == "./" || name == "."
+}
+
+// Entries `offset..offset + limit` of the listing. Zip reads its central directory up
+// to the end of the page (see zip_page); tarballs are read up to the end of the page
+// and no further.
+pub fn list_archive_page(file_path: &Path, offset: usize, limit: usize, metadata_field: Option<u16>, normalize_paths: bool, zstd_dictionary: Option<&Path>) -> Result<ArchivePage> {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    
+    if file_name.ends_with(".zip") {
+        // A spanned zip's last disk points into the others, so read the joined set
+        let stitched = stitch_spanned_zip(file_path, &std::env::temp_dir())?;
+        let file_path = stitched.as_ref().map_or(file_path, |temp_file| temp_file.path());
+        return zip_page(file_path, offset, limit, metadata_field);
+    }
+    
+    let method = tar_stream_method(file_name);
+    let reader = open_tar_reader(file_path, zstd_dictionary)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    // The "./" entry is left out before counting, so pages line up with the full listing
+    let listed = archive.entries()?.filter(|entry| {
+        !normalize_paths || !entry.as_ref().is_ok_and(|entry| entry.path().is_ok_and(|path| is_tar_root(&path.to_string_lossy())))
+    });
+    // One entry past the page tells whether there are more
+    for entry in listed.skip(offset).take(limit.saturating_add(1)) {
+        let entry = entry?;
+        entries.push(ArchiveEntry {
+            name: tar_entry_name(entry.path()?.to_string_lossy().to_string(), normalize_paths),
+            size: entry.header().size()?,
+            is_dir: entry.header().entry_type().is_dir(),
+            metadata: None,
+            method: method.to_string(),
+            encrypted: false,
+        });
+    }
+    let has_more = entries.len() > limit;
+    entries.truncate(limit);
+    Ok(ArchivePage { entries, total: None, has_more })
+}
+
+// Decompress at most `limit` bytes of one entry (named as in list_archive_contents)
+// into `writer`, without touching the disk
+pub fn read_entry<W: Write>(file_path: &Path, entry_name: &str, limit: u64, zstd_dictionary: Option<&Path>, writer: &mut W) -> Result<u64> {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    
+    if file_name.ends_with(".zip") {
+        // A spanned zip's last disk points into the others, so read the joined set
+        let stitched = stitch_spanned_zip(file_path, &std::env::temp_dir())?;
+        let file_path = stitched.as_ref().map_or(file_path, |temp_file| temp_file.path());
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+        // Listed names have forward slashes even where the archive has backslashes
+        let entry_name = entry_name.replace('\\', "/");
+        let index = (0..archive.len())
+            .find(|&i| archive.by_index_raw(i).map_or(false, |file| zip_entry_name(&file) == entry_name))
+            .ok_or_else(|| anyhow::anyhow!("No entry named '{}' in {}", entry_name, file_name))?;
+        let entry = archive.by_index(index)?;
+        return Ok(std::io::copy(&mut entry.take(limit), writer)?);
+    }
+    
+    let reader = open_tar_reader(file_path, zstd_dictionary)?;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        // Matches the name as listed with or without normalize_paths
+        let name = entry.path()?.to_string_lossy().to_string();
+        if name == entry_name || tar_entry_name(name, true) == entry_name {
+            return Ok(std::io::copy(&mut entry.take(limit), writer)?);
+        }
+    }
+    Err(anyhow::anyhow!("No entry named '{}' in {}", entry_name, file_name))
+}
+
+// The first PREVIEW_LIMIT bytes of a text entry. Binary entries are an error, so the
+// caller can fall back to a hex view.
+pub fn read_entry_text(file_path: &Path, entry_name: &str, zstd_dictionary: Option<&Path>) -> Result<EntryText> {
+    // One byte past the limit tells whether there was more
+    let mut data = Vec::new();
+    read_entry(file_path, entry_name, PREVIEW_LIMIT + 1, zstd_dictionary, &mut data)?;
+    let truncated = data.len() as u64 > PREVIEW_LIMIT;
+    data.truncate(PREVIEW_LIMIT as usize);
+    
+    // Same heuristic as text_line_ending: a NUL byte in the first 8000 bytes means binary
+    if data.iter().take(8000).any(|&byte| byte == 0) {
+        return Err(anyhow::anyhow!("'{}' is a binary file", entry_name));
+    }
+    
+    let text = match String::from_utf8(data) {
+        Ok(text) => text,
+        // The cut at the limit can land inside a multi-byte character
+        Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+            let valid_len = e.utf8_error().valid_up_to();
+            let mut data = e.into_bytes();
+            data.truncate(valid_len);
+            String::from_utf8(data)?
+        },
+        Err(_) => return Err(anyhow::anyhow!("'{}' is not UTF-8 text", entry_name)),
+    };
+    Ok(EntryText { text, truncated })
+}
+
+// Result of verify_against_manifest. `mismatched` holds "path: reason" lines.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ManifestReport {
+    // Listed in the manifest but not in the archive
+    pub missing: Vec<String>,
+    // In the archive but not listed
+    pub extra: Vec<String>,
+    pub mismatched: Vec<String>,
+}
+
+impl ManifestReport {
+    pub fn is_match(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+// Check a zip or tar archive against a JSON manifest (as written by emit_manifest, but
+// possibly published on its own): every listed file must be in the archive with the
+// same size and, when the manifest has one, the same SHA-256. Folders aren't compared.
+pub fn verify_against_manifest(file_path: &Path, manifest_path: &Path, zstd_dictionary: Option<&Path>) -> Result<ManifestReport> {
+    let manifest: Vec<ManifestEntry> = serde_json::from_reader(BufReader::new(File::open(manifest_path)?))
+        .with_context(|| format!("Not a JSON manifest: {}", manifest_path.display()))?;
+    let mut expected: std::collections::BTreeMap<String, ManifestEntry> = manifest.into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    
+    let mut report = ManifestReport::default();
+    let mut check = |name: String, size: u64, reader: &mut dyn Read| -> Result<()> {
+        let Some(listed) = expected.remove(&name) else {
+            report.extra.push(name);
+            return Ok(());
+        };
+        if listed.size != size {
+            report.mismatched.push(format!("{}: size is {}, manifest says {}", name, size, listed.size));
+        } else if let Some(sha256) = &listed.sha256 {
+            let actual = sha256_hex(reader)?;
+            if !actual.eq_ignore_ascii_case(sha256) {
+                report.mismatched.push(format!("{}: SHA-256 is {}, manifest says {}", name, actual, sha256));
+            }
+        }
+        Ok(())
+    };
+    
+    let file_name = file_path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if file_name.ends_with(".zip") {
+        // A spanned zip's last disk points into the others, so read the joined set
+        let stitched = stitch_spanned_zip(file_path, &std::env::temp_dir())?;
+        let file_path = stitched.as_ref().map_or(file_path, |temp_file| temp_file.path());
+        let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if !zip_entry_is_dir(&entry) {
+                check(zip_entry_name(&entry), entry.size(), &mut entry)?;
+            }
+        }
+    } else {
+        let mut archive = tar::Archive::new(open_tar_reader(file_path, zstd_dictionary)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let name = tar_entry_name(entry.path()?.to_string_lossy().to_string(), true);
+                let size = entry.size();
+                check(name, size, &mut entry)?;
+            }
+        }
+    }
+    
+    report.missing = expected.into_keys().collect();
+    Ok(report)
+}
+
+// Open a tar-based archive with the right decompressor in front of it. A .tar.zst
+// compressed with a dictionary needs `zstd_dictionary`.
+fn open_tar_reader(file_path: &Path, zstd_dictionary: Option<&Path>) -> Result<Box<dyn Read>> {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    if !is_tar_name(&normalized_name) {
+        return Err(anyhow::anyhow!("Listing is not supported for: {}", file_name));
+    }
+    
+    check_decode_memory(file_path, &normalized_name, DEFAULT_DECOMPRESS_MEMORY_LIMIT)?;
+    let reader = BufReader::new(File::open(file_path)?);
+    match zstd_dictionary_for(file_path, &normalized_name, zstd_dictionary)? {
+        Some(dictionary) => {
+            let mut decoder = zstd::stream::read::Decoder::with_dictionary(reader, &dictionary)?;
+            decoder.window_log_max(zstd_window_log_max(DEFAULT_DECOMPRESS_MEMORY_LIMIT))?;
+            Ok(Box::new(decoder))
+        }
+        None => tar_decoder(&normalized_name, reader, DEFAULT_DECOMPRESS_MEMORY_LIMIT),
+    }
+}
+
+// Pick the decompressor for a tar-based archive from its normalized name. xz and zstd
+// fail on any stream that needs more than `memory_limit` bytes to decode.
+fn tar_decoder<'a, R: Read + 'a>(normalized_name: &str, reader: R, memory_limit: usize) -> Result<Box<dyn Read + 'a>> {
+    // Every gzip member, as resumable jobs write one per part
+    if normalized_name.ends_with(".tar.gz") {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+    } else if normalized_name.ends_with(".tar.br") {
+        Ok(Box::new(brotli::Decompressor::new(reader, 4096)))
+    } else if normalized_name.ends_with(".tar.bz2") {
+        Ok(Box::new(bzip2::read::BzDecoder::new(reader)))
+    } else if normalized_name.ends_with(".tar.xz") {
+        let stream = xz2::stream::Stream::new_stream_decoder(memory_limit as u64, 0)?;
+        Ok(Box::new(xz2::read::XzDecoder::new_stream(reader, stream)))
+    } else if normalized_name.ends_with(".tar.zst") {
+        let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+        decoder.window_log_max(zstd_window_log_max(memory_limit))?;
+        Ok(Box::new(decoder))
+    } else if normalized_name.ends_with(".tar") {
+        Ok(Box::new(reader))
+    } else {
+        Err(anyhow::anyhow!("Not a tar archive: {}", normalized_name))
+    }
+}
+
+// The stream codecs behind TauZip's formats, for compressing data held in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamCodec {
+    Gzip,
+    Brotli,
+    Bzip2,
+    Zstd,
+}
+
+impl StreamCodec {
+    pub const ALL: [StreamCodec; 4] = [StreamCodec::Gzip, StreamCodec::Brotli, StreamCodec::Bzip2, StreamCodec::Zstd];
+
+    // The codec's own level range, with the default TauZip's formats use
+    fn level(self, level: Option<u32>) -> u32 {
+        let (default, min, max) = match self {
+            StreamCodec::Gzip => (6, 0, 9),
+            StreamCodec::Brotli => (BROTLI_QUALITY, 0, 11),
+            StreamCodec::Bzip2 => (BZIP2_LEVEL, 1, 9),
+            StreamCodec::Zstd => (zstd::DEFAULT_COMPRESSION_LEVEL as u32, 1, 22),
+        };
+        level.map_or(default, |level| level.clamp(min, max))
+    }
+}
+
+// Compress `data` in memory. `level` is clamped to the codec's range; None takes the
+// level TauZip's own formats use.
+pub fn compress_bytes(data: &[u8], codec: StreamCodec, level: Option<u32>) -> Result<Vec<u8>> {
+    let level = codec.level(level);
+    let mut output = Vec::new();
+    match codec {
+        StreamCodec::Gzip => {
+            let mut encoder = GzEncoder::new(&mut output, FlateCompression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()?;
+        }
+        StreamCodec::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, level, BROTLI_LGWIN);
+            encoder.write_all(data)?;
+            // Taking the writer back ends the stream
+            encoder.into_inner();
+        }
+        StreamCodec::Bzip2 => {
+            let mut encoder = bzip2::write::BzEncoder::new(&mut output, bzip2::Compression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()?;
+        }
+        StreamCodec::Zstd => output = zstd::encode_all(data, level as i32)?,
+    }
+    Ok(output)
+}
+
+pub fn decompress_bytes(data: &[u8], codec: StreamCodec) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    match codec {
+        StreamCodec::Gzip => { flate2::read::GzDecoder::new(data).read_to_end(&mut output)?; }
+        StreamCodec::Brotli => { brotli::Decompressor::new(data, 4096).read_to_end(&mut output)?; }
+        StreamCodec::Bzip2 => { bzip2::read::BzDecoder::new(data).read_to_end(&mut output)?; }
+        StreamCodec::Zstd => output = zstd::decode_all(data)?,
+    }
+    Ok(output)
+}
+
+// How one codec did on benchmark_formats' sample. `ratio` is the compressed size over
+// the sample size, so smaller is better.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkResult {
+    pub format: StreamCodec,
+    pub ratio: f64,
+    pub compress_ms: u64,
+    pub decompress_ms: u64,
+}
+
+// Sample read for benchmark_formats, small enough that every codec finishes in a few
+// seconds even at high levels
+const BENCHMARK_SAMPLE_SIZE: usize = 4 * 1024 * 1024;
+// Least taken from each file, so a big selection isn't sampled in slivers
+const BENCHMARK_MIN_SHARE: usize = 64 * 1024;
+
+// Compress a sample of `files` with every stream codec at `level` and time it. The
+// sample takes an equal share from the start of each file until it is full.
+pub fn benchmark_formats(files: &[PathBuf], level: Option<u32>) -> Result<Vec<BenchmarkResult>> {
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(files);
+    let options = CompressOptions::default();
+    let entries: Vec<WalkEntry> = walk_sources(&sources, &options, &CompressContext::new(&options)?)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && !entry.special && entry.size > 0)
+        .collect();
+    if entries.is_empty() {
+        return Err(anyhow::anyhow!("No file contents to benchmark"));
+    }
+    
+    let share = (BENCHMARK_SAMPLE_SIZE / entries.len()).max(BENCHMARK_MIN_SHARE);
+    let mut sample = Vec::with_capacity(BENCHMARK_SAMPLE_SIZE);
+    for entry in &entries {
+        let room = BENCHMARK_SAMPLE_SIZE - sample.len();
+        if room == 0 {
+            break;
+        }
+        File::open(&entry.path)?.take(share.min(room) as u64).read_to_end(&mut sample)?;
+    }
+    
+    StreamCodec::ALL.iter().map(|codec| {
+        let started = std::time::Instant::now();
+        let compressed = compress_bytes(&sample, *codec, level)?;
+        let compress_ms = started.elapsed().as_millis() as u64;
+        let started = std::time::Instant::now();
+        let restored = decompress_bytes(&compressed, *codec)?;
+        let decompress_ms = started.elapsed().as_millis() as u64;
+        if restored != sample {
+            return Err(anyhow::anyhow!("{:?} did not restore the sample", codec));
+        }
+        Ok(BenchmarkResult {
+            format: *codec,
+            ratio: compressed.len() as f64 / sample.len().max(1) as f64,
+            compress_ms,
+            decompress_ms,
+        })
+    }).collect()
+}
+
+// Largest zstd window that fits in `memory_limit`, within what zstd accepts
+fn zstd_window_log_max(memory_limit: usize) -> u32 {
+    (usize::BITS - 1 - memory_limit.max(1).leading_zeros()).clamp(10, 31)
+}
+
+// Fail straight away, with the sizes in the message, when the first xz block or zstd
+// frame of a tarball needs more memory than allowed. Later blocks and frames are held
+// to the same limit by the decoders themselves.
+fn check_decode_memory(file_path: &Path, normalized_name: &str, memory_limit: usize) -> Result<()> {
+    let needed = if normalized_name.ends_with(".tar.zst") {
+        let mut header = Vec::with_capacity(18);
+        File::open(file_path)?.take(18).read_to_end(&mut header)?;
+        zstd_frame_window_size(&header)
+    } else if normalized_name.ends_with(".tar.xz") {
+        let mut header = Vec::with_capacity(XZ_HEADERS_SIZE);
+        File::open(file_path)?.take(XZ_HEADERS_SIZE as u64).read_to_end(&mut header)?;
+        xz_dictionary_size(&header)
+    } else {
+        None
+    };
+    match needed {
+        Some(needed) if needed > memory_limit as u64 => Err(anyhow::anyhow!(
+            "Decompressing this archive needs {} MB of memory, more than the limit of {} MB (decompress_memory_limit)",
+            needed.div_ceil(1 << 20), memory_limit >> 20
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Window size the zstd frame at the start of `header` asks for
+fn zstd_frame_window_size(header: &[u8]) -> Option<u64> {
+    if header.len() < 6 || u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != ZSTD_FRAME_MAGIC {
+        return None;
+    }
+    let descriptor = header[4];
+    if descriptor & 0x20 == 0 {
+        let window = header[5];
+        let base = 1u64 << ((window >> 3) + 10);
+        return Some(base + base / 8 * (window & 0x07) as u64);
+    }
+    // A single-segment frame is decoded in one piece as large as its content
+    let id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let size_field = header.get(5 + id_size..)?;
+    match descriptor >> 6 {
+        0 => size_field.first().map(|size| *size as u64),
+        1 => size_field.get(..2).map(|size| u16::from_le_bytes([size[0], size[1]]) as u64 + 256),
+        2 => size_field.get(..4).map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as u64),
+        _ => size_field.get(..8).map(|size| u64::from_le_bytes(size.try_into().unwrap_or_default())),
+    }
+}
+
+// Stream header plus the largest block header
+const XZ_HEADERS_SIZE: usize = 12 + 1024;
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FILTER_LZMA2: u64 = 0x21;
+
+// LZMA2 dictionary size of the first block of the xz stream at the start of `header`;
+// the decoder needs about that much memory
+fn xz_dictionary_size(header: &[u8]) -> Option<u64> {
+    if !header.starts_with(XZ_MAGIC) {
+        return None;
+    }
+    let block = header.get(12..)?;
+    // A zero size byte starts the index instead: the stream has no blocks
+    let block_size = (*block.first()? as usize + 1) * 4;
+    let block = block.get(..block_size)?;
+    let flags = block[1];
+    let mut position = 2;
+    let varint = |position: &mut usize| -> Option<u64> {
+        let mut value = 0u64;
+        for shift in 0..9 {
+            let byte = *block.get(*position)?;
+            *position += 1;
+            value |= ((byte & 0x7F) as u64) << (shift * 7);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    };
+    // Optional compressed and uncompressed sizes
+    if flags & 0x40 != 0 {
+        varint(&mut position)?;
+    }
+    if flags & 0x80 != 0 {
+        varint(&mut position)?;
+    }
+    for _ in 0..(flags & 0x03) + 1 {
+        let filter = varint(&mut position)?;
+        let properties_size = varint(&mut position)? as usize;
+        let properties = block.get(position..position + properties_size)?;
+        position += properties_size;
+        if filter == XZ_FILTER_LZMA2 && properties_size == 1 {
+            let bits = properties[0] & 0x3F;
+            if bits > 40 {
+                return None;
+            }
+            if bits == 40 {
+                return Some(u32::MAX as u64);
+            }
+            return Some(((2 | (bits & 1)) as u64) << (bits / 2 + 11));
+        }
+    }
+    None
+}
+
+// zstd dictionaries start with this magic followed by their id; frames with their own
+const ZSTD_DICT_MAGIC: u32 = 0xEC30A437;
+const ZSTD_FRAME_MAGIC: u32 = 0xFD2FB528;
+const DEFAULT_DICTIONARY_SIZE: usize = 112_640;
+
+// Read a trained dictionary, refusing raw content without an id since nothing could
+// then tell which dictionary an archive needs
+fn load_zstd_dictionary(path: &Path) -> Result<Vec<u8>> {
+    let dictionary = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read zstd dictionary '{}': {}", path.display(), e))?;
+    if zstd_dictionary_id(&dictionary) == 0 {
+        return Err(anyhow::anyhow!("'{}' is not a trained zstd dictionary", path.display()));
+    }
+    Ok(dictionary)
+}
+
+// Id of a trained dictionary, 0 when it isn't one
+pub fn zstd_dictionary_id(dictionary: &[u8]) -> u32 {
+    match dictionary {
+        [a, b, c, d, e, f, g, h, ..] if u32::from_le_bytes([*a, *b, *c, *d]) == ZSTD_DICT_MAGIC => u32::from_le_bytes([*e, *f, *g, *h]),
+        _ => 0,
+    }
+}
+
+// Dictionary id named in the header of the zstd frame at the start of `header`, 0 for none
+fn zstd_frame_dictionary_id(header: &[u8]) -> u32 {
+    if header.len() < 5 || u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != ZSTD_FRAME_MAGIC {
+        return 0;
+    }
+    let descriptor = header[4];
+    let id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    // The window descriptor byte is left out of single-segment frames
+    let start = if descriptor & 0x20 != 0 { 5 } else { 6 };
+    match header.get(start..start + id_size) {
+        Some(id) => id.iter().rev().fold(0u32, |value, byte| (value << 8) | *byte as u32),
+        None => 0,
+    }
+}
+
+// The dictionary to decode a .tar.zst with, checked against the one its first frame
+// was compressed with. None when the archive needs no dictionary.
+fn zstd_dictionary_for(file_path: &Path, normalized_name: &str, zstd_dictionary: Option<&Path>) -> Result<Option<Vec<u8>>> {
+    if !normalized_name.ends_with(".tar.zst") {
+        return Ok(None);
+    }
+    let mut header = Vec::with_capacity(10);
+    File::open(file_path)?.take(10).read_to_end(&mut header)?;
+    let required = zstd_frame_dictionary_id(&header);
+    match (zstd_dictionary, required) {
+        (None, 0) => Ok(None),
+        (None, id) => Err(anyhow::anyhow!("This archive was compressed with a zstd dictionary (id {}); choose that dictionary to extract it", id)),
+        (Some(path), id) => {
+            let dictionary = load_zstd_dictionary(path)?;
+            let given = zstd_dictionary_id(&dictionary);
+            if id != 0 && given != id {
+                return Err(anyhow::anyhow!("Wrong zstd dictionary: the archive needs id {}, '{}' has id {}", id, path.display(), given));
+            }
+            Ok(Some(dictionary))
+        }
+    }
+}
+
+// Train a zstd dictionary on the files in `sources` (folders are walked) and write it to
+// `output`, returning its id. Works best with many small samples of the kind of data
+// that will be compressed with it.
+pub fn train_zstd_dictionary(sources: &[PathBuf], output: &Path, max_size: Option<usize>) -> Result<u32> {
+    let sources: Vec<(PathBuf, PathBuf)> = source_names(sources);
+    let options = CompressOptions::default();
+    let samples: Vec<PathBuf> = walk_sources(&sources, &options, &CompressContext::new(&options)?)?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && !entry.special)
+        .map(|entry| entry.path)
+        .collect();
+    if samples.is_empty() {
+        return Err(anyhow::anyhow!("No files to train a dictionary on"));
+    }
+    let dictionary = zstd::dict::from_files(&samples, max_size.unwrap_or(DEFAULT_DICTIONARY_SIZE))
+        .map_err(|e| anyhow::anyhow!("Failed to train zstd dictionary: {}", e))?;
+    std::fs::write(output, &dictionary)?;
+    Ok(zstd_dictionary_id(&dictionary))
+}
+
+// Map the short tarball extensions onto their long forms, e.g. `foo.tgz` -> `foo.tar.gz`
+fn normalize_archive_name(file_name: &str) -> String {
+    let lower = file_name.to_lowercase();
+    let short_forms = [
+        (".tgz", ".tar.gz"),
+        (".tbz2", ".tar.bz2"),
+        (".tbz", ".tar.bz2"),
+        (".txz", ".tar.xz"),
+        (".tzst", ".tar.zst"),
+    ];
+    for (short, long) in short_forms {
+        if let Some(stem) = lower.strip_suffix(short) {
+            return format!("{}{}", stem, long);
+        }
+    }
+    lower
+}
+
+fn is_tar_name(normalized_name: &str) -> bool {
+    [".tar", ".tar.gz", ".tar.br", ".tar.bz2", ".tar.xz", ".tar.zst"]
+        .iter()
+        .any(|ext| normalized_name.ends_with(ext))
+}
+
+fn is_cpio_name(normalized_name: &str) -> bool {
+    normalized_name.ends_with(".cpio") || normalized_name.ends_with(".cpio.gz")
+}
+
+pub fn is_compressed_file(path: &Path) -> bool {
+    let file_name = path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+
+    let normalized_name = normalize_archive_name(file_name);
+    if is_tar_name(&normalized_name) || is_cpio_name(&normalized_name) {
+        return true;
+    }
+
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    matches!(extension, "zip" | "gz" | "br" | "gzip" | "bzip2" | "bz2" | "rar" | "7z" | "iso" | "tzchunks")
+        || is_zip_disk_part(extension)
+        || codec::custom_codec_for_file(path).is_some()
+}
+
+// The extension of a spanned zip's disks before the last: z01, z02, ...
+fn is_zip_disk_part(extension: &str) -> bool {
+    extension.len() >= 3
+        && extension.starts_with(['z', 'Z'])
+        && extension[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+// A selection of archives with each one once. Every disk of a spanned zip stands for its
+// .zip, which extracts the whole set, so picking all of them still extracts it once.
+pub fn distinct_archives(files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    files.iter()
+        .map(|file| match file.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if is_zip_disk_part(ext) => file.with_extension("zip"),
+            _ => file.clone(),
+        })
+        .filter(|file| seen.insert(file.clone()))
+        .collect()
+}
+
+// Identify a supported archive by its magic bytes, without decompressing anything.
+// Single-stream formats are reported as tarballs when the name says so (.tar.gz, .tgz, ...);
+// brotli has no magic number, so it is the one format recognized by extension alone.
+pub fn sniff_format(path: &Path) -> Result<Option<&'static str>> {
+    let mut file = File::open(path)?;
+    let mut header = Vec::new();
+    (&mut file).take(512).read_to_end(&mut header)?;
+    
+    let file_name = path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    let is_tar = is_tar_name(&normalized_name);
+    
+    let format = if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+        Some("zip")
+    } else if header.starts_with(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C]) {
+        Some("7z")
+    } else if header.starts_with(b"Rar!\x1A\x07") {
+        if cfg!(feature = "rar-support") { Some("rar") } else { None }
+    } else if header.starts_with(&[0x1F, 0x8B]) {
+        Some(if is_tar { "tar.gz" } else if normalized_name.ends_with(".cpio.gz") { "cpio.gz" } else { "gzip" })
+    } else if header.starts_with(b"BZh") {
+        Some(if is_tar { "tar.bz2" } else { "bzip2" })
+    } else if header.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+        // Only tarballs are extracted from xz and zstd streams
+        is_tar.then_some("tar.xz")
+    } else if header.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        is_tar.then_some("tar.zst")
+    } else if header.starts_with(b"070701") || header.starts_with(b"070702") {
+        Some("cpio")
+    } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+        Some("tar")
+    } else if crate::iso::has_iso_signature(&mut file) {
+        Some("iso")
+    } else if normalized_name.ends_with(".br") {
+        Some(if is_tar { "tar.br" } else { "brotli" })
+    } else {
+        None
+    };
+    
+    Ok(format)
+}
+
+// Rewrite an archive at the highest level its format offers, next to the original as
+// "name (optimized).ext". Zip entries keep their names, timestamps and permissions;
+// gzip keeps its header name and timestamp.
+pub fn optimize_archive(file_path: &Path) -> Result<OptimizeResult> {
+    let file_name = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let normalized_name = normalize_archive_name(file_name);
+    let parent = file_path.parent().unwrap_or_else(|| Path::new("."));
+    
+    // Keep compound extensions like .tar.gz together
+    let split_at = file_name.to_lowercase().find(".tar.")
+        .or_else(|| file_name.rfind('.'))
+        .unwrap_or(file_name.len());
+    let (stem, extension) = file_name.split_at(split_at);
+    let output_path = get_unique_name(&parent.join(format!("{} (optimized){}", stem, extension)));
+    
+    let (temp_file, output) = TempFile::new_in(parent, ".tauzip-optimize-")?;
+    if normalized_name.ends_with(".zip") {
+        optimize_zip(file_path, output)?;
+    } else if normalized_name.ends_with(".gz") || normalized_name.ends_with(".gzip") {
+        optimize_gz(file_path, output)?;
+    } else if normalized_name.ends_with(".br") {
+        let mut decoder = brotli::Decompressor::new(BufReader::new(File::open(file_path)?), 4096);
+        let mut encoder = brotli::CompressorWriter::new(BufWriter::new(output), 4096, 11, 22);
+        std::io::copy(&mut decoder, &mut encoder)?;
+        encoder.into_inner().flush()?;
+    } else {
+        return Err(anyhow::anyhow!("Optimizing is not supported for: {}", file_name));
+    }
+    temp_file.persist(&output_path)?;
+    
+    Ok(OptimizeResult {
+        original_size: std::fs::metadata(file_path)?.len(),
+        optimized_size: std::fs::metadata(&output_path)?.len(),
+        output_path,
+    })
+}
+
+fn optimize_zip(file_path: &Path, output: File) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(file_path)?))?;
+    let mut zip = ZipWriter::new(BufWriter::new(output));
+    
+    for index in 0..archive.len() {
+        // Encrypted entries can't be decoded without the password, so they are copied as-is
+        let needs_password = matches!(
+            archive.by_index(index),
+            Err(zip::result::ZipError::UnsupportedArchive(msg)) if msg == zip::result::ZipError::PASSWORD_REQUIRED
+        );
+        if needs_password {
+            zip.raw_copy_file(archive.by_index_raw(index)?)?;
+            continue;
+        }
+        
+        let mut file = archive.by_index(index)?;
+        // Rewritten with the separator the spec asks for
+        let name = zip_entry_name(&file);
+        let mut options = FileOptions::default()
+            .last_modified_time(file.last_modified())
+            .large_file(file.size() > u32::MAX as u64);
+        if let Some(mode) = file.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+        
+        if zip_entry_is_dir(&file) {
+            zip.add_directory(name, options)?;
+            continue;
+        }
+        
+        // Stored entries are often stored because they don't compress (media, archives);
+        // a sample tells whether deflating them is worth it
+        let mut sample = Vec::new();
+        (&mut file).take(64 * 1024).read_to_end(&mut sample)?;
+        if file.compression() == zip::CompressionMethod::Stored && !compresses_well(&sample)? {
+            drop(file);
+            zip.raw_copy_file(archive.by_index_raw(index)?)?;
+            continue;
+        }
+        
+        let options = options
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(9));
+        zip.start_file(name, options)?;
+        zip.write_all(&sample)?;
+        std::io::copy(&mut file, &mut zip)?;
+    }
+    
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn optimize_gz(file_path: &Path, output: File) -> Result<()> {
+    let mut decoder = flate2::read::GzDecoder::new(BufReader::new(File::open(file_path)?));
+    
+    let mut builder = GzBuilder::new();
+    if let Some(header) = decoder.header() {
+        builder = builder.mtime(header.mtime());
+        if let Some(filename) = header.filename() {
+            builder = builder.filename(filename);
+        }
+    }
+    
+    let mut encoder = builder.write(BufWriter::new(output), FlateCompression::best());
+    std::io::copy(&mut decoder, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+// Write a copy of a zip whose central directory is damaged or missing, with a new one
+// built from the local headers, so any tool can open it. Entry data is copied byte for
+// byte. The input is walked header by header; anything that doesn't parse as an entry
+// is skipped up to the next local header signature. An entry with its sizes in a data
+// descriptor is measured by inflating it (deflated, unencrypted) or by finding a
+// descriptor whose size matches. Entries cut off by the end of the file are left out.
+// Without `output_path` the copy goes beside the original as "name (repaired).zip".
+pub fn rebuild_central_directory(file_path: &Path, output_path: Option<&Path>) -> Result<RebuildResult> {
+    let output_path = match output_path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+            get_unique_name(&file_path.with_file_name(format!("{} (repaired).zip", stem)))
+        }
+    };
+    check_output_not_source(&[file_path.to_path_buf()], &output_path)?;
+    
+    let mut file = BufReader::new(File::open(file_path)?);
+    let file_len = file.seek(std::io::SeekFrom::End(0))?;
+    let entries = scan_local_headers(&mut file, file_len)?;
+    if entries.is_empty() {
+        return Err(CompressionError::Corrupt { reason: "no zip entries were found".to_string() }.into());
+    }
+    
+    let parent = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let (temp_file, output) = TempFile::new_in(parent, ".tauzip-rebuild-")?;
+    let mut writer = BufWriter::new(output);
+    let mut offset = 0u64;
+    let mut central = Vec::new();
+    for entry in &entries {
+        file.seek(std::io::SeekFrom::Start(entry.header_offset))?;
+        let len = entry.header.len() as u64 + entry.compressed_size + entry.descriptor_len;
+        if std::io::copy(&mut (&mut file).take(len), &mut writer)? != len {
+            return Err(anyhow::anyhow!("{} changed while it was being repaired", file_path.display()));
+        }
+        central.extend_from_slice(&rebuilt_central_header(entry, offset, None)?);
+        offset += len;
+    }
+    writer.write_all(&central)?;
+    write_end_of_directory(&mut writer, entries.len() as u64, central.len() as u64, offset)?;
+    writer.flush()?;
+    drop(writer);
+    temp_file.persist(&output_path)?;
+    
+    Ok(RebuildResult { output_path, entries_recovered: entries.len() })
+}
+
+const LOCAL_HEADER_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+const DATA_DESCRIPTOR_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x07, 0x08];
+const ZIP64_FIELD: u16 = 0x0001;
+
+// An entry found by rebuild_central_directory
+struct LocalEntry {
+    header_offset: u64,
+    // The local header with its name and extra field
+    header: Vec<u8>,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    // Bytes of data descriptor after the data
+    descriptor_len: u64,
+}
+
+fn scan_local_headers(file: &mut BufReader<File>, file_len: u64) -> Result<Vec<LocalEntry>> {
+    let mut entries = Vec::new();
+    let mut pos = 0u64;
+    while pos + 30 <= file_len {
+        file.seek(std::io::SeekFrom::Start(pos))?;
+        let mut fixed = [0u8; 30];
+        file.read_exact(&mut fixed)?;
+        if fixed[..4] == LOCAL_HEADER_SIGNATURE {
+            if let Some(entry) = read_local_entry(file, pos, &fixed, file_len)? {
+                pos = entry.header_offset + entry.header.len() as u64 + entry.compressed_size + entry.descriptor_len;
+                entries.push(entry);
+                continue;
+            }
+        }
+        match find_signature(file, pos + 1, file_len, &LOCAL_HEADER_SIGNATURE)? {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+    Ok(entries)
+}
+
+fn read_local_entry(file: &mut BufReader<File>, pos: u64, fixed: &[u8; 30], file_len: u64) -> Result<Option<LocalEntry>> {
+    let flags = u16_at(fixed, 6);
+    let method = u16_at(fixed, 8);
+    let name_len = u16_at(fixed, 26) as usize;
+    let extra_len = u16_at(fixed, 28) as usize;
+    let data_offset = pos + 30 + (name_len + extra_len) as u64;
+    if name_len == 0 || data_offset > file_len {
+        return Ok(None);
+    }
+    let mut header = fixed.to_vec();
+    header.resize(30 + name_len + extra_len, 0);
+    file.read_exact(&mut header[30..])?;
+    
+    let mut crc = u32_at(fixed, 14);
+    let mut compressed_size = u32_at(fixed, 18) as u64;
+    let mut size = u32_at(fixed, 22) as u64;
+    // The local Zip64 field holds both sizes, uncompressed first
+    let zip64 = read_entry_metadata(&header[30 + name_len..], ZIP64_FIELD).filter(|data| data.len() >= 16);
+    if let Some(data) = zip64 {
+        if size == 0xFFFF_FFFF {
+            size = u64_at(data, 0);
+        }
+        if compressed_size == 0xFFFF_FFFF {
+            compressed_size = u64_at(data, 8);
+        }
+    }
+    
+    let mut descriptor_len = 0;
+    // Bit 3: the sizes and CRC follow the data in a data descriptor
+    if flags & 0x08 != 0 {
+        let inflated = if method == 8 && flags & 0x01 == 0 { inflate_extent(file, data_offset)? } else { None };
+        let measured = match inflated {
+            Some(measured) => Some(measured),
+            None => find_data_descriptor(file, data_offset, file_len, zip64.is_some())?,
+        };
+        let Some((measured_size, measured_crc, measured_uncompressed)) = measured else { return Ok(None) };
+        let Some((len, descriptor_crc, _, _)) = read_data_descriptor(file, data_offset + measured_size, file_len, zip64.is_some())? else {
+            return Ok(None);
+        };
+        compressed_size = measured_size;
+        // Inflating gives the real CRC and size; otherwise the descriptor's are all there is
+        crc = measured_crc.unwrap_or(descriptor_crc);
+        size = measured_uncompressed;
+        descriptor_len = len;
+    }
+    if data_offset + compressed_size + descriptor_len > file_len {
+        return Ok(None);
+    }
+    Ok(Some(LocalEntry { header_offset: pos, header, crc, compressed_size, size, descriptor_len }))
+}
+
+// Inflate a deflated entry to find where its data ends. Returns the compressed size,
+// the CRC and the uncompressed size, or None when the data doesn't inflate.
+fn inflate_extent(file: &mut BufReader<File>, data_offset: u64) -> Result<Option<(u64, Option<u32>, u64)>> {
+    file.seek(std::io::SeekFrom::Start(data_offset))?;
+    let mut inflater = flate2::Decompress::new(false);
+    let mut crc = flate2::Crc::new();
+    let mut input = vec![0u8; 64 * 1024];
+    let mut output = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut input)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let mut at = 0;
+        loop {
+            let (before_in, before_out) = (inflater.total_in(), inflater.total_out());
+            let Ok(status) = inflater.decompress(&input[at..read], &mut output, flate2::FlushDecompress::None) else {
+                return Ok(None);
+            };
+            let consumed = (inflater.total_in() - before_in) as usize;
+            let produced = (inflater.total_out() - before_out) as usize;
+            crc.update(&output[..produced]);
+            at += consumed;
+            if status == flate2::Status::StreamEnd {
+                return Ok(Some((inflater.total_in(), Some(crc.sum()), inflater.total_out())));
+            }
+            if at == read && produced < output.len() {
+                break;
+            }
+            if consumed == 0 && produced == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+// Look for a signed data descriptor whose compressed size is its distance from the
+// start of the data
+fn find_data_descriptor(file: &mut BufReader<File>, data_offset: u64, file_len: u64, zip64: bool) -> Result<Option<(u64, Option<u32>, u64)>> {
+    let mut from = data_offset;
+    while let Some(at) = find_signature(file, from, file_len, &DATA_DESCRIPTOR_SIGNATURE)? {
+        if let Some((_, _, compressed_size, size)) = read_data_descriptor(file, at, file_len, zip64)? {
+            if compressed_size == at - data_offset {
+                return Ok(Some((compressed_size, None, size)));
+            }
+        }
+        from = at + 1;
+    }
+    Ok(None)
+}
+
+// The data descriptor at `at`, with or without its signature: its length, CRC,
+// compressed size and uncompressed size
+fn read_data_descriptor(file: &mut BufReader<File>, at: u64, file_len: u64, zip64: bool) -> Result<Option<(u64, u32, u64, u64)>> {
+    let mut data = vec![0u8; file_len.saturating_sub(at).min(24) as usize];
+    file.seek(std::io::SeekFrom::Start(at))?;
+    file.read_exact(&mut data)?;
+    let signed = data.starts_with(&DATA_DESCRIPTOR_SIGNATURE);
+    let start = if signed { 4 } else { 0 };
+    let len = start + if zip64 { 20 } else { 12 };
+    if data.len() < len {
+        return Ok(None);
+    }
+    let fields = &data[start..];
+    let (compressed_size, size) = if zip64 {
+        (u64_at(fields, 4), u64_at(fields, 12))
+    } else {
+        (u32_at(fields, 4) as u64, u32_at(fields, 8) as u64)
+    };
+    Ok(Some((len as u64, u32_at(fields, 0), compressed_size, size)))
+}
+
+// Offset of the next `signature` at or after `from`
+fn find_signature(file: &mut BufReader<File>, from: u64, file_len: u64, signature: &[u8; 4]) -> Result<Option<u64>> {
+    let mut window = vec![0u8; 64 * 1024];
+    let mut start = from;
+    while start + 4 <= file_len {
+        let len = (file_len - start).min(window.len() as u64) as usize;
+        file.seek(std::io::SeekFrom::Start(start))?;
+        file.read_exact(&mut window[..len])?;
+        if let Some(found) = window[..len].windows(4).position(|bytes| bytes == signature) {
+            return Ok(Some(start + found as u64));
+        }
+        // Overlap by three bytes so a signature across the boundary is still seen
+        start += len as u64 - 3;
+    }
+    Ok(None)
+}
+
+// Central directory header for a recovered entry written at `offset`. Values too
+// large for the 32-bit fields go into a fresh Zip64 field, which replaces the local one.
+// The version made by and the file attributes come from `host`, the entry's old
+// central header, when there is one.
+fn rebuilt_central_header(entry: &LocalEntry, offset: u64, host: Option<&[u8]>) -> Result<Vec<u8>> {
+    let header = &entry.header;
+    let name_len = u16_at(header, 26) as usize;
+    let name = &header[30..30 + name_len];
+    
+    let mut zip64 = Vec::new();
+    let mut field = |value: u64| -> u32 {
+        if value >= 0xFFFF_FFFF {
+            zip64.extend_from_slice(&value.to_le_bytes());
+            0xFFFF_FFFF
+        } else {
+            value as u32
+        }
+    };
+    let size = field(entry.size);
+    let compressed_size = field(entry.compressed_size);
+    let offset = field(offset);
+    
+    let mut extra = Vec::new();
+    if !zip64.is_empty() {
+        write_extra_field(&mut extra, ZIP64_FIELD, &zip64)?;
+    }
+    let mut rest = &header[30 + name_len..];
+    while rest.len() >= 4 {
+        let len = 4 + u16_at(rest, 2) as usize;
+        let Some(block) = rest.get(..len) else { break };
+        if u16_at(block, 0) != ZIP64_FIELD {
+            extra.extend_from_slice(block);
+        }
+        rest = &rest[len..];
+    }
+    
+    let version_needed = if zip64.is_empty() { u16_at(header, 4) } else { u16_at(header, 4).max(45) };
+    let (version_made_by, attributes) = match host {
+        // Internal and external attributes
+        Some(host) => (u16_at(host, 4), host[36..42].to_vec()),
+        // No host attributes survive in the local header; folders get the DOS directory bit
+        None => {
+            let is_dir = name.ends_with(b"/") || name.ends_with(b"\\");
+            let external: u32 = if is_dir { 0x10 } else { 0 };
+            (version_needed.max(20), [&[0u8; 2][..], &external.to_le_bytes()].concat())
+        }
+    };
+    let mut central = Vec::with_capacity(46 + name_len + extra.len());
+    central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+    central.extend_from_slice(&version_made_by.to_le_bytes());
+    central.extend_from_slice(&version_needed.to_le_bytes());
+    central.extend_from_slice(&header[6..14]);
+    central.extend_from_slice(&entry.crc.to_le_bytes());
+    central.extend_from_slice(&compressed_size.to_le_bytes());
+    central.extend_from_slice(&size.to_le_bytes());
+    central.extend_from_slice(&(name_len as u16).to_le_bytes());
+    central.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    // Comment length and disk number
+    central.extend_from_slice(&[0u8; 4]);
+    central.extend_from_slice(&attributes);
+    central.extend_from_slice(&offset.to_le_bytes());
+    central.extend_from_slice(name);
+    central.extend_from_slice(&extra);
+    Ok(central)
+}
+
+// End of central directory record, preceded by the Zip64 record and locator when a
+// count or offset doesn't fit the classic one
+fn write_end_of_directory<W: Write>(writer: &mut W, entries: u64, directory_len: u64, directory_offset: u64) -> Result<()> {
+    let needs_zip64 = entries >= 0xFFFF || directory_len >= 0xFFFF_FFFF || directory_offset >= 0xFFFF_FFFF;
+    if needs_zip64 {
+        let record_offset = directory_offset + directory_len;
+        writer.write_all(&0x06064b50u32.to_le_bytes())?;
+        writer.write_all(&44u64.to_le_bytes())?;
+        writer.write_all(&45u16.to_le_bytes())?;
+        writer.write_all(&45u16.to_le_bytes())?;
+        writer.write_all(&[0u8; 8])?;
+        writer.write_all(&entries.to_le_bytes())?;
+        writer.write_all(&entries.to_le_bytes())?;
+        writer.write_all(&directory_len.to_le_bytes())?;
+        writer.write_all(&directory_offset.to_le_bytes())?;
+        
+        writer.write_all(&0x07064b50u32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&record_offset.to_le_bytes())?;
+        writer.write_all(&1u32.to_le_bytes())?;
+    }
+    let entries16 = entries.min(0xFFFF) as u16;
+    writer.write_all(&0x06054b50u32.to_le_bytes())?;
+    writer.write_all(&[0u8; 4])?;
+    writer.write_all(&entries16.to_le_bytes())?;
+    writer.write_all(&entries16.to_le_bytes())?;
+    writer.write_all(&(directory_len.min(0xFFFF_FFFF) as u32).to_le_bytes())?;
+    writer.write_all(&(directory_offset.min(0xFFFF_FFFF) as u32).to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    Ok(())
+}
+
+// Replace a file with a compressed copy of itself, e.g. "big.log" with "big.log.gz", to
+// save space. The copy is written beside the file under a scratch name and read back;
+// only when what it decodes to hashes the same as the file is it renamed into place and
+// the original deleted. Any failure up to then leaves the original as it was, and if
+// the original can't be deleted the copy is removed again.
+pub async fn compress_replace<F>(
+    file_path: &Path,
+    compression_type: CompressionType,
+    options: &CompressOptions,
+    progress_callback: F,
+) -> Result<ReplaceResult>
+where
+    F: FnMut(f64, String) + Send,
+{
+    if !std::fs::symlink_metadata(file_path)?.is_file() {
+        return Err(anyhow::anyhow!("Only a regular file can be replaced: {}", file_path.display()));
+    }
+    // Formats whose output can be read back here to check it
+    if !matches!(compression_type,
+        CompressionType::Gz | CompressionType::Gzip | CompressionType::Br | CompressionType::Bzip2
+        | CompressionType::Zip | CompressionType::TarGz | CompressionType::TarZst) {
+        return Err(CompressionError::Unsupported {
+            feature: format!("replacing a file with {:?}", compression_type),
+        }.into());
+    }
+    
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let parent = file_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let extension = compression_type.extension();
+    let output_path = get_unique_name(&parent.join(format!("{}{}", file_name, extension)));
+    // Keeps the extension, which read_entry goes by
+    let scratch_path = get_unique_name(&parent.join(format!("{}replace-{}{}", SCRATCH_PREFIX, file_name, extension)));
+    
+    let replaced = replace_with_compressed(file_path, &file_name, &scratch_path, &output_path, compression_type, options, progress_callback).await;
+    if replaced.is_err() {
+        let _ = std::fs::remove_file(&scratch_path);
+    }
+    replaced
+}
+
+async fn replace_with_compressed<F>(
+    file_path: &Path,
+    file_name: &str,
+    scratch_path: &Path,
+    output_path: &Path,
+    compression_type: CompressionType,
+    options: &CompressOptions,
+    progress_callback: F,
+) -> Result<ReplaceResult>
+where
+    F: FnMut(f64, String) + Send,
+{
+    let options = &CompressOptions { durable: Some(true), ..options.clone() };
+    compress_files_with_progress(&[file_path.to_path_buf()], scratch_path, compression_type.clone(), options, progress_callback).await?;
+    
+    // Hashed after compressing, so a file that changed meanwhile doesn't match either
+    options.enter_phase(OperationPhase::Verifying);
+    let original = sha256_hex(BufReader::new(File::open(file_path)?))?;
+    let decoded = decoded_sha256(scratch_path, &compression_type, file_name)
+        .map_err(|e| CompressionError::Corrupt { reason: format!("the compressed copy could not be read back: {}", e) })?;
+    if decoded != original {
+        return Err(CompressionError::Corrupt {
+            reason: format!("the compressed copy of '{}' does not match it; the original was kept", file_name),
+        }.into());
+    }
+    
+    options.enter_phase(OperationPhase::Finalizing);
+    let original_size = std::fs::metadata(file_path)?.len();
+    std::fs::rename(scratch_path, output_path)?;
+    if let Err(e) = std::fs::remove_file(file_path) {
+        let _ = std::fs::remove_file(output_path);
+        return Err(anyhow::anyhow!("Failed to remove the original, so the compressed copy was removed instead: {}", e));
+    }
+    let parent = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    sync_dir(parent)?;
+    
+    Ok(ReplaceResult {
+        output_path: output_path.to_path_buf(),
+        original_size,
+        compressed_size: std::fs::metadata(output_path)?.len(),
+    })
+}
+
+// SHA-256 of what compress_replace's output decodes to
+fn decoded_sha256(archive_path: &Path, compression_type: &CompressionType, entry_name: &str) -> Result<String> {
+    let reader = BufReader::new(File::open(archive_path)?);
+    match compression_type {
+        CompressionType::Gz | CompressionType::Gzip => sha256_hex(flate2::read::MultiGzDecoder::new(reader)),
+        CompressionType::Br => sha256_hex(brotli::Decompressor::new(reader, 4096)),
+        CompressionType::Bzip2 => sha256_hex(bzip2::read::BzDecoder::new(reader)),
+        _ => {
+            use sha2::{Digest, Sha256};
+            let mut hasher = Sha256::new();
+            read_entry(archive_path, entry_name, u64::MAX, None, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        },
+    }
+}
+
+fn compresses_well(sample: &[u8]) -> Result<bool> {
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), FlateCompression::fast());
+    encoder.write_all(sample)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() as f64) < sample.len() as f64 * 0.9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_file(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn entry_names(archive: &Path) -> Vec<String> {
+        list_archive_contents(archive, None, false, None).unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn selected_folders_keep_their_own_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("work").join("docs");
+        let images = dir.path().join("home").join("images");
+        write_file(&docs.join("a.txt"), b"docs");
+        write_file(&images.join("b.txt"), b"images");
+        
+        for compression_type in [CompressionType::Zip, CompressionType::TarGz] {
+            let output = dir.path().join(format!("out{}", compression_type.extension()));
+            compress_files(&[docs.clone(), images.clone()], &output, compression_type).await.unwrap();
+            let names = entry_names(&output);
+            assert!(names.iter().any(|name| name == "docs/a.txt"), "{:?}", names);
+            assert!(names.iter().any(|name| name == "images/b.txt"), "{:?}", names);
+        }
+    }
+
+    #[tokio::test]
+    async fn same_named_folders_are_numbered() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("one").join("docs");
+        let second = dir.path().join("two").join("docs");
+        write_file(&first.join("a.txt"), b"first");
+        write_file(&second.join("a.txt"), b"second");
+        
+        for compression_type in [CompressionType::Zip, CompressionType::TarGz] {
+            let output = dir.path().join(format!("out{}", compression_type.extension()));
+            compress_files(&[first.clone(), second.clone()], &output, compression_type).await.unwrap();
+            let names = entry_names(&output);
+            assert!(names.iter().any(|name| name == "docs/a.txt"), "{:?}", names);
+            assert!(names.iter().any(|name| name == "docs (2)/a.txt"), "{:?}", names);
+        }
+    }
+
+    #[tokio::test]
+    async fn zstd_dictionary_archives_list_read_and_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("logs");
+        for i in 0..300 {
+            let line = format!("{{\"level\":\"info\",\"service\":\"telemetry\",\"event\":\"heartbeat\",\"seq\":{},\"host\":\"node-{}\"}}\n", i, i % 7);
+            write_file(&logs.join(format!("{}.json", i)), line.repeat(3).as_bytes());
+        }
+        let dictionary = dir.path().join("logs.dict");
+        assert_ne!(train_zstd_dictionary(&[logs.clone()], &dictionary, Some(4096)).unwrap(), 0);
+        
+        let output = dir.path().join("logs.tar.zst");
+        let options = CompressOptions { zstd_dictionary: Some(dictionary.clone()), ..CompressOptions::default() };
+        compress_files_with_progress(&[logs.clone()], &output, CompressionType::TarZst, &options, |_, _| {}).await.unwrap();
+        
+        assert!(list_archive_contents(&output, None, true, None).is_err());
+        let names: Vec<String> = list_archive_contents(&output, None, true, Some(&dictionary)).unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(names.iter().any(|name| name == "logs/7.json"), "{:?}", names);
+        
+        let mut data = Vec::new();
+        read_entry(&output, "logs/7.json", u64::MAX, Some(&dictionary), &mut data).unwrap();
+        assert_eq!(data, std::fs::read(logs.join("7.json")).unwrap());
+        
+        let extract_dir = dir.path().join("out");
+        let options = ExtractOptions { zstd_dictionary: Some(dictionary), ..ExtractOptions::default() };
+        decompress_files_with_progress(&output, &extract_dir, &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(extract_dir.join("logs").join("7.json")).unwrap(), data);
+    }
+
+    // A .tar.xz of one small file, with an LZMA2 dictionary of `dict_size` bytes
+    fn tar_xz_with_dictionary(dict_size: u32) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "hello.txt", &b"hello"[..]).unwrap();
+        let tar = tar.into_inner().unwrap();
+        
+        let mut lzma = xz2::stream::LzmaOptions::new_preset(6).unwrap();
+        lzma.dict_size(dict_size);
+        let mut filters = xz2::stream::Filters::new();
+        filters.lzma2(&lzma);
+        let stream = xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::Crc64).unwrap();
+        let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(&tar).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn xz_dictionary_size_reads_the_first_block() {
+        assert_eq!(xz_dictionary_size(&tar_xz_with_dictionary(64 << 20)), Some(64 << 20));
+        assert_eq!(xz_dictionary_size(&tar_xz_with_dictionary(3 << 20)), Some(3 << 20));
+        assert_eq!(xz_dictionary_size(b"not an xz stream"), None);
+        assert_eq!(xz_dictionary_size(XZ_MAGIC), None);
+    }
+
+    #[test]
+    fn zstd_frame_window_size_reads_the_frame_header() {
+        let magic = ZSTD_FRAME_MAGIC.to_le_bytes();
+        // Window descriptor: exponent 17 is 128 KiB, plus 3/8 of that
+        let header = [&magic[..], &[0x00, (7 << 3) | 3]].concat();
+        assert_eq!(zstd_frame_window_size(&header), Some((128 << 10) + (128 << 10) / 8 * 3));
+        // Single segment with a one-byte content size
+        let header = [&magic[..], &[0x20, 200]].concat();
+        assert_eq!(zstd_frame_window_size(&header), Some(200));
+        // Single segment with a two-byte content size, which is offset by 256
+        let header = [&magic[..], &[0x60, 0x00, 0x01]].concat();
+        assert_eq!(zstd_frame_window_size(&header), Some(256 + 256));
+        assert_eq!(zstd_frame_window_size(b"not zstd"), None);
+        
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.window_log(27).unwrap();
+        encoder.write_all(b"some data").unwrap();
+        let frame = encoder.finish().unwrap();
+        assert_eq!(zstd_frame_window_size(&frame), Some(1 << 27));
+    }
+
+    #[tokio::test]
+    async fn large_window_is_refused_under_a_small_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("big-window.tar.xz");
+        std::fs::write(&archive, tar_xz_with_dictionary(64 << 20)).unwrap();
+        
+        let output = dir.path().join("out");
+        let options = ExtractOptions { decompress_memory_limit: Some(16 << 20), ..ExtractOptions::default() };
+        let error = decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap_err();
+        assert!(error.to_string().contains("decompress_memory_limit"), "{}", error);
+        assert!(!output.join("hello.txt").exists());
+        
+        // The same archive is fine once the limit allows its dictionary
+        let options = ExtractOptions { decompress_memory_limit: Some(128 << 20), ..ExtractOptions::default() };
+        decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(output.join("hello.txt")).unwrap(), b"hello");
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn force_zip64_writes_zip64_end_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("small.txt");
+        write_file(&source, b"small enough for a plain zip");
+        let output = dir.path().join("forced.zip");
+        let options = CompressOptions { force_zip64: true, ..CompressOptions::default() };
+        compress_files_with_progress(&[source.clone()], &output, CompressionType::Zip, &options, |_, _| {}).await.unwrap();
+        
+        let data = std::fs::read(&output).unwrap();
+        // Zip64 end of central directory record and its locator
+        assert!(contains(&data, &0x06064b50u32.to_le_bytes()));
+        assert!(contains(&data, &0x07064b50u32.to_le_bytes()));
+        
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        assert_eq!(archive.len(), 1);
+        let mut contents = Vec::new();
+        archive.by_index(0).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"small enough for a plain zip");
+        
+        let extract_dir = dir.path().join("out");
+        decompress_files(&output, &extract_dir).await.unwrap();
+        assert_eq!(std::fs::read(extract_dir.join("small.txt")).unwrap(), contents);
+    }
+
+    // A zip with one stored entry per (name, contents) pair, names written as given
+    fn zip_with(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, FileOptions::default().compression_method(zip::CompressionMethod::Stored)).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn names_differing_only_in_case_both_survive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("cases.zip");
+        zip_with(&archive, &[("README", b"upper"), ("readme", b"lower")]);
+        
+        let output = dir.path().join("out");
+        let summary = decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(summary.case_collisions, vec!["README / readme".to_string()]);
+        
+        let mut contents: Vec<Vec<u8>> = std::fs::read_dir(&output).unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec![b"lower".to_vec(), b"upper".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn nested_paths_are_stored_with_forward_slashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        write_file(&root.join("sub").join("deep").join("file.txt"), b"nested");
+        let output = dir.path().join("nested.zip");
+        compress_files(&[root], &output, CompressionType::Zip).await.unwrap();
+        
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let names: Vec<String> = (0..archive.len())
+            .map(|i| String::from_utf8(archive.by_index_raw(i).unwrap().name_raw().to_vec()).unwrap())
+            .collect();
+        assert!(names.iter().any(|name| name == "root/sub/deep/file.txt"), "{:?}", names);
+        assert!(names.iter().all(|name| !name.contains('\\')), "{:?}", names);
+    }
+
+    #[tokio::test]
+    async fn backslash_names_extract_into_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("backslashes.zip");
+        zip_with(&archive, &[("folder\\sub\\file.txt", b"inside")]);
+        
+        let output = dir.path().join("out");
+        decompress_files(&archive, &output).await.unwrap();
+        assert!(output.join("folder").join("sub").is_dir());
+        assert_eq!(std::fs::read(output.join("folder").join("sub").join("file.txt")).unwrap(), b"inside");
+    }
+
+    // A zip whose one entry is named `name` and carries a Unicode Path extra field
+    // claiming to stand for a name with CRC-32 `name_crc`. Built by hand, as the zip
+    // crate refuses to write a field ID from the spec's own list.
+    fn zip_with_unicode_path(path: &Path, name: &[u8], name_crc: u32, unicode_name: &str) {
+        let mut field = vec![1];
+        field.extend_from_slice(&name_crc.to_le_bytes());
+        field.extend_from_slice(unicode_name.as_bytes());
+        let mut extra = Vec::new();
+        write_extra_field(&mut extra, UNICODE_PATH_FIELD, &field).unwrap();
+        let data = b"menu";
+        
+        // Fields shared by the local and central headers: version needed, flags, method
+        // (stored), time and date (1980-01-01), CRC, sizes, name and extra lengths
+        let mut fields = vec![20, 0, 0, 0, 0, 0, 0, 0, 0x21, 0];
+        fields.extend_from_slice(&crc32(data).to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        
+        let mut archive = LOCAL_HEADER_SIGNATURE.to_vec();
+        archive.extend_from_slice(&fields);
+        archive.extend_from_slice(name);
+        archive.extend_from_slice(&extra);
+        archive.extend_from_slice(data);
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&[0x50, 0x4b, 0x01, 0x02, 20, 0]);
+        archive.extend_from_slice(&fields);
+        // Comment length, disk, internal and external attributes, then the offset (0)
+        archive.extend_from_slice(&[0u8; 14]);
+        archive.extend_from_slice(name);
+        archive.extend_from_slice(&extra);
+        let directory_size = archive.len() as u32 - directory_offset;
+        // End of central directory: one entry on disk 0, no comment
+        archive.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0, 1, 0, 1, 0]);
+        archive.extend_from_slice(&directory_size.to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&[0, 0]);
+        std::fs::write(path, archive).unwrap();
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        crc.sum()
+    }
+
+    #[tokio::test]
+    async fn unicode_path_field_wins_over_the_name_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("unicode.zip");
+        zip_with_unicode_path(&archive, b"cafe.txt", crc32(b"cafe.txt"), "caf\u{e9}.txt");
+        
+        let names = entry_names(&archive);
+        assert_eq!(names, vec!["caf\u{e9}.txt".to_string()]);
+        let output = dir.path().join("out");
+        decompress_files(&archive, &output).await.unwrap();
+        assert_eq!(std::fs::read(output.join("caf\u{e9}.txt")).unwrap(), b"menu");
+        assert!(!output.join("cafe.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn unicode_path_field_with_a_stale_crc_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("renamed.zip");
+        // The field stands for an earlier name, so the entry was renamed since
+        zip_with_unicode_path(&archive, b"cafe.txt", crc32(b"old name.txt"), "caf\u{e9}.txt");
+        
+        assert_eq!(entry_names(&archive), vec!["cafe.txt".to_string()]);
+        let output = dir.path().join("out");
+        decompress_files(&archive, &output).await.unwrap();
+        assert_eq!(std::fs::read(output.join("cafe.txt")).unwrap(), b"menu");
+    }
+
+    #[tokio::test]
+    async fn output_that_is_a_source_is_refused_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("notes.gz");
+        write_file(&source, b"not actually gzip");
+        
+        assert!(check_output_not_source(&[source.clone()], &source).is_err());
+        // A different spelling of the same path is caught too
+        let spelled = dir.path().join(".").join("notes.gz");
+        let error = compress_files(&[source.clone()], &spelled, CompressionType::Gz).await.unwrap_err();
+        assert!(error.to_string().contains("is one of the files being compressed"), "{}", error);
+        assert_eq!(std::fs::read(&source).unwrap(), b"not actually gzip");
+        
+        assert!(check_output_not_source(&[source], &dir.path().join("other.gz")).is_ok());
+    }
+
+    fn is_corrupt(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| matches!(cause.downcast_ref::<CompressionError>(), Some(CompressionError::Corrupt { .. })))
+    }
+
+    #[tokio::test]
+    async fn truncated_gzip_is_corrupt_and_leaves_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data.txt");
+        let contents: Vec<u8> = (0..200_000u32).flat_map(|i| (i * 7919).to_le_bytes()).collect();
+        write_file(&source, &contents);
+        let archive = dir.path().join("data.txt.gz");
+        compress_files(&[source], &archive, CompressionType::Gz).await.unwrap();
+        
+        let data = std::fs::read(&archive).unwrap();
+        std::fs::write(&archive, &data[..data.len() / 2]).unwrap();
+        let output = dir.path().join("out");
+        let error = decompress_files(&archive, &output).await.unwrap_err();
+        assert!(is_corrupt(&error), "{:?}", error);
+        assert!(!output.join("data.txt").exists());
+    }
+
+    #[test]
+    fn copy_decoded_passes_write_errors_through() {
+        struct FullDisk;
+        impl Write for FullDisk {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let error = copy_decoded(&mut &b"some data"[..], &mut FullDisk).unwrap_err();
+        assert!(!is_corrupt(&error));
+        assert_eq!(error.downcast_ref::<std::io::Error>().map(|e| e.kind()), Some(std::io::ErrorKind::StorageFull));
+    }
+
+    #[tokio::test]
+    async fn strategy_entries_keep_the_time_mode_and_extra_fields_of_regular_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("report.txt");
+        write_file(&source, &b"a line of text\n".repeat(100));
+        // 2021-06-15 12:34:56 UTC
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_623_760_496);
+        File::options().write(true).open(&source).unwrap().set_modified(mtime).unwrap();
+        
+        for strategy in [DeflateStrategy::Default, DeflateStrategy::Filtered] {
+            let output = dir.path().join(format!("{:?}.zip", strategy));
+            let entry_metadata = Some(EntryMetadata { field_id: 0x7A7A, value: "tagged".to_string() });
+            let options = CompressOptions { deflate_strategy: strategy, entry_metadata, ..CompressOptions::default() };
+            compress_files_with_progress(&[source.clone()], &output, CompressionType::Zip, &options, |_, _| {}).await.unwrap();
+            
+            let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+            let entry = archive.by_index(0).unwrap();
+            let time = entry.last_modified();
+            assert_eq!((time.year(), time.month(), time.day()), (2021, 6, 15), "{:?}", strategy);
+            assert_eq!((time.hour(), time.minute(), time.second()), (12, 34, 56), "{:?}", strategy);
+            assert_eq!(entry.unix_mode(), Some(0o100000 | ZIP_ENTRY_MODE), "{:?}", strategy);
+            assert_eq!(dos_time_to_unix(time), 1_623_760_496);
+            assert_eq!(read_entry_metadata(entry.extra_data(), 0x7A7A), Some(&b"tagged"[..]), "{:?}", strategy);
+        }
+    }
+
+    // An uncompressed tar of the given files
+    fn tar_with(path: &Path, files: &[(&str, &[u8])]) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *data).unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tar_extraction_log_tells_overwritten_from_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("files.tar");
+        tar_with(&archive, &[("old.txt", b"new contents"), ("new.txt", b"fresh")]);
+        let output = dir.path().join("out");
+        write_file(&output.join("old.txt"), b"old contents");
+        
+        let log = dir.path().join("extract.log");
+        let options = ExtractOptions {
+            conflict_policy: ConflictPolicy::Overwrite,
+            extraction_log: Some(log.clone()),
+            ..ExtractOptions::default()
+        };
+        decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+        
+        assert_eq!(std::fs::read(output.join("old.txt")).unwrap(), b"new contents");
+        let log = std::fs::read_to_string(log).unwrap();
+        assert!(log.lines().any(|line| line.starts_with("overwritten\told.txt")), "{}", log);
+        assert!(log.lines().any(|line| line.starts_with("written\tnew.txt")), "{}", log);
+    }
+
+    #[tokio::test]
+    async fn tar_extraction_follows_the_conflict_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("files.tar");
+        tar_with(&archive, &[("docs/readme.txt", b"from the archive")]);
+        
+        let output = dir.path().join("skip");
+        write_file(&output.join("docs").join("readme.txt"), b"already here");
+        let options = ExtractOptions { conflict_policy: ConflictPolicy::Skip, ..ExtractOptions::default() };
+        decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(output.join("docs").join("readme.txt")).unwrap(), b"already here");
+        
+        let output = dir.path().join("rename");
+        write_file(&output.join("docs").join("readme.txt"), b"already here");
+        let options = ExtractOptions { conflict_policy: ConflictPolicy::Rename, ..ExtractOptions::default() };
+        decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(output.join("docs").join("readme.txt")).unwrap(), b"already here");
+        let files: Vec<_> = std::fs::read_dir(output.join("docs")).unwrap().collect();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn tar_and_cpio_keep_a_name_that_is_not_utf8() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("latin1");
+        let name = std::ffi::OsString::from_vec(b"caf\xe9.txt".to_vec());
+        write_file(&source.join(&name), b"not utf-8");
+        
+        for (compression_type, extension) in [(CompressionType::TarGz, "tar.gz"), (CompressionType::Cpio, "cpio")] {
+            let archive = dir.path().join(format!("latin1.{}", extension));
+            compress_files_with_progress(&[source.clone()], &archive, compression_type, &CompressOptions::default(), |_, _| {}).await.unwrap();
+            
+            let output = dir.path().join(format!("out-{}", extension));
+            decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+            let extracted: Vec<Vec<u8>> = std::fs::read_dir(output.join("latin1")).unwrap()
+                .map(|entry| entry.unwrap().file_name().as_bytes().to_vec())
+                .collect();
+            assert_eq!(extracted, vec![b"caf\xe9.txt".to_vec()], "{}", extension);
+            assert_eq!(std::fs::read(output.join("latin1").join(&name)).unwrap(), b"not utf-8");
+        }
+    }
+
+    // `files` zipped and split after the first entry into old.z01 and old.zip, the way
+    // spanning tools write them: the first disk starts with the split signature and
+    // every disk counts offsets from its own start
+    fn spanned_zip_with(dir: &Path, files: &[(&str, &[u8])]) -> PathBuf {
+        let whole = dir.join("whole.zip");
+        zip_with(&whole, files);
+        let data = std::fs::read(&whole).unwrap();
+        std::fs::remove_file(&whole).unwrap();
+        let directory = find_zip_directory(&mut std::io::Cursor::new(&data)).unwrap().unwrap();
+        let central_start = directory.offset as usize;
+        let mut central = data[central_start..].to_vec();
+        
+        let mut headers = Vec::new();
+        let mut at = 0;
+        for _ in 0..directory.entries {
+            headers.push(at);
+            at += 46 + u16_at(&central, at + 28) as usize + u16_at(&central, at + 30) as usize + u16_at(&central, at + 32) as usize;
+        }
+        let split = u32_at(&central, headers[1] + 42) as usize;
+        for header in headers {
+            let offset = u32_at(&central, header + 42) as usize;
+            let (disk, offset) = if offset < split { (0u16, offset + 4) } else { (1, offset - split) };
+            central[header + 34..header + 36].copy_from_slice(&disk.to_le_bytes());
+            central[header + 42..header + 46].copy_from_slice(&(offset as u32).to_le_bytes());
+        }
+        // The end record is on disk 1, and so is the central directory
+        central[at + 4..at + 6].copy_from_slice(&1u16.to_le_bytes());
+        central[at + 6..at + 8].copy_from_slice(&1u16.to_le_bytes());
+        central[at + 16..at + 20].copy_from_slice(&((central_start - split) as u32).to_le_bytes());
+        
+        std::fs::write(dir.join("old.z01"), [&DATA_DESCRIPTOR_SIGNATURE[..], &data[..split]].concat()).unwrap();
+        std::fs::write(dir.join("old.zip"), [&data[split..central_start], &central[..]].concat()).unwrap();
+        dir.join("old.zip")
+    }
+
+    #[tokio::test]
+    async fn spanned_zip_is_listed_read_and_extracted_from_its_disks() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = spanned_zip_with(dir.path(), &[("first.txt", b"on the first disk"), ("second.txt", b"on the last disk")]);
+        
+        assert_eq!(entry_names(&archive), vec!["first.txt".to_string(), "second.txt".to_string()]);
+        let mut data = Vec::new();
+        read_entry(&archive, "first.txt", u64::MAX, None, &mut data).unwrap();
+        assert_eq!(data, b"on the first disk");
+        
+        // Either disk extracts the whole set
+        for (disk, output) in [("old.z01", "from-first"), ("old.zip", "from-last")] {
+            let output = dir.path().join(output);
+            decompress_files_with_progress(&dir.path().join(disk), &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+            assert_eq!(std::fs::read(output.join("first.txt")).unwrap(), b"on the first disk");
+            assert_eq!(std::fs::read(output.join("second.txt")).unwrap(), b"on the last disk");
+        }
+    }
+
+    #[tokio::test]
+    async fn spanned_zip_with_a_missing_disk_names_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = spanned_zip_with(dir.path(), &[("first.txt", b"one"), ("second.txt", b"two")]);
+        std::fs::remove_file(dir.path().join("old.z01")).unwrap();
+        
+        let error = decompress_files_with_progress(&archive, &dir.path().join("out"), &ExtractOptions::default(), |_, _| {}).await.unwrap_err();
+        assert!(error.to_string().contains("disk 1 (old.z01)"), "{}", error);
+    }
+
+    #[test]
+    fn distinct_archives_extracts_a_spanned_zip_once() {
+        let selection = [
+            PathBuf::from("/data/old.z01"),
+            PathBuf::from("/data/old.z02"),
+            PathBuf::from("/data/old.zip"),
+            PathBuf::from("/data/other.tar.gz"),
+        ];
+        assert_eq!(distinct_archives(&selection), vec![PathBuf::from("/data/old.zip"), PathBuf::from("/data/other.tar.gz")]);
+    }
+
+    #[tokio::test]
+    async fn dedupe_keeps_each_duplicates_own_time_extra_fields_and_the_entry_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let assets = dir.path().join("assets");
+        let year = |year: u64| std::time::UNIX_EPOCH + std::time::Duration::from_secs((year - 1970) * 365 * 86400 + 200 * 86400);
+        for (name, contents, modified) in [("a.txt", b"same bytes", 2001), ("b.txt", b"same bytes", 2011), ("c.txt", b"different!", 2015)] {
+            write_file(&assets.join(name), contents);
+            File::options().write(true).open(assets.join(name)).unwrap().set_modified(year(modified)).unwrap();
+        }
+        
+        let output = dir.path().join("assets.zip");
+        let entry_metadata = Some(EntryMetadata { field_id: 0x7A7A, value: "tagged".to_string() });
+        let options = CompressOptions { dedupe: true, entry_order: EntryOrder::PathAsc, entry_metadata, ..CompressOptions::default() };
+        let result = compress_files_with_progress(&[assets], &output, CompressionType::Zip, &options, |_, _| {}).await.unwrap();
+        assert_eq!(result.dedupe.duplicate_files, 1);
+        
+        let mut archive = zip::ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let names: Vec<String> = (0..archive.len()).map(|i| archive.by_index(i).unwrap().name().to_string()).collect();
+        assert_eq!(names, ["assets/a.txt", "assets/b.txt", "assets/c.txt"]);
+        assert_eq!(archive.by_name("assets/a.txt").unwrap().last_modified().year(), 2001);
+        let mode = archive.by_name("assets/a.txt").unwrap().unix_mode();
+        let mut duplicate = archive.by_name("assets/b.txt").unwrap();
+        assert_eq!(duplicate.last_modified().year(), 2011);
+        assert_eq!(read_entry_metadata(duplicate.extra_data(), 0x7A7A), Some(&b"tagged"[..]));
+        assert_eq!(duplicate.unix_mode(), mode);
+        let mut data = Vec::new();
+        duplicate.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"same bytes");
+    }
+
+    #[test]
+    fn text_globs_match_names_ignoring_case() {
+        let defaults = text_glob_set(&CompressOptions::default()).unwrap();
+        assert!(defaults.is_match("README.TXT"));
+        assert!(defaults.is_match("build.rs"));
+        assert!(!defaults.is_match("photo.png"));
+        
+        let options = CompressOptions { text_globs: vec!["Makefile".to_string(), "*.{conf,log}".to_string()], ..CompressOptions::default() };
+        let custom = text_glob_set(&options).unwrap();
+        assert!(custom.is_match("makefile"));
+        assert!(custom.is_match("server.LOG"));
+        assert!(!custom.is_match("notes.txt"));
+        
+        let options = CompressOptions { text_globs: vec!["[".to_string()], ..CompressOptions::default() };
+        assert!(text_glob_set(&options).unwrap_err().to_string().contains("Invalid text glob"));
+    }
+
+    #[tokio::test]
+    async fn strategy_scratch_files_go_in_temp_dir_and_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("notes.txt");
+        write_file(&source, &b"scratch ".repeat(1000));
+        let scratch = dir.path().join("scratch");
+        std::fs::create_dir(&scratch).unwrap();
+        
+        let output = dir.path().join("notes.zip");
+        let options = CompressOptions { temp_dir: Some(scratch.clone()), deflate_strategy: DeflateStrategy::Rle, ..CompressOptions::default() };
+        compress_files_with_progress(&[source.clone()], &output, CompressionType::Zip, &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+        let mut data = Vec::new();
+        read_entry(&output, "notes.txt", u64::MAX, None, &mut data).unwrap();
+        assert_eq!(data, std::fs::read(&source).unwrap());
+        
+        let options = CompressOptions { temp_dir: Some(dir.path().join("missing")), ..CompressOptions::default() };
+        let error = compress_files_with_progress(&[source], &dir.path().join("other.zip"), CompressionType::Zip, &options, |_, _| {}).await.unwrap_err();
+        assert!(error.to_string().contains("Temp directory does not exist"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn legacy_zips_have_no_data_descriptors_or_zip64_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let assets = dir.path().join("assets");
+        write_file(&assets.join("a.txt"), &b"legacy ".repeat(500));
+        write_file(&assets.join("b.txt"), &b"legacy ".repeat(500));
+        write_file(&assets.join("c.bin"), b"\x00\x01\x02");
+        
+        // Plain, with a deduplicated entry, and copied in from strategy scratch zips
+        for strategy in [DeflateStrategy::Default, DeflateStrategy::Rle] {
+            let output = dir.path().join(format!("{:?}.zip", strategy));
+            let options = CompressOptions { compatibility: ZipCompat::Legacy, dedupe: true, deflate_strategy: strategy, ..CompressOptions::default() };
+            compress_files_with_progress(&[assets.clone()], &output, CompressionType::Zip, &options, |_, _| {}).await.unwrap();
+            
+            let mut file = BufReader::new(File::open(&output).unwrap());
+            let len = file.get_ref().metadata().unwrap().len();
+            let headers = read_central_headers(&mut file).unwrap();
+            assert_eq!(headers.len(), 3);
+            for (name, central) in &headers {
+                let name = String::from_utf8_lossy(name);
+                let local = local_entry_at(&mut file, central_header_offset(central), len).unwrap();
+                let name_len = u16_at(&local.header, 26) as usize;
+                assert_eq!(u16_at(&local.header, 6) & 0x08, 0, "{} in {:?}", name, strategy);
+                assert_eq!(u16_at(central, 8) & 0x08, 0, "{} in {:?}", name, strategy);
+                assert_eq!(u16_at(&local.header, 8), 8, "{} in {:?}", name, strategy);
+                assert!(read_entry_metadata(&local.header[30 + name_len..], ZIP64_FIELD).is_none(), "{} in {:?}", name, strategy);
+                assert_ne!(u32_at(&local.header, 18), 0, "{} in {:?}", name, strategy);
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn read_only_survives_a_round_trip_and_is_in_the_external_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("config.ini");
+        write_file(&source, b"[settings]");
+        let set_readonly = |path: &Path, readonly: bool| {
+            let mut permissions = std::fs::metadata(path).unwrap().permissions();
+            permissions.set_readonly(readonly);
+            std::fs::set_permissions(path, permissions).unwrap();
+        };
+        set_readonly(&source, true);
+        
+        let output = dir.path().join("config.zip");
+        compress_files_with_progress(&[source.clone()], &output, CompressionType::Zip, &CompressOptions::default(), |_, _| {}).await.unwrap();
+        let headers = read_central_headers(&mut BufReader::new(File::open(&output).unwrap())).unwrap();
+        assert_eq!(u32_at(&headers[b"config.ini".as_slice()], 38) & 0x1, 0x1);
+        
+        let extracted = dir.path().join("out");
+        decompress_files_with_progress(&output, &extracted, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        let restored = extracted.join("config.ini");
+        assert!(std::fs::metadata(&restored).unwrap().permissions().readonly());
+        // So the temporary folder can be removed
+        set_readonly(&source, false);
+        set_readonly(&restored, false);
+    }
+
+    #[tokio::test]
+    async fn failed_entries_are_reported_as_they_fail_and_leave_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("damaged.zip");
+        zip_with(&archive, &[("bad.txt", b"first entry data"), ("good.txt", b"second entry")]);
+        // A flipped byte in the first entry's data, so its CRC no longer matches
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let at = bytes.windows(5).position(|window| window == b"first").unwrap();
+        bytes[at] ^= 0xFF;
+        std::fs::write(&archive, bytes).unwrap();
+        
+        let output = dir.path().join("out");
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let (seen, good) = (reported.clone(), output.join("good.txt"));
+        let options = ExtractOptions {
+            continue_on_error: true,
+            // Each failure is reported before the entries after it are written
+            entry_failed: Some(EntryFailed(Arc::new(move |failed| seen.lock().unwrap().push((failed.name.clone(), good.exists()))))),
+            ..ExtractOptions::default()
+        };
+        let summary = decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+        assert_eq!(*reported.lock().unwrap(), vec![("bad.txt".to_string(), false)]);
+        assert_eq!(summary.failed_entries.len(), 1);
+        assert!(!output.join("bad.txt").exists());
+        assert_eq!(std::fs::read(output.join("good.txt")).unwrap(), b"second entry");
+    }
+
+    // A zip whose one entry claims Deflate64 (method 9). Deflate data without
+    // length-258 matches, which the two formats code differently, decodes the same way
+    // under both.
+    fn deflate64_zip(path: &Path, name: &str, data: &[u8]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        zip.start_file(name, FileOptions::default().compression_method(zip::CompressionMethod::Deflated)).unwrap();
+        zip.write_all(data).unwrap();
+        zip.finish().unwrap();
+        
+        let mut bytes = std::fs::read(path).unwrap();
+        assert_eq!(u16_at(&bytes, 8), 8);
+        bytes[8] = 9;
+        let central = bytes.windows(4).rposition(|window| window == [0x50, 0x4b, 0x01, 0x02]).unwrap();
+        bytes[central + 10] = 9;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deflate64_entries_extract_and_have_their_crc_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: String = (0..3000u64).map(|i| format!("{} ", i * i)).collect();
+        let archive = dir.path().join("deflate64.zip");
+        deflate64_zip(&archive, "squares.txt", data.as_bytes());
+        
+        let output = dir.path().join("out");
+        decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(output.join("squares.txt")).unwrap(), data.as_bytes());
+        
+        // The same entry with a CRC that doesn't match its data
+        let crc = crc32(data.as_bytes()).to_le_bytes();
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let mut at = 0;
+        while let Some(found) = bytes[at..].windows(4).position(|window| window == crc) {
+            bytes[at + found] ^= 1;
+            at += found + 4;
+        }
+        let damaged = dir.path().join("damaged.zip");
+        std::fs::write(&damaged, bytes).unwrap();
+        let output = dir.path().join("damaged");
+        let error = decompress_files_with_progress(&damaged, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("checksum"), "{:#}", error);
+        assert!(!output.join("squares.txt").exists());
+    }
+
+    #[cfg(unix)]
+    fn allocated(path: &Path) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn gnu_sparse_tar_entries_extract_with_their_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("sparse.tar");
+        let real_size = 4u64 << 20;
+        let octal = |value: u64| -> [u8; 12] { format!("{:011o}\0", value).into_bytes().try_into().unwrap() };
+        
+        // 4 KiB of data at each end of 4 MiB, and a hole in between
+        let mut header = tar::Header::new_gnu();
+        header.set_path("disk.img").unwrap();
+        header.set_entry_type(tar::EntryType::GNUSparse);
+        header.set_size(8192);
+        header.set_mode(0o644);
+        let gnu = header.as_gnu_mut().unwrap();
+        for (block, offset) in [0, real_size - 4096].into_iter().enumerate() {
+            gnu.sparse[block].offset = octal(offset);
+            gnu.sparse[block].numbytes = octal(4096);
+        }
+        gnu.realsize = octal(real_size);
+        header.set_cksum();
+        let mut data = vec![b'A'; 4096];
+        data.extend_from_slice(&[b'B'; 4096]);
+        let mut tar = tar::Builder::new(File::create(&archive).unwrap());
+        tar.append(&header, data.as_slice()).unwrap();
+        tar.finish().unwrap();
+        drop(tar);
+        
+        let output = dir.path().join("out");
+        decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        let image = output.join("disk.img");
+        let contents = std::fs::read(&image).unwrap();
+        assert_eq!(contents.len() as u64, real_size);
+        assert!(contents[..4096].iter().all(|byte| *byte == b'A'));
+        assert!(contents[4096..contents.len() - 4096].iter().all(|byte| *byte == 0));
+        assert!(contents[contents.len() - 4096..].iter().all(|byte| *byte == b'B'));
+        assert!(allocated(&image) < real_size / 4, "{} bytes allocated", allocated(&image));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cpio_and_zip_zero_runs_extract_as_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("disk.img");
+        let mut contents = vec![0u8; 4 << 20];
+        contents[..5].copy_from_slice(b"start");
+        let end = contents.len() - 3;
+        contents[end..].copy_from_slice(b"end");
+        write_file(&source, &contents);
+        
+        // Neither reader hands out block-aligned pieces: cpio data follows an odd-sized
+        // header and deflate comes out in whatever runs the decoder produces
+        for (compression_type, name) in [(CompressionType::Cpio, "disk.cpio"), (CompressionType::Zip, "disk.zip")] {
+            let archive = dir.path().join(name);
+            compress_files(&[source.clone()], &archive, compression_type).await.unwrap();
+            for (preserve_sparse, folder) in [(true, "sparse"), (false, "full")] {
+                let output = dir.path().join(format!("{} {}", name, folder));
+                let options = ExtractOptions { preserve_sparse: Some(preserve_sparse), ..ExtractOptions::default() };
+                decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+                let image = output.join("disk.img");
+                assert_eq!(std::fs::read(&image).unwrap(), contents);
+                let sparse = allocated(&image) < contents.len() as u64 / 4;
+                assert_eq!(sparse, preserve_sparse, "{}: {} bytes allocated", name, allocated(&image));
+            }
+        }
+    }
+
+    #[test]
+    fn zip_pages_match_the_full_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("many.zip");
+        let names: Vec<String> = (0..25).map(|i| format!("folder\\file {:02}.txt", i)).collect();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), name.as_bytes())).collect();
+        zip_with(&archive, &files);
+        
+        let summary = |entry: &ArchiveEntry| (entry.name.clone(), entry.size, entry.is_dir, entry.method.clone(), entry.encrypted);
+        let full: Vec<_> = list_archive_contents(&archive, None, false, None).unwrap().iter().map(summary).collect();
+        let page = list_archive_page(&archive, 10, 5, None, false, None).unwrap();
+        assert_eq!(page.entries.iter().map(summary).collect::<Vec<_>>(), full[10..15]);
+        assert_eq!((page.total, page.has_more), (Some(25), true));
+        assert_eq!(page.entries[0].name, "folder/file 10.txt");
+        
+        let last = list_archive_page(&archive, 20, 10, None, false, None).unwrap();
+        assert_eq!(last.entries.iter().map(summary).collect::<Vec<_>>(), full[20..]);
+        assert!(!last.has_more);
+        assert!(list_archive_page(&archive, 30, 10, None, false, None).unwrap().entries.is_empty());
+        
+        let unicode = dir.path().join("unicode.zip");
+        zip_with_unicode_path(&unicode, b"cafe.txt", crc32(b"cafe.txt"), "caf\u{e9}.txt");
+        assert_eq!(list_archive_page(&unicode, 0, 1, None, false, None).unwrap().entries[0].name, "caf\u{e9}.txt");
+        
+        // Without the UTF-8 flag or a current Unicode Path field the name is CP437
+        let cp437 = dir.path().join("cp437.zip");
+        zip_with_unicode_path(&cp437, b"caf\x82 \xe1.txt", crc32(b"old name.txt"), "ignored.txt");
+        assert_eq!(list_archive_page(&cp437, 0, 1, None, false, None).unwrap().entries[0].name, "caf\u{e9} \u{df}.txt");
+    }
+
+    #[tokio::test]
+    async fn resuming_overwrites_only_the_files_the_earlier_run_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: [(&str, &[u8]); 4] = [("half.txt", b"written in full"), ("done.txt", b"finished before"), ("mine.txt", b"from the archive"), ("new.txt", b"not reached")];
+        let zip = dir.path().join("files.zip");
+        zip_with(&zip, &files);
+        let tar = dir.path().join("files.tar");
+        tar_with(&tar, &files);
+        
+        for archive in [zip, tar] {
+            // An interrupted run that finished done.txt and was cut off in half.txt, in
+            // a folder where the user already had a mine.txt
+            let output = dir.path().join(archive.extension().unwrap());
+            write_file(&output.join("half.txt"), b"writ");
+            write_file(&output.join("done.txt"), b"finished before");
+            write_file(&output.join("mine.txt"), b"the user's own");
+            let journal = format!("{}\ns \"done.txt\"\nd \"done.txt\"\ns \"half.txt\"\n", journal_header(&archive).unwrap());
+            std::fs::write(output.join(JOURNAL_NAME), journal).unwrap();
+            
+            let options = ExtractOptions { resume: true, conflict_policy: ConflictPolicy::Skip, ..ExtractOptions::default() };
+            decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+            assert_eq!(std::fs::read(output.join("half.txt")).unwrap(), b"written in full");
+            assert_eq!(std::fs::read(output.join("done.txt")).unwrap(), b"finished before");
+            assert_eq!(std::fs::read(output.join("mine.txt")).unwrap(), b"the user's own", "{}", archive.display());
+            assert_eq!(std::fs::read(output.join("new.txt")).unwrap(), b"not reached");
+            assert!(!output.join(JOURNAL_NAME).exists());
+        }
+    }
+
+    #[test]
+    fn detect_creator_names_the_methods_it_finds() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("stored.zip");
+        zip_with(&archive, &[("stored.txt", b"as is")]);
+        deflate64_zip(&dir.path().join("deflate64.zip"), "packed.txt", b"packed");
+        
+        let guess = detect_creator(&archive).unwrap();
+        assert!(guess.evidence.contains(&"methods: Stored".to_string()), "{:?}", guess.evidence);
+        let guess = detect_creator(&dir.path().join("deflate64.zip")).unwrap();
+        assert!(guess.evidence.contains(&"methods: Deflate64".to_string()), "{:?}", guess.evidence);
+    }
+
+    #[tokio::test]
+    async fn mapped_sources_compress_and_report_progress_like_buffered_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data.bin");
+        let contents: Vec<u8> = (0..800_000u32).flat_map(|i| (i % 1013 * 7919).to_le_bytes()).collect();
+        write_file(&source, &contents);
+        let empty = dir.path().join("empty.bin");
+        write_file(&empty, b"");
+        
+        for compression_type in [CompressionType::Gz, CompressionType::Br, CompressionType::Bzip2] {
+            for file in [&source, &empty] {
+                let mut outputs = Vec::new();
+                for use_mmap in [false, true] {
+                    let archive = dir.path().join(format!("out-{}", use_mmap));
+                    let options = CompressOptions { use_mmap, ..CompressOptions::default() };
+                    let mut reported = Vec::new();
+                    compress_files_with_progress(&[file.clone()], &archive, compression_type.clone(), &options, |percent, _| reported.push(percent)).await.unwrap();
+                    assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", reported);
+                    assert_eq!(reported.last(), Some(&100.0));
+                    outputs.push(std::fs::read(&archive).unwrap());
+                }
+                assert!(outputs[0] == outputs[1], "{:?} of {}", compression_type, file.display());
+            }
+        }
+    }
+
+    // Not run by default, as timings only mean something in a release build on an idle
+    // machine with the file in the page cache:
+    // cargo test --release mmap_source_speedup -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn mmap_source_speedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("large.bin");
+        let contents: Vec<u8> = (0..128 * 1024 * 1024u32).flat_map(|i| (i % 100_003).to_le_bytes()).collect();
+        write_file(&source, &contents);
+        
+        // Reading alone, where the saved copy shows, and then through gzip, where it
+        // mostly doesn't
+        for use_mmap in [false, true] {
+            let options = CompressOptions { use_mmap, ..CompressOptions::default() };
+            let context = &CompressContext::new(&options).unwrap();
+            let started = std::time::Instant::now();
+            std::io::copy(&mut open_source(&source, &options, context).unwrap(), &mut std::io::sink()).unwrap();
+            let read = started.elapsed().as_secs_f64();
+            let started = std::time::Instant::now();
+            let mut encoder = flate2::write::GzEncoder::new(std::io::sink(), flate2::Compression::fast());
+            std::io::copy(&mut open_source(&source, &options, context).unwrap(), &mut encoder).unwrap();
+            encoder.finish().unwrap();
+            let gzip = started.elapsed().as_secs_f64();
+            println!("use_mmap {}: read {:.0} MB/s, gzip {:.0} MB/s", use_mmap, 512.0 / read, 512.0 / gzip);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelling_mid_write_leaves_the_old_archive_and_reports_what_was_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..20)
+            .map(|i| {
+                let path = dir.path().join("src").join(format!("file{:02}.bin", i));
+                write_file(&path, &(0..100_000u32).flat_map(|n| (n * (i + 1)).to_le_bytes()).collect::<Vec<u8>>());
+                path
+            })
+            .collect();
+        
+        for (compression_type, name) in [(CompressionType::Zip, "backup.zip"), (CompressionType::TarGz, "backup.tar.gz")] {
+            let output = dir.path().join("out").join(name);
+            write_file(&output, b"the archive from last week");
+            let cancel = Arc::new(AtomicBool::new(false));
+            let options = CompressOptions { cancel: Some(cancel.clone()), ..CompressOptions::default() };
+            // Cancel a few reports in, once some files are written and others aren't
+            let mut reports = 0;
+            let error = compress_files_with_progress(&files, &output, compression_type, &options, |_, _| {
+                reports += 1;
+                if reports == 4 {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }).await.unwrap_err();
+            
+            let report = error.downcast_ref::<CancelReport>().unwrap_or_else(|| panic!("{:?}", error));
+            assert!(report.partial_output_cleaned);
+            assert_eq!(report.files_completed + report.files_remaining, 20);
+            assert!(report.files_remaining > 0, "{:?}", report);
+            assert_eq!(std::fs::read(&output).unwrap(), b"the archive from last week");
+            assert_eq!(std::fs::read_dir(dir.path().join("out")).unwrap().count(), 1, "{}", name);
+            std::fs::remove_file(&output).unwrap();
+        }
+    }
+
+    // tar_with, but each name goes into the header as given; the tar crate's own
+    // set_path would drop a leading "./". Names ending in '/' are folders.
+    fn tar_with_raw_names(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            let is_dir = name.ends_with('/');
+            header.set_entry_type(if is_dir { tar::EntryType::Directory } else { tar::EntryType::Regular });
+            header.set_mode(if is_dir { 0o755 } else { 0o644 });
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tar.append(&header, *data).unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tar_entries_with_a_leading_dot_slash_extract_and_list_without_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("dotted.tar");
+        tar_with_raw_names(&archive, &[("./", b""), ("./foo/", b""), ("./foo/bar", b"inside")]);
+        
+        // Entry by entry, placed by us and by unpack_in, then the tar crate's own unpack
+        let all_options = [
+            ExtractOptions::default(),
+            ExtractOptions { conflict_policy: ConflictPolicy::Overwrite, ..ExtractOptions::default() },
+            ExtractOptions { conflict_policy: ConflictPolicy::Overwrite, max_depth: Some(0), ..ExtractOptions::default() },
+        ];
+        for (i, options) in all_options.iter().enumerate() {
+            let output = dir.path().join(format!("out{}", i));
+            decompress_files_with_progress(&archive, &output, options, |_, _| {}).await.unwrap();
+            assert_eq!(std::fs::read(output.join("foo").join("bar")).unwrap(), b"inside");
+            let top: Vec<_> = std::fs::read_dir(&output).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+            assert_eq!(top, ["foo"], "options {}", i);
+        }
+        
+        let names = |normalize_paths| -> Vec<String> {
+            list_archive_contents(&archive, None, normalize_paths, None).unwrap().into_iter().map(|entry| entry.name).collect()
+        };
+        assert_eq!(names(true), ["foo/", "foo/bar"]);
+        assert_eq!(names(false), ["./", "./foo/", "./foo/bar"]);
+        let page = list_archive_page(&archive, 1, 10, None, true, None).unwrap();
+        assert_eq!(page.entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["foo/bar"]);
+        
+        let mut contents = Vec::new();
+        read_entry(&archive, "foo/bar", u64::MAX, None, &mut contents).unwrap();
+        assert_eq!(contents, b"inside");
+    }
+
+    #[tokio::test]
+    async fn entries_nested_deeper_than_max_depth_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let deep = format!("{}bottom.txt", "level/".repeat(70));
+        let files: [(&str, &[u8]); 2] = [(&deep, b"far down"), ("top.txt", b"near the top")];
+        let zip = dir.path().join("deep.zip");
+        zip_with(&zip, &files);
+        let tar = dir.path().join("deep.tar");
+        tar_with(&tar, &files);
+        
+        for archive in [zip, tar] {
+            let output = dir.path().join(archive.extension().unwrap());
+            let error = decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap_err();
+            let suspicious = error.chain().any(|cause| matches!(cause.downcast_ref(), Some(CompressionError::SuspiciousArchive { .. })));
+            assert!(suspicious, "{:?}", error);
+            assert!(!output.join("level").exists() && !output.join("top.txt").exists());
+            
+            // A higher limit, or none at all, lets it through
+            for max_depth in [Some(71), Some(0)] {
+                let output = dir.path().join(format!("{}-{:?}", archive.extension().unwrap().to_string_lossy(), max_depth));
+                let options = ExtractOptions { max_depth, ..ExtractOptions::default() };
+                decompress_files_with_progress(&archive, &output, &options, |_, _| {}).await.unwrap();
+                assert_eq!(std::fs::read(output.join(&deep)).unwrap(), b"far down");
+            }
+        }
+    }
+
+    fn modified_secs(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[tokio::test]
+    async fn folder_times_survive_a_tar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("src").join("project");
+        write_file(&project.join("sub").join("deeper").join("notes.txt"), b"notes");
+        let at = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        File::options().write(true).open(project.join("sub").join("deeper").join("notes.txt")).unwrap().set_modified(at(946_684_800)).unwrap();
+        // Deepest first, as each one set moves its parent's time again
+        set_dir_modified(&project.join("sub").join("deeper"), at(978_307_200)).unwrap();
+        set_dir_modified(&project.join("sub"), at(1_009_843_200)).unwrap();
+        set_dir_modified(&project, at(1_041_379_200)).unwrap();
+        
+        let archive = dir.path().join("project.tar.gz");
+        compress_files(&[project], &archive, CompressionType::TarGz).await.unwrap();
+        let output = dir.path().join("out");
+        decompress_files(&archive, &output).await.unwrap();
+        assert_eq!(modified_secs(&output.join("project").join("sub").join("deeper").join("notes.txt")), 946_684_800);
+        assert_eq!(modified_secs(&output.join("project").join("sub").join("deeper")), 978_307_200);
+        assert_eq!(modified_secs(&output.join("project").join("sub")), 1_009_843_200);
+        assert_eq!(modified_secs(&output.join("project")), 1_041_379_200);
+    }
+
+    #[tokio::test]
+    async fn zip_folders_get_their_entry_time_or_their_newest_childs() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("folders.zip");
+        let time = |year| zip::DateTime::from_date_and_time(year, 6, 1, 12, 0, 0).unwrap();
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        zip.add_directory("stored/", FileOptions::default().last_modified_time(time(2001))).unwrap();
+        zip.start_file("stored/a.txt", FileOptions::default().last_modified_time(time(2000))).unwrap();
+        zip.write_all(b"a").unwrap();
+        // No entry of its own
+        zip.start_file("implied/b.txt", FileOptions::default().last_modified_time(time(2002))).unwrap();
+        zip.write_all(b"b").unwrap();
+        zip.finish().unwrap();
+        
+        let output = dir.path().join("out");
+        decompress_files(&archive, &output).await.unwrap();
+        assert_eq!(modified_secs(&output.join("stored")), dos_time_to_unix(time(2001)));
+        assert_eq!(modified_secs(&output.join("implied")), modified_secs(&output.join("implied").join("b.txt")));
+    }
+
+    #[tokio::test]
+    async fn refused_and_rerooted_entries_are_in_the_summarys_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("odd.zip");
+        zip_with(&archive, &[("../evil.txt", b"out"), ("/rooted.txt", b"in"), ("plain.txt", b"in")]);
+        
+        let output = dir.path().join("out");
+        let summary = decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(summary.warnings, [
+            "Refused '../evil.txt': it would be written outside the folder",
+            "'/rooted.txt' has an absolute path; extracted as 'rooted.txt'",
+        ]);
+        assert!(output.join("rooted.txt").exists() && output.join("plain.txt").exists());
+        assert!(!dir.path().join("evil.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn selections_sharing_a_name_are_in_the_results_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("a").join("notes.txt");
+        let second = dir.path().join("b").join("notes.txt");
+        write_file(&first, b"first");
+        write_file(&second, b"second");
+        
+        let archive = dir.path().join("notes.zip");
+        let result = compress_files_with_progress(&[first, second.clone()], &archive, CompressionType::Zip, &CompressOptions::default(), |_, _| {}).await.unwrap();
+        assert_eq!(result.warnings, [format!("More than one selection is named 'notes.txt'; {} is stored as 'notes (2).txt'", second.display())]);
+        let mut names = entry_names(&archive);
+        names.sort();
+        assert_eq!(names, ["notes (2).txt", "notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn progress_only_goes_up_and_ends_at_100() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = dir.path().join("sources");
+        write_file(&sources.join("empty.txt"), b"");
+        write_file(&sources.join("small.txt"), b"a few bytes");
+        let large: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        write_file(&sources.join("large.bin"), &large);
+        
+        let cases = [
+            (CompressionType::Zip, vec![sources.clone()], "sources.zip"),
+            (CompressionType::TarGz, vec![sources.clone()], "sources.tar.gz"),
+            (CompressionType::Gz, vec![sources.join("large.bin")], "large.bin.gz"),
+        ];
+        for (compression_type, files, name) in cases {
+            let archive = dir.path().join(name);
+            let mut compressing = Vec::new();
+            compress_files_with_progress(&files, &archive, compression_type, &CompressOptions::default(), |percent, _| compressing.push(percent)).await.unwrap();
+            let mut extracting = Vec::new();
+            decompress_files_with_progress(&archive, &dir.path().join(format!("{}.out", name)), &ExtractOptions::default(), |percent, _| extracting.push(percent)).await.unwrap();
+            
+            for reported in [compressing, extracting] {
+                assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]), "{}: {:?}", name, reported);
+                assert_eq!(reported.last(), Some(&100.0), "{}", name);
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn encrypted_7z_needs_the_right_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("secret.txt");
+        write_file(&source, b"for your eyes only");
+        let archive = dir.path().join("secret.7z");
+        sevenz_rust::compress_to_path_encrypted(&source, &archive, "hunter2".into()).unwrap();
+        
+        let options = ExtractOptions { password: Some("hunter2".to_string()), ..ExtractOptions::default() };
+        decompress_files_with_progress(&archive, &dir.path().join("right"), &options, |_, _| {}).await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("right/secret.txt")).unwrap(), b"for your eyes only");
+        
+        let options = ExtractOptions { password: Some("hunter3".to_string()), ..ExtractOptions::default() };
+        let error = decompress_files_with_progress(&archive, &dir.path().join("wrong"), &options, |_, _| {}).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<CompressionError>(), Some(CompressionError::WrongPassword)), "{:?}", error);
+        assert!(!dir.path().join("wrong/secret.txt").exists());
+        
+        let error = decompress_files_with_progress(&archive, &dir.path().join("none"), &ExtractOptions::default(), |_, _| {}).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<CompressionError>(), Some(CompressionError::NeedsPassword)), "{:?}", error);
+    }
+    
+    #[tokio::test]
+    async fn drive_letter_and_rooted_entries_land_inside_the_output_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: [(&str, &[u8]); 4] = [("C:/dir/file.txt", b"drive"), ("D:\\back\\slash.txt", b"backslashes"), ("/rooted.txt", b"root"), ("/abs/deep.txt", b"deep")];
+        let zip = dir.path().join("absolute.zip");
+        zip_with(&zip, &files);
+        let tar = dir.path().join("absolute.tar");
+        tar_with_raw_names(&tar, &files);
+        
+        for archive in [zip, tar] {
+            let output = dir.path().join(archive.extension().unwrap()).join("out");
+            decompress_files_with_progress(&archive, &output, &ExtractOptions::default(), |_, _| {}).await.unwrap();
+            assert_eq!(std::fs::read(output.join("dir/file.txt")).unwrap(), b"drive");
+            assert_eq!(std::fs::read(output.join("back/slash.txt")).unwrap(), b"backslashes");
+            assert_eq!(std::fs::read(output.join("rooted.txt")).unwrap(), b"root");
+            assert_eq!(std::fs::read(output.join("abs/deep.txt")).unwrap(), b"deep");
+            // Nothing next to the output folder, and no folder named after the drive
+            assert_eq!(std::fs::read_dir(output.parent().unwrap()).unwrap().count(), 1);
+            assert!(!output.join("C:").exists() && !output.join("D:").exists());
+        }
+    }
+    
+    #[test]
+    fn job_journal_keeps_names_that_are_not_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = [b"plain.txt".to_vec(), b"caf\xe9.txt".to_vec()];
+        JobJournal::open(dir.path()).unwrap().record_part(names.iter().cloned()).unwrap();
+        
+        let journal = JobJournal::open(dir.path()).unwrap();
+        assert_eq!(journal.parts, 1);
+        assert_eq!(journal.done, names.into_iter().collect::<HashSet<_>>());
+    }
+}
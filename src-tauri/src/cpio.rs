@@ -0,0 +1,175 @@
+use anyhow::Result;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use crate::file_utils::path_from_bytes;
+
+// The "newc" (SVR4) cpio format, as used by the Linux initramfs
+
+const MAGIC: &[u8] = b"070701";
+// Same layout plus a data checksum; readable, though the checksum isn't verified
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+pub const MODE_DIR: u32 = 0o040000;
+pub const MODE_FILE: u32 = 0o100000;
+
+pub struct CpioHeader {
+    // Stored as is, since on Unix a name needn't be UTF-8
+    pub name: Vec<u8>,
+    // File type and permission bits, as in st_mode
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub size: u64,
+}
+
+impl CpioHeader {
+    // For messages and matching
+    pub fn name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        path_from_bytes(&self.name)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_FILE
+    }
+}
+
+pub struct CpioWriter<W: Write> {
+    inner: W,
+    next_ino: u32,
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, next_ino: 1 }
+    }
+
+    // Write one entry; `data` must provide exactly `header.size` bytes
+    pub fn append<R: Read>(&mut self, header: &CpioHeader, data: &mut R) -> Result<()> {
+        // Every header field is 8 hex digits
+        if header.size > u32::MAX as u64 {
+            return Err(anyhow::anyhow!("'{}' is too large for cpio (4 GiB limit)", header.name()));
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let nlink = if header.is_dir() { 2 } else { 1 };
+        self.write_header(ino, nlink, header)?;
+
+        let copied = std::io::copy(&mut data.take(header.size), &mut self.inner)?;
+        if copied != header.size {
+            return Err(anyhow::anyhow!("'{}' changed size while it was being archived", header.name()));
+        }
+        self.write_padding(header.size as usize)
+    }
+
+    // Write the trailer entry and hand back the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        let trailer = CpioHeader {
+            name: TRAILER.as_bytes().to_vec(),
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            size: 0,
+        };
+        self.write_header(0, 1, &trailer)?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self, ino: u32, nlink: u32, header: &CpioHeader) -> Result<()> {
+        let name_size = header.name.len() + 1;
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+        // rdevmajor, rdevminor, namesize, check
+        let fields = [
+            ino, header.mode, header.uid, header.gid, nlink, header.mtime, header.size as u32,
+            0, 0, 0, 0, name_size as u32, 0,
+        ];
+
+        self.inner.write_all(MAGIC)?;
+        for field in fields {
+            write!(self.inner, "{:08X}", field)?;
+        }
+        self.inner.write_all(&header.name)?;
+        self.inner.write_all(&[0])?;
+        self.write_padding(HEADER_LEN + name_size)
+    }
+
+    // Headers and data both end on a 4-byte boundary
+    fn write_padding(&mut self, len: usize) -> Result<()> {
+        let padding = (4 - len % 4) % 4;
+        self.inner.write_all(&[0u8; 3][..padding])?;
+        Ok(())
+    }
+}
+
+pub struct CpioReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> CpioReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    // The next entry, or None at the trailer. Its data has to be consumed with
+    // copy_data before the following header can be read.
+    pub fn next_header(&mut self) -> Result<Option<CpioHeader>> {
+        let mut header = [0u8; HEADER_LEN];
+        self.inner.read_exact(&mut header)?;
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+            return Err(anyhow::anyhow!("Not a newc cpio archive (unknown header magic)"));
+        }
+
+        let field = |index: usize| -> Result<u32> {
+            let hex = std::str::from_utf8(&header[6 + index * 8..14 + index * 8])?;
+            Ok(u32::from_str_radix(hex, 16)?)
+        };
+
+        let name_size = field(11)? as usize;
+        let mut name = vec![0u8; name_size];
+        self.inner.read_exact(&mut name)?;
+        self.skip_padding(HEADER_LEN + name_size)?;
+
+        let name = name.strip_suffix(&[0]).unwrap_or(&name).to_vec();
+        if name == TRAILER.as_bytes() {
+            return Ok(None);
+        }
+
+        Ok(Some(CpioHeader {
+            name,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            mtime: field(5)?,
+            size: field(6)? as u64,
+        }))
+    }
+
+    pub fn copy_data<W: Write>(&mut self, header: &CpioHeader, writer: &mut W) -> Result<u64> {
+        let copied = std::io::copy(&mut (&mut self.inner).take(header.size), writer)?;
+        if copied != header.size {
+            return Err(anyhow::anyhow!("Unexpected end of archive in '{}'", header.name()));
+        }
+        self.skip_padding(header.size as usize)?;
+        Ok(copied)
+    }
+
+    fn skip_padding(&mut self, len: usize) -> Result<()> {
+        let padding = (4 - len % 4) % 4;
+        self.inner.read_exact(&mut [0u8; 3][..padding])?;
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn preserve_file_attributes(source: &Path, _target: &Path) -> std::io::Result<()> {
     let metadata = std::fs::metadata(source)?;
@@ -47,4 +52,553 @@ pub fn get_unique_name(base_path: &Path) -> std::path::PathBuf {
     }
     
     unique_path
-}
\ No newline at end of file
+}
+
+// How generate_output_dir names the extraction folder when the plain name is taken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirNaming {
+    // Pattern with `{name}` and `{n}`, e.g. "{name} ({n})" or "{name}_{n}".
+    // The counter starts at 2 and goes up until the name is free.
+    Counter(String),
+    // Always `{name}_{timestamp}` (milliseconds since the epoch), which sorts
+    // chronologically and needs no exists-loop
+    Timestamp,
+}
+
+impl Default for DirNaming {
+    fn default() -> Self {
+        DirNaming::Counter("{name} ({n})".to_string())
+    }
+}
+
+// Endings made of more than one extension, removed whole so "a.tar.gz" names its folder
+// "a" rather than "a.tar"
+const COMPOUND_EXTENSIONS: &[&str] = &[
+    ".tar.gz", ".tar.bz2", ".tar.xz", ".tar.zst", ".tar.br", ".cpio.gz",
+    ".tgz", ".tbz2", ".tbz", ".txz", ".tzst",
+];
+
+// An archive's name without its archive extension(s)
+pub fn archive_base_name(file: &Path) -> String {
+    let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+    let bytes = file_name.as_bytes();
+    // Longer than the extension, so a name that is all extension (".tar.gz") is kept
+    COMPOUND_EXTENSIONS.iter()
+        .find(|ext| bytes.len() > ext.len() && bytes[bytes.len() - ext.len()..].eq_ignore_ascii_case(ext.as_bytes()))
+        .map(|ext| file_name[..file_name.len() - ext.len()].to_string())
+        .unwrap_or_else(|| file.file_stem().unwrap_or_default().to_string_lossy().to_string())
+}
+
+pub fn generate_output_dir(file: &Path, naming: &DirNaming) -> PathBuf {
+    generate_output_dir_in(file.parent().unwrap_or_else(|| Path::new(".")), file, naming)
+}
+
+// Like generate_output_dir, but the folder is created in `parent` instead of beside the archive
+pub fn generate_output_dir_in(parent: &Path, file: &Path, naming: &DirNaming) -> PathBuf {
+    let base_name = archive_base_name(file);
+    
+    match naming {
+        DirNaming::Timestamp => {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0);
+            parent.join(format!("{}_{}", base_name, millis))
+        },
+        DirNaming::Counter(pattern) => {
+            // A pattern without a counter would never produce a free name
+            let pattern = if pattern.contains("{n}") { pattern.as_str() } else { "{name} ({n})" };
+            
+            let mut counter = 1;
+            let mut output_dir = parent.join(&base_name);
+            
+            while output_dir.exists() {
+                counter += 1;
+                let name = pattern
+                    .replace("{name}", &base_name)
+                    .replace("{n}", &counter.to_string());
+                output_dir = parent.join(name);
+            }
+            
+            output_dir
+        },
+    }
+}
+
+// A scratch file that is removed when dropped unless it has been persisted.
+// Dropping on an error path or a cancelled operation therefore cleans it up.
+pub struct TempFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempFile {
+    pub fn new_in(dir: &Path, prefix: &str) -> std::io::Result<(Self, File)> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let path = dir.join(format!("{}{}_{}.tmp", prefix, std::process::id(), nanos));
+        let file = OpenOptions::new().write(true).read(true).create_new(true).open(&path)?;
+        Ok((Self { path, keep: false }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Move the scratch file to its final location; it is no longer cleaned up afterwards
+    pub fn persist(mut self, target: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, target)?;
+        self.keep = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Flush a folder's entries to disk, so a file just renamed into it stays there after a
+// power cut. Windows has no fsync for folders; NTFS journals renames itself.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// File descriptors left for everything else the process has open (the window,
+// the output archive, libraries, ...)
+const OPEN_FILES_HEADROOM: usize = 64;
+const MIN_OPEN_FILES: usize = 16;
+// No point in more even when the OS allows it
+const MAX_OPEN_FILES: usize = 4096;
+
+// Default for CompressOptions.max_open_files: the soft RLIMIT_NOFILE less some headroom
+#[cfg(unix)]
+pub fn default_max_open_files() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes into the struct it is given
+    let soft = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+        usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX)
+    } else {
+        256
+    };
+    soft.saturating_sub(OPEN_FILES_HEADROOM).clamp(MIN_OPEN_FILES, MAX_OPEN_FILES)
+}
+
+// Windows handles are only limited by memory
+#[cfg(not(unix))]
+pub fn default_max_open_files() -> usize {
+    MAX_OPEN_FILES
+}
+
+// A counting semaphore for open files: open() waits while `limit` files opened
+// through it are still open
+#[derive(Debug)]
+pub struct OpenFileLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl OpenFileLimit {
+    pub fn new(limit: usize) -> Self {
+        Self { available: Mutex::new(limit.max(1)), released: Condvar::new() }
+    }
+
+    pub fn open(self: &Arc<Self>, path: &Path) -> std::io::Result<LimitedFile> {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available == 0 {
+            available = self.released.wait(available).unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= 1;
+        drop(available);
+        
+        // The permit goes back as soon as it is dropped, including when opening fails
+        let permit = OpenFilePermit { limit: self.clone() };
+        Ok(LimitedFile { file: open_regular_file(path)?, _permit: permit })
+    }
+}
+
+// Open a file for reading, failing instead of blocking when it turns out to be a FIFO
+// (opening one waits for a writer) or some other non-regular file
+#[cfg(unix)]
+pub fn open_regular_file(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // O_NONBLOCK only changes how FIFOs and devices open; regular files read as usual
+    let file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+pub fn open_regular_file(path: &Path) -> std::io::Result<File> {
+    File::open(path)
+}
+
+struct OpenFilePermit {
+    limit: Arc<OpenFileLimit>,
+}
+
+impl Drop for OpenFilePermit {
+    fn drop(&mut self) {
+        *self.limit.available.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.limit.released.notify_one();
+    }
+}
+
+// A file opened through an OpenFileLimit; closing it frees its slot
+pub struct LimitedFile {
+    file: File,
+    _permit: OpenFilePermit,
+}
+
+impl LimitedFile {
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for LimitedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+// Paths listed in a file, as for `tar -T`: one per line, or NUL-separated (like
+// `find -print0`) when `nul_separated` is set. The text may be UTF-8, with or without a
+// BOM, or UTF-16 with a BOM (Notepad's "Unicode"). Lines are trimmed and blank ones
+// skipped; relative paths are taken relative to the list file's folder.
+pub fn read_file_list(list_path: &Path, nul_separated: bool) -> std::io::Result<Vec<PathBuf>> {
+    let bytes = std::fs::read(list_path)?;
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| from_bytes([pair[0], pair[1]])).collect();
+        String::from_utf16(&units).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    };
+    let text = if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        utf16(rest, u16::from_le_bytes)?
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        utf16(rest, u16::from_be_bytes)?
+    } else {
+        let rest = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(&bytes);
+        String::from_utf8(rest.to_vec()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+    };
+    
+    let base_dir = list_path.parent().unwrap_or_else(|| Path::new("."));
+    let separator = if nul_separated { '\0' } else { '\n' };
+    Ok(text.split(separator)
+        // NUL-separated names are taken exactly, since they may legitimately contain spaces
+        .map(|line| if nul_separated { line } else { line.trim() })
+        .filter(|line| !line.is_empty())
+        .map(|line| base_dir.join(line))
+        .collect())
+}
+
+// Block size holes are made in; matches the usual file system block
+const SPARSE_BLOCK: u64 = 4096;
+
+// Writes a new file without storing runs of zeros: each block that is entirely zero is
+// seeked over instead, which leaves a hole on file systems that support sparse files.
+// Call finish() so that a file ending in a hole still gets its full length.
+pub struct SparseWriter {
+    file: File,
+    position: u64,
+    // The current block's bytes so far, held until the block is complete, since writes
+    // seldom line up with blocks
+    block: Vec<u8>,
+    // Zero bytes skipped since the last real write
+    pending_hole: u64,
+}
+
+impl SparseWriter {
+    pub fn new(file: File) -> Self {
+        Self { file, position: 0, block: Vec::with_capacity(SPARSE_BLOCK as usize), pending_hole: 0 }
+    }
+
+    pub fn finish(mut self) -> std::io::Result<File> {
+        // A last partial block of zeros is left to set_len as well
+        if self.block.iter().all(|&byte| byte == 0) {
+            self.pending_hole += self.block.len() as u64;
+            self.block.clear();
+        }
+        self.write_block()?;
+        if self.pending_hole > 0 {
+            self.file.set_len(self.position)?;
+        }
+        self.file.flush()?;
+        Ok(self.file)
+    }
+
+    // Skip the held bytes if they are a whole block of zeros, or write them out
+    fn write_block(&mut self) -> std::io::Result<()> {
+        if self.block.len() == SPARSE_BLOCK as usize && self.block.iter().all(|&byte| byte == 0) {
+            self.pending_hole += SPARSE_BLOCK;
+        } else if !self.block.is_empty() {
+            if self.pending_hole > 0 {
+                self.file.seek(SeekFrom::Current(self.pending_hole as i64))?;
+                self.pending_hole = 0;
+            }
+            self.file.write_all(&self.block)?;
+        }
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Never cross a block boundary, so holes line up with the file system's blocks
+        let to_boundary = (SPARSE_BLOCK - self.position % SPARSE_BLOCK) as usize;
+        let chunk = &buf[..buf.len().min(to_boundary)];
+        self.block.extend_from_slice(chunk);
+        self.position += chunk.len() as u64;
+        if self.position % SPARSE_BLOCK == 0 {
+            self.write_block()?;
+        }
+        Ok(chunk.len())
+    }
+
+    // Writes out a partial block; the rest of that block is then written as it comes
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_block()?;
+        self.file.flush()
+    }
+}
+
+// The real path of an existing folder, every link on the way resolved. Windows gives
+// canonical paths the \\?\ prefix, which is dropped again for plain drive paths since
+// not every program accepts it.
+pub fn real_dir(path: &Path) -> std::io::Result<PathBuf> {
+    let real = std::fs::canonicalize(path)?;
+    #[cfg(target_os = "windows")]
+    if let Some(stripped) = real.to_str().and_then(|s| s.strip_prefix(r"\\?\")) {
+        if stripped.as_bytes().get(1) == Some(&b':') {
+            return Ok(PathBuf::from(stripped));
+        }
+    }
+    Ok(real)
+}
+
+// Bytes free on the disk holding `path` (an existing folder), for an unprivileged
+// user. None when the disk can't be asked.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs only reads the NUL-terminated path and writes into the struct it
+    // is given, which is plain data that zeroes are valid for
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// On Windows, from the mounted disk with the longest mount point above it. None when
+// no disk matches.
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    let path = real_dir(path).ok()?;
+    let disks = sysinfo::Disks::new_with_refreshed_list();
+    disks.list().iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+// An archive entry name as the bytes to store, with forward slashes. On Unix these are
+// the name's own bytes, which need not be UTF-8 (older systems wrote names in Latin-1
+// or Shift-JIS, and tar and cpio keep whatever bytes they are given); Windows names are
+// always Unicode.
+#[cfg(unix)]
+pub fn name_bytes(name: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub fn name_bytes(name: &Path) -> Vec<u8> {
+    name.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+// The reverse of name_bytes, for names read from an archive
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).to_string())
+}
+
+// Where compress_to_temp puts archives meant to be shared right away
+pub fn share_dir() -> PathBuf {
+    std::env::temp_dir().join("tauzip-share")
+}
+
+// Where unfinished compression jobs are remembered, so they can be resumed after a restart
+pub fn jobs_dir() -> PathBuf {
+    dirs::data_local_dir().unwrap_or_else(std::env::temp_dir).join("TauZip").join("jobs")
+}
+
+// Delete the files in `dir` last modified more than `max_age` ago; returns how many went
+pub fn remove_files_older_than(dir: &Path, max_age: Duration) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let expired = metadata.modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > max_age);
+        if metadata.is_file() && expired && std::fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Chop any file into `name.001`, `name.002`, ... of at most `part_size` bytes.
+// This is plain byte splitting; the parts only make sense once joined again.
+pub fn split_file(path: &Path, part_size: u64) -> std::io::Result<Vec<PathBuf>> {
+    if part_size == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Part size must be greater than zero"));
+    }
+    
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut parts = Vec::new();
+    
+    loop {
+        let part_path = path.with_file_name(format!("{}.{:03}", file_name, parts.len() + 1));
+        let mut writer = BufWriter::new(File::create(&part_path)?);
+        let copied = std::io::copy(&mut (&mut reader).take(part_size), &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        
+        // The previous part ended exactly at the end of the file
+        if copied == 0 && !parts.is_empty() {
+            std::fs::remove_file(&part_path)?;
+            break;
+        }
+        
+        parts.push(part_path);
+        if copied < part_size {
+            break;
+        }
+    }
+    
+    Ok(parts)
+}
+
+// Rebuild a file from its parts, given the first one (`name.001`).
+// The result is written beside the parts and never overwrites an existing file.
+// A gap in the numbering is an error naming the missing parts, not a short file.
+pub fn join_files(first_part: &Path) -> std::io::Result<PathBuf> {
+    let part_name = first_part.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let base_name = part_name.strip_suffix(".001").ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Expected the first part of a split file (.001)")
+    })?;
+    
+    let last_part = last_part_number(first_part, base_name)?;
+    let missing: Vec<String> = (1..=last_part)
+        .filter(|index| !first_part.with_file_name(format!("{}.{:03}", base_name, index)).exists())
+        .map(|index| format!("{:03}", index))
+        .collect();
+    if !missing.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Missing part(s) {} of '{}' (last part is {:03})", missing.join(", "), base_name, last_part),
+        ));
+    }
+    
+    let output_path = get_unique_name(&first_part.with_file_name(base_name));
+    let mut writer = BufWriter::new(File::create(&output_path)?);
+    
+    for index in 1..=last_part {
+        let part_path = first_part.with_file_name(format!("{}.{:03}", base_name, index));
+        let mut reader = BufReader::new(File::open(&part_path)?);
+        std::io::copy(&mut reader, &mut writer)?;
+    }
+    
+    writer.flush()?;
+    Ok(output_path)
+}
+
+// The highest `base_name.NNN` beside `first_part`
+fn last_part_number(first_part: &Path, base_name: &str) -> std::io::Result<u32> {
+    let dir = first_part.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let prefix = format!("{}.", base_name);
+    let mut last = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let number = name.strip_prefix(&prefix)
+            .filter(|suffix| suffix.len() >= 3 && suffix.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|suffix| suffix.parse::<u32>().ok());
+        if let Some(number) = number {
+            last = last.max(number);
+        }
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_base_name_strips_whole_archive_extensions() {
+        assert_eq!(archive_base_name(Path::new("/backups/archive.tar.gz")), "archive");
+        assert_eq!(archive_base_name(Path::new("archive.TAR.GZ")), "archive");
+        assert_eq!(archive_base_name(Path::new("archive.tgz")), "archive");
+        assert_eq!(archive_base_name(Path::new("photos.zip")), "photos");
+        assert_eq!(archive_base_name(Path::new("release.1.2.zip")), "release.1.2");
+    }
+
+    #[test]
+    fn archive_base_name_keeps_a_name_that_is_only_an_extension() {
+        assert_eq!(archive_base_name(Path::new(".zip")), ".zip");
+        assert!(!archive_base_name(Path::new(".tar.gz")).is_empty());
+        assert!(!archive_base_name(Path::new(".tgz")).is_empty());
+    }
+
+    #[test]
+    fn join_files_round_trips_split_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("data.bin");
+        let contents: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&original, &contents).unwrap();
+        let parts = split_file(&original, 1000).unwrap();
+        assert_eq!(parts.len(), 3);
+        std::fs::remove_file(&original).unwrap();
+
+        let joined = join_files(&parts[0]).unwrap();
+        assert_eq!(std::fs::read(joined).unwrap(), contents);
+    }
+
+    #[test]
+    fn join_files_names_missing_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        for index in [1, 2, 4, 6] {
+            std::fs::write(dir.path().join(format!("data.bin.{:03}", index)), b"part").unwrap();
+        }
+
+        let err = join_files(&dir.path().join("data.bin.001")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let message = err.to_string();
+        assert!(message.contains("003") && message.contains("005"), "{}", message);
+        assert!(!dir.path().join("data.bin").exists());
+    }
+}
@@ -1,6 +1,6 @@
-use super::compression::{compress_files, decompress_files_with_progress, CompressionType};
+use super::compression::{self, compress_files, CancelReport, decompress_files_with_progress, progress_is_estimated, quick_output_path, ArchiveEntry, ArchiveInspection, ArchivePage, BenchmarkResult, CompressJob, CompressionError, CompressionType, CompressOptions, CreatorGuess, EntryFailed, EntryText, ExtractOptions, ExtractSummary, ExtractionEstimate, GroupBy, ManifestReport, OperationPhase, PhaseChange, ScanProgress, OptimizeResult, RebuildResult, ReplaceResult, RequestProblem, TreeNode};
 use anyhow::Result;
-use std::ffi::c_void;
+use std::ffi::{c_void, OsStr};
 use std::path::{PathBuf, Path};
 use std::thread;
 use std::time::Duration;
@@ -13,14 +13,60 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 //use tauri_plugin_shell::ShellExt;
 use sysinfo::{System, Process, Signal};
 use crate::GuiState;
+use crate::codec;
+use crate::file_utils::{self, generate_output_dir_in};
+use crate::password::{self, PasswordPolicy};
+use crate::settings::Settings;
+use crate::zipcrypt;
 
 #[derive(Clone, Serialize)]
 pub struct CompressionProgressUpdate {
     progress: f64,
     current_file: String,
+    // Archive (compressing) or folder (extracting) the update belongs to
+    current_output: String,
     total_files: usize,
     current_file_index: usize,
-    operation: String, // "compressing" or "extracting"
+    operation: OperationPhase,
+    // Progress comes from compressed bytes read rather than known output size
+    estimated: bool,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ArchiveStartedEvent {
+    path: String,
+    index: usize,
+    total: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct EntryFailedEvent {
+    archive: String,
+    name: String,
+    error: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ArchiveFinishedEvent {
+    path: String,
+    output_dir: String,
+    success: bool,
+}
+
+// Sent once an operation succeeds, with the exact paths it produced, so the frontend
+// doesn't have to parse them back out of the result message
+// Sent when the disk fills up part way through, so the window can offer to retry once
+// space is freed; the operation still fails with CompressionError::DiskFull
+#[derive(Clone, Serialize)]
+pub struct DiskFullEvent {
+    operation: OperationPhase,
+    bytes_written: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct OperationCompletedEvent {
+    operation: OperationPhase,
+    output_paths: Vec<String>,
 }
 
 fn count_processes_by_name(name: &str) -> usize {
@@ -46,15 +92,41 @@ fn kill_processes_by_name(name: &str) {
 }
 
 
+// Mirror progress onto the taskbar button so it is visible while the window is minimized.
+// Passing None clears the indicator.
+#[cfg(target_os = "windows")]
+fn set_taskbar_progress(window: &tauri::Window, progress: Option<f64>) {
+    use tauri::window::{ProgressBarState, ProgressBarStatus};
+    
+    let state = match progress {
+        Some(progress) => ProgressBarState {
+            status: Some(ProgressBarStatus::Normal),
+            progress: Some(progress.clamp(0.0, 100.0) as u64),
+        },
+        None => ProgressBarState {
+            status: Some(ProgressBarStatus::None),
+            progress: None,
+        },
+    };
+    let _ = window.set_progress_bar(state);
+}
+
 #[tauri::command]
 async fn close_all() {
 	kill_processes_by_name("TauZip.exe");
 }
 
+// The window confirms every batch of paths it gets from files-selected/archives-selected.
+// Once it has confirmed as many as were forwarded to it, OK can be enabled.
 #[tauri::command]
-async fn count_now(count: usize, state: tauri::State<'_, Arc<GuiState>>) -> Result<(), String> {
-	*state.count_now.lock().unwrap() = count;
-	return Ok(());
+async fn files_received(app: AppHandle, count: usize, state: tauri::State<'_, Arc<GuiState>>) -> Result<(), String> {
+	state.touch();
+	let mut files = state.files.lock().unwrap();
+	files.acknowledged += count;
+	if files.forwarded > 0 && files.acknowledged >= files.forwarded {
+		let _ = app.emit("enable-ok", "");
+	}
+	Ok(())
 }
 
 #[tauri::command]
@@ -63,6 +135,7 @@ async fn compress_files_command(
     files: Vec<String>, 
     outputfile: String, 
     compressiontype: String,
+    options: Option<CompressOptions>,
 	state: tauri::State<'_, Arc<GuiState>>
 ) -> Result<String, String> {
     println!("Compression request received - files: {:?}, output: {}, type: {}", 
@@ -75,120 +148,509 @@ async fn compress_files_command(
 	}
 	
     // Convert string to CompressionType enum
-    let compression_enum = match compressiontype.as_str() {
-        "Zip" => CompressionType::Zip,
-        "TarGz" => CompressionType::TarGz,
-        "TarBr" => CompressionType::TarBr,
-        "Gz" => CompressionType::Gz,
-        "Br" => CompressionType::Br,
-        "Gzip" => CompressionType::Gzip,
-        "Bzip2" => CompressionType::Bzip2,
-        _ => return Err(format!("Unsupported compression type: {}", compressiontype)),
-    };
+    let compression_enum = parse_compression_type(&compressiontype)?;
     
-    // Convert string paths back to PathBuf
-    let file_paths: Vec<PathBuf> = files.iter().map(|f| PathBuf::from(f)).collect();
+    let mut options = options.unwrap_or_else(|| Settings::load().compress);
+    
+    // Convert string paths back to PathBuf, plus any from the options' file list
+    let file_paths = options.source_files(files.iter().map(|f| PathBuf::from(f)).collect())
+        .map_err(|e| e.to_string())?;
+    
+    let output_path = resolve_output_path(&outputfile, &file_paths);
+    
+    // Use the new progress version
+    use super::compression::compress_files_with_progress;
+    
+    state.cancel_requested.store(false, Ordering::SeqCst);
+    options.cancel = Some(state.cancel_requested.clone());
+    options.scan_progress = Some(scan_progress(window.app_handle().clone(), output_path.display().to_string()));
+    options.phase_change = Some(phase_change(window.app_handle().clone(), output_path.display().to_string()));
     
-    // Construct the full output path
-    let output_path = if std::path::Path::new(&outputfile).is_absolute() {
+    // One archive per group; without group_by that's the single requested archive
+    let groups = compression::group_sources(&file_paths, options.group_by);
+    let total_groups = groups.len();
+    let mut produced: Vec<PathBuf> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    
+    for (index, (group, group_files)) in groups.iter().enumerate() {
+        let group_output = if options.group_by == GroupBy::None {
+            output_path.clone()
+        } else {
+            compression::group_output_path(&output_path, group, &compression_enum)
+        };
+        println!("Compressing group '{}' ({} file(s)) to {}", group, group_files.len(), group_output.display());
+        
+        let result = compress_files_with_progress(group_files, &group_output, compression_enum.clone(), &options, |progress, current_filename| {
+            let progress_update = CompressionProgressUpdate {
+                progress,
+                current_file: current_filename,
+                current_output: group_output.display().to_string(),
+                total_files: total_groups,
+                current_file_index: index + 1,
+                operation: OperationPhase::Compressing,
+                estimated: false,
+            };
+            let _ = window.app_handle().emit("compression-progress", &progress_update);
+            #[cfg(target_os = "windows")]
+            set_taskbar_progress(&window, Some(progress));
+        })
+        .await;
+        
+        // Auto appends the extension of the format it picked
+        match result {
+            Ok(result) => {
+                produced.push(result.output_path);
+                warnings.extend(result.warnings);
+            },
+            Err(e) => {
+                #[cfg(target_os = "windows")]
+                set_taskbar_progress(&window, None);
+                
+                let error_msg = compression_error(&window, e);
+                println!("{}", error_msg);
+                return Err(error_msg);
+            }
+        }
+    }
+    
+    #[cfg(target_os = "windows")]
+    set_taskbar_progress(&window, None);
+    
+    let output_paths: Vec<String> = produced.iter().map(|p| p.display().to_string()).collect();
+    
+    // Final progress update
+    let final_progress = CompressionProgressUpdate {
+        progress: 100.0,
+        current_file: "Complete".to_string(),
+        current_output: output_paths.last().cloned().unwrap_or_default(),
+        total_files: total_groups,
+        current_file_index: total_groups,
+        operation: OperationPhase::Compressing,
+        estimated: false,
+    };
+    let _ = window.emit("compression-progress", &final_progress);
+    let _ = window.emit("operation-completed", &OperationCompletedEvent {
+        operation: OperationPhase::Compressing,
+        output_paths: output_paths.clone(),
+    });
+    
+    let mut success_msg = format!("Files compressed successfully to: {}", output_paths.join(", "));
+    if !warnings.is_empty() {
+        success_msg.push_str(&format!("\n{}", warnings.join("\n")));
+    }
+    println!("{}", success_msg);
+    Ok(success_msg)
+}
+
+// Construct the full output path
+fn resolve_output_path(outputfile: &str, file_paths: &[PathBuf]) -> PathBuf {
+    let output_path = if std::path::Path::new(outputfile).is_absolute() {
         // If it's already an absolute path, use it as-is
-        PathBuf::from(&outputfile)
+        PathBuf::from(outputfile)
     } else {
         // If it's a relative path, use the directory of the first file
         if !file_paths.is_empty() {
             let first_file_dir = file_paths[0]
                 .parent()
                 .unwrap_or_else(|| std::path::Path::new("."));
-            first_file_dir.join(&outputfile)
+            first_file_dir.join(outputfile)
         } else {
-            PathBuf::from(&outputfile)
+            PathBuf::from(outputfile)
         }
     };
     
     println!("Output path resolved to: {}", output_path.display());
+    output_path
+}
+
+// Progress of a resumable job, tagged with its id
+#[derive(Clone, Serialize)]
+pub struct JobProgressUpdate {
+    job_id: String,
+    #[serde(flatten)]
+    update: CompressionProgressUpdate,
+}
+
+#[derive(Clone, Serialize)]
+pub struct JobFinishedEvent {
+    job_id: String,
+    output_path: Option<String>,
+    error: Option<String>,
+}
+
+// Start compressing as a resumable job and return its id right away. Progress comes as
+// "compression-progress" events carrying the id, and the outcome as "job-finished". An
+// interrupted job is continued with resume_compress_job.
+#[tauri::command]
+async fn start_compress_job(
+    window: tauri::Window,
+    files: Vec<String>,
+    outputfile: String,
+    compressiontype: String,
+    options: Option<CompressOptions>,
+    state: tauri::State<'_, Arc<GuiState>>
+) -> Result<String, String> {
+    let compression_enum = parse_compression_type(&compressiontype)?;
+    let options = options.unwrap_or_else(|| Settings::load().compress);
+    let file_paths = options.source_files(files.iter().map(|f| PathBuf::from(f)).collect())
+        .map_err(|e| e.to_string())?;
+    let output_path = resolve_output_path(&outputfile, &file_paths);
+    
+    let job = CompressJob::create(file_paths, output_path, compression_enum, options)
+        .map_err(|e| format!("Failed to start job: {}", e))?;
+    let job_id = job.id.clone();
+    spawn_compress_job(window, job, state.inner().clone());
+    Ok(job_id)
+}
+
+// Continue a job that was cancelled, failed or cut off by a restart; the parts it had
+// finished are kept
+#[tauri::command]
+async fn resume_compress_job(
+    window: tauri::Window,
+    job_id: String,
+    state: tauri::State<'_, Arc<GuiState>>
+) -> Result<String, String> {
+    let job = CompressJob::load(&job_id)
+        .map_err(|e| format!("Failed to resume job '{}': {}", job_id, e))?;
+    spawn_compress_job(window, job, state.inner().clone());
+    Ok(job_id)
+}
+
+fn spawn_compress_job(window: tauri::Window, mut job: CompressJob, state: Arc<GuiState>) {
+    state.cancel_requested.store(false, Ordering::SeqCst);
+    job.options.cancel = Some(state.cancel_requested.clone());
+    
+    tauri::async_runtime::spawn(async move {
+        let output = job.output_path.display().to_string();
+        let progress_window = window.clone();
+        let result = compression::run_compress_job(&job, |progress, current_filename| {
+            let progress_update = JobProgressUpdate {
+                job_id: job.id.clone(),
+                update: CompressionProgressUpdate {
+                    progress,
+                    current_file: current_filename,
+                    current_output: output.clone(),
+                    total_files: 1,
+                    current_file_index: 1,
+                    operation: OperationPhase::Compressing,
+                    estimated: false,
+                },
+            };
+            let _ = progress_window.emit("compression-progress", &progress_update);
+        })
+        .await;
+        
+        let finished = match result {
+            Ok(output_path) => JobFinishedEvent {
+                job_id: job.id.clone(),
+                output_path: Some(output_path.display().to_string()),
+                error: None,
+            },
+            Err(e) => {
+                let error_msg = compression_error(&window, e);
+                println!("Job {} stopped: {}", job.id, error_msg);
+                JobFinishedEvent { job_id: job.id.clone(), output_path: None, error: Some(error_msg) }
+            },
+        };
+        let _ = window.emit("job-finished", &finished);
+    });
+}
+
+// The message for a failed compression. A cancelled one also sends its CancelReport as
+// "operation-cancelled", so the window can say how far it got.
+fn compression_error(window: &tauri::Window, e: anyhow::Error) -> String {
+    report_disk_full(window, OperationPhase::Compressing, &e);
+    match e.downcast_ref::<CancelReport>() {
+        Some(report) => {
+            let _ = window.emit("compression-progress", &CompressionProgressUpdate {
+                progress: 0.0,
+                current_file: String::new(),
+                current_output: String::new(),
+                total_files: 0,
+                current_file_index: 0,
+                operation: OperationPhase::Cancelled,
+                estimated: false,
+            });
+            let _ = window.emit("operation-cancelled", report);
+            report.to_string()
+        },
+        None => format!("Compression failed: {}", e),
+    }
+}
+
+fn report_disk_full(window: &tauri::Window, operation: OperationPhase, e: &anyhow::Error) {
+    if let Some(CompressionError::DiskFull { bytes_written }) = e.downcast_ref::<CompressionError>() {
+        let _ = window.emit("disk-full", &DiskFullEvent {
+            operation,
+            bytes_written: *bytes_written,
+        });
+    }
+}
+
+// Reports the walk over the sources as its own "scanning" phase, with the number of
+// files found so far, before there is anything to compress
+fn scan_progress(app: AppHandle, output: String) -> ScanProgress {
+    ScanProgress(Arc::new(move |found| {
+        let progress_update = CompressionProgressUpdate {
+            progress: 0.0,
+            current_file: format!("{} files found", found),
+            current_output: output.clone(),
+            total_files: 0,
+            current_file_index: 0,
+            operation: OperationPhase::Scanning,
+            estimated: true,
+        };
+        let _ = app.emit("compression-progress", &progress_update);
+    }))
+}
+
+// Reports the phases after the last byte of progress (see CompressOptions::phase_change)
+fn phase_change(app: AppHandle, output: String) -> PhaseChange {
+    PhaseChange(Arc::new(move |phase| {
+        let progress_update = CompressionProgressUpdate {
+            progress: 100.0,
+            current_file: String::new(),
+            current_output: output.clone(),
+            total_files: 0,
+            current_file_index: 0,
+            operation: phase,
+            estimated: false,
+        };
+        let _ = app.emit("compression-progress", &progress_update);
+    }))
+}
+
+// Compress beside the sources with an automatically chosen name, e.g. for "Compress here".
+// Returns the path of the created archive.
+#[tauri::command]
+async fn quick_compress(
+    window: tauri::Window,
+    files: Vec<String>,
+    compressiontype: String,
+    state: tauri::State<'_, Arc<GuiState>>
+) -> Result<String, String> {
+    let compression_enum = parse_compression_type(&compressiontype)?;
+    let file_paths: Vec<PathBuf> = files.iter().map(|f| PathBuf::from(f)).collect();
+    
+    if !compression_enum.supports_multiple_files() && file_paths.len() > 1 {
+        return Err(format!("Compression type {} does not support multiple files", compressiontype));
+    }
+    
+    let output_path = quick_output_path(&file_paths, &compression_enum).map_err(|e| e.to_string())?;
+    println!("Quick compress to: {}", output_path.display());
     
-    // Use the new progress version
     use super::compression::compress_files_with_progress;
     
-    compress_files_with_progress(&file_paths, &output_path, compression_enum, |progress, current_filename| {
+    state.cancel_requested.store(false, Ordering::SeqCst);
+    let options = CompressOptions {
+        cancel: Some(state.cancel_requested.clone()),
+        scan_progress: Some(scan_progress(window.app_handle().clone(), output_path.display().to_string())),
+        phase_change: Some(phase_change(window.app_handle().clone(), output_path.display().to_string())),
+        ..Settings::load().compress
+    };
+    
+    let output_path = compress_files_with_progress(&file_paths, &output_path, compression_enum, &options, |progress, current_filename| {
         let progress_update = CompressionProgressUpdate {
             progress,
             current_file: current_filename,
+            current_output: output_path.display().to_string(),
             total_files: file_paths.len(),
             current_file_index: 1,
-            operation: "compressing".to_string(),
+            operation: OperationPhase::Compressing,
+            estimated: false,
         };
         let _ = window.app_handle().emit("compression-progress", &progress_update);
     })
     .await
-    .map_err(|e| {
-        let error_msg = format!("Compression failed: {}", e);
-        println!("{}", error_msg);
-        error_msg
-    })?;
+    .map_err(|e| compression_error(&window, e))?
+    .output_path;
     
-    // Final progress update
-    let final_progress = CompressionProgressUpdate {
-        progress: 100.0,
-        current_file: "Complete".to_string(),
-        total_files: 1,
-        current_file_index: 1,
-        operation: "compressing".to_string(),
-    };
-    let _ = window.emit("compression-progress", &final_progress);
+    let _ = window.emit("operation-completed", &OperationCompletedEvent {
+        operation: OperationPhase::Compressing,
+        output_paths: vec![output_path.display().to_string()],
+    });
     
-    let success_msg = format!("Files compressed successfully to: {}", output_path.display());
-    println!("{}", success_msg);
-    Ok(success_msg)
+    Ok(output_path.display().to_string())
+}
+
+// Compress into a private temp folder instead of beside the sources, for handing the
+// archive straight to a share sheet or email. Returns the path of the created archive;
+// anything left there is removed a day later, on the next startup.
+#[tauri::command]
+async fn compress_to_temp(
+    window: tauri::Window,
+    files: Vec<String>,
+    compressiontype: String,
+    options: Option<CompressOptions>,
+    state: tauri::State<'_, Arc<GuiState>>
+) -> Result<String, String> {
+    let compression_enum = parse_compression_type(&compressiontype)?;
+    let mut options = options.unwrap_or_else(|| Settings::load().compress);
+    let file_paths = options.source_files(files.iter().map(|f| PathBuf::from(f)).collect())
+        .map_err(|e| e.to_string())?;
+    
+    if !compression_enum.supports_multiple_files() && file_paths.len() > 1 {
+        return Err(format!("Compression type {} does not support multiple files", compressiontype));
+    }
+    
+    let share_dir = file_utils::share_dir();
+    std::fs::create_dir_all(&share_dir).map_err(|e| format!("Failed to create {}: {}", share_dir.display(), e))?;
+    // Same name quick_compress would pick, only in the share folder
+    let file_name = quick_output_path(&file_paths, &compression_enum).map_err(|e| e.to_string())?
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    let output_path = file_utils::get_unique_name(&share_dir.join(file_name));
+    println!("Compress to temp: {}", output_path.display());
+    
+    use super::compression::compress_files_with_progress;
+    
+    state.cancel_requested.store(false, Ordering::SeqCst);
+    options.cancel = Some(state.cancel_requested.clone());
+    options.scan_progress = Some(scan_progress(window.app_handle().clone(), output_path.display().to_string()));
+    options.phase_change = Some(phase_change(window.app_handle().clone(), output_path.display().to_string()));
+    
+    let output_path = compress_files_with_progress(&file_paths, &output_path, compression_enum, &options, |progress, current_filename| {
+        let progress_update = CompressionProgressUpdate {
+            progress,
+            current_file: current_filename,
+            current_output: output_path.display().to_string(),
+            total_files: file_paths.len(),
+            current_file_index: 1,
+            operation: OperationPhase::Compressing,
+            estimated: false,
+        };
+        let _ = window.app_handle().emit("compression-progress", &progress_update);
+    })
+    .await
+    .map_err(|e| compression_error(&window, e))?
+    .output_path;
+    
+    Ok(output_path.display().to_string())
 }
 
 #[tauri::command]
 async fn decompress_files_command(
     window: tauri::Window,
-    files: Vec<String>
+    files: Vec<String>,
+    options: Option<ExtractOptions>,
+    // Folder to extract into; beside each archive when not given
+    output_dir: Option<String>,
+    // Wrap each archive's contents in a folder named after it (the default). Without
+    // it everything goes straight into the output folder, and files already there are
+    // handled by the conflict policy.
+    create_subfolder: Option<bool>,
 ) -> Result<String, String> {
     println!("Decompression request received - files: {:?}", files);
     
-    let file_paths: Vec<PathBuf> = files.iter().map(|f| PathBuf::from(f)).collect();
+    let options = options.unwrap_or_else(|| Settings::load().extract);
+    let file_paths = compression::distinct_archives(&files.iter().map(PathBuf::from).collect::<Vec<_>>());
     let total_files = file_paths.len();
+    let create_subfolder = create_subfolder.unwrap_or(true);
+    let chosen_dir = output_dir.map(PathBuf::from);
     
     let mut decompressed_to = Vec::new();
+    let mut failed_entries = 0usize;
+    let mut warnings: Vec<String> = Vec::new();
+    
+    // Each archive advances the overall bar in proportion to its size, so a small
+    // archive next to a huge one doesn't count for half of the work
+    let weights: Vec<u64> = file_paths.iter().map(|path| compression::get_archive_size(path).max(1)).collect();
+    let total_weight: u64 = weights.iter().sum();
+    let mut completed_weight = 0u64;
     
     for (index, file_path) in file_paths.iter().enumerate() {
         // Generate output directory for this file
-        let output_dir = generate_output_dir(file_path);
+        let parent = chosen_dir.clone()
+            .unwrap_or_else(|| file_path.parent().unwrap_or_else(|| Path::new(".")).to_path_buf());
+        let output_dir = if let Some(merge_dir) = &options.merge_into {
+            // Merging always writes into the chosen existing folder
+            merge_dir.clone()
+        } else if create_subfolder {
+            // Resuming continues in the folder the interrupted run was writing to
+            let resumable = if options.resume && chosen_dir.is_none() {
+                compression::find_resumable_output_dir(file_path)
+            } else {
+                None
+            };
+            resumable.unwrap_or_else(|| generate_output_dir_in(&parent, file_path, &options.dir_naming))
+        } else {
+            parent
+        };
+        let estimated = progress_is_estimated(file_path);
         
         // Update progress
         let progress = CompressionProgressUpdate {
-            progress: (index as f64 / total_files as f64) * 100.0,
+            progress: (completed_weight as f64 / total_weight as f64) * 100.0,
             current_file: file_path.file_name()
                 .unwrap_or_default()
                 .to_string_lossy()
                 .to_string(),
+            current_output: output_dir.display().to_string(),
             total_files,
             current_file_index: index + 1,
-            operation: "extracting".to_string(),
+            operation: OperationPhase::Extracting,
+            estimated: false,
         };
         
         let _ = window.emit("compression-progress", &progress);
         
+        let _ = window.emit("archive-started", &ArchiveStartedEvent {
+            path: file_path.display().to_string(),
+            index,
+            total: total_files,
+        });
+        
+        // Entries continue_on_error skips are reported as they fail
+        let mut archive_options = options.clone();
+        let (app, archive) = (window.app_handle().clone(), file_path.display().to_string());
+        archive_options.entry_failed = Some(EntryFailed(Arc::new(move |failed| {
+            let _ = app.emit("entry-failed", &EntryFailedEvent {
+                archive: archive.clone(),
+                name: failed.name.clone(),
+                error: failed.error.clone(),
+            });
+        })));
+        
         // Decompress the file
-        match decompress_files_with_progress(file_path, &output_dir, |file_progress, current_filename| {
+        let result = decompress_files_with_progress(file_path, &output_dir, &archive_options, |file_progress, current_filename| {
             // Create a more detailed progress update
+            let done = completed_weight as f64 + weights[index] as f64 * file_progress / 100.0;
+            let overall_progress = (done / total_weight as f64) * 100.0;
             let detailed_progress = CompressionProgressUpdate {
-                progress: ((index as f64 + file_progress / 100.0) / total_files as f64) * 100.0,
+                progress: overall_progress,
                 current_file: current_filename,
+                current_output: output_dir.display().to_string(),
                 total_files,
                 current_file_index: index + 1,
-                operation: "extracting".to_string(),
+                operation: OperationPhase::Extracting,
+                estimated,
             };
             let _ = window.emit("compression-progress", &detailed_progress);
-        }).await {
-            Ok(_) => {
+            #[cfg(target_os = "windows")]
+            set_taskbar_progress(&window, Some(overall_progress));
+        }).await;
+        
+        let _ = window.emit("archive-finished", &ArchiveFinishedEvent {
+            path: file_path.display().to_string(),
+            output_dir: output_dir.display().to_string(),
+            success: result.is_ok(),
+        });
+        
+        match result {
+            Ok(summary) => {
+                failed_entries += summary.failed_entries.len();
+                warnings.extend(summary.warnings);
+                completed_weight += weights[index];
                 decompressed_to.push(output_dir.display().to_string());
                 println!("File decompressed to: {}", output_dir.display());
             },
             Err(e) => {
+                #[cfg(target_os = "windows")]
+                set_taskbar_progress(&window, None);
+                
+                report_disk_full(&window, OperationPhase::Extracting, &e);
                 let error_msg = format!("Failed to decompress '{}': {}", file_path.display(), e);
                 println!("{}", error_msg);
                 return Err(error_msg);
@@ -200,48 +662,304 @@ async fn decompress_files_command(
     let final_progress = CompressionProgressUpdate {
         progress: 100.0,
         current_file: "Complete".to_string(),
+        // The whole batch is done; each folder is in operation-completed
+        current_output: String::new(),
         total_files,
         current_file_index: total_files,
-        operation: "extracting".to_string(),
+        operation: OperationPhase::Extracting,
+        estimated: false,
     };
     let _ = window.app_handle().emit("compression-progress", &final_progress);
     
-    let success_msg = if decompressed_to.len() == 1 {
+    #[cfg(target_os = "windows")]
+    set_taskbar_progress(&window, None);
+    
+    let _ = window.emit("operation-completed", &OperationCompletedEvent {
+        operation: OperationPhase::Extracting,
+        output_paths: decompressed_to.clone(),
+    });
+    
+    let mut success_msg = if decompressed_to.len() == 1 {
         format!("File decompressed successfully to: {}", decompressed_to[0])
     } else {
         format!("Files decompressed successfully. {} archives processed.", decompressed_to.len())
     };
+    if failed_entries > 0 {
+        success_msg.push_str(&format!(" {} entries could not be extracted.", failed_entries));
+    }
+    if !warnings.is_empty() {
+        success_msg.push_str(&format!("\n{}", warnings.join("\n")));
+    }
     
     println!("{}", success_msg);
     Ok(success_msg)
 }
 
+// Any registered codec's name, or "Auto"
+fn parse_compression_type(compressiontype: &str) -> Result<CompressionType, String> {
+    CompressionType::from_name(compressiontype)
+        .ok_or_else(|| format!("Unsupported compression type: {}", compressiontype))
+}
+
+#[tauri::command]
+async fn split_file(path: String, part_size: u64) -> Result<Vec<String>, String> {
+    file_utils::split_file(Path::new(&path), part_size)
+        .map(|parts| parts.iter().map(|part| part.display().to_string()).collect())
+        .map_err(|e| format!("Failed to split '{}': {}", path, e))
+}
+
+#[tauri::command]
+async fn join_files(first_part: String) -> Result<String, String> {
+    file_utils::join_files(Path::new(&first_part))
+        .map(|output| output.display().to_string())
+        .map_err(|e| format!("Failed to join '{}': {}", first_part, e))
+}
+
+#[tauri::command]
+async fn list_archive_contents(path: String, metadata_field: Option<u16>, normalize_paths: Option<bool>, zstd_dictionary: Option<String>) -> Result<Vec<ArchiveEntry>, String> {
+    compression::list_archive_contents(Path::new(&path), metadata_field, normalize_paths.unwrap_or(true), zstd_dictionary.as_deref().map(Path::new))
+        .map_err(|e| format!("Failed to list '{}': {}", path, e))
+}
+
+#[tauri::command]
+async fn list_archive_page(path: String, offset: usize, limit: usize, metadata_field: Option<u16>, normalize_paths: Option<bool>, zstd_dictionary: Option<String>) -> Result<ArchivePage, String> {
+    compression::list_archive_page(Path::new(&path), offset, limit, metadata_field, normalize_paths.unwrap_or(true), zstd_dictionary.as_deref().map(Path::new))
+        .map_err(|e| format!("Failed to list '{}': {}", path, e))
+}
+
+// Checked before an "extract here", so the frontend can offer a new folder when the
+// archive would scatter many loose items
+#[tauri::command]
+async fn inspect_archive(path: String, threshold: Option<usize>) -> Result<ArchiveInspection, String> {
+    compression::inspect_archive(Path::new(&path), threshold)
+        .map_err(|e| format!("Failed to inspect '{}': {}", path, e))
+}
+
+// How long extracting the selected archives should take, summed over all of them
+#[tauri::command]
+async fn estimate_extraction(paths: Vec<String>) -> Result<ExtractionEstimate, String> {
+    let mut total = ExtractionEstimate::default();
+    for path in &paths {
+        let estimate = compression::estimate_extraction(Path::new(path))
+            .map_err(|e| format!("Failed to estimate '{}': {}", path, e))?;
+        total.add(&estimate);
+    }
+    Ok(total)
+}
+
+// Compress a sample of the selection with each codec, for a speed/ratio comparison.
+// Every codec runs in turn, so it gets a blocking thread instead of holding up the runtime.
+#[tauri::command]
+async fn benchmark_formats(files: Vec<String>, level: Option<u32>) -> Result<Vec<BenchmarkResult>, String> {
+    let file_paths: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    tokio::task::spawn_blocking(move || compression::benchmark_formats(&file_paths, level))
+        .await
+        .map_err(|e| format!("Failed to benchmark formats: {}", e))?
+        .map_err(|e| format!("Failed to benchmark formats: {}", e))
+}
+
+// The archive's entries nested by folder, for a tree view
+#[tauri::command]
+async fn list_archive_tree(path: String) -> Result<TreeNode, String> {
+    compression::list_archive_tree(Path::new(&path))
+        .map_err(|e| format!("Failed to list '{}': {}", path, e))
+}
+
+#[tauri::command]
+async fn read_entry_text(path: String, entry_name: String, zstd_dictionary: Option<String>) -> Result<EntryText, String> {
+    compression::read_entry_text(Path::new(&path), &entry_name, zstd_dictionary.as_deref().map(Path::new))
+        .map_err(|e| format!("Failed to read '{}' from '{}': {}", entry_name, path, e))
+}
+
+#[tauri::command]
+async fn verify_against_manifest(path: String, manifest: String, zstd_dictionary: Option<String>) -> Result<ManifestReport, String> {
+    compression::verify_against_manifest(Path::new(&path), Path::new(&manifest), zstd_dictionary.as_deref().map(Path::new))
+        .map_err(|e| format!("Failed to verify '{}' against '{}': {}", path, manifest, e))
+}
+
+// Train a zstd dictionary for TarZst from sample files; returns the dictionary's id
+#[tauri::command]
+async fn train_dictionary(files: Vec<String>, output: String, max_size: Option<usize>) -> Result<u32, String> {
+    let samples: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    compression::train_zstd_dictionary(&samples, Path::new(&output), max_size)
+        .map_err(|e| format!("Failed to train dictionary '{}': {}", output, e))
+}
+
+// Add files to the end of an uncompressed .tar in place
+#[tauri::command]
+async fn append_to_tar(
+    window: tauri::Window,
+    path: String,
+    files: Vec<String>,
+    options: Option<CompressOptions>,
+) -> Result<(), String> {
+    let options = options.unwrap_or_else(|| Settings::load().compress);
+    let file_paths: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    compression::append_to_tar(Path::new(&path), &file_paths, &options, |progress, current_file| {
+        let progress_update = CompressionProgressUpdate {
+            progress,
+            current_file,
+            current_output: path.clone(),
+            total_files: 1,
+            current_file_index: 1,
+            operation: OperationPhase::Archiving,
+            estimated: false,
+        };
+        let _ = window.emit("compression-progress", &progress_update);
+    })
+    .await
+    .map_err(|e| format!("Failed to append to '{}': {}", path, e))
+}
+
+// Extract only the entries of one archive whose path matches a glob such as "*.jpg",
+// using the saved extraction settings. Progress covers just the matching entries.
+#[tauri::command]
+async fn extract_matching(
+    window: tauri::Window,
+    archive: String,
+    pattern: String,
+    output_dir: String,
+) -> Result<ExtractSummary, String> {
+    // ExtractOptions has private fields, so it can't be built with ..settings here
+    let mut options = Settings::load().extract;
+    options.pattern = Some(pattern);
+    let archive_path = PathBuf::from(&archive);
+    let estimated = progress_is_estimated(&archive_path);
+    decompress_files_with_progress(&archive_path, Path::new(&output_dir), &options, |progress, current_file| {
+        let progress_update = CompressionProgressUpdate {
+            progress,
+            current_file,
+            current_output: output_dir.clone(),
+            total_files: 1,
+            current_file_index: 1,
+            operation: OperationPhase::Extracting,
+            estimated,
+        };
+        let _ = window.emit("compression-progress", &progress_update);
+    })
+    .await
+    .map_err(|e| format!("Failed to extract from '{}': {}", archive, e))
+}
+
+// Change the password of an encrypted zip in place. Entries are decrypted and
+// encrypted again but not recompressed; progress is reported like an extraction's.
+// With a policy, a new password that breaks it is rejected with WeakPassword.
+#[tauri::command]
+async fn reencrypt_archive(
+    window: tauri::Window,
+    path: String,
+    oldpassword: String,
+    newpassword: String,
+    policy: Option<PasswordPolicy>,
+) -> Result<(), String> {
+    zipcrypt::reencrypt_zip(Path::new(&path), &oldpassword, &newpassword, policy.as_ref(), |progress, current_file| {
+        let progress_update = CompressionProgressUpdate {
+            progress,
+            current_file,
+            current_output: path.clone(),
+            total_files: 1,
+            current_file_index: 1,
+            operation: OperationPhase::Archiving,
+            estimated: false,
+        };
+        let _ = window.emit("compression-progress", &progress_update);
+    })
+    .map_err(|e| format!("Failed to change the password of '{}': {}", path, e))
+}
+
+// Replace a file with a verified compressed copy of itself (see compression::compress_replace)
+#[tauri::command]
+async fn compress_replace(
+    window: tauri::Window,
+    path: String,
+    compressiontype: String,
+    options: Option<CompressOptions>,
+    state: tauri::State<'_, Arc<GuiState>>
+) -> Result<ReplaceResult, String> {
+    let compression_enum = parse_compression_type(&compressiontype)?;
+    
+    state.cancel_requested.store(false, Ordering::SeqCst);
+    let options = CompressOptions {
+        cancel: Some(state.cancel_requested.clone()),
+        phase_change: Some(phase_change(window.app_handle().clone(), path.clone())),
+        ..options.unwrap_or_else(|| Settings::load().compress)
+    };
+    
+    compression::compress_replace(Path::new(&path), compression_enum, &options, |progress, current_filename| {
+        let progress_update = CompressionProgressUpdate {
+            progress,
+            current_file: current_filename,
+            current_output: path.clone(),
+            total_files: 1,
+            current_file_index: 1,
+            operation: OperationPhase::Compressing,
+            estimated: false,
+        };
+        let _ = window.app_handle().emit("compression-progress", &progress_update);
+    })
+    .await
+    .map_err(|e| compression_error(&window, e))
+}
+
+#[tauri::command]
+async fn optimize_archive(path: String) -> Result<OptimizeResult, String> {
+    compression::optimize_archive(Path::new(&path))
+        .map_err(|e| format!("Failed to optimize '{}': {}", path, e))
+}
+
+// Write a copy of a zip with its central directory rebuilt from the local headers
+#[tauri::command]
+async fn rebuild_central_directory(path: String, output_path: Option<String>) -> Result<RebuildResult, String> {
+    compression::rebuild_central_directory(Path::new(&path), output_path.as_deref().map(Path::new))
+        .map_err(|e| format!("Failed to repair '{}': {}", path, e))
+}
+
+// The detected archive format, or None when the file isn't an archive TauZip can extract
+#[tauri::command]
+async fn is_archive(path: String) -> Result<Option<String>, String> {
+    compression::sniff_format(Path::new(&path))
+        .map(|format| format.map(str::to_string))
+        .map_err(|e| format!("Failed to read '{}': {}", path, e))
+}
+
+// Best guess at the tool that made a zip, for explaining compatibility quirks
+#[tauri::command]
+async fn detect_creator(path: String) -> Result<CreatorGuess, String> {
+    compression::detect_creator(Path::new(&path))
+        .map_err(|e| format!("Failed to read '{}': {}", path, e))
+}
+
+// Strength score (0-4) for a meter in the UI. With a policy, a password that breaks it
+// is rejected with a WeakPassword error explaining why.
+#[tauri::command]
+async fn check_password_strength(password: String, policy: Option<PasswordPolicy>) -> Result<u8, String> {
+    if let Some(policy) = policy {
+        password::check_password_policy(&password, &policy).map_err(|e| e.to_string())?;
+    }
+    Ok(password::password_strength(&password))
+}
+
+#[tauri::command]
+async fn get_settings() -> Settings {
+    Settings::load()
+}
+
+#[tauri::command]
+async fn update_settings(settings: Settings) -> Result<(), String> {
+    settings.save().map_err(|e| format!("Failed to save settings: {}", e))
+}
+
 #[tauri::command]
 async fn get_compression_types() -> Vec<String> {
-    vec![
-        "Zip".to_string(),
-        "TarGz".to_string(),
-        "TarBr".to_string(),
-        "Gz".to_string(),
-        "Br".to_string(),
-        "Gzip".to_string(),
-        "Bzip2".to_string(),
-    ]
+    let mut types = codec::codec_names();
+    types.push("Auto".to_string());
+    types
 }
 
 #[tauri::command]
 async fn validate_compression_type(files: Vec<String>, compressiontype: String) -> Result<bool, String> {
     // Convert string to CompressionType enum
-    let compression_enum = match compressiontype.as_str() {
-        "Zip" => CompressionType::Zip,
-        "TarGz" => CompressionType::TarGz,
-        "TarBr" => CompressionType::TarBr,
-        "Gz" => CompressionType::Gz,
-        "Br" => CompressionType::Br,
-        "Gzip" => CompressionType::Gzip,
-        "Bzip2" => CompressionType::Bzip2,
-        _ => return Err(format!("Unsupported compression type: {}", compressiontype)),
-    };
+    let compression_enum = parse_compression_type(&compressiontype)?;
     
     if !compression_enum.supports_multiple_files() && files.len() > 1 {
         return Ok(false);
@@ -249,6 +967,33 @@ async fn validate_compression_type(files: Vec<String>, compressiontype: String)
     Ok(true)
 }
 
+// Everything that would stop this compression, checked before it starts so the window
+// can list all of it at once; empty when it is good to go
+#[tauri::command]
+async fn validate_compression_request(
+    files: Vec<String>,
+    outputfile: String,
+    compressiontype: String,
+    options: Option<CompressOptions>,
+) -> Result<Vec<RequestProblem>, String> {
+    let compression_enum = parse_compression_type(&compressiontype)?;
+    let options = options.unwrap_or_else(|| Settings::load().compress);
+    let file_paths = options.source_files(files.iter().map(PathBuf::from).collect())
+        .map_err(|e| e.to_string())?;
+    let output_path = resolve_output_path(&outputfile, &file_paths);
+    Ok(compression::validate_compression_request(&file_paths, &output_path, &compression_enum, &options))
+}
+
+// Stop the running compression. The partly written archive is discarded and any
+// existing file at the output path is left as it was.
+#[tauri::command]
+async fn cancel_operation(state: tauri::State<'_, Arc<GuiState>>) -> Result<(), String> {
+    println!("Cancellation requested");
+    state.touch();
+    state.cancel_requested.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
 #[tauri::command]
 fn close(app: tauri::AppHandle) -> Result<(), String> {
 	let count = count_processes_by_name("TauZip.exe");
@@ -314,35 +1059,104 @@ async fn open_file_location(file_path: String) -> Result<(), String> {
     Ok(())
 }
 
-pub fn run_app(app: &AppHandle, mut file_strings2: Vec<String>, argv: Vec<String>, gui_state: Arc<GuiState>) {
+#[tauri::command]
+async fn copy_to_clipboard(text: String) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new()
+        .map_err(|e| format!("Failed to access clipboard: {}", e))?;
+    clipboard.set_text(text)
+        .map_err(|e| format!("Failed to copy to clipboard: {}", e))
+}
+
+// Verbs the context menu and the command line put before the paths
+const LAUNCH_VERBS: &[&str] = &[
+	"gui-compress", "gui-decompress", "gui-compress-multiple", "gui-decompress-multiple",
+	"gui-decompress-here", "gui-compress-selection", "compress", "decompress", "decompress-here",
+];
+
+// The file paths in a launch command line, argv[0] included. Instances started from
+// "Open with" or the context menu don't all look alike: the verb may be missing, and
+// Windows sometimes leaves the quotes around a path with spaces on, or hands several
+// quoted paths over as one argument. So rather than skipping a fixed number of
+// arguments, the verbs and flags are picked out and everything else is taken as a path.
+// Arguments stay OsStrings, since a Unix file name needn't be valid UTF-8.
+pub fn launch_paths<S: AsRef<OsStr>>(argv: &[S]) -> Vec<PathBuf> {
+	let mut paths = Vec::new();
+	for arg in argv.iter().skip(1) {
+		// Such a name can't be a verb, a flag or a quoted list, so it is taken as it is
+		let Some(arg) = arg.as_ref().to_str() else {
+			paths.push(PathBuf::from(arg.as_ref()));
+			continue;
+		};
+		let arg = arg.trim();
+		let unquoted = arg.trim_matches('"');
+		if unquoted.is_empty() {
+			continue;
+		}
+		if LAUNCH_VERBS.contains(&unquoted.to_lowercase().as_str()) || (arg.starts_with('-') && !Path::new(arg).exists()) {
+			continue;
+		}
+		// Several quoted paths that arrived as a single argument
+		if arg.contains("\" \"") && !Path::new(unquoted).exists() {
+			paths.extend(arg.split("\" \"").map(|part| part.trim_matches('"')).filter(|part| !part.is_empty()).map(PathBuf::from));
+		} else {
+			paths.push(PathBuf::from(unquoted));
+		}
+	}
+	paths
+}
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Close a dialog nobody is using: no window interaction, path handshake or progress for
+// Settings::idle_timeout. "idle-close" goes out first so the window can note why.
+fn watch_idle(app: AppHandle, gui_state: Arc<GuiState>) {
+	let Some(timeout) = Settings::load().idle_timeout() else { return };
+	let progress_state = gui_state.clone();
+	app.listen_any("compression-progress", move |_| progress_state.touch());
+	
+	thread::spawn(move || loop {
+		thread::sleep(IDLE_CHECK_INTERVAL);
+		if gui_state.idle_for() >= timeout {
+			println!("Closing after {} seconds without activity", timeout.as_secs());
+			let _ = app.emit("idle-close", timeout.as_secs());
+			app.exit(0);
+			return;
+		}
+	});
+}
+
+pub fn run_app(app: &AppHandle, mut file_strings2: Vec<String>, paths: Vec<PathBuf>, gui_state: Arc<GuiState>) {
 	let log = false;
 	if log { std::fs::write("aa.txt", format!("run_app")); }
 	
-	let file_args: Vec<String> = argv.into_iter().skip(2).collect();
-	file_strings2.extend(file_args);
+	// The setup hook hands over the files the dialog was opened with, which are
+	// usually among file_strings2 already. The window takes paths as (JSON) strings,
+	// so this is the one place a name that isn't UTF-8 gets a replacement character.
+	for path in paths {
+		let path = path.to_string_lossy().to_string();
+		if !file_strings2.contains(&path) {
+			file_strings2.push(path);
+		}
+	}
 	
 	if log { std::fs::write("aa.txt", format!("file_strings2 {:?}", file_strings2)); }
 	
-	let c = *&file_strings2.len();
+	let c = file_strings2.len();
 	let app2 = app.clone();
-	let app3 = app.clone();
 	let window = gui_state.window_count.clone();
-	let item2 = gui_state.item_count.clone();
-	let item3 = gui_state.item_count.clone();
-	let count_now_clone = gui_state.count_now.clone();
-	let count_now_clone2 = gui_state.count_now.clone();
-	let arg_received_clone = gui_state.arg_received.clone();
-	let arg_received_clone2 = gui_state.arg_received.clone();
-	let arg_received_clone3 = gui_state.arg_received.clone();
-	let mut total_arg = 0;
-	{
-		*arg_received_clone3.lock().unwrap() += c;
-		total_arg = *arg_received_clone3.lock().unwrap();
-	}
+	let files = gui_state.files.clone();
+	// Counted before the window can possibly acknowledge them
+	files.lock().unwrap().forwarded += c;
+	
+	let idle_timeout = Settings::load().idle_timeout();
 	
 	thread::spawn(move || {
 		let mut count = window.fetch_add(0, Ordering::SeqCst);
 		while count <= 0 {
+			// The window never came up, so there is nobody to hand the paths to
+			if idle_timeout.is_some_and(|timeout| gui_state.idle_for() >= timeout) {
+				return;
+			}
 			count = window.fetch_add(0, Ordering::SeqCst);
 			thread::sleep(Duration::from_millis(100));
 		}
@@ -353,76 +1167,50 @@ pub fn run_app(app: &AppHandle, mut file_strings2: Vec<String>, argv: Vec<String
 		
 		match app2.emit("files-selected", file_strings2) {
 			Ok(_) => {
-				item2.fetch_add(c, Ordering::SeqCst);
 				//let s = Path::new(&c);
 				//if log { std::fs::write(format!("aa {}.txt", s.file_name().unwrap().to_string_lossy()), format!("emit success {:?}", s)); }
 				//println!("Successfully emitted files-selected event with {} files", file_strings3.len())
 			},
 			Err(e) => {
+				// The window will never acknowledge these
+				files.lock().unwrap().forwarded -= c;
 				//let s = Path::new(&c);
 				//if log { std::fs::write(format!("aa {}.txt", s.file_name().unwrap().to_string_lossy()), format!("emit fail {:?}", file_strings4)); }
 				//println!("Failed to emit files-selected event: {}", e)
 			},
 		}
-		// let mut count1 = item3.fetch_add(0, Ordering::SeqCst);
-		// while true {
-			// thread::sleep(Duration::from_millis(1200));
-			// let count2 = item3.fetch_add(0, Ordering::SeqCst);
-			// if count1 == count2 {
-				// app2.emit("enable-ok", "");
-				// break;
-			// }
-			// count1 = count2;
-		// }
 	});
-	
-	//if enable_thread 
-	// {
-		// thread::spawn(move || {
-			// while true {
-				// let mut c = 0;
-				// {
-					// c = *count_now_clone2.lock().unwrap();
-				// }
-				// thread::sleep(Duration::from_millis(1000));
-				// if total_arg == c {
-					// app3.emit("enable-ok", "");
-					// break;
-				// }
-			// }
-		// });
-	// }
-}
-
-pub fn run_decom_app(app: &AppHandle, mut file_strings2: Vec<String>, argv: Vec<String>, gui_state: Arc<GuiState>) {
+}
+
+pub fn run_decom_app(app: &AppHandle, mut file_strings2: Vec<String>, paths: Vec<PathBuf>, gui_state: Arc<GuiState>) {
 	let log = false;
 	if log { std::fs::write("aa.txt", "got main decom"); }
 		
-	let file_args: Vec<String> = argv.into_iter().skip(2).collect();
-	file_strings2.extend(file_args);
+	for path in paths {
+		let path = path.to_string_lossy().to_string();
+		if !file_strings2.contains(&path) {
+			file_strings2.push(path);
+		}
+	}
 		
 	if log { std::fs::write("b.txt", format!("file_strings2 decom 3 {:?}", &file_strings2)); }
 	
-	let c = *&file_strings2.len();
+	let c = file_strings2.len();
 	let app2 = app.clone();
-	let app3 = app.clone();
 	let window = gui_state.window_count.clone();
-	let item2 = gui_state.item_count.clone();
-	let item3 = gui_state.item_count.clone();
-	let count_now_clone = gui_state.count_now.clone();
-	let count_now_clone2 = gui_state.count_now.clone();
-	let arg_received_clone = gui_state.arg_received.clone();
-	let arg_received_clone2 = gui_state.arg_received.clone();
-	let arg_received_clone3 = gui_state.arg_received.clone();
-	let mut total_arg = 0;
-	{
-		*arg_received_clone3.lock().unwrap() += c;
-		total_arg = *arg_received_clone3.lock().unwrap();
-	}
+	let files = gui_state.files.clone();
+	// Counted before the window can possibly acknowledge them
+	files.lock().unwrap().forwarded += c;
+	
+	let idle_timeout = Settings::load().idle_timeout();
 	
 	thread::spawn(move || {
 		let mut count = window.fetch_add(0, Ordering::SeqCst);;
 		while count <= 0 {
+			// The window never came up, so there is nobody to hand the paths to
+			if idle_timeout.is_some_and(|timeout| gui_state.idle_for() >= timeout) {
+				return;
+			}
 			count = window.fetch_add(0, Ordering::SeqCst);
 			thread::sleep(Duration::from_millis(100));
 		}
@@ -437,12 +1225,13 @@ pub fn run_decom_app(app: &AppHandle, mut file_strings2: Vec<String>, argv: Vec<
 		}
 		match app2.emit("archives-selected", file_strings2) {
 			Ok(_) => {
-				item2.fetch_add(c, Ordering::SeqCst);
 				//let s = Path::new(&c);
 				//if log { std::fs::write(format!("ba {}.txt", s.file_name().unwrap().to_string_lossy()), format!("emit decom success {:?}", s)); }
 				//println!("Successfully emitted files-selected event with {} files", file_strings3.len())
 			},
 			Err(e) => {
+				// The window will never acknowledge these
+				files.lock().unwrap().forwarded -= c;
 				//let s = Path::new(&c);
 				//if log { std::fs::write(format!("ba {}.txt", s.file_name().unwrap().to_string_lossy()), format!("emit decom fail {:?}", file_strings4)); }
 				//println!("Failed to emit files-selected event: {}", e)
@@ -460,70 +1249,53 @@ pub async fn run_compression_dialog(file_strings: Vec<String>, files: Vec<PathBu
 	let file_strings2b = file_strings.clone();
     if log { std::fs::write("a.txt", "before"); }
 	
-	let item_clone = gui_state.item_count.clone();
-	let item_clone2 = gui_state.item_count.clone();
-	let item_clone3 = gui_state.item_count.clone();
 	let window_count_clone = gui_state.window_count.clone();
-	let window_count_clone2 = gui_state.window_count.clone();
-	let window_count_clone3 = gui_state.window_count.clone();
-	let count_now_clone = gui_state.count_now.clone();
-	let count_now_clone2 = gui_state.count_now.clone();
-	let count_now_clone3 = gui_state.count_now.clone();
-	let arg_received_clone = gui_state.arg_received.clone();
-	let arg_received_clone2 = gui_state.arg_received.clone();
-	let arg_received_clone3 = gui_state.arg_received.clone();
+	let gui_state2 = gui_state.clone();
+	let gui_state3 = gui_state.clone();
+	let gui_state4 = gui_state.clone();
 	
 	tauri::Builder::default()
 		.invoke_handler(tauri::generate_handler![
             compress_files_command,
+            start_compress_job,
+            resume_compress_job,
+            quick_compress,
+            append_to_tar,
+            compress_to_temp,
+            compress_replace,
+            check_password_strength,
+            split_file,
+            join_files,
             get_compression_types,
             validate_compression_type,
+            validate_compression_request,
+            benchmark_formats,
             open_file_location,
+            copy_to_clipboard,
+            cancel_operation,
+            get_settings,
+            update_settings,
 			close,
-			count_now
+			files_received
         ])
 		.manage(gui_state.clone()) // store it in Tauri state
+		.on_window_event(move |_window, _event| gui_state4.touch())
 		//.manage(item_clone.clone()) // store it in Tauri state
 		//.plugin(tauri_plugin_shell::init())
 		//.plugin(tauri_plugin_cli::init())
         .plugin(tauri_plugin_single_instance::init(move |app, argv, _cwd| {
 			//println!("Tauri compression app setup started");
 			if log { std::fs::write("abc.txt", format!("{:?}", argv.clone())); }
-            run_app(app, file_strings2.clone(), argv.clone(), Arc::new(GuiState { window_count: window_count_clone2.clone(), item_count: item_clone.clone(), count_now: count_now_clone.clone(), arg_received: arg_received_clone.clone() }));
+            run_app(app, file_strings2.clone(), launch_paths(&argv), gui_state2.clone());
 			//return Ok(());
 		}))
 		.setup(move |app| {
-			let appx = app.app_handle().clone();
 			let count = window_count_clone.fetch_add(1, Ordering::SeqCst);
 			if let Some(window) = app.get_webview_window("main") {
 				let _ = window.center();
 			}
-			let mut fb = vec![];
-			for x in files {
-				fb.push(x.display().to_string());
-			}
-			run_app(&app.app_handle(), file_strings2b.clone(), fb.clone(), Arc::new(GuiState { window_count: window_count_clone3.clone(), item_count: item_clone2.clone(), count_now: count_now_clone2.clone(), arg_received: arg_received_clone2.clone() }));
-			
-			let app3 = appx.clone();
-			{
-				tokio::spawn(async move {
-					while true {
-						let mut c = 0;
-						{
-							c = *count_now_clone3.lock().unwrap();
-						}
-						
-						tokio::time::sleep(Duration::from_millis(1000));
-						
-						let itemc = item_clone3.fetch_add(0, Ordering::SeqCst);
-						let total_arg = *arg_received_clone3.lock().unwrap();
-						if ((total_arg == itemc && itemc != 0)){
-							app3.emit("enable-ok", "");
-							break;
-						}
-					}
-				});
-			}
+			run_app(&app.app_handle(), file_strings2b.clone(), files.clone(), gui_state3.clone());
+			watch_idle(app.app_handle().clone(), gui_state3.clone());
 			return Ok(());
 		}
 		)
@@ -540,66 +1312,55 @@ pub async fn run_decompression_dialog(file_strings: Vec<String>, files: Vec<Path
     let file_strings2 = file_strings.clone();
 	let file_strings2b = file_strings.clone();
 	
-	let item_clone = gui_state.item_count.clone();
-	let item_clone2 = gui_state.item_count.clone();
-	let item_clone3 = gui_state.item_count.clone();
 	let window_count_clone = gui_state.window_count.clone();
-	let window_count_clone2 = gui_state.window_count.clone();
-	let window_count_clone3 = gui_state.window_count.clone();
-	let count_now_clone = gui_state.count_now.clone();
-	let count_now_clone2 = gui_state.count_now.clone();
-	let count_now_clone3 = gui_state.count_now.clone();
-	let arg_received_clone = gui_state.arg_received.clone();
-	let arg_received_clone2 = gui_state.arg_received.clone();
-	let arg_received_clone3 = gui_state.arg_received.clone();
+	let gui_state2 = gui_state.clone();
+	let gui_state3 = gui_state.clone();
+	let gui_state4 = gui_state.clone();
 	
 	tauri::Builder::default()
 		.invoke_handler(tauri::generate_handler![
             decompress_files_command,
+            list_archive_contents,
+            list_archive_page,
+            list_archive_tree,
+            inspect_archive,
+            estimate_extraction,
+            benchmark_formats,
+            read_entry_text,
+            extract_matching,
+            verify_against_manifest,
+            train_dictionary,
+            is_archive,
+            detect_creator,
+            optimize_archive,
+            rebuild_central_directory,
+            reencrypt_archive,
+            split_file,
+            join_files,
             open_file_location,
+            copy_to_clipboard,
+            cancel_operation,
+            get_settings,
+            update_settings,
 			close,
-			count_now
+			files_received
         ])
 		.manage(gui_state.clone()) // store it in Tauri state
+		.on_window_event(move |_window, _event| gui_state4.touch())
 		//.manage(item_clone.clone()) // store it in Tauri state
 		//.plugin(tauri_plugin_shell::init())
 		//.plugin(tauri_plugin_cli::init())
         .plugin(tauri_plugin_single_instance::init(move |app, argv, _cwd| {
 			if log { std::fs::write("def.txt", format!("{:?}", argv.clone())); }
-			run_decom_app(app, file_strings2.clone(), argv.clone(), Arc::new(GuiState { window_count: window_count_clone2.clone(), item_count: item_clone.clone(), count_now: count_now_clone.clone(), arg_received: arg_received_clone.clone()}));
+			run_decom_app(app, file_strings2.clone(), launch_paths(&argv), gui_state2.clone());
         }))
 		.setup(move |app| {
-			let appx = app.app_handle().clone();
 			let count = window_count_clone.fetch_add(1, Ordering::SeqCst);
 			if let Some(window) = app.get_webview_window("main") {
 				let _ = window.center();
 			}
-			let mut fb = vec![];
-			for x in files {
-				fb.push(x.display().to_string());
-			}
-			run_decom_app(&app.app_handle(), file_strings2b.clone(), fb.clone(), Arc::new(GuiState { window_count: window_count_clone3.clone(), item_count: item_clone2.clone(), count_now: count_now_clone2.clone(), arg_received: arg_received_clone2.clone()}));
-			
-			let app3 = appx.clone();
-			{
-				tokio::spawn(async move {
-					while true {
-						let mut c = 0;
-						{
-							c = *count_now_clone3.lock().unwrap();
-						}
-						
-						tokio::time::sleep(Duration::from_millis(1000));
-						
-						let itemc = item_clone3.fetch_add(0, Ordering::SeqCst);
-						let total_arg = *arg_received_clone3.lock().unwrap();
-						if ((total_arg == itemc && total_arg != 0)){
-							app3.emit("enable-ok", "");
-							break;
-						}
-					}
-				});
-			}
+			run_decom_app(&app.app_handle(), file_strings2b.clone(), files.clone(), gui_state3.clone());
+			watch_idle(app.app_handle().clone(), gui_state3.clone());
 			return Ok(());
 		}
         )
@@ -609,17 +1370,46 @@ pub async fn run_decompression_dialog(file_strings: Vec<String>, files: Vec<Path
 	Ok(())
 }
 
-fn generate_output_dir(file: &PathBuf) -> PathBuf {
-    let base_name = file.file_stem().unwrap_or_default().to_string_lossy();
-    let parent = file.parent().unwrap_or_else(|| std::path::Path::new("."));
-    
-    let mut counter = 1;
-    let mut output_dir = parent.join(base_name.as_ref());
-    
-    while output_dir.exists() {
-        counter += 1;
-        output_dir = parent.join(format!("{} ({})", base_name, counter));
-    }
-    
-    output_dir
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn argv(args: &[&str]) -> Vec<String> {
+		args.iter().map(|arg| arg.to_string()).collect()
+	}
+
+	fn paths(args: &[&str]) -> Vec<PathBuf> {
+		args.iter().map(PathBuf::from).collect()
+	}
+
+	#[test]
+	fn launch_paths_drops_the_executable_and_verb() {
+		assert_eq!(launch_paths(&argv(&["tauzip", "gui-compress", "a.txt", "b.txt"])), paths(&["a.txt", "b.txt"]));
+		// argv[0] goes whatever it looks like, even a name that could be a file
+		assert_eq!(launch_paths(&argv(&["./build/app", "notes.txt"])), paths(&["notes.txt"]));
+		assert_eq!(launch_paths(&argv(&["tauzip", "GUI-DECOMPRESS", "--verbose", "x.zip"])), paths(&["x.zip"]));
+		assert!(launch_paths(&argv(&["tauzip"])).is_empty());
+	}
+
+	#[test]
+	fn launch_paths_strips_quotes_left_on_a_path() {
+		let found = launch_paths(&argv(&["C:\\Tools\\tauzip.exe", "gui-compress", "\"C:\\My Files\\report.docx\""]));
+		assert_eq!(found, paths(&["C:\\My Files\\report.docx"]));
+	}
+
+	#[test]
+	fn launch_paths_splits_several_quoted_paths_in_one_argument() {
+		let found = launch_paths(&argv(&["tauzip", "gui-compress-multiple", "\"/tmp/no such/a.txt\" \"/tmp/no such/b c.txt\""]));
+		assert_eq!(found, paths(&["/tmp/no such/a.txt", "/tmp/no such/b c.txt"]));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn launch_paths_keeps_a_name_that_is_not_utf8() {
+		use std::ffi::OsString;
+		use std::os::unix::ffi::OsStringExt;
+		let name = OsString::from_vec(b"caf\xe9.txt".to_vec());
+		let found = launch_paths(&[OsString::from("tauzip"), OsString::from("gui-compress"), name.clone()]);
+		assert_eq!(found, vec![PathBuf::from(name)]);
+	}
+}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::compression::CompressionError;
+
+// Read-only access to ISO 9660 disc images, including Joliet long names.
+// Only what extraction needs: the directory tree and the file extents.
+
+const SECTOR_SIZE: u64 = 2048;
+// Volume descriptors start at sector 16; the identifier sits one byte in
+const SIGNATURE_OFFSET: u64 = 16 * SECTOR_SIZE + 1;
+const SIGNATURE: &[u8] = b"CD001";
+
+const FLAG_DIRECTORY: u8 = 0x02;
+// The file continues in the next directory record (files over 4 GiB)
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+// Directory sizes come from the image and are read whole, so anything bigger
+// (or running past the end of the image) is corrupt rather than allocated.
+// Real directories are a few sectors; this is room for 100,000+ entries.
+const MAX_DIRECTORY_SIZE: u64 = 16 << 20;
+
+pub struct IsoEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    // (first sector, length in bytes); more than one only for multi-extent files
+    extents: Vec<(u32, u64)>,
+}
+
+impl IsoEntry {
+    pub fn size(&self) -> u64 {
+        self.extents.iter().map(|(_, len)| len).sum()
+    }
+}
+
+pub struct IsoImage<R> {
+    reader: R,
+    root_extent: u32,
+    root_size: u32,
+    image_len: u64,
+    // Joliet names are UCS-2 big endian instead of upper-case 8.3 ASCII
+    joliet: bool,
+    // Names entries() left out because no file could be given them
+    skipped: Vec<String>,
+}
+
+pub fn has_iso_signature<R: Read + Seek>(reader: &mut R) -> bool {
+    let mut signature = [0u8; 5];
+    reader.seek(SeekFrom::Start(SIGNATURE_OFFSET)).is_ok()
+        && reader.read_exact(&mut signature).is_ok()
+        && signature == SIGNATURE
+}
+
+impl<R: Read + Seek> IsoImage<R> {
+    pub fn open(mut reader: R) -> Result<Self> {
+        if !has_iso_signature(&mut reader) {
+            return Err(anyhow::anyhow!("Not an ISO 9660 image (no CD001 identifier)"));
+        }
+
+        let mut primary_root = None;
+        let mut joliet_root = None;
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+
+        for index in 16.. {
+            reader.seek(SeekFrom::Start(index * SECTOR_SIZE))?;
+            reader.read_exact(&mut sector)?;
+            if &sector[1..6] != SIGNATURE {
+                return Err(anyhow::anyhow!("Corrupt volume descriptor at sector {}", index));
+            }
+
+            // The root directory record is embedded at offset 156 of both descriptor kinds
+            let root = (read_u32(&sector[158..162]), read_u32(&sector[166..170]));
+            match sector[0] {
+                1 => primary_root = Some(root),
+                // A supplementary descriptor is Joliet when it declares a UCS-2 escape sequence
+                2 if matches!(&sector[88..91], b"%/@" | b"%/C" | b"%/E") => joliet_root = Some(root),
+                255 => break,
+                _ => {},
+            }
+        }
+
+        let (joliet, (root_extent, root_size)) = match (joliet_root, primary_root) {
+            (Some(root), _) => (true, root),
+            (None, Some(root)) => (false, root),
+            (None, None) => return Err(anyhow::anyhow!("ISO image has no primary volume descriptor")),
+        };
+
+        let image_len = reader.seek(SeekFrom::End(0))?;
+        Ok(Self { reader, root_extent, root_size, image_len, joliet, skipped: Vec::new() })
+    }
+
+    // Every directory and file in the image, parents before their children
+    pub fn entries(&mut self) -> Result<Vec<IsoEntry>> {
+        let mut entries: Vec<IsoEntry> = Vec::new();
+        self.skipped.clear();
+        let mut pending = vec![(PathBuf::new(), self.root_extent, self.root_size)];
+        // Directories are only read once, so a corrupt image can't send the walk in circles
+        let mut visited = HashSet::new();
+
+        while let Some((dir_path, extent, size)) = pending.pop() {
+            if !visited.insert(extent) {
+                continue;
+            }
+
+            let start = extent as u64 * SECTOR_SIZE;
+            if size as u64 > MAX_DIRECTORY_SIZE || start + size as u64 > self.image_len {
+                return Err(CompressionError::Corrupt {
+                    reason: format!("directory '{}' claims {} bytes at sector {}, beyond the image", dir_path.display(), size, extent),
+                }.into());
+            }
+            let mut data = vec![0u8; size as usize];
+            self.reader.seek(SeekFrom::Start(start))?;
+            self.reader.read_exact(&mut data)?;
+
+            let mut pos = 0usize;
+            let mut continues_previous = false;
+            while pos < data.len() {
+                let record_len = data[pos] as usize;
+                // Records never cross a sector; the rest of the sector is zero padding
+                if record_len == 0 {
+                    pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                    continue;
+                }
+                let record = data.get(pos..pos + record_len)
+                    .filter(|record| record.len() >= 33)
+                    .ok_or_else(|| anyhow::anyhow!("Corrupt directory record in '{}'", dir_path.display()))?;
+                pos += record_len;
+
+                let name_len = record[32] as usize;
+                let raw_name = record.get(33..33 + name_len).unwrap_or_default();
+                // "\0" and "\1" are the directory itself and its parent
+                if matches!(raw_name, [0] | [1]) {
+                    continue;
+                }
+
+                let flags = record[25];
+                let extent = (read_u32(&record[2..6]), read_u32(&record[10..14]) as u64);
+                let is_dir = flags & FLAG_DIRECTORY != 0;
+
+                if continues_previous {
+                    if let Some(last) = entries.last_mut() {
+                        last.extents.push(extent);
+                    }
+                    continues_previous = flags & FLAG_MULTI_EXTENT != 0;
+                    continue;
+                }
+                continues_previous = flags & FLAG_MULTI_EXTENT != 0;
+
+                let name = self.decode_name(raw_name, is_dir);
+                if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                    self.skipped.push(name);
+                    continue;
+                }
+
+                let path = dir_path.join(&name);
+                if is_dir {
+                    pending.push((path.clone(), extent.0, extent.1 as u32));
+                }
+                entries.push(IsoEntry { path, is_dir, extents: vec![extent] });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // The entries the last entries() call left out, by their unusable names
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    pub fn copy_entry<W: Write>(&mut self, entry: &IsoEntry, writer: &mut W) -> std::io::Result<u64> {
+        let mut copied = 0;
+        for &(sector, len) in &entry.extents {
+            self.reader.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE))?;
+            copied += std::io::copy(&mut (&mut self.reader).take(len), writer)?;
+        }
+        Ok(copied)
+    }
+
+    fn decode_name(&self, raw_name: &[u8], is_dir: bool) -> String {
+        let name = if self.joliet {
+            let units: Vec<u16> = raw_name.chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            raw_name.iter().map(|&byte| byte as char).collect()
+        };
+
+        if is_dir {
+            return name;
+        }
+        // File names carry a ";1" version suffix, and "NAME." when there is no extension
+        let name = name.split(';').next().unwrap_or_default();
+        name.strip_suffix('.').unwrap_or(name).to_string()
+    }
+}
+
+// Both-endian fields store little endian first
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A primary descriptor at sector 16 and a terminator at 17, with the
+    // root directory record pointing at sector 18
+    fn image_with_root(root_size: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 19 * SECTOR_SIZE as usize];
+        let primary = 16 * SECTOR_SIZE as usize;
+        image[primary] = 1;
+        image[primary + 1..primary + 6].copy_from_slice(SIGNATURE);
+        image[primary + 158..primary + 162].copy_from_slice(&18u32.to_le_bytes());
+        image[primary + 166..primary + 170].copy_from_slice(&root_size.to_le_bytes());
+        let terminator = 17 * SECTOR_SIZE as usize;
+        image[terminator] = 255;
+        image[terminator + 1..terminator + 6].copy_from_slice(SIGNATURE);
+        image
+    }
+
+    #[test]
+    fn directory_larger_than_the_image_is_corrupt() {
+        let mut iso = IsoImage::open(Cursor::new(image_with_root(1 << 30))).unwrap();
+        let err = iso.entries().err().expect("oversized directory was read");
+        assert!(matches!(err.downcast_ref::<CompressionError>(), Some(CompressionError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn empty_root_directory_lists_nothing() {
+        let mut iso = IsoImage::open(Cursor::new(image_with_root(SECTOR_SIZE as u32))).unwrap();
+        assert!(iso.entries().unwrap().is_empty());
+    }
+}
@@ -7,21 +7,30 @@
 #[allow(unused_imports)]
 use clap::{Arg, Command};
 use std::path::PathBuf;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, BufRead, BufReader};
 use serde::{Serialize, Deserialize};
 use std::sync::Mutex;
 use std::sync::Arc;
-use std::ffi::CString;
+use std::ffi::{CString, OsString};
+mod chunkstore;
+mod codec;
 mod compression;
 mod context_menu;
+mod cpio;
+mod deflate;
 mod file_utils;
 mod gui;
+mod iso;
+mod password;
+mod settings;
+mod zipcrypt;
 use compression::{compress_files, decompress_files, CompressionType, is_compressed_file};
 use context_menu::ContextMenuManager;
+use file_utils::{generate_output_dir, DirNaming};
 use tauri::{Manager, AppHandle};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct FileCollectionSession {
@@ -32,29 +41,53 @@ struct FileCollectionSession {
 
 const COLLECTION_TIMEOUT_MS: u64 = 500; // Wait 500ms for more files
 const SESSION_FILE_PREFIX: &str = "tauzip_session_";
+// What is_compressed_file accepts, for the messages when nothing matches
+const SUPPORTED_FORMATS: &str = ".zip, .rar, .gz, .bz2, .tar, .7z, .iso, .cpio, .cpio.gz, .gzip, .br, .tgz, .tar.gz, .tar.br, .tar.bz2, .tbz, .tar.xz, .txz, .tar.zst, .tzst";
+
+// Paths sent to the window vs. paths the window has confirmed (files_received command)
+#[derive(Default)]
+pub struct FileHandshake {
+	pub forwarded: usize,
+	pub acknowledged: usize,
+}
 
 pub struct GuiState {
 	pub window_count: Arc<AtomicUsize>,
-	pub item_count: Arc<AtomicUsize>,
-	pub count_now: Arc<Mutex<usize>>,
-	pub arg_received: Arc<Mutex<usize>>
+	pub files: Arc<Mutex<FileHandshake>>,
+	// Raised by the cancel_operation command, cleared when a new operation starts
+	pub cancel_requested: Arc<AtomicBool>,
+	// Last interaction with the window or progress report, for Settings::idle_timeout
+	pub last_activity: Arc<Mutex<Instant>>
+}
+
+impl GuiState {
+	pub fn touch(&self) {
+		*self.last_activity.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+	}
+
+	pub fn idle_for(&self) -> Duration {
+		self.last_activity.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+	}
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
 	let window_count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
-	let item_count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
-	let ars = std::env::args().into_iter().collect::<Vec<String>>();
-	let gui_state = Arc::new(GuiState { window_count: Arc::new(AtomicUsize::new(0)), item_count: Arc::new(AtomicUsize::new(0)), count_now: Arc::new(Mutex::new(0)), arg_received: Arc::new(Mutex::new(0)) });
-	if ars.len() > 2 && ars[1].to_string().to_lowercase() == "gui-compress".to_string() {
-		let args: Vec<String> = std::env::args().into_iter().skip(2).collect::<Vec<String>>();
+	// std::env::args panics on an argument that isn't UTF-8, and a Unix file name needn't
+	// be, so the arguments stay OsStrings: launch_paths and clap both give back PathBufs
+	let ars = std::env::args_os().collect::<Vec<OsString>>();
+	let gui_state = Arc::new(GuiState { window_count: Arc::new(AtomicUsize::new(0)), files: Arc::new(Mutex::new(FileHandshake::default())), cancel_requested: Arc::new(AtomicBool::new(false)), last_activity: Arc::new(Mutex::new(Instant::now())) });
+	// Archives made for sharing only need to outlive the share itself
+	let _ = file_utils::remove_files_older_than(&file_utils::share_dir(), Duration::from_secs(24 * 60 * 60));
+	if ars.len() > 2 && ars[1].to_string_lossy().to_lowercase() == "gui-compress".to_string() {
+		let args = gui::launch_paths(&ars);
 	
-		gui::run_compression_dialog(args, vec![], gui_state.clone()).await?;
+		gui::run_compression_dialog(vec![], args, gui_state.clone()).await?;
 		return Ok(());
-	} else if ars.len() > 2 && ars[1].to_string().to_lowercase() == "gui-decompress".to_string() {
-		let args: Vec<String> = std::env::args().into_iter().skip(2).collect::<Vec<String>>();
+	} else if ars.len() > 2 && ars[1].to_string_lossy().to_lowercase() == "gui-decompress".to_string() {
+		let args = gui::launch_paths(&ars);
 	
-		gui::run_decompression_dialog(args, vec![], gui_state.clone()).await?;
+		gui::run_decompression_dialog(vec![], args, gui_state.clone()).await?;
 		return Ok(());
 	}
 	
@@ -168,7 +201,7 @@ async fn main() -> anyhow::Result<()> {
                     .required(true)
                     .value_parser(clap::value_parser!(PathBuf)))
         )
-        .get_matches();
+        .get_matches_from(&ars);
 
     match matches.subcommand() {
         Some(("install", _)) => {
@@ -210,11 +243,11 @@ async fn main() -> anyhow::Result<()> {
                 
                 if !is_compressed_file(&file) {
                     eprintln!("Error: '{}' is not a supported archive format.", file.display());
-                    eprintln!("Supported formats: .zip, .rar, .gz, .bz2, .tar, .7z, .gzip, .br, .tgz, .tar.gz, .tar.br");
+                    eprintln!("Supported formats: {}", SUPPORTED_FORMATS);
                     continue;
                 }
                 
-                let output_dir = generate_output_dir(&file);
+                let output_dir = generate_output_dir(&file, &DirNaming::default());
                 match decompress_files(&file, &output_dir).await {
                     Ok(_) => println!("File decompressed to: {}", output_dir.display()),
                     Err(e) => eprintln!("Failed to decompress '{}': {}", file.display(), e),
@@ -243,11 +276,13 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
             }
+            // A spanned zip's .z01, .z02, ... and .zip are one archive
+            let archive_files = compression::distinct_archives(&archive_files);
 			let mut files2 = archive_files.iter().map(|x| x.display().to_string()).collect::<Vec<String>>();
             
             if archive_files.is_empty() {
                 println!("No supported archive files found in the directory.");
-                println!("Supported formats: .zip, .rar, .gz, .bz2, .tar, .7z, .gzip, .br, .tgz, .tar.gz, .tar.br");
+                println!("Supported formats: {}", SUPPORTED_FORMATS);
                 return Ok(());
             }
             
@@ -258,7 +293,7 @@ async fn main() -> anyhow::Result<()> {
             
             // Extract each archive
             for file in archive_files {
-                let output_dir = generate_output_dir(&file);
+                let output_dir = generate_output_dir(&file, &DirNaming::default());
                 match decompress_files(&file, &output_dir).await {
                     Ok(_) => println!("✓ Extracted: {} -> {}", 
                         file.file_name().unwrap_or_default().to_string_lossy(),
@@ -368,7 +403,7 @@ async fn main() -> anyhow::Result<()> {
 			
 			if archive_files.is_empty() {
 				eprintln!("Error: No valid archive files found.");
-				eprintln!("Supported formats: .zip, .rar, .gz, .bz2, .tar, .7z, .gzip, .br, .tgz, .tar.gz, .tar.br");
+				eprintln!("Supported formats: {}", SUPPORTED_FORMATS);
 				return Ok(());
 			}
 			
@@ -421,11 +456,13 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
             }
+            // A spanned zip's .z01, .z02, ... and .zip are one archive
+            let archive_files = compression::distinct_archives(&archive_files);
 			let mut files2 = archive_files.iter().map(|x| x.display().to_string()).collect::<Vec<String>>();
             
             if archive_files.is_empty() {
                 eprintln!("Error: No supported archive files found in the directory.");
-                eprintln!("Supported formats: .zip, .rar, .gz, .bz2, .tar, .7z, .gzip, .br, .tgz, .tar.gz, .tar.br");
+                eprintln!("Supported formats: {}", SUPPORTED_FORMATS);
                 return Ok(());
             }
             
@@ -719,19 +756,4 @@ fn generate_output_path(files: &[PathBuf], compression_type: CompressionType) ->
     
     let parent = files[0].parent().unwrap_or_else(|| std::path::Path::new("."));
     parent.join(format!("{}{}", base_name, compression_type.extension()))
-}
-
-fn generate_output_dir(file: &PathBuf) -> PathBuf {
-    let base_name = file.file_stem().unwrap_or_default().to_string_lossy();
-    let parent = file.parent().unwrap_or_else(|| std::path::Path::new("."));
-    
-    let mut counter = 1;
-    let mut output_dir = parent.join(base_name.as_ref());
-    
-    while output_dir.exists() {
-        counter += 1;
-        output_dir = parent.join(format!("{} ({})", base_name, counter));
-    }
-    
-    output_dir
 }
\ No newline at end of file
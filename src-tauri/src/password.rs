@@ -0,0 +1,73 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::compression::CompressionError;
+
+// A rough zxcvbn-style strength estimate: 0 (trivially guessable) to 4 (very strong).
+// It works from the character classes used, discounts repeats and runs like "aaaa" or
+// "1234", and treats anything built around a well-known password as weak.
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "123456", "qwerty", "letmein", "welcome", "admin", "iloveyou", "monkey",
+    "dragon", "abc123", "football", "baseball", "master", "sunshine", "princess", "passw0rd",
+];
+
+// Opt-in rules for passwords used to encrypt archives
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    // Reject passwords scoring below this (0-4)
+    pub min_score: Option<u8>,
+}
+
+pub fn password_strength(password: &str) -> u8 {
+    let lower = password.to_lowercase();
+    if password.is_empty() || COMMON_PASSWORDS.iter().any(|common| lower.contains(common)) {
+        return 0;
+    }
+
+    let mut charset = 0u32;
+    if password.chars().any(|c| c.is_ascii_lowercase()) { charset += 26; }
+    if password.chars().any(|c| c.is_ascii_uppercase()) { charset += 26; }
+    if password.chars().any(|c| c.is_ascii_digit()) { charset += 10; }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') { charset += 33; }
+    if password.chars().any(|c| !c.is_ascii()) { charset += 100; }
+
+    // A character that repeats or continues a run from the previous one adds little
+    let chars: Vec<char> = password.chars().collect();
+    let predictable = chars.windows(2)
+        .filter(|pair| {
+            let step = pair[1] as i64 - pair[0] as i64;
+            step == 0 || step == 1 || step == -1
+        })
+        .count();
+    let effective_len = chars.len() as f64 - predictable as f64 * 0.75;
+
+    let bits = effective_len * (charset as f64).log2();
+    match bits {
+        b if b < 25.0 => 0,
+        b if b < 40.0 => 1,
+        b if b < 55.0 => 2,
+        b if b < 70.0 => 3,
+        _ => 4,
+    }
+}
+
+pub fn check_password_policy(password: &str, policy: &PasswordPolicy) -> Result<()> {
+    let length = password.chars().count();
+    if length < policy.min_length {
+        return Err(CompressionError::WeakPassword {
+            reason: format!("must be at least {} characters (has {})", policy.min_length, length),
+        }.into());
+    }
+
+    if let Some(min_score) = policy.min_score {
+        let score = password_strength(password);
+        if score < min_score {
+            return Err(CompressionError::WeakPassword {
+                reason: format!("strength {} of 4 is below the required {}", score, min_score),
+            }.into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::compression::{CompressOptions, ExtractOptions};
+
+// Defaults kept across runs in `<config dir>/TauZip/settings.json`. Commands called
+// without options use these, so the frontend doesn't have to resend everything.
+
+const SETTINGS_DIR: &str = "TauZip";
+const SETTINGS_FILE: &str = "settings.json";
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 30 * 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Preselected in the compression dialog; one of get_compression_types
+    pub default_format: Option<String>,
+    pub compress: CompressOptions,
+    pub extract: ExtractOptions,
+    // Seconds a dialog may sit with no interaction and no progress before it closes
+    // itself (DEFAULT_IDLE_TIMEOUT_SECS when unset, 0 never). Catches "Open with"
+    // launches whose window never finished the handshake.
+    pub idle_timeout_secs: Option<u64>,
+}
+
+fn settings_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(SETTINGS_DIR).join(SETTINGS_FILE))
+}
+
+impl Settings {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    // A missing or unreadable file just means the built-in defaults
+    pub fn load() -> Self {
+        let Some(path) = settings_path() else { return Self::default() };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                println!("Ignoring invalid settings in {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = settings_path().ok_or_else(|| anyhow::anyhow!("No config directory on this system"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Never write a password to disk
+        let mut settings = self.clone();
+        settings.extract.password = None;
+        std::fs::write(&path, serde_json::to_string_pretty(&settings)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,660 @@
+use anyhow::Result;
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::compression::{find_zip_directory, u16_at, u32_at, u64_at, CompressionError};
+use crate::file_utils::TempFile;
+use crate::password::{check_password_policy, PasswordPolicy};
+
+// Changing the password of an encrypted zip without recompressing anything.
+// Traditional PKWARE encryption and WinZip AES both leave the encrypted data exactly as
+// long as the compressed data, so every entry keeps its size and offset: only the
+// encrypted bytes are rewritten and the headers and central directory are copied as-is.
+
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_STRONG_ENCRYPTION: u16 = 1 << 6;
+const METHOD_AES: u16 = 99;
+const AES_EXTRA_ID: u16 = 0x9901;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+// PKWARE encryption header in front of the data, and the WinZip AES trailer
+const ZIPCRYPTO_HEADER_LEN: u64 = 12;
+const AES_PASSWORD_CHECK_LEN: u64 = 2;
+const AES_AUTH_CODE_LEN: usize = 10;
+const AES_KEY_ITERATIONS: u32 = 1000;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+enum Encryption {
+    ZipCrypto,
+    // Key strength 1-3 (AES-128/192/256)
+    Aes(u8),
+}
+
+struct EncryptedEntry {
+    name: String,
+    flags: u16,
+    crc: u32,
+    modified_time: u16,
+    compressed_size: u64,
+    local_header_offset: u64,
+    encryption: Encryption,
+}
+
+// Re-encrypt every encrypted entry of the zip at `file_path` under `new_password`,
+// replacing the file once all of them are done. Progress goes over the encrypted bytes.
+// With a policy, a new password that breaks it is refused with WeakPassword before
+// the archive is read.
+pub fn reencrypt_zip<F>(file_path: &Path, old_password: &str, new_password: &str, policy: Option<&PasswordPolicy>, mut progress_callback: F) -> Result<()>
+where
+    F: FnMut(f64, String),
+{
+    if let Some(policy) = policy {
+        check_password_policy(new_password, policy)?;
+    }
+    let mut input = BufReader::new(File::open(file_path)?);
+    let mut entries = read_encrypted_entries(&mut input)?;
+    if entries.is_empty() {
+        return Err(anyhow::anyhow!("The archive has no encrypted entries"));
+    }
+    entries.sort_by_key(|entry| entry.local_header_offset);
+
+    // The PKWARE header only checks one byte of the key, so about 1 in 256 wrong
+    // passwords would get through and garble the data; decode those entries first
+    if entries.iter().any(|entry| matches!(entry.encryption, Encryption::ZipCrypto)) {
+        verify_password(file_path, &entries, old_password)?;
+    }
+
+    let parent = file_path.parent().unwrap_or_else(|| Path::new("."));
+    let (temp_file, output) = TempFile::new_in(parent, ".tauzip-reencrypt-")?;
+    let mut output = BufWriter::new(output);
+
+    let total: u64 = entries.iter().map(|entry| entry.compressed_size).sum();
+    let mut done = 0u64;
+    let mut position = 0u64;
+    input.seek(SeekFrom::Start(0))?;
+
+    for entry in &entries {
+        // Everything up to the entry's data (earlier entries, its local header) is unchanged
+        let data_offset = entry_data_offset(&mut input, entry.local_header_offset)?;
+        input.seek(SeekFrom::Start(position))?;
+        copy_exact(&mut input, &mut output, data_offset - position)?;
+
+        progress_callback(done as f64 / total.max(1) as f64 * 100.0, entry.name.clone());
+        match entry.encryption {
+            Encryption::ZipCrypto => reencrypt_zipcrypto(&mut input, &mut output, entry, old_password, new_password)?,
+            Encryption::Aes(strength) => reencrypt_aes(&mut input, &mut output, entry, strength, old_password, new_password)?,
+        }
+        done += entry.compressed_size;
+        position = data_offset + entry.compressed_size;
+    }
+
+    // The central directory and end record
+    std::io::copy(&mut input, &mut output)?;
+    output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    drop(input);
+    temp_file.persist(file_path)?;
+
+    progress_callback(100.0, "Complete".to_string());
+    Ok(())
+}
+
+fn read_encrypted_entries<R: Read + Seek>(file: &mut R) -> Result<Vec<EncryptedEntry>> {
+    let directory = find_zip_directory(file)?
+        .ok_or_else(|| anyhow::anyhow!("Not a zip archive (no end of central directory record)"))?;
+    if directory.disk != 0 {
+        return Err(CompressionError::Unsupported { feature: "disk spanning (a multi-part .z01/.z02 archive)".to_string() }.into());
+    }
+
+    file.seek(SeekFrom::Start(directory.offset))?;
+    let mut entries = Vec::new();
+    for _ in 0..directory.entries {
+        let mut header = [0u8; 46];
+        file.read_exact(&mut header)?;
+        if header[..4] != [0x50, 0x4b, 0x01, 0x02] {
+            return Err(anyhow::anyhow!("Damaged central directory"));
+        }
+        let mut name = vec![0u8; u16_at(&header, 28) as usize];
+        let mut extra = vec![0u8; u16_at(&header, 30) as usize];
+        file.read_exact(&mut name)?;
+        file.read_exact(&mut extra)?;
+        file.seek(SeekFrom::Current(u16_at(&header, 32) as i64))?;
+
+        let flags = u16_at(&header, 8);
+        if flags & FLAG_ENCRYPTED == 0 {
+            continue;
+        }
+        if flags & FLAG_STRONG_ENCRYPTION != 0 {
+            return Err(CompressionError::Unsupported { feature: "strong encryption".to_string() }.into());
+        }
+
+        let encryption = if u16_at(&header, 10) == METHOD_AES {
+            // Version (2), vendor "AE" (2), strength (1), actual method (2)
+            let strength = find_extra_field(&extra, AES_EXTRA_ID)
+                .filter(|data| data.len() >= 7)
+                .map(|data| data[4])
+                .filter(|strength| (1..=3).contains(strength))
+                .ok_or_else(|| anyhow::anyhow!("Missing or invalid AES extra field"))?;
+            Encryption::Aes(strength)
+        } else {
+            Encryption::ZipCrypto
+        };
+
+        // Sizes and offsets that don't fit in 32 bits are in the Zip64 extra field, in
+        // this order, and only the ones that overflowed
+        let uncompressed_size = u32_at(&header, 24) as u64;
+        let mut compressed_size = u32_at(&header, 20) as u64;
+        let mut local_header_offset = u32_at(&header, 42) as u64;
+        if let Some(zip64) = find_extra_field(&extra, ZIP64_EXTRA_ID) {
+            let mut at = 0;
+            if uncompressed_size == 0xFFFF_FFFF {
+                at += 8;
+            }
+            if compressed_size == 0xFFFF_FFFF && zip64.len() >= at + 8 {
+                compressed_size = u64_at(zip64, at);
+                at += 8;
+            }
+            if local_header_offset == 0xFFFF_FFFF && zip64.len() >= at + 8 {
+                local_header_offset = u64_at(zip64, at);
+            }
+        }
+
+        entries.push(EncryptedEntry {
+            name: String::from_utf8_lossy(&name).to_string(),
+            flags,
+            crc: u32_at(&header, 16),
+            modified_time: u16_at(&header, 12),
+            compressed_size,
+            local_header_offset,
+            encryption,
+        });
+    }
+    Ok(entries)
+}
+
+fn find_extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+    let mut at = 0;
+    while at + 4 <= extra.len() {
+        let len = u16_at(extra, at + 2) as usize;
+        let end = (at + 4 + len).min(extra.len());
+        if u16_at(extra, at) == id {
+            return Some(&extra[at + 4..end]);
+        }
+        at = end;
+    }
+    None
+}
+
+// The data follows the local header, whose name and extra field may differ in length
+// from the central directory's copy
+fn entry_data_offset<R: Read + Seek>(file: &mut R, local_header_offset: u64) -> Result<u64> {
+    let mut header = [0u8; 30];
+    file.seek(SeekFrom::Start(local_header_offset))?;
+    file.read_exact(&mut header)?;
+    if header[..4] != [0x50, 0x4b, 0x03, 0x04] {
+        return Err(anyhow::anyhow!("Damaged local header at offset {}", local_header_offset));
+    }
+    Ok(local_header_offset + 30 + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64)
+}
+
+fn copy_exact<R: Read, W: Write>(reader: &mut R, writer: &mut W, len: u64) -> Result<()> {
+    let copied = std::io::copy(&mut reader.take(len), writer)?;
+    if copied != len {
+        return Err(anyhow::anyhow!("Unexpected end of archive"));
+    }
+    Ok(())
+}
+
+// Decode the ZipCrypto entries with the zip crate, which checks each one's CRC. A
+// wrong password that gets past the check byte garbles the data, so it shows up as
+// a CRC mismatch or as compressed data that doesn't decode; any other error is the
+// archive's own and is passed on.
+fn verify_password(file_path: &Path, entries: &[EncryptedEntry], password: &str) -> Result<()> {
+    let zipcrypto: HashSet<u64> = entries.iter()
+        .filter(|entry| matches!(entry.encryption, Encryption::ZipCrypto))
+        .map(|entry| entry.local_header_offset)
+        .collect();
+    let mut archive = zip::ZipArchive::new(FullReads(BufReader::new(File::open(file_path)?)))?;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    for index in 0..archive.len() {
+        if !zipcrypto.contains(&archive.by_index_raw(index)?.header_start()) {
+            continue;
+        }
+
+        let mut file = match archive.by_index_decrypt(index, password.as_bytes())? {
+            Ok(file) => file,
+            Err(_) => return Err(CompressionError::WrongPassword.into()),
+        };
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::InvalidData || e.to_string() == "Invalid checksum" => {
+                    return Err(CompressionError::WrongPassword.into());
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Fills each read as far as the file goes. The zip crate's ZipCrypto reader decrypts,
+// and moves its keys on over, the whole buffer it is given even when the read under it
+// came back short, which garbles the rest of the entry; a BufReader comes back short at
+// every refill.
+struct FullReads<R>(R);
+
+impl<R: Read> Read for FullReads<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.0.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(bytes) => filled += bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl<R: Seek> Seek for FullReads<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+fn random_bytes(buffer: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buffer).map_err(|e| anyhow::anyhow!("No random numbers available: {}", e))
+}
+
+fn reencrypt_zipcrypto<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    entry: &EncryptedEntry,
+    old_password: &str,
+    new_password: &str,
+) -> Result<()> {
+    if entry.compressed_size < ZIPCRYPTO_HEADER_LEN {
+        return Err(anyhow::anyhow!("Damaged encryption header in '{}'", entry.name));
+    }
+    let mut old_keys = ZipCryptoKeys::new(old_password.as_bytes());
+    let mut new_keys = ZipCryptoKeys::new(new_password.as_bytes());
+
+    // The last header byte checks the key: the CRC's high byte, or the modification
+    // time's when the CRC comes after the data
+    let check = if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+        (entry.modified_time >> 8) as u8
+    } else {
+        (entry.crc >> 24) as u8
+    };
+    let mut header = [0u8; ZIPCRYPTO_HEADER_LEN as usize];
+    input.read_exact(&mut header)?;
+    old_keys.decrypt(&mut header);
+    if header[11] != check {
+        return Err(CompressionError::WrongPassword.into());
+    }
+    random_bytes(&mut header[..11])?;
+    new_keys.encrypt(&mut header);
+    output.write_all(&header)?;
+
+    let mut remaining = entry.compressed_size - ZIPCRYPTO_HEADER_LEN;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(BUFFER_SIZE as u64) as usize];
+        input.read_exact(chunk)?;
+        old_keys.decrypt(chunk);
+        new_keys.encrypt(chunk);
+        output.write_all(chunk)?;
+        remaining -= chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn reencrypt_aes<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    entry: &EncryptedEntry,
+    strength: u8,
+    old_password: &str,
+    new_password: &str,
+) -> Result<()> {
+    let salt_len = 4 + strength as usize * 4;
+    let overhead = salt_len as u64 + AES_PASSWORD_CHECK_LEN + AES_AUTH_CODE_LEN as u64;
+    if entry.compressed_size < overhead {
+        return Err(anyhow::anyhow!("Damaged AES header in '{}'", entry.name));
+    }
+
+    let mut salt = vec![0u8; salt_len];
+    let mut password_check = [0u8; AES_PASSWORD_CHECK_LEN as usize];
+    input.read_exact(&mut salt)?;
+    input.read_exact(&mut password_check)?;
+    let old_key = AesKey::derive(old_password.as_bytes(), &salt, strength)?;
+    if old_key.password_check != password_check {
+        return Err(CompressionError::WrongPassword.into());
+    }
+
+    random_bytes(&mut salt)?;
+    let new_key = AesKey::derive(new_password.as_bytes(), &salt, strength)?;
+    output.write_all(&salt)?;
+    output.write_all(&new_key.password_check)?;
+
+    let mut old_stream = AesCtr::new(&old_key);
+    let mut new_stream = AesCtr::new(&new_key);
+    let mut old_mac = old_key.mac.clone();
+    let mut new_mac = new_key.mac.clone();
+
+    let mut remaining = entry.compressed_size - overhead;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(BUFFER_SIZE as u64) as usize];
+        input.read_exact(chunk)?;
+        // The authentication code covers the encrypted bytes
+        old_mac.update(chunk);
+        old_stream.apply(chunk);
+        new_stream.apply(chunk);
+        new_mac.update(chunk);
+        output.write_all(chunk)?;
+        remaining -= chunk.len() as u64;
+    }
+
+    let mut auth_code = [0u8; AES_AUTH_CODE_LEN];
+    input.read_exact(&mut auth_code)?;
+    if old_mac.finalize().into_bytes()[..AES_AUTH_CODE_LEN] != auth_code {
+        return Err(CompressionError::Corrupt {
+            reason: format!("'{}' fails its AES authentication check", entry.name),
+        }.into());
+    }
+    output.write_all(&new_mac.finalize().into_bytes()[..AES_AUTH_CODE_LEN])?;
+    Ok(())
+}
+
+// Traditional PKWARE ("ZipCrypto") stream cipher state
+struct ZipCryptoKeys {
+    keys: [u32; 3],
+}
+
+impl ZipCryptoKeys {
+    fn new(password: &[u8]) -> Self {
+        let mut keys = Self { keys: [0x1234_5678, 0x2345_6789, 0x3456_7890] };
+        for &byte in password {
+            keys.update(byte);
+        }
+        keys
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.keys[0] = crc32_byte(self.keys[0], byte);
+        self.keys[1] = self.keys[1].wrapping_add(self.keys[0] & 0xFF).wrapping_mul(134_775_813).wrapping_add(1);
+        self.keys[2] = crc32_byte(self.keys[2], (self.keys[1] >> 24) as u8);
+    }
+
+    fn stream_byte(&self) -> u8 {
+        let temp = (self.keys[2] | 2) as u16;
+        (temp.wrapping_mul(temp ^ 1) >> 8) as u8
+    }
+
+    fn decrypt(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte ^= self.stream_byte();
+            self.update(*byte);
+        }
+    }
+
+    fn encrypt(&mut self, data: &mut [u8]) {
+        for byte in data {
+            let plain = *byte;
+            *byte ^= self.stream_byte();
+            self.update(plain);
+        }
+    }
+}
+
+// One step of CRC-32 without the usual pre/post inversion, as the key schedule needs
+fn crc32_byte(crc: u32, byte: u8) -> u32 {
+    (crc >> 8) ^ CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize]
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+enum AesCipher {
+    Aes128(aes::Aes128),
+    Aes192(aes::Aes192),
+    Aes256(aes::Aes256),
+}
+
+// Keys derived from the password and an entry's salt (PBKDF2-HMAC-SHA1, 1000 rounds):
+// the AES key, the HMAC-SHA1 key and a 2-byte password check
+struct AesKey {
+    cipher: AesCipher,
+    mac: Hmac<Sha1>,
+    password_check: [u8; 2],
+}
+
+impl AesKey {
+    fn derive(password: &[u8], salt: &[u8], strength: u8) -> Result<Self> {
+        let key_len = 8 + strength as usize * 8;
+        let mut derived = vec![0u8; key_len * 2 + 2];
+        pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, AES_KEY_ITERATIONS, &mut derived);
+
+        let key = &derived[..key_len];
+        let cipher = match strength {
+            1 => AesCipher::Aes128(aes::Aes128::new(GenericArray::from_slice(key))),
+            2 => AesCipher::Aes192(aes::Aes192::new(GenericArray::from_slice(key))),
+            _ => AesCipher::Aes256(aes::Aes256::new(GenericArray::from_slice(key))),
+        };
+        let mac = <Hmac<Sha1> as Mac>::new_from_slice(&derived[key_len..key_len * 2])
+            .map_err(|e| anyhow::anyhow!("Invalid HMAC key: {}", e))?;
+        Ok(Self {
+            cipher,
+            mac,
+            password_check: [derived[key_len * 2], derived[key_len * 2 + 1]],
+        })
+    }
+}
+
+// WinZip's AES counter mode: a little-endian block counter starting at 1. Applying it
+// encrypts and decrypts alike.
+struct AesCtr<'a> {
+    key: &'a AesKey,
+    counter: u128,
+    block: [u8; 16],
+    used: usize,
+}
+
+impl<'a> AesCtr<'a> {
+    fn new(key: &'a AesKey) -> Self {
+        Self { key, counter: 0, block: [0u8; 16], used: 16 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == 16 {
+                self.counter += 1;
+                let mut block = GenericArray::from(self.counter.to_le_bytes());
+                match &self.key.cipher {
+                    AesCipher::Aes128(cipher) => cipher.encrypt_block(&mut block),
+                    AesCipher::Aes192(cipher) => cipher.encrypt_block(&mut block),
+                    AesCipher::Aes256(cipher) => cipher.encrypt_block(&mut block),
+                }
+                self.block.copy_from_slice(&block);
+                self.used = 0;
+            }
+            *byte ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_new_password_breaking_the_policy_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locked.zip");
+        std::fs::write(&path, b"not read").unwrap();
+        let policy = PasswordPolicy { min_length: 12, min_score: None };
+        let error = reencrypt_zip(&path, "old", "short", Some(&policy), |_, _| {}).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(CompressionError::WeakPassword { .. })), "{}", error);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not read");
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        data.iter().fold(0xFFFF_FFFF, |crc, &byte| crc32_byte(crc, byte)) ^ 0xFFFF_FFFF
+    }
+
+    // A zip of stored entries encrypted under `password`, with ZipCrypto or, given a key
+    // strength, AE-1 AES. The zip crate can decrypt both but not write them. Salts and
+    // headers are fixed so the fixture is the same every run.
+    fn encrypted_zip(path: &Path, entries: &[(&str, &[u8])], password: &str, aes_strength: Option<u8>) {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, contents) in entries {
+            let crc = crc32(contents);
+            let mut data = Vec::new();
+            let mut extra = Vec::new();
+            match aes_strength {
+                None => {
+                    let mut header = [7u8; ZIPCRYPTO_HEADER_LEN as usize];
+                    header[11] = (crc >> 24) as u8;
+                    let mut encrypted = [&header[..], contents].concat();
+                    ZipCryptoKeys::new(password.as_bytes()).encrypt(&mut encrypted);
+                    data.extend_from_slice(&encrypted);
+                }
+                Some(strength) => {
+                    let salt = vec![9u8; 4 + strength as usize * 4];
+                    let key = AesKey::derive(password.as_bytes(), &salt, strength).unwrap();
+                    let mut encrypted = contents.to_vec();
+                    AesCtr::new(&key).apply(&mut encrypted);
+                    let mut mac = key.mac.clone();
+                    mac.update(&encrypted);
+                    data.extend_from_slice(&salt);
+                    data.extend_from_slice(&key.password_check);
+                    data.extend_from_slice(&encrypted);
+                    data.extend_from_slice(&mac.finalize().into_bytes()[..AES_AUTH_CODE_LEN]);
+                    // Version 1 (AE-1, which keeps the CRC), vendor, strength, stored
+                    extra.extend_from_slice(&AES_EXTRA_ID.to_le_bytes());
+                    extra.extend_from_slice(&7u16.to_le_bytes());
+                    extra.extend_from_slice(&[1, 0, b'A', b'E', strength, 0, 0]);
+                }
+            }
+            let method: u16 = if aes_strength.is_some() { METHOD_AES } else { 0 };
+            
+            // Fields shared by the local and central headers, from version needed on
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&[51, 0]);
+            fields.extend_from_slice(&FLAG_ENCRYPTED.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0, 0, 0x21, 0x50]);
+            fields.extend_from_slice(&crc.to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            
+            let offset = archive.len() as u32;
+            archive.extend_from_slice(&[0x50, 0x4b, 0x03, 0x04]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&extra);
+            archive.extend_from_slice(&data);
+            
+            directory.extend_from_slice(&[0x50, 0x4b, 0x01, 0x02, 51, 0]);
+            directory.extend_from_slice(&fields);
+            // Comment length, disk, internal and external attributes, then the offset
+            directory.extend_from_slice(&[0u8; 10]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+            directory.extend_from_slice(&extra);
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&[0, 0]);
+        std::fs::write(path, archive).unwrap();
+    }
+
+    // The entry's contents as the zip crate decrypts them, or None for a wrong password
+    fn decrypted(path: &Path, name: &str, password: &str) -> Option<Vec<u8>> {
+        let mut archive = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut file = archive.by_name_decrypt(name, password.as_bytes()).unwrap().ok()?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).ok()?;
+        Some(contents)
+    }
+
+    const FILES: [(&str, &[u8]); 2] = [("notes.txt", b"meet at noon"), ("data.bin", &[0x55; 70_000])];
+
+    #[test]
+    fn zipcrypto_entries_decrypt_under_the_new_password_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locked.zip");
+        encrypted_zip(&path, &FILES, "old secret", None);
+        assert_eq!(decrypted(&path, "notes.txt", "old secret").unwrap(), b"meet at noon");
+        
+        reencrypt_zip(&path, "old secret", "new secret", None, |_, _| {}).unwrap();
+        for (name, contents) in FILES {
+            assert_eq!(decrypted(&path, name, "new secret").unwrap(), contents);
+            assert_eq!(decrypted(&path, name, "old secret"), None);
+        }
+    }
+
+    #[test]
+    fn aes_entries_decrypt_under_the_new_password_only() {
+        let dir = tempfile::tempdir().unwrap();
+        for strength in 1..=3 {
+            let path = dir.path().join(format!("aes{}.zip", strength));
+            encrypted_zip(&path, &FILES, "old secret", Some(strength));
+            assert_eq!(decrypted(&path, "data.bin", "old secret").unwrap(), FILES[1].1);
+            
+            reencrypt_zip(&path, "old secret", "new secret", None, |_, _| {}).unwrap();
+            for (name, contents) in FILES {
+                assert_eq!(decrypted(&path, name, "new secret").unwrap(), contents, "AES strength {}", strength);
+                assert_eq!(decrypted(&path, name, "old secret"), None);
+            }
+        }
+    }
+
+    #[test]
+    fn a_wrong_old_password_is_refused_and_leaves_the_archive_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        for aes_strength in [None, Some(3)] {
+            let path = dir.path().join("locked.zip");
+            encrypted_zip(&path, &FILES, "old secret", aes_strength);
+            let before = std::fs::read(&path).unwrap();
+            
+            let error = reencrypt_zip(&path, "guess", "new secret", None, |_, _| {}).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(CompressionError::WrongPassword)), "{}", error);
+            assert_eq!(std::fs::read(&path).unwrap(), before);
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+    }
+}